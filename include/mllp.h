@@ -0,0 +1,44 @@
+/* C bindings of mllp-rs, built with the `ffi` feature. See src/ffi.rs for the details. */
+
+#ifndef MLLP_H
+#define MLLP_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define MLLP_OK 0
+#define MLLP_INCOMPLETE 1
+#define MLLP_ERR_ARGUMENT (-1)
+#define MLLP_ERR_IO (-2)
+#define MLLP_ERR_SYNTAX (-3)
+#define MLLP_ERR_TIMEOUT (-4)
+#define MLLP_ERR_NAK (-5)
+#define MLLP_ERR_PROTOCOL (-6)
+
+typedef struct mllp_decoder mllp_decoder;
+typedef struct mllp_client mllp_client;
+
+/* Buffers written to `out` or `ack` are released with mllp_buffer_free. */
+int mllp_encode(const uint8_t *payload, size_t len, uint8_t **out, size_t *out_len);
+void mllp_buffer_free(uint8_t *buffer, size_t len);
+
+mllp_decoder *mllp_decoder_new(void);
+int mllp_decoder_extend(mllp_decoder *decoder, const uint8_t *data, size_t len);
+int mllp_decoder_next_frame(mllp_decoder *decoder, uint8_t **out, size_t *out_len);
+void mllp_decoder_free(mllp_decoder *decoder);
+
+/* An ack_timeout_ms of 0 waits forever. */
+int mllp_client_connect(const char *addr, uint64_t ack_timeout_ms, uint32_t max_retries, mllp_client **client);
+/* On MLLP_OK, `ack` is NULL for a commit ACK. */
+int mllp_client_send(mllp_client *client, const uint8_t *payload, size_t len, uint8_t **ack, size_t *ack_len);
+void mllp_client_free(mllp_client *client);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,208 @@
+//! Snapshot of the public API.
+//!
+//! The public items of the library, as declared in the sources, are listed in `api/stable.txt`
+//! and `api/unstable.txt`. The test fails when the listing changes, so that any change to the
+//! public API shows up in review as a diff of these files. After an intended change, update them
+//! with `MLLP_UPDATE_API=1 cargo test api`; a change removing or modifying a line of
+//! `api/stable.txt` is a breaking change.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Modules only available with the `unstable` feature.
+const UNSTABLE: &[&str] = &["cluster", "leader"];
+
+/// Public declarations of the sources under `src`, one per line, prefixed by their module.
+fn public_items(src: &Path) -> (Vec<String>, Vec<String>) {
+    let mut files = Vec::new();
+    collect_sources(src, &mut files);
+    files.sort();
+
+    let mut stable = Vec::new();
+    let mut unstable = Vec::new();
+    for file in files {
+        let module = module_path(src, &file);
+        let tier = if UNSTABLE.contains(&module.as_str()) { &mut unstable } else { &mut stable };
+        let source = fs::read_to_string(&file).unwrap();
+        tier.extend(declarations(&source).into_iter().map(|item| format!("{}: {}", module, item)));
+    }
+
+    (stable, unstable)
+}
+
+fn collect_sources(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            // binaries are not part of the library
+            if !path.ends_with("bin") {
+                collect_sources(&path, files);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "rs") && !path.ends_with("api.rs") {
+            files.push(path);
+        }
+    }
+}
+
+/// `lib.rs` is `crate`, `server/worker_pool.rs` is `server::worker_pool`.
+fn module_path(src: &Path, file: &Path) -> String {
+    let relative = file.strip_prefix(src).unwrap().with_extension("");
+    let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy().into_owned()).collect();
+    match parts.as_slice() {
+        [lib] if lib == "lib" => "crate".to_owned(),
+        parts => parts.join("::"),
+    }
+}
+
+/// Public items of `source`: `pub` declarations, with the block they are in, and the variants
+/// and methods of public enums and traits. Test modules are left out.
+fn declarations(source: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut block: Option<String> = None;
+    let mut lines = source.lines();
+
+    while let Some(line) = lines.next() {
+        if line.starts_with("#[cfg(test)]") {
+            // skip the test module
+            if lines.next().is_some_and(|item| item.ends_with('{')) {
+                lines.by_ref().find(|line| *line == "}");
+            }
+            continue;
+        }
+        let trimmed = line.trim();
+        if trimmed.starts_with("//") || trimmed.starts_with("#[") || trimmed.is_empty() {
+            continue;
+        }
+        if line == "}" {
+            block = None;
+            continue;
+        }
+
+        if !line.starts_with(' ') {
+            let opens_block = line.ends_with('{');
+            let item = signature(trimmed, &mut lines);
+            if trimmed.starts_with("pub ") {
+                items.push(item.clone());
+            }
+            block = opens_block.then_some(item);
+            continue;
+        }
+
+        let Some(header) = &block else {
+            continue;
+        };
+        // members are indented once; deeper lines are bodies
+        if !line.starts_with("    ") || line.starts_with("     ") || trimmed.starts_with('}') {
+            continue;
+        }
+        let private_type = ["struct ", "enum ", "trait "].iter().any(|kind| header.starts_with(kind));
+        let implicitly_public = header.starts_with("pub enum ") || header.starts_with("pub trait ");
+        if (trimmed.starts_with("pub ") && !private_type) || implicitly_public {
+            let member = signature(trimmed, &mut lines);
+            items.push(format!("{} => {}", header, member));
+        }
+    }
+
+    items
+}
+
+/// Declaration starting with `first`, joined with the following lines until its body or end.
+fn signature<'a>(first: &str, lines: &mut impl Iterator<Item = &'a str>) -> String {
+    let mut signature = first.to_owned();
+    // parameters of functions may each be on their own line
+    let ends = |signature: &str| match signature.contains("fn ") {
+        true => signature.ends_with(['{', ';']),
+        false => signature.ends_with(['{', ';', ',', '}']),
+    };
+    while !ends(&signature) {
+        match lines.next() {
+            Some(line) => {
+                signature.push(' ');
+                signature.push_str(line.trim());
+            }
+            None => break,
+        }
+    }
+
+    signature.trim_end_matches(" {").trim_end_matches([',', ';']).to_owned()
+}
+
+fn check_snapshot(path: &Path, items: &[String]) {
+    let listing = items.iter().map(|item| format!("{}\n", item)).collect::<String>();
+    if env::var_os("MLLP_UPDATE_API").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, listing).unwrap();
+        return;
+    }
+
+    let snapshot = fs::read_to_string(path).unwrap_or_default();
+    let removed: Vec<_> = snapshot.lines().filter(|line| !items.iter().any(|item| item == line)).collect();
+    let added: Vec<_> = items.iter().filter(|item| !snapshot.lines().any(|line| line == item.as_str())).collect();
+    assert!(
+        removed.is_empty() && added.is_empty(),
+        "public API differs from {}, update it with MLLP_UPDATE_API=1 if intended\nremoved:\n{}\nadded:\n{}",
+        path.display(),
+        removed.join("\n"),
+        added.iter().map(|item| item.as_str()).collect::<Vec<_>>().join("\n"),
+    );
+}
+
+#[test]
+fn it_matches_public_api_snapshot() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let (stable, unstable) = public_items(&root.join("src"));
+
+    check_snapshot(&root.join("api/stable.txt"), &stable);
+    check_snapshot(&root.join("api/unstable.txt"), &unstable);
+}
+
+#[test]
+fn it_lists_declarations() {
+    let source = "\
+pub struct Config {
+    /// Doc.
+    pub timeout: u32,
+    private: bool,
+}
+
+pub enum Mode {
+    Fast,
+    Slow(u32),
+}
+
+struct Hidden {
+    pub field: u32,
+}
+
+impl Config {
+    pub fn new(
+        timeout: u32,
+    ) -> Self {
+        Config { timeout, private: false }
+    }
+
+    fn hidden(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    pub fn helper() {}
+}
+
+pub const LAST: u8 = 0;
+";
+
+    assert_eq!(
+        declarations(source),
+        vec![
+            "pub struct Config",
+            "pub struct Config => pub timeout: u32",
+            "pub enum Mode",
+            "pub enum Mode => Fast",
+            "pub enum Mode => Slow(u32)",
+            "impl Config => pub fn new( timeout: u32, ) -> Self",
+            "pub const LAST: u8 = 0",
+        ]
+    );
+}
@@ -0,0 +1,478 @@
+//! gzip-compressed archives of messages, with the `archive` feature.
+//!
+//! An archive keeps every message of a feed with when, from or to whom, and in which direction
+//! it went, for replay and export long after [captures](crate::capture) are gone. Records are
+//! written one after the other into a gzip stream with an [`ArchiveWriter`], and read back one at
+//! a time by iterating over an [`ArchiveReader`], so years of traffic never have to fit in memory.
+//! ```no_run
+//! use std::time::SystemTime;
+//! use mllp_rs::archive::{ArchiveReader, ArchiveWriter, RecordMetadata};
+//! use mllp_rs::capture::Direction;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let mut writer = ArchiveWriter::open("/var/lib/mllp/archive/2024-01.gz")?;
+//! let metadata = RecordMetadata {
+//!     time: SystemTime::now(),
+//!     direction: Direction::Inbound,
+//!     peer_addr: "10.20.0.5:40112".parse().unwrap(),
+//! };
+//! writer.append(&metadata, b"MSH|^~\\&|...")?;
+//! writer.finish()?;
+//!
+//! for record in ArchiveReader::open("/var/lib/mllp/archive/2024-01.gz")? {
+//!     let (metadata, payload) = record?;
+//!     println!("{:?} {}", metadata.direction, String::from_utf8_lossy(&payload));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Records are laid out in an [`ArchiveFormat`], [`LengthPrefixed`] unless another one is given
+//! with [`ArchiveWriter::with_format`], and the same to [`ArchiveReader::with_format`].
+//!
+//! Each [`ArchiveWriter`] writes a gzip member of its own, and opening an existing archive
+//! appends a new member to it, so an archive can be written to across restarts. A member cut
+//! short by a crash ends the iteration with an [`io::ErrorKind::UnexpectedEof`] error, after the
+//! records before it.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter::Peekable;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::Chars;
+use std::time::{SystemTime, UNIX_EPOCH};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use crate::capture::Direction;
+use crate::event::{format_rfc3339, json_string, parse_rfc3339};
+use crate::journal;
+use crate::{base64, MllpCodec, CR, EB};
+
+/// What is archived along with a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordMetadata {
+    /// When the message was sent or received.
+    pub time: SystemTime,
+    pub direction: Direction,
+    /// Receiver of an outbound message, sender of an inbound one.
+    pub peer_addr: SocketAddr,
+}
+
+/// Layout of the records in an archive.
+///
+/// Built-in formats are [`LengthPrefixed`], the default, [`RawFramed`] and [`NdJson`]. A format
+/// only deals with the records: archives are gzip-compressed whatever the format, and
+/// decompressed by [`ArchiveReader`] before the format reads them.
+pub trait ArchiveFormat {
+    /// Writes the record of a message.
+    fn write_record(&self, writer: &mut dyn Write, metadata: &RecordMetadata, payload: &[u8]) -> io::Result<()>;
+
+    /// Reads the next record, or returns `None` at the end of the stream, between two records.
+    fn read_record(&self, reader: &mut dyn BufRead) -> io::Result<Option<(RecordMetadata, Vec<u8>)>>;
+}
+
+/// Compact binary records: the time in milliseconds since the epoch as a big-endian `u64`, the
+/// direction as a byte, 0 for inbound and 1 for outbound, the length of the peer address as a
+/// byte and the address as text, and the length of the payload as a big-endian `u32` and the
+/// payload. The entries of a [frame journal](crate::journal) have the same layout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LengthPrefixed;
+
+impl ArchiveFormat for LengthPrefixed {
+    fn write_record(&self, writer: &mut dyn Write, metadata: &RecordMetadata, payload: &[u8]) -> io::Result<()> {
+        journal::write_record(writer, metadata.time, metadata.direction, metadata.peer_addr, payload)
+    }
+
+    fn read_record(&self, reader: &mut dyn BufRead) -> io::Result<Option<(RecordMetadata, Vec<u8>)>> {
+        let record = journal::read_record(reader)?;
+        Ok(record.map(|entry| {
+            let metadata = RecordMetadata {
+                time: entry.time,
+                direction: entry.direction,
+                peer_addr: entry.peer_addr,
+            };
+            (metadata, entry.frame)
+        }))
+    }
+}
+
+/// MLLP frames, `<SB>payload<EB><CR>`, as they were on the wire, for the tools replaying
+/// captured streams. The metadata is not kept: records are read back as inbound, from
+/// `0.0.0.0:0` at the epoch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RawFramed;
+
+impl ArchiveFormat for RawFramed {
+    fn write_record(&self, writer: &mut dyn Write, _: &RecordMetadata, payload: &[u8]) -> io::Result<()> {
+        writer.write_all(&MllpCodec::encode(payload))
+    }
+
+    fn read_record(&self, reader: &mut dyn BufRead) -> io::Result<Option<(RecordMetadata, Vec<u8>)>> {
+        let mut frame = Vec::new();
+        loop {
+            if reader.read_until(CR, &mut frame)? == 0 {
+                return match frame.is_empty() {
+                    true => Ok(None),
+                    false => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                };
+            }
+            if frame.ends_with(&[EB, CR]) {
+                break;
+            }
+        }
+        let payload = MllpCodec::decode(&frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let metadata = RecordMetadata {
+            time: UNIX_EPOCH,
+            direction: Direction::Inbound,
+            peer_addr: SocketAddr::from(([0, 0, 0, 0], 0)),
+        };
+        Ok(Some((metadata, payload.to_vec())))
+    }
+}
+
+/// A JSON object per line, for log tooling and `jq`:
+/// `{"time":"2024-01-31T08:00:00.000Z","direction":"inbound","peer_addr":"10.20.0.5:40112","payload":"MSH|..."}`.
+/// A payload which is not UTF-8 is stored base64-encoded in `payload_base64` instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NdJson;
+
+impl ArchiveFormat for NdJson {
+    fn write_record(&self, writer: &mut dyn Write, metadata: &RecordMetadata, payload: &[u8]) -> io::Result<()> {
+        let direction = match metadata.direction {
+            Direction::Inbound => "inbound",
+            Direction::Outbound => "outbound",
+        };
+        let payload = match std::str::from_utf8(payload) {
+            Ok(text) => format!("\"payload\":{}", json_string(text)),
+            Err(_) => format!("\"payload_base64\":\"{}\"", base64(payload)),
+        };
+
+        writeln!(
+            writer,
+            "{{\"time\":\"{}\",\"direction\":\"{}\",\"peer_addr\":{},{}}}",
+            format_rfc3339(metadata.time),
+            direction,
+            json_string(&metadata.peer_addr.to_string()),
+            payload
+        )
+    }
+
+    fn read_record(&self, reader: &mut dyn BufRead) -> io::Result<Option<(RecordMetadata, Vec<u8>)>> {
+        let mut line = String::new();
+        while line.trim().is_empty() {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+        }
+        let fields = parse_json_object(line.trim()).ok_or_else(|| invalid("invalid JSON record"))?;
+        let field = |name: &str| fields.get(name).ok_or_else(|| invalid(format!("record without {}", name)));
+
+        let time = parse_rfc3339(field("time")?).ok_or_else(|| invalid("invalid record time"))?;
+        let direction = match field("direction")?.as_str() {
+            "inbound" => Direction::Inbound,
+            "outbound" => Direction::Outbound,
+            _ => return Err(invalid("invalid record direction")),
+        };
+        let peer_addr = field("peer_addr")?.parse().map_err(|_| invalid("invalid record peer address"))?;
+        let payload = match (fields.get("payload"), fields.get("payload_base64")) {
+            (Some(payload), _) => payload.clone().into_bytes(),
+            (None, Some(encoded)) => decode_base64(encoded).ok_or_else(|| invalid("invalid record payload"))?,
+            (None, None) => return Err(invalid("record without payload")),
+        };
+
+        Ok(Some((RecordMetadata { time, direction, peer_addr }, payload)))
+    }
+}
+
+/// Writer of records into a gzip stream, in the format `F`.
+#[derive(Debug)]
+pub struct ArchiveWriter<W: Write, F: ArchiveFormat = LengthPrefixed> {
+    encoder: GzEncoder<W>,
+    format: F,
+}
+
+impl ArchiveWriter<File> {
+    /// Opens the archive at `path` to append records to it, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        ArchiveWriter::open_with_format(path, LengthPrefixed)
+    }
+}
+
+impl<F: ArchiveFormat> ArchiveWriter<File, F> {
+    /// Same as [`ArchiveWriter::open`], writing records in `format`.
+    pub fn open_with_format<P: AsRef<Path>>(path: P, format: F) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(ArchiveWriter::with_format(file, format))
+    }
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(inner: W) -> Self {
+        ArchiveWriter::with_format(inner, LengthPrefixed)
+    }
+}
+
+impl<W: Write, F: ArchiveFormat> ArchiveWriter<W, F> {
+    pub fn with_format(inner: W, format: F) -> Self {
+        ArchiveWriter {
+            encoder: GzEncoder::new(inner, Compression::default()),
+            format,
+        }
+    }
+
+    /// Appends the record of a message. Records are compressed in blocks: they are only all on
+    /// disk once the writer is [finished](ArchiveWriter::finish).
+    pub fn append(&mut self, metadata: &RecordMetadata, payload: &[u8]) -> io::Result<()> {
+        self.format.write_record(&mut self.encoder, metadata, payload)
+    }
+
+    /// Ends the gzip member, and returns the underlying writer, flushed.
+    pub fn finish(self) -> io::Result<W> {
+        let mut inner = self.encoder.finish()?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+/// Iterator over the records of a gzip stream written by [`ArchiveWriter`]s in the format `F`,
+/// oldest first.
+///
+/// Records are decompressed and read as the iteration goes. After an error, the iteration
+/// ends.
+#[derive(Debug)]
+pub struct ArchiveReader<R: Read, F: ArchiveFormat = LengthPrefixed> {
+    decoder: BufReader<MultiGzDecoder<R>>,
+    format: F,
+    failed: bool,
+}
+
+impl ArchiveReader<File> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        ArchiveReader::open_with_format(path, LengthPrefixed)
+    }
+}
+
+impl<F: ArchiveFormat> ArchiveReader<File, F> {
+    pub fn open_with_format<P: AsRef<Path>>(path: P, format: F) -> io::Result<Self> {
+        Ok(ArchiveReader::with_format(File::open(path)?, format))
+    }
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(inner: R) -> Self {
+        ArchiveReader::with_format(inner, LengthPrefixed)
+    }
+}
+
+impl<R: Read, F: ArchiveFormat> ArchiveReader<R, F> {
+    pub fn with_format(inner: R, format: F) -> Self {
+        ArchiveReader {
+            decoder: BufReader::new(MultiGzDecoder::new(inner)),
+            format,
+            failed: false,
+        }
+    }
+}
+
+impl<R: Read, F: ArchiveFormat> Iterator for ArchiveReader<R, F> {
+    type Item = io::Result<(RecordMetadata, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let record = self.format.read_record(&mut self.decoder);
+        self.failed = record.is_err();
+
+        record.transpose()
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Fields of a JSON object whose values are all strings.
+fn parse_json_object(text: &str) -> Option<HashMap<String, String>> {
+    let mut chars = text.strip_prefix('{')?.strip_suffix('}')?.trim().chars().peekable();
+    let mut fields = HashMap::new();
+
+    while chars.peek().is_some() {
+        let key = parse_json_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        (chars.next()? == ':').then_some(())?;
+        skip_whitespace(&mut chars);
+        let value = parse_json_string(&mut chars)?;
+        fields.insert(key, value);
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') => skip_whitespace(&mut chars),
+            None => break,
+            Some(_) => return None,
+        }
+    }
+
+    Some(fields)
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars<'_>>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_json_string(chars: &mut Peekable<Chars<'_>>) -> Option<String> {
+    (chars.next()? == '"').then_some(())?;
+    let mut text = String::new();
+
+    loop {
+        match chars.next()? {
+            '"' => return Some(text),
+            '\\' => match chars.next()? {
+                'n' => text.push('\n'),
+                'r' => text.push('\r'),
+                't' => text.push('\t'),
+                'b' => text.push('\u{8}'),
+                'f' => text.push('\u{c}'),
+                'u' => {
+                    let high = parse_code_unit(chars)?;
+                    let code = match high {
+                        0xD800..=0xDBFF => {
+                            (chars.next()? == '\\' && chars.next()? == 'u').then_some(())?;
+                            0x10000 + ((high - 0xD800) << 10) + (parse_code_unit(chars)?.checked_sub(0xDC00)?)
+                        }
+                        code => code,
+                    };
+                    text.push(char::from_u32(code)?);
+                }
+                c => text.push(c),
+            },
+            c => text.push(c),
+        }
+    }
+}
+
+fn parse_code_unit(chars: &mut Peekable<Chars<'_>>) -> Option<u32> {
+    u32::from_str_radix(&chars.take(4).collect::<String>(), 16).ok()
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut count = 0;
+
+    for c in text.bytes().take_while(|c| *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::archive::{ArchiveFormat, ArchiveReader, ArchiveWriter, NdJson, RawFramed, RecordMetadata};
+    use crate::capture::Direction;
+
+    fn metadata(second: u64, direction: Direction) -> RecordMetadata {
+        RecordMetadata {
+            time: UNIX_EPOCH + Duration::from_secs(second),
+            direction,
+            peer_addr: SocketAddr::from(([10, 20, 0, 5], 2575)),
+        }
+    }
+
+    #[test]
+    fn it_reads_records_appended_across_writers() {
+        let path = env::temp_dir().join(format!("mllp-rs-archive-{}.gz", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut writer = ArchiveWriter::open(&path).unwrap();
+        writer.append(&metadata(1, Direction::Inbound), b"MSH|1").unwrap();
+        writer.append(&metadata(2, Direction::Outbound), b"MSA|AA").unwrap();
+        writer.finish().unwrap();
+        let mut writer = ArchiveWriter::open(&path).unwrap();
+        writer.append(&metadata(3, Direction::Inbound), &vec![b'x'; 100_000]).unwrap();
+        writer.finish().unwrap();
+
+        let records: Vec<_> = ArchiveReader::open(&path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], (metadata(1, Direction::Inbound), b"MSH|1".to_vec()));
+        assert_eq!(records[1], (metadata(2, Direction::Outbound), b"MSA|AA".to_vec()));
+        assert_eq!(records[2].1.len(), 100_000);
+        assert!(fs::metadata(&path).unwrap().len() < 1_000);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn it_stops_at_a_truncated_member() {
+        let mut writer = ArchiveWriter::new(Vec::new());
+        writer.append(&metadata(1, Direction::Inbound), b"MSH|1").unwrap();
+        let complete = writer.finish().unwrap();
+        let mut writer = ArchiveWriter::new(complete.clone());
+        writer.append(&metadata(2, Direction::Inbound), &vec![b'x'; 10_000]).unwrap();
+        let mut archive = writer.finish().unwrap();
+        archive.truncate(complete.len() + 20);
+
+        let mut reader = ArchiveReader::new(archive.as_slice());
+        assert_eq!(reader.next().unwrap().unwrap().1, b"MSH|1");
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn it_reads_records_back_in_each_format() {
+        fn round_trip<F: ArchiveFormat + Copy>(format: F, payload: &[u8]) -> (RecordMetadata, Vec<u8>) {
+            let mut writer = ArchiveWriter::with_format(Vec::new(), format);
+            writer.append(&metadata(1, Direction::Outbound), payload).unwrap();
+            writer.append(&metadata(2, Direction::Inbound), b"MSA|AA").unwrap();
+            let archive = writer.finish().unwrap();
+
+            let records: Vec<_> = ArchiveReader::with_format(archive.as_slice(), format).map(Result::unwrap).collect();
+            assert_eq!(records.len(), 2);
+            assert_eq!(records[1].1, b"MSA|AA");
+            records.into_iter().next().unwrap()
+        }
+
+        let text = "MSH|^~\\&|\"quoted\"\rPID|||Ünïcode\u{1F600}".as_bytes();
+        assert_eq!(round_trip(NdJson, text), (metadata(1, Direction::Outbound), text.to_vec()));
+        let binary = [0xFF, 0x00, 0x0B, 0x1C, 0x0D];
+        assert_eq!(round_trip(NdJson, &binary), (metadata(1, Direction::Outbound), binary.to_vec()));
+
+        let (raw_metadata, raw_payload) = round_trip(RawFramed, b"MSH|1\rPID|||\r");
+        assert_eq!(raw_payload, b"MSH|1\rPID|||\r");
+        assert_eq!(raw_metadata.time, UNIX_EPOCH);
+    }
+
+    #[test]
+    fn it_reads_ndjson_records_written_by_other_tools() {
+        let lines = b"\n{ \"peer_addr\": \"[fd00::1]:2575\", \"payload\": \"MSH|\\u00e9\", \"direction\": \"inbound\", \"time\": \"1970-01-01T00:00:03Z\" }\n";
+        let mut reader = io::Cursor::new(&lines[..]);
+
+        let (metadata, payload) = NdJson.read_record(&mut reader).unwrap().unwrap();
+        assert_eq!(metadata.time, UNIX_EPOCH + Duration::from_secs(3));
+        assert_eq!(metadata.peer_addr, "[fd00::1]:2575".parse().unwrap());
+        assert_eq!(payload, "MSH|é".as_bytes());
+        assert!(NdJson.read_record(&mut reader).unwrap().is_none());
+    }
+}
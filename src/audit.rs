@@ -0,0 +1,242 @@
+//! Audit trail of the messages crossing the transport boundary.
+//!
+//! An [`AuditSink`] set in [`MllpClientConfig::audit`](crate::client::MllpClientConfig::audit)
+//! or [`MllpServerConfig::audit`](crate::server::MllpServerConfig::audit) is given an
+//! [`AuditRecord`] for every message sent or received: who the peer was, when, the MSH-10
+//! control ID, and whether the message was acknowledged. Payloads are left out, so that the trail
+//! holds no patient data. [`FileAuditSink`] appends the records to a file as JSON lines.
+//! ```no_run
+//! use std::sync::Arc;
+//! use mllp_rs::audit::FileAuditSink;
+//! use mllp_rs::server::MllpServerConfig;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let config = MllpServerConfig {
+//!     audit: Some(Arc::new(FileAuditSink::open("/var/log/mllp/audit.jsonl")?)),
+//!     ..MllpServerConfig::default()
+//! };
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Recording is best effort: a sink failing does not fail the message.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use crate::capture::Direction;
+use crate::event::{format_rfc3339, json_string};
+
+/// What became of an audited message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposition {
+    /// Acknowledged, with a commit ACK or an application acknowledgement. On the server, the
+    /// commit ACK written in [automatic responder mode](crate::server::MllpServerConfig::auto_ack)
+    /// counts, whatever the handler decides next.
+    Acked,
+    /// Answered with a NAK, retries included on the client.
+    Naked,
+    /// Neither: no acknowledgement in time, or the connection failed. On the server, nothing
+    /// was written back, be it the decision of the handler or of the
+    /// [acknowledgement mode](crate::server::MllpServerConfig::ack_mode).
+    Failed,
+}
+
+impl fmt::Display for Disposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Disposition::Acked => write!(f, "acked"),
+            Disposition::Naked => write!(f, "naked"),
+            Disposition::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// A message sent or received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the message was received, or its delivery was over.
+    pub time: SystemTime,
+    /// Inbound on the server, outbound on the client.
+    pub direction: Direction,
+    /// Other side of the connection: the sender on the server, the receiver on the client.
+    pub peer_addr: SocketAddr,
+    /// Identifier of the connection on the server, unique among its connections.
+    pub connection_id: Option<u64>,
+    /// MSH-10 message control ID.
+    pub control_id: Option<String>,
+    pub disposition: Disposition,
+    /// Length of the payload.
+    pub bytes: usize,
+}
+
+impl AuditRecord {
+    pub(crate) fn new(direction: Direction, peer_addr: SocketAddr, payload: &[u8], disposition: Disposition) -> Self {
+        AuditRecord {
+            time: SystemTime::now(),
+            direction,
+            peer_addr,
+            connection_id: None,
+            control_id: crate::control_id(payload),
+            disposition,
+            bytes: payload.len(),
+        }
+    }
+}
+
+/// Destination of the audit records.
+///
+/// Sinks are called synchronously on the connection's thread, once the message is answered.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord) -> io::Result<()>;
+}
+
+impl<S: AuditSink + ?Sized> AuditSink for Arc<S> {
+    fn record(&self, record: &AuditRecord) -> io::Result<()> {
+        (**self).record(record)
+    }
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AuditSink")
+    }
+}
+
+/// Sink appending each record to a file as a JSON line, synced to disk before returning.
+///
+/// The file is opened in append mode and never truncated nor rewritten.
+#[derive(Debug)]
+pub struct FileAuditSink {
+    file: Mutex<File>,
+}
+
+impl FileAuditSink {
+    /// Opens `path` for appending, creating the file if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileAuditSink { file: Mutex::new(file) })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, record: &AuditRecord) -> io::Result<()> {
+        let mut line = format!(
+            "{{\"time\":\"{}\",\"direction\":\"{}\",\"peer_addr\":\"{}\"",
+            format_rfc3339(record.time),
+            match record.direction {
+                Direction::Inbound => "inbound",
+                Direction::Outbound => "outbound",
+            },
+            record.peer_addr
+        );
+        if let Some(connection_id) = record.connection_id {
+            line.push_str(&format!(",\"connection_id\":{}", connection_id));
+        }
+        if let Some(control_id) = &record.control_id {
+            line.push_str(&format!(",\"control_id\":{}", json_string(control_id)));
+        }
+        line.push_str(&format!(",\"disposition\":\"{}\",\"bytes\":{}}}\n", record.disposition, record.bytes));
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use crate::audit::{AuditRecord, AuditSink, Disposition, FileAuditSink};
+    use crate::capture::Direction;
+    use crate::client::{MllpClient, MllpClientConfig};
+    use crate::handler::AckDecision;
+    use crate::server::{MllpServer, MllpServerConfig};
+
+    #[derive(Default)]
+    struct Records(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for Records {
+        fn record(&self, record: &AuditRecord) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_audits_messages_on_both_sides() {
+        let received = Arc::new(Records::default());
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            audit: Some(received.clone()),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            server.serve(|message: &[u8]| match message.ends_with(b"|ORD2") {
+                true => AckDecision::CommitNak,
+                false => AckDecision::CommitAck,
+            })
+        });
+
+        let sent = Arc::new(Records::default());
+        let mut client = MllpClient::connect_with_config(addr, MllpClientConfig {
+            audit: Some(sent.clone()),
+            max_retries: 0,
+            ..MllpClientConfig::default()
+        })
+        .unwrap();
+        client.send(b"MSH|^~\\&|EHR|||||||ORD1").unwrap();
+        client.send(b"MSH|^~\\&|EHR|||||||ORD2").unwrap_err();
+
+        let sent = sent.0.lock().unwrap();
+        let summary: Vec<_> = sent.iter().map(|record| (record.control_id.as_deref(), record.disposition)).collect();
+        assert_eq!(summary, [(Some("ORD1"), Disposition::Acked), (Some("ORD2"), Disposition::Naked)]);
+        assert_eq!((sent[0].direction, sent[0].peer_addr), (Direction::Outbound, addr));
+
+        // recorded once the response is written
+        let start = Instant::now();
+        while received.0.lock().unwrap().len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let received = received.0.lock().unwrap();
+        let summary: Vec<_> = received.iter().map(|record| (record.control_id.as_deref(), record.disposition)).collect();
+        assert_eq!(summary, [(Some("ORD1"), Disposition::Acked), (Some("ORD2"), Disposition::Naked)]);
+        assert_eq!((received[0].direction, received[0].peer_addr), (Direction::Inbound, client.local_addr()));
+        assert!(received[0].connection_id.is_some());
+    }
+
+    #[test]
+    fn it_appends_json_lines() {
+        let path = env::temp_dir().join(format!("mllp-rs-audit-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let record = AuditRecord {
+            time: UNIX_EPOCH + Duration::from_millis(1500),
+            direction: Direction::Inbound,
+            peer_addr: "10.1.2.3:40000".parse().unwrap(),
+            connection_id: Some(7),
+            control_id: Some("MSG\"1".into()),
+            disposition: Disposition::Acked,
+            bytes: 120,
+        };
+        FileAuditSink::open(&path).unwrap().record(&record).unwrap();
+        FileAuditSink::open(&path).unwrap().record(&AuditRecord { connection_id: None, control_id: None, ..record }).unwrap();
+
+        let lines = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = lines.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"time":"1970-01-01T00:00:01.500Z","direction":"inbound","peer_addr":"10.1.2.3:40000","connection_id":7,"control_id":"MSG\"1","disposition":"acked","bytes":120}"#
+        );
+        assert_eq!(lines[1], r#"{"time":"1970-01-01T00:00:01.500Z","direction":"inbound","peer_addr":"10.1.2.3:40000","disposition":"acked","bytes":120}"#);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,156 @@
+//! HL7 batches: several messages sent as one payload.
+//!
+//! A batch wraps messages between a `BHS` header and a `BTS` trailer, and a file wraps batches
+//! between `FHS` and `FTS`; registries often send a night's worth of VXU messages that way, in a
+//! single frame. [`split`] takes the messages out of a received payload, and [`assemble`] or
+//! [`MllpCodec::encode_batch`](crate::MllpCodec::encode_batch) puts messages together into one.
+//! ```
+//! use mllp_rs::batch;
+//! use mllp_rs::MllpCodec;
+//!
+//! let frame = MllpCodec::encode_batch(&[b"MSH|^~\\&|REG|||||VXU^V04|1|P|2.5", b"MSH|^~\\&|REG|||||VXU^V04|2|P|2.5"]);
+//! let payload = MllpCodec::decode(&frame).unwrap();
+//! assert_eq!(batch::split(payload).unwrap().len(), 2);
+//! ```
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str;
+use crate::CR;
+
+/// Line Feed, used by some senders as a segment separator.
+const LF: u8 = 10u8;
+
+/// Why a batch could not be split.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchError {
+    /// The `BTS` or `FTS` trailer counts `declared` messages or batches, but `found` were sent.
+    CountMismatch { declared: usize, found: usize },
+    /// The segment at `offset` is neither in a message nor a header or trailer.
+    UnexpectedSegment { offset: usize },
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BatchError::CountMismatch { declared, found } => write!(f, "Trailer counts {} but {} were found", declared, found),
+            BatchError::UnexpectedSegment { offset } => write!(f, "Segment outside of a message at offset {}", offset),
+        }
+    }
+}
+
+impl core::error::Error for BatchError { }
+
+/// Whether `payload` is a batch or a file of batches rather than a single message.
+pub fn is_batch(payload: &[u8]) -> bool {
+    payload.starts_with(b"BHS") || payload.starts_with(b"FHS")
+}
+
+/// Messages of `payload`, without their trailing segment separator.
+///
+/// A payload holding a single message is returned as is. The counts of the `BTS` and `FTS`
+/// trailers are checked when present; trailers left out are accepted, as many senders do.
+pub fn split(payload: &[u8]) -> Result<Vec<&[u8]>, BatchError> {
+    let mut messages = Vec::new();
+    // bounds of the message being read
+    let mut message: Option<(usize, usize)> = None;
+    let (mut batches, mut in_batch) = (0, 0);
+    let mut offset = 0;
+
+    for segment in payload.split(|b| *b == CR || *b == LF) {
+        let start = offset;
+        offset += segment.len() + 1;
+        if segment.is_empty() {
+            continue;
+        }
+
+        match segment.get(..3) {
+            Some(b"MSH") => {
+                messages.extend(message.take().map(|(start, end)| &payload[start..end]));
+                message = Some((start, start + segment.len()));
+                in_batch += 1;
+            }
+            Some(name @ (b"FHS" | b"BHS" | b"BTS" | b"FTS")) => {
+                messages.extend(message.take().map(|(start, end)| &payload[start..end]));
+                match name {
+                    b"BHS" => {
+                        batches += 1;
+                        in_batch = 0;
+                    }
+                    b"BTS" => check_count(segment, in_batch)?,
+                    b"FTS" => check_count(segment, batches)?,
+                    _ => {}
+                }
+            }
+            _ => match &mut message {
+                Some((_, end)) => *end = start + segment.len(),
+                None => return Err(BatchError::UnexpectedSegment { offset: start }),
+            },
+        }
+    }
+    messages.extend(message.map(|(start, end)| &payload[start..end]));
+
+    Ok(messages)
+}
+
+/// Checks the count of a `BTS` or `FTS` trailer, its first field.
+fn check_count(trailer: &[u8], found: usize) -> Result<(), BatchError> {
+    let Some(&separator) = trailer.get(3) else {
+        return Ok(());
+    };
+    let count = trailer[4..].split(|b| *b == separator).next().and_then(|count| str::from_utf8(count).ok()?.trim().parse().ok());
+
+    match count {
+        Some(declared) if declared != found => Err(BatchError::CountMismatch { declared, found }),
+        _ => Ok(()),
+    }
+}
+
+/// Batch of `messages`, between a `BHS` header and a `BTS` trailer counting them.
+///
+/// The header takes its encoding characters and its sending and receiving applications and
+/// facilities from the MSH segment of the first message.
+pub fn assemble(messages: &[&[u8]]) -> Vec<u8> {
+    let msh = messages.first().and_then(|message| message.split(|b| *b == CR || *b == LF).next()).filter(|msh| msh.starts_with(b"MSH") && msh.len() > 3);
+    let separator = msh.map_or(b'|', |msh| msh[3]);
+    let mut header: Vec<&[u8]> = msh.map_or(Vec::new(), |msh| msh.split(|b| *b == separator).take(6).collect());
+    if header.len() < 2 {
+        header = Vec::from([&b"MSH"[..], b"^~\\&"]);
+    }
+    header[0] = b"BHS";
+
+    let mut batch = header.join(&separator);
+    for message in messages {
+        batch.push(CR);
+        batch.extend_from_slice(message.strip_suffix(b"\r\n").or_else(|| message.strip_suffix(b"\r")).unwrap_or(message));
+    }
+    batch.push(CR);
+    batch.extend_from_slice(format!("BTS{}{}", separator as char, messages.len()).as_bytes());
+
+    batch
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{assemble, is_batch, split, BatchError};
+
+    #[test]
+    fn it_splits_batches_and_files() {
+        let file = b"FHS|^~\\&|REG\rBHS|^~\\&|REG\rMSH|^~\\&|REG|1\rPID|1\r\nMSH|^~\\&|REG|2\rBTS|2\rBHS|^~\\&|REG\rMSH|^~\\&|REG|3\rBTS|1\rFTS|2\r";
+        assert!(is_batch(file));
+        assert_eq!(split(file).unwrap(), [&b"MSH|^~\\&|REG|1\rPID|1"[..], b"MSH|^~\\&|REG|2", b"MSH|^~\\&|REG|3"]);
+
+        assert_eq!(split(b"MSH|^~\\&|REG|1\rPID|1").unwrap(), [b"MSH|^~\\&|REG|1\rPID|1"]);
+        assert_eq!(split(b"BHS|^~\\&\rMSH|1\rBTS|3"), Err(BatchError::CountMismatch { declared: 3, found: 1 }));
+        assert_eq!(split(b"BHS|^~\\&\rPID|1\rMSH|1"), Err(BatchError::UnexpectedSegment { offset: 9 }));
+    }
+
+    #[test]
+    fn it_assembles_batches() {
+        let batch = assemble(&[b"MSH|^~\\&|REG|NORTH|IIS|STATE|2024||VXU^V04|1\rPID|1\r", b"MSH|^~\\&|REG|NORTH|IIS|STATE|2024||VXU^V04|2"]);
+        assert_eq!(batch, b"BHS|^~\\&|REG|NORTH|IIS|STATE\rMSH|^~\\&|REG|NORTH|IIS|STATE|2024||VXU^V04|1\rPID|1\rMSH|^~\\&|REG|NORTH|IIS|STATE|2024||VXU^V04|2\rBTS|2");
+        assert_eq!(split(&batch).unwrap().len(), 2);
+        assert_eq!(assemble(&[]), b"BHS|^~\\&\rBTS|0");
+    }
+}
@@ -0,0 +1,333 @@
+//! `mllp-listen`: receives messages and shows them, to see what a sending system transmits.
+//!
+//! ```text
+//! mllp-listen --listen <addr> [--out <dir>] [--respond ack|nak|aa|ae|ar|none]
+//!             [--tls-cert <pem> --tls-key <pem>] [--json]
+//! ```
+//!
+//! Each message received is printed, one segment per line, after a line telling where it came
+//! from. With `--out`, it is written to `<dir>/message-<number>.hl7` too, as received. Messages
+//! are answered according to `--respond`: a commit ACK by default, a commit NAK, an HL7
+//! application ACK with an `AA`, `AE` or `AR` code, or nothing.
+//!
+//! The address listened on is printed on the first line of the output. With the `tls` feature,
+//! `--tls-cert` and `--tls-key` accept connections over TLS.
+//!
+//! With `--json`, the output is one JSON object per line instead, for scripts and monitoring
+//! checks: `{"listening":<addr>}` first, then one object per message with its `number`, `from`,
+//! `bytes`, `control_id` and `message`, the `outcome` of its answer (`acked`, `naked` or
+//! `unanswered`), the `ack_code` (`CA` for a commit ACK, MSA-1 for an application
+//! acknowledgement), the `latency_ms` taken to answer it, and the `errors` met, such as a file
+//! that could not be written. A fatal error is printed as `{"outcome":"failed","errors":[..]}`.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use mllp_rs::handler::{AckDecision, MllpHandler, ReceivedFrame};
+use mllp_rs::event::json_string;
+use mllp_rs::server::{MllpServer, MllpServerConfig};
+
+const USAGE: &str = "usage: mllp-listen --listen <addr> [--out <dir>] [--respond ack|nak|aa|ae|ar|none] \
+                     [--tls-cert <pem> --tls-key <pem>] [--json]";
+
+/// Answer to the messages received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Respond {
+    CommitAck,
+    CommitNak,
+    /// Application ACK with this MSA-1 acknowledgment code.
+    Application(&'static str),
+    None,
+}
+
+#[derive(Debug)]
+struct Args {
+    listen: String,
+    out: Option<PathBuf>,
+    respond: Respond,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    json: bool,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let json = args.json;
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) if json => {
+            println!("{{\"outcome\":\"failed\",\"errors\":[{}]}}", json_string(&message));
+            ExitCode::FAILURE
+        }
+        Err(message) => {
+            eprintln!("mllp-listen: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args {
+        listen: String::new(),
+        out: None,
+        respond: Respond::CommitAck,
+        tls_cert: None,
+        tls_key: None,
+        json: false,
+    };
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--listen" => parsed.listen = value()?,
+            "--out" => parsed.out = Some(value()?.into()),
+            "--respond" => {
+                parsed.respond = match value()?.as_str() {
+                    "ack" => Respond::CommitAck,
+                    "nak" => Respond::CommitNak,
+                    "aa" => Respond::Application("AA"),
+                    "ae" => Respond::Application("AE"),
+                    "ar" => Respond::Application("AR"),
+                    "none" => Respond::None,
+                    other => return Err(format!("unknown response {}", other)),
+                }
+            }
+            "--tls-cert" => parsed.tls_cert = Some(value()?.into()),
+            "--tls-key" => parsed.tls_key = Some(value()?.into()),
+            "--json" => parsed.json = true,
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    if parsed.listen.is_empty() {
+        return Err("--listen is required".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let mut config = MllpServerConfig::default();
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        configure_tls(&mut config, cert, key)?;
+    }
+    if let Some(dir) = &args.out {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+
+    let server = MllpServer::bind(args.listen.as_str(), config).map_err(|e| format!("cannot listen on {}: {}", args.listen, e))?;
+    let local_addr = server.local_addr().map_err(|e| e.to_string())?;
+    match args.json {
+        true => println!("{{\"listening\":{}}}", json_string(&local_addr.to_string())),
+        false => println!("listening on {}", local_addr),
+    }
+    let _ = io::stdout().flush();
+
+    let listener = Listener {
+        out: args.out,
+        respond: args.respond,
+        json: args.json,
+        received: AtomicU64::new(0),
+    };
+    server.serve(listener).map_err(|e| e.to_string())
+}
+
+/// Handler showing the messages and answering them.
+struct Listener {
+    out: Option<PathBuf>,
+    respond: Respond,
+    json: bool,
+    /// Number of messages received so far.
+    received: AtomicU64,
+}
+
+impl Listener {
+    fn receive(&self, message: &[u8], from: &str) -> AckDecision {
+        let start = Instant::now();
+        let number = self.received.fetch_add(1, Ordering::Relaxed) + 1;
+        let text = String::from_utf8_lossy(message);
+        let segments: Vec<&str> = text.split(['\r', '\n']).filter(|segment| !segment.is_empty()).collect();
+        if !self.json {
+            // a line at once, the messages of several connections do not mix
+            let mut stdout = io::stdout().lock();
+            let _ = writeln!(stdout, "--- message {} from {}, {} bytes\n{}", number, from, message.len(), segments.join("\n"));
+            let _ = stdout.flush();
+        }
+
+        let mut errors = Vec::new();
+        if let Some(dir) = &self.out {
+            let path = dir.join(format!("message-{:06}.hl7", number));
+            if let Err(e) = fs::write(&path, message) {
+                errors.push(format!("cannot write {}: {}", path.display(), e));
+            }
+        }
+
+        let (decision, ack_code) = match self.respond {
+            Respond::CommitAck => (AckDecision::CommitAck, Some("CA")),
+            Respond::CommitNak => (AckDecision::CommitNak, None),
+            Respond::Application(code) => match application_ack(message, code, number, SystemTime::now()) {
+                Some(ack) => (AckDecision::ApplicationAck(ack), Some(code)),
+                None => {
+                    errors.push(format!("message {} has no MSH segment, answered with a NAK", number));
+                    (AckDecision::CommitNak, None)
+                }
+            },
+            Respond::None => (AckDecision::None, None),
+        };
+
+        if !self.json {
+            for error in &errors {
+                eprintln!("mllp-listen: {}", error);
+            }
+            return decision;
+        }
+        let outcome = match decision {
+            AckDecision::CommitNak => "naked",
+            AckDecision::None => "unanswered",
+            _ => "acked",
+        };
+        let errors: Vec<String> = errors.iter().map(|error| json_string(error)).collect();
+        let mut stdout = io::stdout().lock();
+        let _ = writeln!(
+            stdout,
+            "{{\"number\":{},\"from\":{},\"bytes\":{},\"control_id\":{},\"message\":{},\"outcome\":\"{}\",\"ack_code\":{},\"latency_ms\":{},\"errors\":[{}]}}",
+            number,
+            json_string(from),
+            message.len(),
+            control_id(message).as_deref().map_or("null".to_owned(), json_string),
+            json_string(&segments.join("\r")),
+            outcome,
+            ack_code.map_or("null".to_owned(), json_string),
+            start.elapsed().as_millis(),
+            errors.join(",")
+        );
+        let _ = stdout.flush();
+
+        decision
+    }
+}
+
+impl MllpHandler for Listener {
+    fn on_message(&self, message: &[u8]) -> AckDecision {
+        self.receive(message, "an unknown peer")
+    }
+
+    fn on_frame(&self, frame: &ReceivedFrame<'_>) -> AckDecision {
+        self.receive(frame.payload, &frame.peer_addr.to_string())
+    }
+}
+
+/// Builds the HL7 ACK of `message`, with the acknowledgment `code`, going back to its sender.
+fn application_ack(message: &[u8], code: &str, number: u64, time: SystemTime) -> Option<Vec<u8>> {
+    let separator = *message.strip_prefix(b"MSH")?.first()? as char;
+    let segment = message.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let segment = String::from_utf8_lossy(segment);
+    let fields: Vec<&str> = segment.split(separator).collect();
+    let field = |index: usize| fields.get(index).copied().unwrap_or("");
+    let component = field(1).chars().next().unwrap_or('^');
+    let trigger = field(8).split(component).nth(1).unwrap_or("");
+
+    let msh = [
+        "MSH",
+        field(1),
+        field(4),
+        field(5),
+        field(2),
+        field(3),
+        &timestamp(time),
+        "",
+        &format!("ACK{}{}", component, trigger),
+        &format!("ACK{}", number),
+        field(10),
+        field(11),
+    ];
+    let msa = ["MSA", code, field(9)];
+
+    Some(format!("{}\r{}", msh.join(&separator.to_string()), msa.join(&separator.to_string())).into_bytes())
+}
+
+/// MSH-10, the control ID of `message`.
+fn control_id(message: &[u8]) -> Option<String> {
+    let separator = *message.strip_prefix(b"MSH")?.first()?;
+    let segment = message.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let id = segment.split(|b| *b == separator).nth(9).filter(|id| !id.is_empty())?;
+
+    Some(String::from_utf8_lossy(id).into_owned())
+}
+
+
+/// HL7 `YYYYMMDDHHMMSS` timestamp, in UTC.
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // civil date of a count of days since 1970-01-01, after Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(feature = "tls")]
+fn configure_tls(config: &mut MllpServerConfig, cert: &std::path::Path, key: &std::path::Path) -> Result<(), String> {
+    let acceptor = mllp_rs::tls::TlsAcceptor::from_pem_files(cert, key).map_err(|e| format!("cannot load the certificate: {}", e))?;
+    config.tls = Some(Arc::new(acceptor));
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn configure_tls(_: &mut MllpServerConfig, _: &std::path::Path, _: &std::path::Path) -> Result<(), String> {
+    Err("--tls-cert needs the tls feature".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::{application_ack, control_id, parse_args, timestamp, Respond};
+
+    #[test]
+    fn it_parses_arguments() {
+        let args: Vec<String> = ["--listen", "0.0.0.0:2575", "--respond", "ae"].iter().map(|arg| arg.to_string()).collect();
+        let args = parse_args(&args).unwrap();
+        assert_eq!(args.respond, Respond::Application("AE"));
+        assert!(!args.json);
+        assert!(parse_args(&["--listen".to_owned(), "0.0.0.0:2575".to_owned(), "--json".to_owned()]).unwrap().json);
+        assert!(parse_args(&["--listen".to_owned(), "0.0.0.0:2575".to_owned(), "--respond".to_owned(), "maybe".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn it_builds_application_acks() {
+        let time = UNIX_EPOCH + Duration::from_secs(1706702400);
+        let ack = application_ack(b"MSH|^~\\&|LAB|NORTH|EHR|SOUTH|20240131||ORU^R01|MSG42|P|2.5\rPID|1", "AA", 7, time).unwrap();
+        assert_eq!(ack, b"MSH|^~\\&|EHR|SOUTH|LAB|NORTH|20240131120000||ACK^R01|ACK7|P|2.5\rMSA|AA|MSG42");
+        assert_eq!(application_ack(b"PID|1", "AA", 1, time), None);
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951782400)), "20000229000000");
+    }
+
+    #[test]
+    fn it_reads_control_ids() {
+        assert_eq!(control_id(b"MSH|^~\\&|LAB|NORTH|EHR|SOUTH|20240131||ORU^R01|MSG42|P|2.5\rPID|1").as_deref(), Some("MSG42"));
+        assert_eq!(control_id(b"MSH|^~\\&|LAB"), None);
+    }
+}
@@ -0,0 +1,284 @@
+//! `mllp-receiver`: durable receiver, a reference of the server side subsystems working together.
+//!
+//! ```text
+//! mllp-receiver --listen <addr> --archive <file> [--webhook <http://host:port/path>]
+//!               [--metrics <addr>] [--tls-cert <pem> --tls-key <pem>]
+//! ```
+//!
+//! Each message received is validated: messages which are not HL7 v2, or have no message type or
+//! control ID, are refused with a NAK. Valid messages are appended to the gzip archive, and
+//! acknowledged once archived. With `--webhook`, each archived message is then announced with a
+//! JSON `POST` of its control ID and message type; a failing webhook does not fail the message.
+//!
+//! The address listened on is printed on the first line of the output. With `--metrics`, the
+//! Prometheus metrics of the server are served on `<addr>`. With the `tls` feature, `--tls-cert`
+//! and `--tls-key` accept connections over TLS.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use mllp_rs::archive::{ArchiveWriter, RecordMetadata};
+use mllp_rs::capture::Direction;
+use mllp_rs::event::json_string;
+use mllp_rs::handler::{AckDecision, MllpHandler, ReceivedFrame};
+use mllp_rs::metrics::PrometheusMetrics;
+use mllp_rs::server::{MllpServer, MllpServerConfig};
+
+const USAGE: &str = "usage: mllp-receiver --listen <addr> --archive <file> [--webhook <http://host:port/path>] \
+                     [--metrics <addr>] [--tls-cert <pem> --tls-key <pem>]";
+
+/// Timeout of the connection to the webhook, and of its response.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default)]
+struct Args {
+    listen: String,
+    archive: PathBuf,
+    webhook: Option<String>,
+    metrics: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+/// Fields of a valid message.
+#[derive(Debug, PartialEq, Eq)]
+struct Header {
+    message_type: String,
+    control_id: String,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("mllp-receiver: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--listen" => parsed.listen = value()?,
+            "--archive" => parsed.archive = value()?.into(),
+            "--webhook" => parsed.webhook = Some(value()?),
+            "--metrics" => parsed.metrics = Some(value()?),
+            "--tls-cert" => parsed.tls_cert = Some(value()?.into()),
+            "--tls-key" => parsed.tls_key = Some(value()?.into()),
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    if parsed.listen.is_empty() || parsed.archive.as_os_str().is_empty() {
+        return Err("--listen and --archive are required".to_owned());
+    }
+    if parsed.webhook.as_deref().is_some_and(|url| split_url(url).is_none()) {
+        return Err("--webhook expects an http:// URL".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let metrics = Arc::new(PrometheusMetrics::new());
+    if let Some(addr) = &args.metrics {
+        let listener = TcpListener::bind(addr).map_err(|e| format!("cannot serve metrics on {}: {}", addr, e))?;
+        let exporter = metrics.clone();
+        thread::spawn(move || exporter.serve(listener));
+    }
+    let mut config = MllpServerConfig {
+        metrics: Some(metrics),
+        ..MllpServerConfig::default()
+    };
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        configure_tls(&mut config, cert, key)?;
+    }
+
+    let server = MllpServer::bind(args.listen.as_str(), config).map_err(|e| format!("cannot listen on {}: {}", args.listen, e))?;
+    let local_addr = server.local_addr().map_err(|e| e.to_string())?;
+    println!("listening on {}", local_addr);
+    let _ = io::stdout().flush();
+
+    let receiver = Receiver {
+        archive: Mutex::new(args.archive),
+        webhook: args.webhook,
+        local_addr,
+    };
+    server.serve(receiver).map_err(|e| e.to_string())
+}
+
+/// Handler archiving the valid messages, and announcing them to the webhook.
+struct Receiver {
+    /// Records of a message are written as a gzip member of their own, complete once acknowledged.
+    archive: Mutex<PathBuf>,
+    webhook: Option<String>,
+    local_addr: SocketAddr,
+}
+
+impl Receiver {
+    fn receive(&self, message: &[u8], metadata: RecordMetadata) -> AckDecision {
+        let Some(header) = validate(message) else {
+            return AckDecision::CommitNak;
+        };
+        let archive = self.archive.lock().unwrap_or_else(|e| e.into_inner());
+        let archived = ArchiveWriter::open(&*archive).and_then(|mut writer| {
+            writer.append(&metadata, message)?;
+            writer.finish()?.sync_data()
+        });
+        drop(archive);
+        if let Err(e) = archived {
+            eprintln!("mllp-receiver: cannot archive {}: {}", header.control_id, e);
+            return AckDecision::CommitNak;
+        }
+
+        if let Some(url) = &self.webhook {
+            if let Err(e) = notify(url, &header) {
+                eprintln!("mllp-receiver: webhook failed for {}: {}", header.control_id, e);
+            }
+        }
+        AckDecision::CommitAck
+    }
+}
+
+impl MllpHandler for Receiver {
+    fn on_message(&self, message: &[u8]) -> AckDecision {
+        // without the sender's address, records carry the listening one
+        let metadata = RecordMetadata {
+            time: SystemTime::now(),
+            direction: Direction::Inbound,
+            peer_addr: self.local_addr,
+        };
+        self.receive(message, metadata)
+    }
+
+    fn on_frame(&self, frame: &ReceivedFrame<'_>) -> AckDecision {
+        let metadata = RecordMetadata {
+            time: frame.received_at,
+            direction: Direction::Inbound,
+            peer_addr: frame.peer_addr,
+        };
+        self.receive(frame.payload, metadata)
+    }
+}
+
+/// Reads the message type (MSH-9) and control ID (MSH-10) of an HL7 v2 message.
+fn validate(message: &[u8]) -> Option<Header> {
+    let separator = *message.strip_prefix(b"MSH")?.first()?;
+    let segment = message.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let segment = std::str::from_utf8(segment).ok()?;
+    let fields: Vec<&str> = segment.split(separator as char).collect();
+    let (message_type, control_id) = (*fields.get(8)?, *fields.get(9)?);
+    if message_type.is_empty() || control_id.is_empty() {
+        return None;
+    }
+
+    Some(Header {
+        message_type: message_type.to_owned(),
+        control_id: control_id.to_owned(),
+    })
+}
+
+/// Splits an `http://host[:port]/path` URL into the address and the path.
+fn split_url(url: &str) -> Option<(String, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |slash| (&rest[..slash], &rest[slash..]));
+    if authority.is_empty() {
+        return None;
+    }
+    let addr = match authority.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_owned(),
+        _ => format!("{}:80", authority),
+    };
+
+    Some((addr, path))
+}
+
+/// Posts the header of an archived message to the webhook.
+fn notify(url: &str, header: &Header) -> io::Result<()> {
+    let (addr, path) = split_url(url).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid URL"))?;
+    let host = addr.clone();
+    let addr: SocketAddr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "webhook host not found"))?;
+    let body = format!(
+        "{{\"control_id\":{},\"message_type\":{}}}",
+        json_string(&header.control_id),
+        json_string(&header.message_type)
+    );
+
+    let mut stream = TcpStream::connect_timeout(&addr, WEBHOOK_TIMEOUT)?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+
+    let status = String::from_utf8_lossy(&response);
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("unexpected response {:?}", status.lines().next().unwrap_or("")))),
+    }
+}
+
+
+#[cfg(feature = "tls")]
+fn configure_tls(config: &mut MllpServerConfig, cert: &std::path::Path, key: &std::path::Path) -> Result<(), String> {
+    let acceptor = mllp_rs::tls::TlsAcceptor::from_pem_files(cert, key).map_err(|e| format!("cannot load the certificate: {}", e))?;
+    config.tls = Some(Arc::new(acceptor));
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn configure_tls(_: &mut MllpServerConfig, _: &std::path::Path, _: &std::path::Path) -> Result<(), String> {
+    Err("--tls-cert needs the tls feature".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{split_url, validate, Header};
+
+    #[test]
+    fn it_validates_messages() {
+        assert_eq!(
+            validate(b"MSH|^~\\&|LAB||EHR||20240131||ORU^R01|MSG42|P|2.5\rPID|1"),
+            Some(Header {
+                message_type: "ORU^R01".to_owned(),
+                control_id: "MSG42".to_owned(),
+            })
+        );
+        assert_eq!(validate(b"MSH|^~\\&|LAB||EHR||20240131||ORU^R01||P|2.5"), None);
+        assert_eq!(validate(b"PID|1"), None);
+    }
+
+    #[test]
+    fn it_splits_webhook_urls() {
+        assert_eq!(split_url("http://hooks.local:8080/mllp"), Some(("hooks.local:8080".to_owned(), "/mllp")));
+        assert_eq!(split_url("http://hooks.local"), Some(("hooks.local:80".to_owned(), "/")));
+        assert_eq!(split_url("https://hooks.local/"), None);
+    }
+}
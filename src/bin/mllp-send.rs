@@ -0,0 +1,356 @@
+//! `mllp-send`: sends an HL7 message and prints its acknowledgement, to smoke-test an interface.
+//!
+//! ```text
+//! mllp-send --to <host:port> [--timeout <ms>] [--retries <n>] [--tls-ca <pem> [--tls-name <name>]]
+//!           [--json] [<file>]
+//! ```
+//!
+//! The message is read from `<file>`, or from the standard input without one or with `-`. Its
+//! lines are joined with `\r`, the HL7 segment separator, as files are usually edited with line
+//! feeds. The commit acknowledgement is printed as `ACK`, an application acknowledgement one
+//! segment per line.
+//!
+//! Exits with 0 when the message is accepted: a commit ACK, or an application ACK with an `AA`
+//! or `CA` code. Exits with 1 when it is refused or not acknowledged within the timeout, 10
+//! seconds by default, and with 2 on invalid arguments. `--retries` sends the message again up to
+//! `<n>` times after a NAK or a timeout, none by default. With the `tls` feature, `--tls-ca`
+//! connects over TLS, trusting the certificates of the PEM file.
+//!
+//! With `--json`, the result is printed as one JSON object instead, for scripts and monitoring
+//! checks: the `outcome` (`accepted`, `rejected` or `failed`), the `ack_code` (`CA` for a commit
+//! ACK, MSA-1 for an application acknowledgement), the `latency_ms` from sending to the
+//! acknowledgement, the number of `retries`, and the `errors`, outermost cause first.
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use mllp_rs::client::{Ack, MllpClient, MllpClientConfig};
+use mllp_rs::event::{json_string, Event, EventKind, EventSink};
+use mllp_rs::MllpError;
+
+const USAGE: &str = "usage: mllp-send --to <host:port> [--timeout <ms>] [--retries <n>] [--tls-ca <pem> [--tls-name <name>]] \
+                     [--json] [<file>]";
+
+#[derive(Debug, Default)]
+struct Args {
+    to: String,
+    file: Option<PathBuf>,
+    timeout: Duration,
+    retries: u32,
+    tls_ca: Option<PathBuf>,
+    tls_name: Option<String>,
+    json: bool,
+}
+
+/// What became of the message.
+#[derive(Debug, Default)]
+struct Report {
+    ack: Option<Ack>,
+    /// Whether the message was answered with a commit NAK.
+    nak: bool,
+    /// From sending the message to its acknowledgement, retries included.
+    latency: Option<Duration>,
+    retries: u32,
+    /// Why the message was not acknowledged, outermost cause first.
+    errors: Vec<String>,
+}
+
+impl Report {
+    fn failed(mut self, errors: Vec<String>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    fn outcome(&self) -> &'static str {
+        match &self.ack {
+            Some(ack) if accepted(ack) => "accepted",
+            Some(_) => "rejected",
+            None if self.nak => "rejected",
+            None => "failed",
+        }
+    }
+}
+
+/// Counts the retransmissions of the message.
+#[derive(Debug, Default)]
+struct RetryCounter(AtomicU32);
+
+impl EventSink for RetryCounter {
+    fn on_event(&self, event: &Event) {
+        if let EventKind::Retry { .. } = event.kind {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let report = send(&args);
+    if args.json {
+        println!("{}", to_json(&report));
+    } else if let Some(ack) = &report.ack {
+        println!("{}", describe(ack));
+    } else {
+        eprintln!("mllp-send: {}", report.errors.join(": "));
+    }
+
+    match report.outcome() {
+        "accepted" => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args {
+        timeout: Duration::from_secs(10),
+        ..Args::default()
+    };
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--to" => parsed.to = value()?,
+            "--timeout" => {
+                let value = value()?;
+                parsed.timeout = Duration::from_millis(value.parse().map_err(|_| format!("invalid duration {}", value))?);
+            }
+            "--retries" => {
+                let value = value()?;
+                parsed.retries = value.parse().map_err(|_| format!("invalid number of retries {}", value))?;
+            }
+            "--tls-ca" => parsed.tls_ca = Some(value()?.into()),
+            "--tls-name" => parsed.tls_name = Some(value()?),
+            "--json" => parsed.json = true,
+            "-" if parsed.file.is_none() => {}
+            other if !other.starts_with("--") && parsed.file.is_none() => parsed.file = Some(other.into()),
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    if parsed.to.is_empty() {
+        return Err("--to is required".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn send(args: &Args) -> Report {
+    let report = Report::default();
+    let message = match &args.file {
+        Some(path) => match fs::read(path) {
+            Ok(message) => message,
+            Err(e) => return report.failed(chain(format!("cannot read {}", path.display()), &e)),
+        },
+        None => {
+            let mut message = Vec::new();
+            if let Err(e) = io::stdin().read_to_end(&mut message) {
+                return report.failed(chain("cannot read the standard input".to_owned(), &e));
+            }
+            message
+        }
+    };
+    let message = to_segment_separators(&message);
+    if message.is_empty() {
+        return report.failed(vec!["empty message".to_owned()]);
+    }
+
+    let retries = Arc::new(RetryCounter::default());
+    let mut config = MllpClientConfig {
+        ack_timeout: Some(args.timeout),
+        max_retries: args.retries,
+        event_sink: Some(retries.clone()),
+        ..MllpClientConfig::default()
+    };
+    if let Some(ca) = &args.tls_ca {
+        if let Err(message) = configure_tls(&mut config, ca, args.tls_name.as_deref().unwrap_or(host(&args.to))) {
+            return report.failed(vec![message]);
+        }
+    }
+
+    let mut client = match MllpClient::connect_with_config(args.to.as_str(), config) {
+        Ok(client) => client,
+        Err(e) => return report.failed(chain(format!("cannot connect to {}", args.to), &e)),
+    };
+    let start = Instant::now();
+    let result = client.send(&message);
+    let report = Report {
+        retries: retries.0.load(Ordering::Relaxed),
+        ..report
+    };
+    match result {
+        Ok(ack) => Report {
+            ack: Some(ack),
+            latency: Some(start.elapsed()),
+            ..report
+        },
+        Err(MllpError::Nak) => Report { nak: true, ..report }.failed(vec!["commit NAK".to_owned()]),
+        Err(MllpError::AckTimeout) => report.failed(vec![format!("no ACK within {} ms", args.timeout.as_millis())]),
+        Err(e) => report.failed(chain("cannot send the message".to_owned(), &e)),
+    }
+}
+
+/// `context`, followed by `error` and its sources.
+fn chain(context: String, error: &(dyn Error + 'static)) -> Vec<String> {
+    let mut errors = vec![context];
+    let mut source = Some(error);
+    while let Some(error) = source {
+        let message = error.to_string();
+        // an error often repeats the message of its source
+        if errors.last() != Some(&message) {
+            errors.push(message);
+        }
+        source = error.source();
+    }
+
+    errors
+}
+
+/// Joins the lines of `message` with `\r`, leaving out the empty ones.
+fn to_segment_separators(message: &[u8]) -> Vec<u8> {
+    let segments: Vec<&[u8]> = message
+        .split(|b| *b == b'\n' || *b == b'\r')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    segments.join(&b'\r')
+}
+
+fn describe(ack: &Ack) -> String {
+    match ack {
+        Ack::Commit => "ACK".to_owned(),
+        Ack::Application(payload) => String::from_utf8_lossy(payload).split('\r').collect::<Vec<_>>().join("\n"),
+        Ack::None => String::new(),
+    }
+}
+
+/// Whether the receiver accepted the message: a commit ACK, or MSA-1 is `AA` or `CA`.
+fn accepted(ack: &Ack) -> bool {
+    ack_code(ack).is_some_and(|code| code == "AA" || code == "CA")
+}
+
+/// Code of `ack`: `CA` for a commit ACK, MSA-1 for an application acknowledgement.
+fn ack_code(ack: &Ack) -> Option<String> {
+    match ack {
+        Ack::Commit => Some("CA".to_owned()),
+        Ack::Application(payload) => {
+            let text = String::from_utf8_lossy(payload);
+            let msa = text.split(['\r', '\n']).find(|segment| segment.starts_with("MSA|"))?;
+            msa.split('|').nth(1).map(str::to_owned)
+        }
+        Ack::None => None,
+    }
+}
+
+fn to_json(report: &Report) -> String {
+    let errors: Vec<String> = report.errors.iter().map(|error| json_string(error)).collect();
+    format!(
+        "{{\"outcome\":\"{}\",\"ack_code\":{},\"latency_ms\":{},\"retries\":{},\"errors\":[{}]}}",
+        report.outcome(),
+        report.ack.as_ref().and_then(ack_code).as_deref().map_or("null".to_owned(), json_string),
+        report.latency.map_or("null".to_owned(), |latency| latency.as_millis().to_string()),
+        report.retries,
+        errors.join(",")
+    )
+}
+
+
+/// Host part of a `host:port` address.
+fn host(addr: &str) -> &str {
+    addr.rsplit_once(':').map_or(addr, |(host, _)| host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+#[cfg(feature = "tls")]
+fn configure_tls(config: &mut MllpClientConfig, ca: &Path, server_name: &str) -> Result<(), String> {
+    use mllp_rs::tls::TlsConnector;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+    use rustls::{ClientConfig, RootCertStore};
+
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca).map_err(|e| format!("cannot read {}: {}", ca.display(), e))?;
+    for cert in certs {
+        let cert = cert.map_err(|e| format!("cannot read {}: {}", ca.display(), e))?;
+        roots.add(cert).map_err(|e| format!("invalid certificate in {}: {}", ca.display(), e))?;
+    }
+    let tls = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let connector = TlsConnector::new(Arc::new(tls), server_name).map_err(|e| e.to_string())?;
+    config.tls = Some(Arc::new(connector));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn configure_tls(_: &mut MllpClientConfig, _: &Path, _: &str) -> Result<(), String> {
+    Err("--tls-ca needs the tls feature".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use mllp_rs::client::Ack;
+    use std::io;
+    use std::time::Duration;
+    use crate::{accepted, chain, describe, parse_args, to_json, to_segment_separators, Report};
+
+    #[test]
+    fn it_parses_arguments() {
+        let args: Vec<String> = ["--to", "lab:2575", "adt.hl7"].iter().map(|arg| arg.to_string()).collect();
+        let args = parse_args(&args).unwrap();
+        assert_eq!(args.to, "lab:2575");
+        assert_eq!(args.file.unwrap().to_str(), Some("adt.hl7"));
+        assert!(parse_args(&["--to".to_owned(), "lab:2575".to_owned(), "-".to_owned()]).unwrap().file.is_none());
+        assert!(parse_args(&["adt.hl7".to_owned()]).is_err());
+
+        let args: Vec<String> = ["--to", "lab:2575", "--retries", "2", "--json"].iter().map(|arg| arg.to_string()).collect();
+        let args = parse_args(&args).unwrap();
+        assert_eq!(args.retries, 2);
+        assert!(args.json);
+    }
+
+    #[test]
+    fn it_joins_lines_with_segment_separators() {
+        assert_eq!(to_segment_separators(b"MSH|^~\\&|LAB\r\nPID|1\n\n"), b"MSH|^~\\&|LAB\rPID|1");
+    }
+
+    #[test]
+    fn it_tells_accepted_messages() {
+        let ack = Ack::Application(b"MSH|^~\\&|EHR\rMSA|AE|MSG42".to_vec());
+        assert!(!accepted(&ack));
+        assert_eq!(describe(&ack), "MSH|^~\\&|EHR\nMSA|AE|MSG42");
+        assert!(accepted(&Ack::Application(b"MSH|^~\\&|EHR\rMSA|AA|MSG42".to_vec())));
+        assert!(accepted(&Ack::Commit));
+    }
+
+    #[test]
+    fn it_reports_as_json() {
+        let report = Report {
+            ack: Some(Ack::Application(b"MSH|^~\\&|EHR\rMSA|AE|MSG42".to_vec())),
+            latency: Some(Duration::from_millis(42)),
+            retries: 1,
+            ..Report::default()
+        };
+        assert_eq!(report.outcome(), "rejected");
+        assert_eq!(to_json(&report), r#"{"outcome":"rejected","ack_code":"AE","latency_ms":42,"retries":1,"errors":[]}"#);
+
+        let e = io::Error::new(io::ErrorKind::ConnectionRefused, "connection \"refused\"");
+        let report = Report::default().failed(chain("cannot connect to lab:2575".to_owned(), &e));
+        assert_eq!(report.outcome(), "failed");
+        assert_eq!(
+            to_json(&report),
+            r#"{"outcome":"failed","ack_code":null,"latency_ms":null,"retries":0,"errors":["cannot connect to lab:2575","connection \"refused\""]}"#
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! `mllp-sender`: durable sender, a reference of the client side subsystems working together.
+//!
+//! ```text
+//! mllp-sender --drop <dir> --spool <dir> --to <host:port> [--dead-letter <dir>]
+//!             [--metrics <addr>] [--tls-ca <pem> --tls-name <name>] [--poll-ms <ms>]
+//! ```
+//!
+//! Each file written to the drop folder is an HL7 message. It is moved to the spool, an on-disk
+//! queue, and delivered over MLLP, the file being removed once the message is spooled. Messages
+//! survive a restart or an unreachable receiver in the spool; those refused for good go to the
+//! dead-letter directory. Files whose name starts with `.` are being written, and left alone.
+//!
+//! With `--metrics`, the Prometheus metrics of the client are served on `<addr>`. With the `tls`
+//! feature, `--tls-ca` connects over TLS, trusting the certificates of the PEM file.
+
+use std::env;
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use mllp_rs::client::{MllpClient, MllpClientConfig};
+use mllp_rs::dead_letter::DirectoryDeadLetterSink;
+use mllp_rs::metrics::PrometheusMetrics;
+use mllp_rs::spool::{Metadata, Spool, SpoolingClient};
+
+const USAGE: &str = "usage: mllp-sender --drop <dir> --spool <dir> --to <host:port> [--dead-letter <dir>] \
+                     [--metrics <addr>] [--tls-ca <pem> --tls-name <name>] [--poll-ms <ms>]";
+
+/// Metadata key of the name of the file a message came from.
+const FILE: &str = "file";
+
+#[derive(Debug, Default)]
+struct Args {
+    drop_dir: PathBuf,
+    spool_dir: PathBuf,
+    to: String,
+    dead_letter: Option<PathBuf>,
+    metrics: Option<String>,
+    tls_ca: Option<PathBuf>,
+    tls_name: Option<String>,
+    poll: Duration,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("mllp-sender: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut parsed = Args {
+        poll: Duration::from_secs(1),
+        ..Args::default()
+    };
+    let mut iter = args.iter();
+
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--drop" => parsed.drop_dir = value()?.into(),
+            "--spool" => parsed.spool_dir = value()?.into(),
+            "--to" => parsed.to = value()?,
+            "--dead-letter" => parsed.dead_letter = Some(value()?.into()),
+            "--metrics" => parsed.metrics = Some(value()?),
+            "--tls-ca" => parsed.tls_ca = Some(value()?.into()),
+            "--tls-name" => parsed.tls_name = Some(value()?),
+            "--poll-ms" => {
+                let value = value()?;
+                parsed.poll = Duration::from_millis(value.parse().map_err(|_| format!("invalid duration {}", value))?);
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    if parsed.drop_dir.as_os_str().is_empty() || parsed.spool_dir.as_os_str().is_empty() || parsed.to.is_empty() {
+        return Err("--drop, --spool and --to are required".to_owned());
+    }
+    Ok(parsed)
+}
+
+fn run(args: &Args) -> Result<(), String> {
+    let metrics = Arc::new(PrometheusMetrics::new());
+    if let Some(addr) = &args.metrics {
+        let listener = TcpListener::bind(addr).map_err(|e| format!("cannot serve metrics on {}: {}", addr, e))?;
+        let exporter = metrics.clone();
+        thread::spawn(move || exporter.serve(listener));
+    }
+    let mut config = MllpClientConfig {
+        keep_open: true,
+        metrics: Some(metrics),
+        ..MllpClientConfig::default()
+    };
+    if let Some(dir) = &args.dead_letter {
+        let sink = DirectoryDeadLetterSink::new(dir).map_err(|e| format!("cannot open {}: {}", dir.display(), e))?;
+        config.dead_letter = Some(Arc::new(sink));
+    }
+    if let Some(ca) = &args.tls_ca {
+        configure_tls(&mut config, ca, args.tls_name.as_deref().unwrap_or(host(&args.to)))?;
+    }
+
+    loop {
+        let client = match MllpClient::connect_with_config(args.to.as_str(), config.clone()) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("mllp-sender: cannot connect to {}: {}", args.to, e);
+                thread::sleep(args.poll);
+                continue;
+            }
+        };
+        let mut client = SpoolingClient::new(client, &args.spool_dir).map_err(|e| format!("cannot open spool: {}", e))?;
+        if let Err(e) = deliver(&mut client, args) {
+            eprintln!("mllp-sender: {}", e);
+            thread::sleep(args.poll);
+        }
+    }
+}
+
+/// Spools and sends the files of the drop folder, until a delivery fails.
+fn deliver(client: &mut SpoolingClient, args: &Args) -> Result<(), String> {
+    client.send_pending().map_err(|e| format!("cannot send the spooled messages: {}", e))?;
+
+    loop {
+        let mut files: Vec<PathBuf> = fs::read_dir(&args.drop_dir)
+            .map_err(|e| format!("cannot read {}: {}", args.drop_dir.display(), e))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && !file_name(path).starts_with('.'))
+            .collect();
+        files.sort();
+
+        for path in files {
+            let payload = fs::read(&path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+            let metadata = Metadata::from([(FILE.to_owned(), file_name(&path))]);
+            let result = client.send_with_metadata(&payload, &metadata);
+            // once spooled, the message is delivered from the spool whatever happens to the file
+            if result.is_ok() || is_spooled(client.spool(), &file_name(&path)) {
+                let _ = fs::remove_file(&path);
+            }
+            result.map_err(|e| format!("cannot deliver {}: {}", path.display(), e))?;
+        }
+        thread::sleep(args.poll);
+    }
+}
+
+/// Whether the message of the file `name` is waiting in the spool.
+fn is_spooled(spool: &Spool, name: &str) -> bool {
+    let pending = spool.pending().unwrap_or_default();
+    pending
+        .iter()
+        .rev()
+        .any(|id| spool.metadata(*id).is_ok_and(|metadata| metadata.get(FILE).is_some_and(|file| file == name)))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Host part of a `host:port` address.
+fn host(addr: &str) -> &str {
+    addr.rsplit_once(':').map_or(addr, |(host, _)| host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+#[cfg(feature = "tls")]
+fn configure_tls(config: &mut MllpClientConfig, ca: &Path, server_name: &str) -> Result<(), String> {
+    use mllp_rs::tls::TlsConnector;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::CertificateDer;
+    use rustls::{ClientConfig, RootCertStore};
+
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca).map_err(|e| format!("cannot read {}: {}", ca.display(), e))?;
+    for cert in certs {
+        let cert = cert.map_err(|e| format!("cannot read {}: {}", ca.display(), e))?;
+        roots.add(cert).map_err(|e| format!("invalid certificate in {}: {}", ca.display(), e))?;
+    }
+    let tls = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    let connector = TlsConnector::new(Arc::new(tls), server_name).map_err(|e| e.to_string())?;
+    config.tls = Some(Arc::new(connector));
+
+    Ok(())
+}
+
+#[cfg(not(feature = "tls"))]
+fn configure_tls(_: &mut MllpClientConfig, _: &Path, _: &str) -> Result<(), String> {
+    Err("--tls-ca needs the tls feature".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::{host, parse_args};
+
+    #[test]
+    fn it_parses_arguments() {
+        let args: Vec<String> = ["--drop", "in", "--spool", "spool", "--to", "lab:2575", "--poll-ms", "200"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let args = parse_args(&args).unwrap();
+        assert_eq!(args.to, "lab:2575");
+        assert_eq!(args.poll, Duration::from_millis(200));
+        assert!(parse_args(&["--drop".to_owned(), "in".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn it_takes_the_host_of_addresses() {
+        assert_eq!(host("lab.example.org:2575"), "lab.example.org");
+        assert_eq!(host("[fd00::1]:2575"), "fd00::1");
+    }
+}
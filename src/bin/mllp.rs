@@ -0,0 +1,297 @@
+//! `mllp` command line tool.
+//!
+//! ```text
+//! mllp check --host <host> --port <port> [--send-test-msg <file>] [--timeout <ms>]
+//!            [--warn-ms <ms>] [--crit-ms <ms>] [--json]
+//! ```
+//!
+//! `check` probes an MLLP endpoint the way monitoring systems (Nagios, Icinga, ...) expect: it
+//! prints a one-line summary and exits with 0 (OK), 1 (WARNING), 2 (CRITICAL) or 3 (UNKNOWN).
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use mllp_rs::event::json_string;
+use mllp_rs::{MllpCodec, MllpDecoder, MllpError};
+
+const USAGE: &str = "usage: mllp check --host <host> --port <port> [--send-test-msg <file>] [--timeout <ms>] \
+                     [--warn-ms <ms>] [--crit-ms <ms>] [--json]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Warning => "WARNING",
+            Status::Critical => "CRITICAL",
+            Status::Unknown => "UNKNOWN",
+        }
+    }
+
+    fn exit_code(self) -> ExitCode {
+        ExitCode::from(self as u8)
+    }
+}
+
+#[derive(Debug)]
+struct CheckArgs {
+    host: String,
+    port: u16,
+    test_message: Option<String>,
+    timeout: Duration,
+    warn: Duration,
+    crit: Duration,
+    json: bool,
+}
+
+#[derive(Debug, Default)]
+struct CheckResult {
+    status: Option<Status>,
+    summary: String,
+    connect: Option<Duration>,
+    ack: Option<Duration>,
+    ack_code: Option<String>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("check") => match parse_check_args(&args[1..]) {
+            Ok(check_args) => {
+                let result = check(&check_args);
+                print_result(&result, check_args.json);
+                result.status.unwrap_or(Status::Unknown).exit_code()
+            }
+            Err(message) => {
+                eprintln!("{}\n{}", message, USAGE);
+                Status::Unknown.exit_code()
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            Status::Unknown.exit_code()
+        }
+    }
+}
+
+fn parse_check_args(args: &[String]) -> Result<CheckArgs, String> {
+    let mut host = None;
+    let mut port = None;
+    let mut check_args = CheckArgs {
+        host: String::new(),
+        port: 0,
+        test_message: None,
+        timeout: Duration::from_secs(10),
+        warn: Duration::from_secs(1),
+        crit: Duration::from_secs(5),
+        json: false,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or_else(|| format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--host" => host = Some(value()?),
+            "--port" => port = Some(value()?.parse().map_err(|_| "invalid port".to_owned())?),
+            "--send-test-msg" => check_args.test_message = Some(value()?),
+            "--timeout" => check_args.timeout = parse_millis(&value()?)?,
+            "--warn-ms" => check_args.warn = parse_millis(&value()?)?,
+            "--crit-ms" => check_args.crit = parse_millis(&value()?)?,
+            "--json" => check_args.json = true,
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+
+    check_args.host = host.ok_or("missing --host")?;
+    check_args.port = port.ok_or("missing --port")?;
+    Ok(check_args)
+}
+
+fn parse_millis(value: &str) -> Result<Duration, String> {
+    value.parse().map(Duration::from_millis).map_err(|_| format!("invalid duration {}", value))
+}
+
+fn check(args: &CheckArgs) -> CheckResult {
+    let mut result = CheckResult::default();
+
+    let test_message = match &args.test_message {
+        Some(path) => match fs::read(path) {
+            Ok(message) => Some(to_segment_separators(&message)),
+            Err(e) => return result.finish(Status::Unknown, format!("cannot read {}: {}", path, e)),
+        },
+        None => None,
+    };
+
+    let addrs = match (args.host.as_str(), args.port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect::<Vec<_>>(),
+        Err(e) => return result.finish(Status::Critical, format!("cannot resolve {}: {}", args.host, e)),
+    };
+
+    let start = Instant::now();
+    let mut connected = Err(io::Error::new(io::ErrorKind::NotFound, "no address"));
+    for addr in &addrs {
+        connected = TcpStream::connect_timeout(addr, args.timeout);
+        if connected.is_ok() {
+            break;
+        }
+    }
+    let mut stream = match connected {
+        Ok(stream) => stream,
+        Err(e) => return result.finish(Status::Critical, format!("cannot connect to {}:{}: {}", args.host, args.port, e)),
+    };
+    let connect_time = start.elapsed();
+    result.connect = Some(connect_time);
+
+    let Some(test_message) = test_message else {
+        let status = latency_status(connect_time, args);
+        return result.finish(status, format!("connected in {} ms", connect_time.as_millis()));
+    };
+
+    let start = Instant::now();
+    let response = stream
+        .set_read_timeout(Some(args.timeout))
+        .and_then(|_| stream.write_all(&MllpCodec::encode(&test_message)))
+        .map_err(MllpError::from)
+        .and_then(|_| MllpDecoder::new().read_frame(&mut stream));
+    let ack_time = start.elapsed();
+
+    let response = match response {
+        Ok(response) => response,
+        Err(MllpError::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+            return result.finish(Status::Critical, format!("no ACK within {} ms", args.timeout.as_millis()));
+        }
+        Err(e) => return result.finish(Status::Critical, format!("no ACK: {}", e)),
+    };
+    result.ack = Some(ack_time);
+
+    let (ack_status, description) = if MllpCodec::is_ack(&MllpCodec::encode(&response)) {
+        (Status::Ok, "commit ACK".to_owned())
+    } else if MllpCodec::is_nak(&MllpCodec::encode(&response)) {
+        (Status::Warning, "commit NAK".to_owned())
+    } else {
+        match acknowledgment_code(&response) {
+            Some(code) => {
+                let status = match code.as_str() {
+                    "AA" | "CA" => Status::Ok,
+                    "AR" | "CR" => Status::Critical,
+                    _ => Status::Warning,
+                };
+                result.ack_code = Some(code.clone());
+                (status, format!("ACK {}", code))
+            }
+            None => (Status::Warning, "response without MSA segment".to_owned()),
+        }
+    };
+
+    let status = ack_status.max(latency_status(ack_time, args));
+    result.finish(
+        status,
+        format!("connected in {} ms, {} in {} ms", connect_time.as_millis(), description, ack_time.as_millis()),
+    )
+}
+
+impl CheckResult {
+    fn finish(mut self, status: Status, summary: String) -> Self {
+        self.status = Some(status);
+        self.summary = summary;
+        self
+    }
+}
+
+fn latency_status(latency: Duration, args: &CheckArgs) -> Status {
+    if latency >= args.crit {
+        Status::Critical
+    } else if latency >= args.warn {
+        Status::Warning
+    } else {
+        Status::Ok
+    }
+}
+
+/// HL7 files are usually edited with line feeds, while segments must be separated by `\r`.
+fn to_segment_separators(message: &[u8]) -> Vec<u8> {
+    let text = String::from_utf8_lossy(message);
+    let segments: Vec<&str> = text.lines().filter(|line| !line.is_empty()).collect();
+    segments.join("\r").into_bytes()
+}
+
+/// Returns MSA-1, the acknowledgment code of an HL7 ACK message.
+fn acknowledgment_code(message: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(message);
+    let msa = text.split(['\r', '\n']).find(|segment| segment.starts_with("MSA|"))?;
+    msa.split('|').nth(1).map(str::to_owned)
+}
+
+fn print_result(result: &CheckResult, json: bool) {
+    let status = result.status.unwrap_or(Status::Unknown);
+
+    if json {
+        let millis = |duration: Option<Duration>| duration.map_or("null".to_owned(), |d| d.as_millis().to_string());
+        println!(
+            "{{\"status\":\"{}\",\"summary\":{},\"connect_ms\":{},\"ack_ms\":{},\"ack_code\":{}}}",
+            status.label(),
+            json_string(&result.summary),
+            millis(result.connect),
+            millis(result.ack),
+            result.ack_code.as_deref().map_or("null".to_owned(), json_string)
+        );
+        return;
+    }
+
+    let mut perfdata = Vec::new();
+    if let Some(connect) = result.connect {
+        perfdata.push(format!("connect={:.3}s", connect.as_secs_f64()));
+    }
+    if let Some(ack) = result.ack {
+        perfdata.push(format!("ack={:.3}s", ack.as_secs_f64()));
+    }
+    if perfdata.is_empty() {
+        println!("MLLP {} - {}", status.label(), result.summary);
+    } else {
+        println!("MLLP {} - {} | {}", status.label(), result.summary, perfdata.join(" "));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_acknowledgment_code() {
+        let ack = b"MSH|^~\\&|LIS|LAB|||20240101||ACK|1|P|2.5\rMSA|AE|MSG00001|bad field";
+        assert_eq!(acknowledgment_code(ack).as_deref(), Some("AE"));
+        assert_eq!(acknowledgment_code(b"MSH|^~\\&|LIS"), None);
+    }
+
+    #[test]
+    fn it_converts_line_feeds_to_segment_separators() {
+        assert_eq!(to_segment_separators(b"MSH|1\nPID|2\r\n"), b"MSH|1\rPID|2");
+    }
+
+    #[test]
+    fn it_parses_check_arguments() {
+        let args: Vec<String> = ["--host", "lab", "--port", "2575", "--warn-ms", "200", "--json"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let args = parse_check_args(&args).unwrap();
+
+        assert_eq!(args.host, "lab");
+        assert_eq!(args.port, 2575);
+        assert_eq!(args.warn, Duration::from_millis(200));
+        assert!(args.json);
+        assert!(parse_check_args(&["--host".to_owned()]).is_err());
+    }
+}
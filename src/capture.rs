@@ -0,0 +1,217 @@
+//! Capture of message payloads to disk, for troubleshooting.
+//!
+//! Keeping every payload of a high-volume feed is rarely possible, for storage as well as privacy
+//! reasons. [`PayloadCapture`] only keeps a sample of the messages, and/or the messages whose
+//! delivery failed, and stops writing once its size budget is used.
+//! ```no_run
+//! use std::sync::Arc;
+//! use mllp_rs::capture::{CaptureConfig, PayloadCapture};
+//! use mllp_rs::client::MllpClientConfig;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let capture = PayloadCapture::new("/var/lib/mllp/capture", CaptureConfig {
+//!     sample_rate: 0.01,
+//!     on_error: true,
+//!     ..CaptureConfig::default()
+//! })?;
+//! let config = MllpClientConfig {
+//!     capture: Some(Arc::new(capture)),
+//!     ..MllpClientConfig::default()
+//! };
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether a captured message was sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Settings of a [`PayloadCapture`].
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    /// Fraction of the messages to capture, between 0 (none) and 1 (all). Sampling is
+    /// deterministic: with 0.25, every fourth message is captured.
+    pub sample_rate: f64,
+    /// Also capture every message whose delivery failed, whether or not it is sampled.
+    pub on_error: bool,
+    /// Payloads longer than this are truncated in their capture file.
+    pub max_file_bytes: u64,
+    /// Once the capture directory holds this many bytes, nothing more is captured.
+    pub max_total_bytes: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig {
+            sample_rate: 0.0,
+            on_error: true,
+            max_file_bytes: 1024 * 1024,
+            max_total_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Writer of sampled payloads, one file per message, in a directory.
+///
+/// Files are named `<milliseconds since epoch>-<sequence>-<in|out>-<peer address>.hl7`, with the
+/// `:` of the address replaced by `_`, and hold the raw payload. Files already in the directory
+/// count towards [`CaptureConfig::max_total_bytes`].
+#[derive(Debug)]
+pub struct PayloadCapture {
+    dir: PathBuf,
+    config: CaptureConfig,
+    state: Mutex<CaptureState>,
+}
+
+#[derive(Debug)]
+struct CaptureState {
+    /// Messages seen, sampled or not.
+    seen: u64,
+    /// Files written, used to keep file names unique.
+    written: u64,
+    total_bytes: u64,
+}
+
+impl PayloadCapture {
+    /// Creates a capture writing to `dir`, creating the directory if needed.
+    pub fn new<P: AsRef<Path>>(dir: P, config: CaptureConfig) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let mut total_bytes = 0;
+        for entry in fs::read_dir(&dir)? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                total_bytes += metadata.len();
+            }
+        }
+
+        Ok(PayloadCapture {
+            dir,
+            config,
+            state: Mutex::new(CaptureState {
+                seen: 0,
+                written: 0,
+                total_bytes,
+            }),
+        })
+    }
+
+    /// Bytes currently used in the capture directory.
+    pub fn total_bytes(&self) -> u64 {
+        self.lock().total_bytes
+    }
+
+    /// Offers a message to the capture. Returns whether it was written.
+    ///
+    /// `failed` tells whether the delivery of the message failed, for
+    /// [`CaptureConfig::on_error`].
+    pub fn record(&self, direction: Direction, peer: SocketAddr, payload: &[u8], failed: bool) -> io::Result<bool> {
+        let mut state = self.lock();
+        state.seen += 1;
+
+        let sampled = sample(self.config.sample_rate, state.seen);
+        if !(sampled || (failed && self.config.on_error)) {
+            return Ok(false);
+        }
+
+        let kept = &payload[..payload.len().min(self.config.max_file_bytes as usize)];
+        if state.total_bytes + kept.len() as u64 > self.config.max_total_bytes {
+            return Ok(false);
+        }
+
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let suffix = match direction {
+            Direction::Inbound => "in",
+            Direction::Outbound => "out",
+        };
+        state.written += 1;
+        let peer = peer.to_string().replace(':', "_");
+        let path = self.dir.join(format!("{}-{:06}-{}-{}.hl7", millis, state.written, suffix, peer));
+
+        File::create(path)?.write_all(kept)?;
+        state.total_bytes += kept.len() as u64;
+
+        Ok(true)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CaptureState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Whether message number `n` (starting at 1) is part of a sample taken at `rate`.
+fn sample(rate: f64, n: u64) -> bool {
+    let rate = rate.clamp(0.0, 1.0);
+    (n as f64 * rate).floor() > ((n - 1) as f64 * rate).floor()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net::SocketAddr;
+    use crate::capture::{sample, CaptureConfig, Direction, PayloadCapture};
+
+    fn capture_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("mllp-rs-capture-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 2575))
+    }
+
+    #[test]
+    fn it_samples_at_the_configured_rate() {
+        assert_eq!((1..=100).filter(|n| sample(0.25, *n)).count(), 25);
+        assert_eq!((1..=100).filter(|n| sample(1.0, *n)).count(), 100);
+        assert_eq!((1..=100).filter(|n| sample(0.0, *n)).count(), 0);
+    }
+
+    #[test]
+    fn it_captures_failed_messages_only() {
+        let dir = capture_dir("errors");
+        let capture = PayloadCapture::new(&dir, CaptureConfig::default()).unwrap();
+
+        assert!(!capture.record(Direction::Outbound, peer(), b"MSH|ok", false).unwrap());
+        assert!(capture.record(Direction::Outbound, peer(), b"MSH|failed", true).unwrap());
+
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_enforces_size_caps() {
+        let dir = capture_dir("caps");
+        let config = CaptureConfig {
+            sample_rate: 1.0,
+            max_file_bytes: 4,
+            max_total_bytes: 10,
+            ..CaptureConfig::default()
+        };
+        let capture = PayloadCapture::new(&dir, config).unwrap();
+
+        assert!(capture.record(Direction::Inbound, peer(), b"MSH|1234", false).unwrap());
+        assert!(capture.record(Direction::Inbound, peer(), b"MSH|1234", false).unwrap());
+        assert!(!capture.record(Direction::Inbound, peer(), b"MSH|1234", false).unwrap());
+        assert_eq!(capture.total_bytes(), 8);
+
+        // files from a previous run count towards the budget
+        let capture = PayloadCapture::new(&dir, CaptureConfig::default()).unwrap();
+        assert_eq!(capture.total_bytes(), 8);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,147 @@
+//! Decoding of HL7 payloads according to their character set, MSH-18.
+//!
+//! A payload is bytes; reading it as UTF-8 garbles the diacritics of a message sent in ISO
+//! 8859-1, as many are. [`decode_payload`] reads the character set the sender declared and
+//! decodes with it, and fails instead of guessing on bytes which are not valid in it.
+//! ```
+//! use mllp_rs::charset::decode_payload;
+//!
+//! let payload = b"MSH|^~\\&|LAB||EHR||20240131||ADT^A01|MSG1|P|2.5||||||8859/1\rPID|1||||M\xfcller^J\xfcrgen";
+//! assert!(decode_payload(payload).unwrap().ends_with("PID|1||||Müller^Jürgen"));
+//! ```
+
+use alloc::string::String;
+use core::fmt;
+use core::str;
+
+/// Character set of a payload, among the values of HL7 table 0211.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Charset {
+    /// `ASCII`, the default when MSH-18 is empty: 7-bit ASCII.
+    Ascii,
+    /// `8859/1`: ISO 8859-1, Western European.
+    Latin1,
+    /// `8859/15`: ISO 8859-15, ISO 8859-1 with the euro sign, `Œ`, `Š`, `Ž` and `Ÿ`.
+    Latin9,
+    /// `UNICODE UTF-8`.
+    Utf8,
+}
+
+impl Charset {
+    /// Character set named by an MSH-18 value, `None` if it is not supported. Both the HL7 names
+    /// and the `ISO IR` names of DICOM are recognized, in any case.
+    pub fn from_name(name: &str) -> Option<Charset> {
+        match name.trim().to_ascii_uppercase().as_str() {
+            "" | "ASCII" | "ISO IR6" => Some(Charset::Ascii),
+            "8859/1" | "ISO IR100" => Some(Charset::Latin1),
+            "8859/15" => Some(Charset::Latin9),
+            "UNICODE UTF-8" | "ISO IR192" => Some(Charset::Utf8),
+            _ => None,
+        }
+    }
+
+    /// Decodes `bytes`, failing at the first byte which is not valid in this character set.
+    pub fn decode(self, bytes: &[u8]) -> Result<String, CharsetError> {
+        let invalid = |offset| CharsetError::Invalid { charset: self, offset };
+        match self {
+            Charset::Ascii => match bytes.iter().position(|b| !b.is_ascii()) {
+                Some(offset) => Err(invalid(offset)),
+                None => Ok(bytes.iter().map(|b| *b as char).collect()),
+            },
+            Charset::Latin1 => Ok(bytes.iter().map(|b| *b as char).collect()),
+            Charset::Latin9 => Ok(bytes.iter().map(|b| latin9(*b)).collect()),
+            Charset::Utf8 => match str::from_utf8(bytes) {
+                Ok(text) => Ok(text.into()),
+                Err(e) => Err(invalid(e.valid_up_to())),
+            },
+        }
+    }
+}
+
+/// Why a payload could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CharsetError {
+    /// MSH-18 names a character set which is not supported. Holds the name.
+    Unsupported(String),
+    /// The byte at `offset` is not valid in `charset`.
+    Invalid { charset: Charset, offset: usize },
+}
+
+impl fmt::Display for CharsetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CharsetError::Unsupported(name) => write!(f, "Unsupported character set {}", name),
+            CharsetError::Invalid { charset, offset } => write!(f, "Invalid {:?} byte at offset {}", charset, offset),
+        }
+    }
+}
+
+impl core::error::Error for CharsetError { }
+
+/// Character set declared in MSH-18 of `payload`. Without an MSH segment or an MSH-18 field, the
+/// HL7 default, ASCII. Of a repeated field, the first repetition is the character set of the
+/// message, the others those switched to by escape sequences.
+pub fn charset(payload: &[u8]) -> Result<Charset, CharsetError> {
+    let name = msh_18(payload).unwrap_or_default();
+    // the MSH segment is ASCII in any supported character set
+    let name = String::from_utf8_lossy(name);
+
+    Charset::from_name(&name).ok_or(CharsetError::Unsupported(name.into_owned()))
+}
+
+/// Decodes `payload` with the character set declared in its MSH-18.
+pub fn decode_payload(payload: &[u8]) -> Result<String, CharsetError> {
+    charset(payload)?.decode(payload)
+}
+
+/// First repetition of MSH-18.
+fn msh_18(payload: &[u8]) -> Option<&[u8]> {
+    let separator = *payload.strip_prefix(b"MSH")?.first()?;
+    let segment = payload.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let mut fields = segment.split(|b| *b == separator);
+    // MSH-2 holds the encoding characters, the repetition separator second
+    let repetition = fields.nth(1)?.get(1).copied();
+    let field = fields.nth(15)?;
+
+    field.split(|b| Some(*b) == repetition).next()
+}
+
+/// Character of an ISO 8859-15 byte.
+fn latin9(byte: u8) -> char {
+    match byte {
+        0xA4 => '€',
+        0xA6 => 'Š',
+        0xA8 => 'š',
+        0xB4 => 'Ž',
+        0xB8 => 'ž',
+        0xBC => 'Œ',
+        0xBD => 'œ',
+        0xBE => 'Ÿ',
+        _ => byte as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::charset::{charset, decode_payload, Charset, CharsetError};
+
+    fn message(msh_18: &str, name: &[u8]) -> Vec<u8> {
+        let mut payload = format!("MSH|^~\\&|LAB||EHR||20240131||ADT^A01|MSG1|P|2.5||||||{}\rPID|1||||", msh_18).into_bytes();
+        payload.extend_from_slice(name);
+        payload
+    }
+
+    #[test]
+    fn it_decodes_with_the_declared_charset() {
+        assert_eq!(charset(&message("UNICODE UTF-8~8859/1", b"")), Ok(Charset::Utf8));
+        assert_eq!(charset(b"MSH|^~\\&|LAB"), Ok(Charset::Ascii));
+        assert!(decode_payload(&message("UNICODE UTF-8", "Müller".as_bytes())).unwrap().ends_with("Müller"));
+        assert!(decode_payload(&message("8859/15", b"\xa6imon \xa4")).unwrap().ends_with("Šimon €"));
+
+        let ascii = message("", b"M\xfcller");
+        assert_eq!(decode_payload(&ascii), Err(CharsetError::Invalid { charset: Charset::Ascii, offset: ascii.len() - 5 }));
+        let utf8 = message("UNICODE UTF-8", b"M\xfcller");
+        assert_eq!(decode_payload(&utf8), Err(CharsetError::Invalid { charset: Charset::Utf8, offset: utf8.len() - 5 }));
+        assert_eq!(decode_payload(&message("8859/5", b"")), Err(CharsetError::Unsupported("8859/5".into())));
+    }
+}
@@ -0,0 +1,2191 @@
+//! Blocking MLLP client.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::mem::MaybeUninit;
+use std::ops::RangeInclusive;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use crate::audit::{AuditRecord, AuditSink, Disposition};
+use crate::capture::{Direction, PayloadCapture};
+use crate::clock;
+use crate::config::MllpClientConfigBuilder;
+use crate::dead_letter::{DeadLetter, DeadLetterReason, DeadLetterSink};
+use crate::discovery::SrvDestination;
+#[cfg(feature = "hl7")]
+use crate::hl7::{Acknowledgement, Message};
+use crate::proxy::Proxy;
+use crate::rate_limit::Throttle;
+use crate::reconnect::{ExponentialBackoff, ReconnectPolicy};
+use crate::sequence::SequenceNumbers;
+use crate::spool::Metadata;
+use crate::stats::{DestinationStats, StatsRecorder};
+use crate::memory::{DuplexStream, MemoryConnector};
+use crate::trace;
+use crate::transport::Transport;
+use crate::event::{Event, EventKind, EventSink};
+use crate::journal::FrameJournal;
+use crate::metrics::{Counter, Histogram, Metrics};
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConnector, TlsStream};
+use crate::{random, AckMode, LowerLayerCodec, MllpCodec, MllpDecoder, MllpError, MllpSyntaxError, Timeout, ACK, NAK};
+use crate::UNSPECIFIED_ADDR;
+
+/// Acknowledgement returned by the receiver of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Ack {
+    /// MLLP commit acknowledgement, `<SB><ACK><EB><CR>`.
+    Commit,
+    /// Any other frame, usually an HL7 ACK message. Holds the decoded payload.
+    Application(Vec<u8>),
+    /// Nothing was waited for, in [`AckMode::None`], or nothing was sent, for a message already
+    /// delivered by a [`SpoolingClient`](crate::spool::SpoolingClient).
+    None,
+}
+
+/// Settings of an [`MllpClient`].
+#[derive(Debug, Clone)]
+pub struct MllpClientConfig {
+    /// How long to wait for the acknowledgement of a message. `None` waits forever.
+    pub ack_timeout: Option<Duration>,
+    /// How long to wait for a connection to be established, to each address tried. `None`
+    /// waits for the system to give up.
+    pub connect_timeout: Option<Duration>,
+    /// How long to wait for the first byte of the acknowledgement of a message, for the
+    /// receivers which accept connections but never answer. `None` waits for the
+    /// [acknowledgement timeout](MllpClientConfig::ack_timeout).
+    pub first_byte_timeout: Option<Duration>,
+    /// Longest silence in the middle of an acknowledgement frame. `None` waits for the
+    /// [acknowledgement timeout](MllpClientConfig::ack_timeout).
+    pub inter_byte_timeout: Option<Duration>,
+    /// How long a write may wait for the receiver to read. `None` waits forever.
+    ///
+    /// Unlike the acknowledgement timeout, the connect, first byte, inter-byte and write
+    /// timeouts fail the message with [`MllpError::Timeout`] without retransmitting it: the
+    /// connection is given up as if it failed.
+    pub write_timeout: Option<Duration>,
+    /// How many times a message is retransmitted after a timeout or a NAK.
+    pub max_retries: u32,
+    /// Delay before the first retransmission. It doubles with each further retransmission.
+    pub retry_backoff: Duration,
+    /// When connected to a fallback endpoint, how long to wait before trying the first endpoint
+    /// again. `None` stays on the fallback endpoint.
+    pub failback_after: Option<Duration>,
+    /// Keep-open mode: when the connection is found closed or fails while sending, reconnect to
+    /// the same endpoint and send the message again, instead of returning the error.
+    pub keep_open: bool,
+    /// Enables TCP keepalive probes on the connections, sent after the connection has been idle
+    /// for the given duration. `None` leaves the system default.
+    pub tcp_keepalive: Option<Duration>,
+    /// How many reconnections are attempted in keep-open mode before giving up.
+    pub max_reconnect_attempts: u32,
+    /// Delay between the first and second reconnection attempts. It doubles with each further
+    /// attempt, up to one minute.
+    pub reconnect_backoff: Duration,
+    /// Timing of the reconnection attempts, in place of `max_reconnect_attempts` and
+    /// `reconnect_backoff`, and told when the client gives up.
+    pub reconnect_policy: Option<Arc<dyn ReconnectPolicy>>,
+    /// Receiver of the protocol events of the client's connections, including the connection
+    /// state changes.
+    pub event_sink: Option<Arc<dyn EventSink>>,
+    /// Capture of the sent payloads. A message counts as failed when `send` returns an error.
+    pub capture: Option<Arc<PayloadCapture>>,
+    /// Journal of every frame written and read, acknowledgements and retransmissions included.
+    pub journal: Option<Arc<FrameJournal>>,
+    /// Destination of the messages failing with [`MllpError::AckTimeout`] or [`MllpError::Nak`].
+    pub dead_letter: Option<Arc<dyn DeadLetterSink>>,
+    /// Audit trail of the messages sent, and of whether they were acknowledged.
+    pub audit: Option<Arc<dyn AuditSink>>,
+    /// Local address the connections are made from, for firewalls only accepting given source
+    /// addresses. `None` lets the system pick the address of the outgoing interface.
+    pub bind_addr: Option<IpAddr>,
+    /// Local ports the connections are made from, for firewalls only accepting given source
+    /// ports. The first free port of the range is used, starting from a random one so that a
+    /// port just closed and still in `TIME_WAIT` is not reused right away. `None` lets the
+    /// system pick an ephemeral port.
+    pub source_ports: Option<RangeInclusive<u16>>,
+    /// Network interface the connections are made through, such as `eth1.20` on a multi-homed
+    /// host, whatever the routing table says. Only supported on Linux and Android, where it may
+    /// need the `CAP_NET_RAW` capability; elsewhere connecting fails.
+    pub bind_interface: Option<String>,
+    /// Acknowledgement frames the receiver sends back. Frames not expected in this mode are
+    /// ignored; in [`AckMode::Both`], [`MllpClient::send`] returns the application
+    /// acknowledgement once both frames arrived. `None` takes the first frame received, commit
+    /// or application acknowledgement, as the acknowledgement.
+    pub ack_mode: Option<AckMode>,
+    /// How long the connection may stay idle, nothing written, before
+    /// [`MllpClient::heartbeat`] sends a heartbeat, so that firewalls dropping idle connections
+    /// keep this one. `None` sends no heartbeat.
+    pub heartbeat_interval: Option<Duration>,
+    /// Payload of the heartbeats, the empty block `<SB><EB><CR>` by default. Heartbeats are not
+    /// acknowledged: the receiver must swallow them, as an [`MllpServer`] does with the same
+    /// [`heartbeat_payload`](crate::server::MllpServerConfig::heartbeat_payload).
+    ///
+    /// [`MllpServer`]: crate::server::MllpServer
+    pub heartbeat_payload: Vec<u8>,
+    /// Largest acknowledgement frame read, `<SB>`, `<EB>` and `<CR>` included; a larger one
+    /// fails the message with [`MllpError::Syntax`]. `None` reads frames of any size.
+    pub max_frame_size: Option<usize>,
+    /// Framing of the messages and acknowledgements on the wire. `None` frames them with MLLP.
+    pub codec: Option<Arc<dyn LowerLayerCodec>>,
+    /// Ignores the bytes a receiver sends before its first frame, such as the text banner some
+    /// devices print on connect, instead of failing the first message with
+    /// [`MllpError::Syntax`].
+    pub skip_banner: bool,
+    /// Receiver of the metrics of the connections and of the messages sent.
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// How many messages [`MllpClient::send_batch`] writes ahead of their acknowledgements.
+    pub max_in_flight: usize,
+    /// Numbers the messages in MSH-13, following the HL7 sequence number protocol. See
+    /// [`sequence`](crate::sequence).
+    pub sequence_numbers: Option<Arc<SequenceNumbers>>,
+    /// Limits of the messages and bytes sent per second, adjustable while sending. `send`
+    /// waits for the throttle before each message, retransmissions excepted.
+    pub throttle: Option<Arc<Throttle>>,
+    /// Proxy the connections go through. The TLS session, if any, is made through the tunnel.
+    pub proxy: Option<Proxy>,
+    /// Makes the connections over TLS. Connections opened with the same connector resume the
+    /// TLS sessions of the previous ones.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<TlsConnector>>,
+}
+
+impl Default for MllpClientConfig {
+    fn default() -> Self {
+        MllpClientConfig {
+            ack_timeout: Some(Duration::from_secs(30)),
+            connect_timeout: None,
+            first_byte_timeout: None,
+            inter_byte_timeout: None,
+            write_timeout: None,
+            max_retries: 3,
+            retry_backoff: Duration::from_secs(1),
+            failback_after: None,
+            keep_open: false,
+            tcp_keepalive: None,
+            max_reconnect_attempts: 5,
+            reconnect_backoff: Duration::from_millis(500),
+            reconnect_policy: None,
+            event_sink: None,
+            capture: None,
+            journal: None,
+            dead_letter: None,
+            audit: None,
+            bind_addr: None,
+            source_ports: None,
+            bind_interface: None,
+            ack_mode: None,
+            heartbeat_interval: None,
+            heartbeat_payload: Vec::new(),
+            max_frame_size: None,
+            codec: None,
+            skip_banner: false,
+            metrics: None,
+            max_in_flight: 64,
+            sequence_numbers: None,
+            throttle: None,
+            proxy: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+/// Upper bound of the delay between reconnection attempts.
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+impl MllpClientConfig {
+    /// Builder of a configuration, checking it when built.
+    pub fn builder() -> MllpClientConfigBuilder {
+        MllpClientConfigBuilder::default()
+    }
+
+    /// Delay to wait before retransmission number `retry` (starting at 0).
+    fn backoff(&self, retry: u32) -> Duration {
+        self.retry_backoff.saturating_mul(2u32.saturating_pow(retry))
+    }
+
+    /// Policy of the reconnection attempts, exponential from `reconnect_backoff` unless set.
+    fn effective_reconnect_policy(&self) -> Arc<dyn ReconnectPolicy> {
+        match &self.reconnect_policy {
+            Some(policy) => policy.clone(),
+            None => Arc::new(ExponentialBackoff::new(self.reconnect_backoff, MAX_RECONNECT_BACKOFF, self.max_reconnect_attempts)),
+        }
+    }
+
+    fn decoder(&self) -> MllpDecoder {
+        MllpDecoder::framed(self.codec.clone(), self.max_frame_size)
+    }
+
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        match &self.codec {
+            Some(codec) => codec.encode(payload),
+            None => MllpCodec::encode(payload),
+        }
+    }
+}
+
+/// Client sending MLLP framed messages and waiting for their acknowledgement.
+/// ```no_run
+/// use mllp_rs::client::{Ack, MllpClient};
+///
+/// # fn main() -> Result<(), mllp_rs::MllpError> {
+/// let mut client = MllpClient::connect("127.0.0.1:5000")?;
+/// match client.send(b"MSH|^~\\&|WIR|||36|20200514123930||VXU^V04^VXU_V04|43|P|2.5.1|||ER")? {
+///     Ack::Commit => println!("committed"),
+///     Ack::Application(ack) => println!("{}", String::from_utf8_lossy(&ack)),
+///     Ack::None => {}
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A client can be given several endpoints with [`MllpClient::connect_failover`], e.g. the
+/// active and passive nodes of an interface engine. It sends to the first reachable endpoint,
+/// and moves on to the next one when the connection fails or the receiver keeps answering NAK.
+///
+/// With [`MllpClient::connect_srv`], the endpoints are the targets of DNS SRV records instead,
+/// looked up again when their TTL expires.
+pub struct MllpClient {
+    endpoints: Vec<Endpoint>,
+    srv: Option<SrvDestination>,
+    active: usize,
+    failed_over_at: Option<Instant>,
+    connection: Connection,
+    config: MllpClientConfig,
+    /// Statistics of each destination sent to, in the order they were first sent to.
+    stats: Vec<(SocketAddr, StatsRecorder)>,
+}
+
+/// Where a connection is made to.
+enum Endpoint {
+    /// Addresses of a receiver, tried in order.
+    Tcp(Vec<SocketAddr>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Memory(MemoryConnector),
+    /// Function opening a transport.
+    Transport(Arc<OpenTransport>),
+}
+
+/// Opens the transport of a client, see [`MllpClient::connect_transport`].
+type OpenTransport = dyn Fn() -> io::Result<Box<dyn Transport>> + Send + Sync;
+
+impl Endpoint {
+    fn contains(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Endpoint::Tcp(addrs) => addrs.contains(addr),
+            #[cfg(unix)]
+            Endpoint::Unix(_) => false,
+            Endpoint::Memory(_) | Endpoint::Transport(_) => false,
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    decoder: MllpDecoder,
+    /// A frame was received, any banner is over.
+    framed: bool,
+    /// When a frame was last written, for the heartbeats.
+    last_written: Instant,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl Connection {
+    fn open(endpoint: &Endpoint, config: &MllpClientConfig) -> io::Result<Self> {
+        let addrs = match endpoint {
+            Endpoint::Tcp(addrs) => addrs.as_slice(),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => return Self::open_unix(path, config),
+            Endpoint::Memory(connector) => return Self::open_memory(connector, config),
+            Endpoint::Transport(open) => return Self::open_transport(&**open, config),
+        };
+        let (stream, peer_addr) = match &config.proxy {
+            Some(proxy) => Self::tunnel(proxy, addrs, config)?,
+            None => {
+                let stream = Self::connect_tcp(addrs, config)?;
+                let peer_addr = stream.peer_addr()?;
+                (stream, peer_addr)
+            }
+        };
+        if let Some(time) = config.tcp_keepalive {
+            SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        stream.set_write_timeout(config.write_timeout)?;
+        let local_addr = stream.local_addr()?;
+        #[cfg(feature = "tls")]
+        let stream = match &config.tls {
+            Some(connector) => Stream::Tls(Box::new(connector.connect(stream)?)),
+            None => Stream::Tcp(stream),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream = Stream::Tcp(stream);
+
+        Ok(Connection {
+            local_addr,
+            peer_addr,
+            stream,
+            decoder: config.decoder(),
+            framed: false,
+            last_written: Instant::now(),
+        })
+    }
+
+    fn connect_tcp(addrs: &[SocketAddr], config: &MllpClientConfig) -> io::Result<TcpStream> {
+        let default_source = config.bind_addr.is_none() && config.source_ports.is_none() && config.bind_interface.is_none();
+        let connected = match (config.connect_timeout, default_source) {
+            (None, true) => TcpStream::connect(addrs),
+            (Some(timeout), true) => connect_timeout(addrs, timeout),
+            (timeout, false) => connect_from(addrs, config, timeout),
+        };
+
+        connected.map_err(|e| match e.kind() {
+            io::ErrorKind::TimedOut => Timeout::Connect.into(),
+            _ => e,
+        })
+    }
+
+    /// Connects through `proxy` to the first address of `addrs` it opens a tunnel to, and
+    /// returns that address.
+    fn tunnel(proxy: &Proxy, addrs: &[SocketAddr], config: &MllpClientConfig) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to");
+        for addr in addrs {
+            let mut stream = Self::connect_tcp(&[proxy.addr()], config)?;
+            match proxy.tunnel(&mut stream, *addr) {
+                Ok(()) => return Ok((stream, *addr)),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    #[cfg(unix)]
+    fn open_unix(path: &Path, config: &MllpClientConfig) -> io::Result<Self> {
+        if config.proxy.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no proxy for a Unix domain socket"));
+        }
+        #[cfg(feature = "tls")]
+        if config.tls.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no TLS over a Unix domain socket"));
+        }
+
+        let stream = UnixStream::connect(path)?;
+        stream.set_write_timeout(config.write_timeout)?;
+
+        Ok(Connection {
+            stream: Stream::Unix(stream),
+            decoder: config.decoder(),
+            framed: false,
+            last_written: Instant::now(),
+            local_addr: UNSPECIFIED_ADDR,
+            peer_addr: UNSPECIFIED_ADDR,
+        })
+    }
+
+    fn open_memory(connector: &MemoryConnector, config: &MllpClientConfig) -> io::Result<Self> {
+        if config.proxy.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no proxy for an in-memory connection"));
+        }
+        #[cfg(feature = "tls")]
+        if config.tls.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no TLS over an in-memory connection"));
+        }
+
+        Ok(Connection {
+            stream: Stream::Memory(connector.connect()?),
+            decoder: config.decoder(),
+            framed: false,
+            last_written: Instant::now(),
+            local_addr: UNSPECIFIED_ADDR,
+            peer_addr: UNSPECIFIED_ADDR,
+        })
+    }
+
+    fn open_transport(open: &OpenTransport, config: &MllpClientConfig) -> io::Result<Self> {
+        if config.proxy.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no proxy for a transport"));
+        }
+        #[cfg(feature = "tls")]
+        if config.tls.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no TLS over a transport"));
+        }
+
+        Ok(Connection {
+            stream: Stream::Transport(open()?),
+            decoder: config.decoder(),
+            framed: false,
+            last_written: Instant::now(),
+            local_addr: UNSPECIFIED_ADDR,
+            peer_addr: UNSPECIFIED_ADDR,
+        })
+    }
+}
+
+/// Connection stream, plain or over TLS, over a Unix domain socket, in memory, or over a
+/// transport.
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Memory(DuplexStream),
+    Transport(Box<dyn Transport>),
+}
+
+impl Stream {
+    /// Underlying socket, for its options, unless in memory or over a transport.
+    fn socket(&self) -> Option<SockRef<'_>> {
+        match self {
+            Stream::Tcp(stream) => Some(SockRef::from(stream)),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Some(SockRef::from(stream.get_ref())),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Some(SockRef::from(stream)),
+            Stream::Memory(_) | Stream::Transport(_) => None,
+        }
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Memory(stream) => stream.set_read_timeout(timeout),
+            Stream::Transport(transport) => transport.set_read_timeout(timeout),
+            stream => stream.socket().map_or(Ok(()), |socket| socket.set_read_timeout(timeout)),
+        }
+    }
+
+    /// Another handle on the same socket, for the halves of a split connection.
+    fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "only TCP and Unix domain socket connections can be split")),
+        }
+    }
+
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Memory(stream) => stream.shutdown(how),
+            Stream::Transport(transport) => transport.close(),
+            stream => stream.socket().map_or(Ok(()), |socket| socket.shutdown(how)),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Memory(stream) => stream.read(buf),
+            Stream::Transport(transport) => transport.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Memory(stream) => stream.write(buf),
+            Stream::Transport(transport) => transport.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Memory(stream) => stream.flush(),
+            Stream::Transport(transport) => transport.flush(),
+        }
+    }
+}
+
+/// Connects to the first reachable address of `addrs`, waiting at most `timeout` for each.
+fn connect_timeout(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses");
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Connects to the first reachable address of `addrs`, from the `bind_addr`, `bind_interface`
+/// and first free port of the `source_ports` of `config`, waiting at most `timeout` for each.
+fn connect_from(addrs: &[SocketAddr], config: &MllpClientConfig, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let ports: Vec<u16> = match config.source_ports.clone() {
+        Some(ports) if ports.is_empty() => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty source port range"));
+        }
+        Some(ports) => {
+            let mut ports: Vec<u16> = ports.collect();
+            let start = (random() % ports.len() as u64) as usize;
+            ports.rotate_left(start);
+            ports
+        }
+        None => vec![0],
+    };
+
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses");
+    'addrs: for addr in addrs {
+        let ip = match (config.bind_addr, addr) {
+            (Some(ip), _) if ip.is_ipv4() != addr.is_ipv4() => {
+                last_error = io::Error::new(io::ErrorKind::InvalidInput, "bind address family does not match");
+                continue;
+            }
+            (Some(ip), _) => ip,
+            (None, SocketAddr::V4(_)) => Ipv4Addr::UNSPECIFIED.into(),
+            (None, SocketAddr::V6(_)) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+
+        for port in &ports {
+            let result = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))
+                .and_then(|socket| {
+                    if let Some(interface) = &config.bind_interface {
+                        bind_interface(&socket, interface)?;
+                    }
+                    socket.bind(&SocketAddr::new(ip, *port).into())?;
+                    match timeout {
+                        Some(timeout) => socket.connect_timeout(&(*addr).into(), timeout)?,
+                        None => socket.connect(&(*addr).into())?,
+                    }
+                    Ok(socket)
+                });
+            match result {
+                Ok(socket) => return Ok(socket.into()),
+                // port taken, or same local and remote address pair still in TIME_WAIT
+                Err(e) if matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable) => last_error = e,
+                Err(e) => {
+                    last_error = e;
+                    continue 'addrs;
+                }
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_interface(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_interface(_: &Socket, _: &str) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "binding to an interface is only supported on Linux"))
+}
+
+impl MllpClient {
+    /// Connects to `addr` with the default configuration.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::connect_with_config(addr, MllpClientConfig::default())
+    }
+
+    pub fn connect_with_config<A: ToSocketAddrs>(addr: A, config: MllpClientConfig) -> io::Result<Self> {
+        Self::connect_failover(&[addr], config)
+    }
+
+    /// Connects to the first reachable endpoint of `endpoints`, in order.
+    ///
+    /// Further endpoints are used as fallbacks by [`MllpClient::send`].
+    pub fn connect_failover<A: ToSocketAddrs>(endpoints: &[A], config: MllpClientConfig) -> io::Result<Self> {
+        let endpoints = endpoints
+            .iter()
+            .map(|endpoint| endpoint.to_socket_addrs().map(|addrs| Endpoint::Tcp(addrs.collect())))
+            .collect::<io::Result<Vec<Endpoint>>>()?;
+
+        Self::connect_endpoints(endpoints, config)
+    }
+
+    /// Connects to the first reachable target of the SRV records of `destination`, in order of
+    /// priority and weight.
+    ///
+    /// Further targets are used as fallbacks by [`MllpClient::send`]. Once the TTL of the records
+    /// has expired, they are looked up again before the next message; if the receiver connected to
+    /// is no longer listed, the client moves to the first reachable target of the new records.
+    pub fn connect_srv(mut destination: SrvDestination, config: MllpClientConfig) -> io::Result<Self> {
+        let endpoints = destination.endpoints()?.iter().cloned().map(Endpoint::Tcp).collect();
+        let mut client = Self::connect_endpoints(endpoints, config)?;
+        client.srv = Some(destination);
+
+        Ok(client)
+    }
+
+    /// Connects to the Unix domain socket at `path`, for a receiver on the same host.
+    ///
+    /// Such connections have no IP address: [`MllpClient::local_addr`] and
+    /// [`MllpClient::peer_addr`] are the unspecified address `0.0.0.0:0`. The TCP settings of
+    /// `config` are not used, and setting [`MllpClientConfig::tls`] is an error.
+    #[cfg(unix)]
+    pub fn connect_uds<P: AsRef<Path>>(path: P, config: MllpClientConfig) -> io::Result<Self> {
+        Self::connect_endpoints(vec![Endpoint::Unix(path.as_ref().to_owned())], config)
+    }
+
+    /// Connects to the [`MemoryListener`](crate::memory::MemoryListener) of `connector`, for
+    /// tests without sockets.
+    ///
+    /// Such connections have no IP address, like those over a
+    /// [Unix domain socket](MllpClient::connect_uds). The TCP settings of `config` are not used,
+    /// and setting [`MllpClientConfig::tls`] is an error.
+    pub fn connect_memory(connector: MemoryConnector, config: MllpClientConfig) -> io::Result<Self> {
+        Self::connect_endpoints(vec![Endpoint::Memory(connector)], config)
+    }
+
+    /// Connects over the transport opened by `open`, called again to reconnect, e.g. a serial
+    /// link. See the [`transport`](crate::transport) module.
+    ///
+    /// Transports have no IP address, like the connections over a
+    /// [Unix domain socket](MllpClient::connect_uds). The TCP settings of `config` are not used,
+    /// and setting [`MllpClientConfig::tls`] is an error.
+    pub fn connect_transport<F, T>(open: F, config: MllpClientConfig) -> io::Result<Self>
+    where
+        F: Fn() -> io::Result<T> + Send + Sync + 'static,
+        T: Transport + 'static,
+    {
+        let open: Arc<OpenTransport> = Arc::new(move || Ok(Box::new(open()?) as Box<dyn Transport>));
+        Self::connect_endpoints(vec![Endpoint::Transport(open)], config)
+    }
+
+    fn connect_endpoints(endpoints: Vec<Endpoint>, config: MllpClientConfig) -> io::Result<Self> {
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no endpoint to connect to");
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            match Connection::open(endpoint, &config) {
+                Ok(connection) => {
+                    let client = MllpClient {
+                        failed_over_at: (index != 0).then(Instant::now),
+                        endpoints,
+                        srv: None,
+                        active: index,
+                        connection,
+                        config,
+                        stats: Vec::new(),
+                    };
+                    client.emit(EventKind::Connected);
+                    return Ok(client);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    pub fn config(&self) -> &MllpClientConfig {
+        &self.config
+    }
+
+    /// Index, in the list given at construction, of the endpoint currently connected to.
+    pub fn active_endpoint(&self) -> usize {
+        self.active
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.connection.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.peer_addr
+    }
+
+    /// Statistics of each destination sent to, see [`stats`](crate::stats).
+    pub fn stats(&self) -> Vec<DestinationStats> {
+        self.stats.iter().map(|(peer_addr, recorder)| DestinationStats { peer_addr: *peer_addr, stats: recorder.snapshot() }).collect()
+    }
+
+    /// Checks, without blocking, that the connection was not closed by the peer.
+    pub fn is_connected(&self) -> bool {
+        let Some(socket) = self.connection.stream.socket() else {
+            return match &self.connection.stream {
+                Stream::Memory(stream) => stream.is_open(),
+                // no telling without reading
+                _ => true,
+            };
+        };
+        if socket.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let connected = match socket.peek(&mut [MaybeUninit::uninit(); 1]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+        };
+
+        socket.set_nonblocking(false).is_ok() && connected
+    }
+
+    /// Sends `payload` and waits for its acknowledgement.
+    ///
+    /// The message is retransmitted if no acknowledgement arrives within
+    /// [`MllpClientConfig::ack_timeout`] or if the receiver answers with a NAK, up to
+    /// [`MllpClientConfig::max_retries`] times. Once the retries are used up,
+    /// [`MllpError::AckTimeout`] or [`MllpError::Nak`] is returned.
+    ///
+    /// After a timeout, the connection is replaced with a new one, so that a late
+    /// acknowledgement is not taken for the answer to the next message. Application
+    /// acknowledgements whose MSA-2 names another message than the MSH-10 of `payload` are
+    /// skipped.
+    ///
+    /// In [keep-open mode](MllpClientConfig::keep_open), a closed or failed connection is first
+    /// reopened and the message sent again.
+    ///
+    /// If the connection fails or the retries end with a NAK, the message is sent to the next
+    /// endpoint instead, each endpoint being tried at most once per message.
+    ///
+    /// Messages failing for good are handed to the [dead-letter sink](MllpClientConfig::dead_letter)
+    /// before the error is returned.
+    pub fn send(&mut self, payload: &[u8]) -> Result<Ack, MllpError> {
+        self.send_or_dead_letter(payload, &Metadata::new()).map_err(|(e, _)| e)
+    }
+
+    /// Sends `message` as [`MllpClient::send`] does, and returns the answer read from the MSA of
+    /// its acknowledgement. See [`Acknowledgement::from_ack`].
+    #[cfg(feature = "hl7")]
+    pub fn send_hl7(&mut self, message: &Message<'_>) -> Result<Acknowledgement, MllpError> {
+        let ack = self.send(message.payload())?;
+        Ok(Acknowledgement::from_ack(&ack))
+    }
+
+    /// Sends a heartbeat if nothing was written on the connection for
+    /// [`MllpClientConfig::heartbeat_interval`], and returns whether one was sent. Meant to be
+    /// called regularly by the application, between messages; no acknowledgement is waited for.
+    pub fn heartbeat(&mut self) -> Result<bool, MllpError> {
+        let Some(interval) = self.config.heartbeat_interval else {
+            return Ok(false);
+        };
+        if self.connection.last_written.elapsed() < interval {
+            return Ok(false);
+        }
+
+        let frame = self.config.encode(&self.config.heartbeat_payload);
+        if let Err(e) = self.connection.stream.write_all(&frame) {
+            return Err(self.write_error(e));
+        }
+        self.connection.last_written = Instant::now();
+        self.journal(Direction::Outbound, &frame);
+
+        Ok(true)
+    }
+
+    /// Splits the connection into a half receiving frames and a half sending them, to read the
+    /// messages the peer sends on its own on one thread while sending on another, as some
+    /// bidirectional ADT links require.
+    ///
+    /// The halves neither wait for acknowledgements, nor retry, nor reconnect: acknowledging the
+    /// messages received and matching the acknowledgements to the messages sent is left to the
+    /// application. TLS and in-memory connections cannot be split.
+    pub fn into_split(mut self) -> io::Result<(MllpReader, MllpWriter)> {
+        let (mut read, write) = (self.connection.stream.try_clone()?, self.connection.stream.try_clone()?);
+        read.set_read_timeout(None)?;
+        let reader = MllpReader {
+            stream: read,
+            decoder: mem::take(&mut self.connection.decoder),
+            skip_banner: self.config.skip_banner && !self.connection.framed,
+            peer_addr: self.connection.peer_addr,
+        };
+        // the writer takes the event sink along, the client dropped is not a disconnection
+        let writer = MllpWriter {
+            stream: write,
+            config: mem::take(&mut self.config),
+            local_addr: self.connection.local_addr,
+            peer_addr: self.connection.peer_addr,
+        };
+
+        Ok((reader, writer))
+    }
+
+    /// Sends `payloads` pipelined: frames are written without waiting for the acknowledgements
+    /// of the previous ones, up to [`MllpClientConfig::max_in_flight`] ahead, and the
+    /// acknowledgements are read back in order. Returns the outcome of each message, in order.
+    ///
+    /// Messages are not retransmitted: a NAK fails its message with [`MllpError::Nak`] and the
+    /// batch goes on. A timeout or a connection failure ends the batch; it fails the message
+    /// it happened to, the one waiting for its acknowledgement or the one being written, and
+    /// the others not acknowledged yet with an [`io::ErrorKind::ConnectionAborted`] error,
+    /// although those already written may have been received. The connection is then replaced,
+    /// so that the acknowledgements still to come do not answer the next messages.
+    pub fn send_batch(&mut self, payloads: &[&[u8]]) -> Vec<Result<Ack, MllpError>> {
+        let stamped: Vec<Option<(i64, Vec<u8>)>> = payloads
+            .iter()
+            .enumerate()
+            .map(|(i, payload)| self.config.sequence_numbers.as_ref().and_then(|numbers| numbers.stamp(payload, i as i64)))
+            .collect();
+        let payloads: Vec<&[u8]> = payloads.iter().zip(&stamped).map(|(payload, stamped)| stamped.as_ref().map_or(*payload, |(_, stamped)| stamped)).collect();
+
+        let mut results = Vec::with_capacity(payloads.len());
+        let mut latencies = Vec::with_capacity(payloads.len());
+        let mut sent_at = VecDeque::new();
+        let mut failure = None;
+        while results.len() < payloads.len() && failure.is_none() {
+            while sent_at.len() < self.config.max_in_flight.max(1) && results.len() + sent_at.len() < payloads.len() {
+                let payload = payloads[results.len() + sent_at.len()];
+                if let Some(throttle) = &self.config.throttle {
+                    throttle.acquire(payload.len());
+                }
+                let frame = self.config.encode(payload);
+                trace::frame_encoded(frame.len());
+                self.observe(Histogram::FrameSize, frame.len() as f64);
+                if let Err(e) = self.connection.stream.write_all(&frame) {
+                    failure = Some((results.len() + sent_at.len(), self.write_error(e)));
+                    break;
+                }
+                self.connection.last_written = Instant::now();
+                self.journal(Direction::Outbound, &frame);
+                self.emit(EventKind::MessageSent { bytes: payload.len() });
+                sent_at.push_back(Instant::now());
+            }
+
+            if failure.is_some() {
+                break;
+            }
+            let Some(sent) = sent_at.pop_front() else { break };
+            if self.config.ack_mode == Some(AckMode::None) {
+                latencies.push(sent.elapsed());
+                results.push(Ok(Ack::None));
+                continue;
+            }
+            let acked = self.wait_ack(crate::control_id(payloads[results.len()]).as_deref());
+            let latency = sent.elapsed();
+            match acked {
+                Ok(ack) => {
+                    trace::round_trip(latency);
+                    self.observe(Histogram::AckLatency, latency.as_secs_f64());
+                    latencies.push(latency);
+                    results.push(Ok(ack));
+                }
+                Err(MllpError::Nak) => {
+                    latencies.push(latency);
+                    results.push(Err(MllpError::Nak));
+                }
+                // the message waited for is the oldest in flight
+                Err(e) => failure = Some((results.len(), e)),
+            }
+        }
+
+        if let Some((failed, e)) = failure {
+            self.emit(EventKind::Error { message: e.to_string() });
+            // the frames in flight may still be answered, or were cut short
+            let _ = self.reset();
+            let mut e = Some(e);
+            while results.len() < payloads.len() {
+                match e.take_if(|_| results.len() == failed) {
+                    Some(e) => results.push(Err(e)),
+                    None => {
+                        let aborted = io::Error::new(io::ErrorKind::ConnectionAborted, "another message of the batch failed");
+                        results.push(Err(aborted.into()));
+                    }
+                }
+            }
+        }
+
+        for (i, result) in results.iter().enumerate() {
+            match (&self.config.sequence_numbers, &stamped[i], result) {
+                (Some(numbers), Some((number, _)), Ok(ack)) => numbers.acknowledged(*number, ack),
+                // the numbers move on as long as the messages are acknowledged in a row
+                (Some(_), _, Err(_)) => break,
+                _ => {}
+            }
+        }
+        for (i, (payload, result)) in payloads.iter().zip(&results).enumerate() {
+            self.record_stats(payload.len(), latencies.get(i).copied().unwrap_or_default(), result);
+            if let Some(capture) = &self.config.capture {
+                // capturing is best effort and never fails the delivery
+                let _ = capture.record(Direction::Outbound, self.peer_addr(), payload, result.is_err());
+            }
+            self.audit(payload, result);
+            if let Err(e) = result {
+                // a batch is never retried, each message was written once
+                self.dead_letter(payload, &Metadata::new(), e, 1);
+            }
+        }
+
+        results
+    }
+
+    /// Same as [`MllpClient::send`], also telling on failure whether the message was accepted by
+    /// the dead-letter sink. `metadata` goes to the dead letter.
+    pub(crate) fn send_or_dead_letter(&mut self, payload: &[u8], metadata: &Metadata) -> Result<Ack, (MllpError, bool)> {
+        let _span = trace::message(self.peer_addr(), payload);
+        let stamped = self.config.sequence_numbers.as_ref().and_then(|numbers| numbers.stamp(payload, 0));
+        let payload = stamped.as_ref().map_or(payload, |(_, stamped)| stamped);
+        if let Some(throttle) = &self.config.throttle {
+            throttle.acquire(payload.len());
+        }
+        let started = Instant::now();
+        let mut attempts = 0;
+        let result = self.deliver(payload, &mut attempts);
+        self.record_stats(payload.len(), started.elapsed(), &result);
+        if let (Some(numbers), Some((number, _)), Ok(ack)) = (&self.config.sequence_numbers, &stamped, &result) {
+            numbers.acknowledged(*number, ack);
+        }
+        if let Some(capture) = &self.config.capture {
+            // capturing is best effort and never fails the delivery
+            let _ = capture.record(Direction::Outbound, self.peer_addr(), payload, result.is_err());
+        }
+        self.audit(payload, &result);
+
+        result.map_err(|e| {
+            let dead_lettered = self.dead_letter(payload, metadata, &e, attempts);
+            (e, dead_lettered)
+        })
+    }
+
+    /// Records a message of `bytes` in the statistics of the destination it was last sent to.
+    fn record_stats(&mut self, bytes: usize, latency: Duration, result: &Result<Ack, MllpError>) {
+        let peer_addr = self.peer_addr();
+        let index = match self.stats.iter().position(|(destination, _)| *destination == peer_addr) {
+            Some(index) => index,
+            None => {
+                self.stats.push((peer_addr, StatsRecorder::default()));
+                self.stats.len() - 1
+            }
+        };
+        let latency = matches!(result, Ok(ack) if *ack != Ack::None).then_some(latency);
+        self.stats[index].1.record(bytes, latency, result.is_err());
+    }
+
+    fn audit(&self, payload: &[u8], result: &Result<Ack, MllpError>) {
+        let Some(sink) = &self.config.audit else { return };
+        let disposition = match result {
+            Ok(_) => Disposition::Acked,
+            Err(MllpError::Nak) => Disposition::Naked,
+            Err(_) => Disposition::Failed,
+        };
+        // auditing is best effort and never fails the delivery
+        let _ = sink.record(&AuditRecord::new(Direction::Outbound, self.peer_addr(), payload, disposition));
+    }
+
+    /// Hands `payload` to the dead-letter sink, after `attempts` transmissions ending with `error`.
+    fn dead_letter(&self, payload: &[u8], metadata: &Metadata, error: &MllpError, attempts: u32) -> bool {
+        let reason = match error {
+            MllpError::AckTimeout => DeadLetterReason::AckTimeout,
+            MllpError::Nak => DeadLetterReason::Nak,
+            _ => return false,
+        };
+        let Some(sink) = &self.config.dead_letter else {
+            return false;
+        };
+
+        sink.dead_letter(&DeadLetter {
+            payload: payload.to_vec(),
+            reason,
+            peer_addr: self.peer_addr(),
+            time: SystemTime::now(),
+            attempts,
+            metadata: metadata.clone(),
+        })
+        .is_ok()
+    }
+
+    /// Sends `payload`, retrying and failing over as configured. `attempts` counts its
+    /// transmissions, over all the endpoints tried.
+    fn deliver(&mut self, payload: &[u8], attempts: &mut u32) -> Result<Ack, MllpError> {
+        self.refresh_endpoints();
+        self.try_fail_back();
+
+        let mut tried = vec![self.active];
+        let mut reconnected = false;
+
+        if self.config.keep_open && !self.is_connected() {
+            self.emit(EventKind::ConnectionLost { message: "connection closed by peer".to_owned() });
+            reconnected = true;
+            if let Err(e) = self.reconnect() {
+                if !matches!(self.fail_over(&mut tried), Some(Ok(()))) {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        loop {
+            let error = match self.send_to_active(payload, attempts) {
+                Err(e @ (MllpError::Io(_) | MllpError::Timeout(_) | MllpError::ConnectionClosed { .. } | MllpError::Nak)) => e,
+                result => return result,
+            };
+
+            let lost = match &error {
+                MllpError::Io(e) => Some(e.to_string()),
+                MllpError::Timeout(timeout) => Some(timeout.to_string()),
+                e @ MllpError::ConnectionClosed { .. } => Some(e.to_string()),
+                _ => None,
+            };
+            if let Some(message) = lost {
+                if self.config.keep_open && !reconnected {
+                    self.emit(EventKind::ConnectionLost { message });
+                    reconnected = true;
+                    if self.reconnect().is_ok() {
+                        continue;
+                    }
+                }
+            }
+
+            match self.fail_over(&mut tried) {
+                Some(Ok(())) => continue,
+                Some(Err(_)) | None => return Err(error),
+            }
+        }
+    }
+
+    /// Reopens the connection to the active endpoint, with exponential backoff between attempts.
+    fn reconnect(&mut self) -> io::Result<()> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "no reconnection attempt allowed");
+        let policy = self.config.effective_reconnect_policy();
+
+        let mut attempt = 0;
+        while let Some(delay) = policy.delay(attempt) {
+            thread::sleep(delay);
+            attempt += 1;
+            self.emit(EventKind::Reconnecting { attempt });
+
+            match Connection::open(&self.endpoints[self.active], &self.config) {
+                Ok(connection) => {
+                    self.connection = connection;
+                    self.emit(EventKind::Connected);
+                    return Ok(());
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        policy.on_give_up(attempt, &last_error);
+        Err(last_error)
+    }
+
+    /// Connects to the next endpoint not in `tried`. Returns `None` once all endpoints were tried.
+    fn fail_over(&mut self, tried: &mut Vec<usize>) -> Option<io::Result<()>> {
+        let count = self.endpoints.len();
+        let mut last_error = None;
+
+        for next in (1..count).map(|offset| (self.active + offset) % count) {
+            if tried.contains(&next) {
+                continue;
+            }
+            tried.push(next);
+            match Connection::open(&self.endpoints[next], &self.config) {
+                Ok(connection) => {
+                    self.switch_to(next, connection);
+                    return Some(Ok(()));
+                }
+                Err(e) => last_error = Some(Err(e)),
+            }
+        }
+
+        last_error
+    }
+
+    /// Looks the SRV records up again once their TTL has expired.
+    fn refresh_endpoints(&mut self) {
+        let Some(srv) = self.srv.as_mut().filter(|srv| srv.is_expired()) else {
+            return;
+        };
+        let endpoints = match srv.endpoints() {
+            Ok(endpoints) => endpoints.iter().cloned().map(Endpoint::Tcp).collect(),
+            Err(_) => return,
+        };
+        self.endpoints = endpoints;
+
+        let peer_addr = self.peer_addr();
+        if let Some(index) = self.endpoints.iter().position(|endpoint| endpoint.contains(&peer_addr)) {
+            self.active = index;
+            if index == 0 {
+                self.failed_over_at = None;
+            } else if self.failed_over_at.is_none() {
+                self.failed_over_at = Some(Instant::now());
+            }
+            return;
+        }
+
+        // the receiver connected to was withdrawn
+        let connected = self
+            .endpoints
+            .iter()
+            .enumerate()
+            .find_map(|(index, endpoint)| Connection::open(endpoint, &self.config).ok().map(|connection| (index, connection)));
+        match connected {
+            Some((index, connection)) => self.switch_to(index, connection),
+            // keep the current connection for now, the next reconnection uses the new records
+            None => self.active = 0,
+        }
+    }
+
+    /// Goes back to the first endpoint once [`MllpClientConfig::failback_after`] has elapsed.
+    fn try_fail_back(&mut self) {
+        let (Some(failed_over_at), Some(failback_after)) = (self.failed_over_at, self.config.failback_after) else {
+            return;
+        };
+        if failed_over_at.elapsed() < failback_after {
+            return;
+        }
+
+        match Connection::open(&self.endpoints[0], &self.config) {
+            Ok(connection) => self.switch_to(0, connection),
+            // still down, check again after another recovery period
+            Err(_) => self.failed_over_at = Some(Instant::now()),
+        }
+    }
+
+    fn switch_to(&mut self, endpoint: usize, connection: Connection) {
+        self.emit(EventKind::Disconnected);
+        self.active = endpoint;
+        self.failed_over_at = (endpoint != 0).then(Instant::now);
+        self.connection = connection;
+        self.emit(EventKind::Connected);
+    }
+
+    fn send_to_active(&mut self, payload: &[u8], attempts: &mut u32) -> Result<Ack, MllpError> {
+        let control_id = crate::control_id(payload);
+        let frame = self.config.encode(payload);
+        trace::frame_encoded(frame.len());
+        self.observe(Histogram::FrameSize, frame.len() as f64);
+        let mut retry = 0;
+
+        loop {
+            let sent_at = Instant::now();
+            *attempts += 1;
+            if let Err(e) = self.connection.stream.write_all(&frame) {
+                let e = self.write_error(e);
+                self.emit(EventKind::Error { message: e.to_string() });
+                return Err(e);
+            }
+            self.connection.last_written = Instant::now();
+            self.journal(Direction::Outbound, &frame);
+            self.emit(EventKind::MessageSent { bytes: payload.len() });
+            if self.config.ack_mode == Some(AckMode::None) {
+                return Ok(Ack::None);
+            }
+
+            let failure = match self.wait_ack(control_id.as_deref()) {
+                Ok(ack) => {
+                    trace::round_trip(sent_at.elapsed());
+                    self.observe(Histogram::AckLatency, sent_at.elapsed().as_secs_f64());
+                    return Ok(ack);
+                }
+                Err(e @ MllpError::Nak) => e,
+                Err(e @ MllpError::AckTimeout) => {
+                    // the acknowledgement may still come, and would answer the next frame written
+                    if let Err(reset) = self.reset() {
+                        return Err(if retry >= self.config.max_retries { e } else { reset.into() });
+                    }
+                    e
+                }
+                Err(e) => {
+                    self.emit(EventKind::Error { message: e.to_string() });
+                    return Err(e);
+                }
+            };
+
+            if retry >= self.config.max_retries {
+                return Err(failure);
+            }
+            thread::sleep(self.config.backoff(retry));
+            retry += 1;
+            self.emit(EventKind::Retry { attempt: retry });
+        }
+    }
+
+    /// Replaces the connection, whose stream may hold acknowledgements of messages given up on,
+    /// with a new one to the active endpoint. If none can be opened, the connection is shut down,
+    /// to be reopened by the next message in keep-open mode.
+    fn reset(&mut self) -> io::Result<()> {
+        self.emit(EventKind::Disconnected);
+        match Connection::open(&self.endpoints[self.active], &self.config) {
+            Ok(connection) => {
+                self.connection = connection;
+                self.emit(EventKind::Connected);
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.connection.stream.shutdown(Shutdown::Both);
+                self.connection.decoder.clear();
+                Err(e)
+            }
+        }
+    }
+
+    /// Error of a failed write, a write blocked past [`MllpClientConfig::write_timeout`] being
+    /// [`Timeout::Write`].
+    fn write_error(&self, e: io::Error) -> MllpError {
+        if !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+            return e.into();
+        }
+        self.emit(EventKind::TimedOut { timeout: Timeout::Write });
+        MllpError::Timeout(Timeout::Write)
+    }
+
+    /// Waits for the acknowledgement of the message `control_id`, application acknowledgements of
+    /// other messages being skipped.
+    fn wait_ack(&mut self, control_id: Option<&str>) -> Result<Ack, MllpError> {
+        let mut deadline = self.config.ack_timeout.map(|timeout| Instant::now() + timeout);
+        // when the wait started, then when the last byte was received
+        let mut waiting_since = Instant::now();
+        let mut received = false;
+        let mode = self.config.ack_mode;
+        let mut chunk = [0u8; 4096];
+        let mut committed = false;
+        let mut application = None;
+
+        loop {
+            while let Some(frame) = self.next_frame() {
+                trace::frame_decoded(frame.as_ref().ok().map(|frame| frame.len() + 3));
+                if frame.is_err() {
+                    self.increment(Counter::DecodeErrors);
+                }
+                let frame = match frame {
+                    Ok(frame) => frame,
+                    Err(_) if self.config.skip_banner && !self.connection.framed => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                self.connection.framed = true;
+                match frame.as_slice() {
+                    [ACK] if mode != Some(AckMode::ApplicationOnly) => {
+                        self.emit(EventKind::AckReceived);
+                        committed = true;
+                    }
+                    [NAK] if mode != Some(AckMode::ApplicationOnly) => {
+                        self.emit(EventKind::NakReceived);
+                        return Err(MllpError::Nak);
+                    }
+                    [ACK] | [NAK] => {}
+                    frame if control_id.is_some() && crate::acknowledged_id(frame).is_some_and(|id| Some(id.as_str()) != control_id) => {}
+                    _ if mode != Some(AckMode::TransportOnly) => {
+                        self.emit(EventKind::ApplicationAckReceived { bytes: frame.len() });
+                        application = Some(frame);
+                    }
+                    _ => {}
+                }
+
+                match (mode, committed, application.take()) {
+                    (Some(AckMode::Both), false, Some(frame)) => application = Some(frame),
+                    (_, _, Some(frame)) => return Ok(Ack::Application(frame)),
+                    (Some(AckMode::Both), _, None) => {}
+                    (_, true, None) => return Ok(Ack::Commit),
+                    (_, false, None) => {}
+                }
+            }
+
+            let byte_timeout = match (received, self.connection.decoder.buffered()) {
+                (false, 0) => self.config.first_byte_timeout.map(|timeout| (timeout, Timeout::FirstByte)),
+                (true, 0) => None,
+                (_, _) => self.config.inter_byte_timeout.map(|timeout| (timeout, Timeout::InterByte)),
+            };
+            let byte_deadline = byte_timeout.map(|(timeout, kind)| (waiting_since + timeout, kind));
+            let now = Instant::now();
+            let remaining = |deadline: Instant| deadline.checked_duration_since(now).filter(|remaining| !remaining.is_zero());
+
+            if deadline.is_some_and(|deadline| remaining(deadline).is_none()) {
+                self.emit(EventKind::AckTimeout);
+                return Err(MllpError::AckTimeout);
+            }
+            if let Some((_, timeout)) = byte_deadline.filter(|(deadline, _)| remaining(*deadline).is_none()) {
+                self.emit(EventKind::TimedOut { timeout });
+                return Err(MllpError::Timeout(timeout));
+            }
+            let timeout = [deadline, byte_deadline.map(|(deadline, _)| deadline)].into_iter().flatten().min().and_then(remaining);
+            self.connection.stream.set_read_timeout(timeout)?;
+
+            let started = Instant::now();
+            let read = self.connection.stream.read(&mut chunk);
+            // the receiver had no chance to answer while this process was stalled
+            let stalled = timeout.map_or(Duration::ZERO, |timeout| clock::stalled(started, timeout));
+            if let Some(deadline) = deadline.as_mut() {
+                *deadline += stalled;
+            }
+            waiting_since += stalled;
+            match read {
+                Ok(0) => {
+                    let partial_bytes = self.connection.decoder.clear();
+                    if partial_bytes > 0 {
+                        self.emit(EventKind::ClosedMidFrame { partial_bytes });
+                    }
+                    return Err(MllpError::ConnectionClosed { partial_bytes });
+                }
+                Ok(n) => {
+                    received = true;
+                    waiting_since = Instant::now();
+                    self.connection.decoder.extend(&chunk[..n]);
+                }
+                // the deadlines are checked again
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn journal(&self, direction: Direction, frame: &[u8]) {
+        if let Some(journal) = &self.config.journal {
+            // journaling is best effort and never fails the delivery
+            let _ = journal.record(direction, self.connection.peer_addr, frame);
+        }
+    }
+
+    /// Takes the next frame out of the decoder, journaling its bytes as they were received,
+    /// those of malformed frames and banners included.
+    fn next_frame(&mut self) -> Option<Result<Vec<u8>, MllpSyntaxError>> {
+        let Some(journal) = &self.config.journal else {
+            return self.connection.decoder.next_frame();
+        };
+        let mut raw = Vec::new();
+        let frame = self.connection.decoder.next_raw_frame(&mut raw);
+        if !raw.is_empty() {
+            // journaling is best effort and never fails the delivery
+            let _ = journal.record(Direction::Inbound, self.connection.peer_addr, &raw);
+        }
+        frame
+    }
+
+    fn increment(&self, counter: Counter) {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.increment(counter);
+        }
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.observe(histogram, value);
+        }
+    }
+
+    fn emit(&self, kind: EventKind) {
+        emit(&self.config, self.connection.local_addr, self.connection.peer_addr, kind);
+    }
+}
+
+/// Hands `kind` to the tracing, the metrics and the event sink of `config`.
+fn emit(config: &MllpClientConfig, local_addr: SocketAddr, peer_addr: SocketAddr, kind: EventKind) {
+    trace::event(local_addr, peer_addr, &kind);
+    let counter = match kind {
+        EventKind::Connected => Some(Counter::ConnectionsOpened),
+        EventKind::Disconnected | EventKind::ConnectionLost { .. } => Some(Counter::ConnectionsClosed),
+        EventKind::MessageSent { .. } => Some(Counter::MessagesSent),
+        EventKind::AckReceived => Some(Counter::AcksReceived),
+        EventKind::NakReceived => Some(Counter::NaksReceived),
+        _ => None,
+    };
+    if let Some((counter, metrics)) = counter.zip(config.metrics.as_ref()) {
+        metrics.increment(counter);
+    }
+    if let Some(sink) = &config.event_sink {
+        sink.on_event(&Event {
+            time: SystemTime::now(),
+            local_addr,
+            peer_addr,
+            kind,
+        });
+    }
+}
+
+impl Drop for MllpClient {
+    fn drop(&mut self) {
+        self.emit(EventKind::Disconnected);
+    }
+}
+
+/// Receiving half of a split [`MllpClient`], see [`MllpClient::into_split`].
+pub struct MllpReader {
+    stream: Stream,
+    decoder: MllpDecoder,
+    /// No frame was received yet, and a banner is to be skipped.
+    skip_banner: bool,
+    peer_addr: SocketAddr,
+}
+
+impl MllpReader {
+    /// Waits for the next frame and returns its payload, be it a message, an ACK or a NAK.
+    ///
+    /// An end of stream is a [`MllpError::ConnectionClosed`] error, and a wait longer than the
+    /// [read timeout](MllpReader::set_read_timeout) an [`io::ErrorKind::WouldBlock`] or
+    /// [`io::ErrorKind::TimedOut`] error, after which reading can go on.
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, MllpError> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            while let Some(frame) = self.decoder.next_frame() {
+                trace::frame_decoded(frame.as_ref().ok().map(|frame| frame.len() + 3));
+                match frame {
+                    Err(_) if self.skip_banner => continue,
+                    frame => {
+                        self.skip_banner = false;
+                        return frame.map_err(MllpError::from);
+                    }
+                }
+            }
+
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(MllpError::ConnectionClosed { partial_bytes: self.decoder.clear() }),
+                Ok(n) => self.decoder.extend(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Sets how long [`MllpReader::read_frame`] waits for bytes, `None` waiting forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+/// Sending half of a split [`MllpClient`], see [`MllpClient::into_split`].
+///
+/// It keeps the configuration of the client, for its codec, write timeout, journal and events.
+pub struct MllpWriter {
+    stream: Stream,
+    config: MllpClientConfig,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl MllpWriter {
+    /// Writes `payload` in a frame, without waiting for an acknowledgement. Acknowledging a
+    /// message received is writing the ACK as a frame too.
+    pub fn write_frame(&mut self, payload: &[u8]) -> Result<(), MllpError> {
+        let frame = self.config.encode(payload);
+        trace::frame_encoded(frame.len());
+        if let Err(e) = self.stream.write_all(&frame) {
+            if !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+                return Err(e.into());
+            }
+            self.emit(EventKind::TimedOut { timeout: Timeout::Write });
+            return Err(MllpError::Timeout(Timeout::Write));
+        }
+        if let Some(journal) = &self.config.journal {
+            // journaling is best effort and never fails the delivery
+            let _ = journal.record(Direction::Outbound, self.peer_addr, &frame);
+        }
+        self.emit(EventKind::MessageSent { bytes: payload.len() });
+
+        Ok(())
+    }
+
+    /// Shuts down writing: the peer reads the end of the stream, while the reader goes on
+    /// receiving.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.stream.shutdown(Shutdown::Write)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn emit(&self, kind: EventKind) {
+        emit(&self.config, self.local_addr, self.peer_addr, kind);
+    }
+}
+
+impl Drop for MllpWriter {
+    fn drop(&mut self) {
+        self.emit(EventKind::Disconnected);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::client::{Ack, MllpClient, MllpClientConfig};
+    use crate::dead_letter::{DeadLetter, DeadLetterReason, DeadLetterSink};
+    use crate::discovery::{SrvDestination, SrvLookup, SrvRecord, SrvResolver};
+    use crate::event::EventKind;
+    use crate::reconnect::{FixedBackoff, NotifyGiveUp};
+    use crate::testing::MemoryListener;
+    use crate::timeline::Timeline;
+    use crate::{AckMode, MllpCodec, MllpDecoder, MllpError, Timeout, ACK, SB};
+
+    /// Spawns a receiver answering each message with the next of `responses`.
+    fn receiver(responses: Vec<Option<Vec<u8>>>) -> (SocketAddr, thread::JoinHandle<usize>) {
+        receiver_on("127.0.0.1:0".parse().unwrap(), responses)
+    }
+
+    fn receiver_on(addr: SocketAddr, responses: Vec<Option<Vec<u8>>>) -> (SocketAddr, thread::JoinHandle<usize>) {
+        let listener = TcpListener::bind(addr).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            let mut received = 0;
+            for response in responses {
+                // the client reconnects after a timeout
+                while decoder.read_frame(&mut stream).is_err() {
+                    (stream, _) = listener.accept().unwrap();
+                    decoder = MllpDecoder::new();
+                }
+                received += 1;
+                if let Some(response) = response {
+                    stream.write_all(&response).unwrap();
+                }
+            }
+            // keep the connection open until the client is done
+            let _ = decoder.read_frame(&mut stream);
+            received
+        });
+
+        (addr, handler)
+    }
+
+    fn quick_config(max_retries: u32) -> MllpClientConfig {
+        MllpClientConfig {
+            ack_timeout: Some(Duration::from_millis(100)),
+            max_retries,
+            retry_backoff: Duration::from_millis(10),
+            ..MllpClientConfig::default()
+        }
+    }
+
+    /// Subscriber recording the names and fields of spans and events.
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl Recorder {
+        fn push(&self, name: &str, fields: &dyn Fn(&mut dyn tracing::field::Visit)) {
+            let mut line = name.to_owned();
+            fields(&mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                line.push_str(&format!(" {}={:?}", field.name(), value));
+            });
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            self.push(span.metadata().name(), &|visitor| span.record(visitor));
+            tracing::span::Id::from_u64(self.0.lock().unwrap().len() as u64)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            self.push("event", &|visitor| event.record(visitor));
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn it_traces_messages() {
+        let (addr, _) = receiver(vec![Some(MllpCodec::ack().to_vec())]);
+        let recorder = Recorder::default();
+
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut client = MllpClient::connect_with_config(addr, quick_config(0)).unwrap();
+            client.send(b"MSH|^~\\&|LAB||EHR||20240131||ORU^R01|MSG42|P|2.5").unwrap();
+        });
+
+        let lines = recorder.0.lock().unwrap();
+        let expected_span = format!("mllp.message peer_addr={} frame_size=51 control_id=\"MSG42\"", addr);
+        assert!(lines.contains(&expected_span), "{:?}", lines);
+        assert!(lines.iter().any(|line| line.starts_with("event message=Connected")));
+        assert!(lines.iter().any(|line| line.starts_with("event message=acknowledged round_trip_ms=")));
+    }
+
+    #[test]
+    fn it_returns_commit_ack() {
+        let (addr, handler) = receiver(vec![Some(MllpCodec::ack().to_vec())]);
+        let mut client = MllpClient::connect_with_config(addr, quick_config(0)).unwrap();
+
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Commit);
+        drop(client);
+        assert_eq!(handler.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn it_returns_application_ack() {
+        let (addr, handler) = receiver(vec![Some(MllpCodec::encode(b"MSH|ACK"))]);
+        let mut client = MllpClient::connect_with_config(addr, quick_config(0)).unwrap();
+
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Application(b"MSH|ACK".to_vec()));
+        drop(client);
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn it_retransmits_after_nak() {
+        let (addr, handler) = receiver(vec![Some(MllpCodec::nak().to_vec()), Some(MllpCodec::ack().to_vec())]);
+        let mut client = MllpClient::connect_with_config(addr, quick_config(1)).unwrap();
+
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Commit);
+        drop(client);
+        assert_eq!(handler.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn it_times_out_after_retries() {
+        let (addr, handler) = receiver(vec![None, None, None]);
+        let mut client = MllpClient::connect_with_config(addr, quick_config(2)).unwrap();
+
+        assert!(matches!(client.send(b"MSH|"), Err(MllpError::AckTimeout)));
+        drop(client);
+        assert_eq!(handler.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn it_ignores_late_acknowledgements() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut decoder = MllpDecoder::new();
+                    while let Ok(message) = decoder.read_frame(&mut stream) {
+                        let control_id = String::from_utf8(message.rsplit(|b| *b == b'|').next().unwrap().to_vec()).unwrap();
+                        let ack = |id: &str| MllpCodec::encode(format!("MSH|^~\\&|LAB|||||ACK||P|2.5\rMSA|AA|{}", id).as_bytes());
+                        match control_id.as_str() {
+                            "1" => thread::sleep(Duration::from_millis(150)),
+                            // acknowledging a message twice
+                            "3" => drop(stream.write_all(&ack("2"))),
+                            _ => {}
+                        }
+                        let _ = stream.write_all(&ack(&control_id));
+                    }
+                });
+            }
+        });
+
+        let mut client = MllpClient::connect_with_config(addr, quick_config(1)).unwrap();
+        assert!(matches!(client.send(b"MSH|^~\\&|EHR||||||ADT^A01|1"), Err(MllpError::AckTimeout)));
+        let msa = |ack: Ack| match ack {
+            Ack::Application(frame) => String::from_utf8(frame).unwrap().rsplit('\r').next().unwrap().to_owned(),
+            ack => panic!("{:?}", ack),
+        };
+        assert_eq!(msa(client.send(b"MSH|^~\\&|EHR||||||ADT^A01|2").unwrap()), "MSA|AA|2");
+        assert_eq!(msa(client.send(b"MSH|^~\\&|EHR||||||ADT^A01|3").unwrap()), "MSA|AA|3");
+
+        let mut client = MllpClient::connect_with_config(addr, quick_config(0)).unwrap();
+        assert!(matches!(client.send(b"MSH|^~\\&|EHR||||||ADT^A01|1"), Err(MllpError::AckTimeout)));
+        assert_eq!(msa(client.send(b"MSH|^~\\&|EHR||||||ADT^A01|4").unwrap()), "MSA|AA|4");
+    }
+
+    #[test]
+    fn it_times_out_on_silent_and_stalled_receivers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (_silent, _) = listener.accept().unwrap();
+            let (mut stalled, _) = listener.accept().unwrap();
+            stalled.write_all(&[SB, ACK]).unwrap();
+            // keep the connections open until the client is done
+            let _ = stalled.read(&mut [0u8; 16]);
+            let _ = stalled.read(&mut [0u8; 16]);
+        });
+        let config = MllpClientConfig {
+            ack_timeout: Some(Duration::from_secs(5)),
+            first_byte_timeout: Some(Duration::from_millis(100)),
+            inter_byte_timeout: Some(Duration::from_millis(100)),
+            max_retries: 0,
+            ..MllpClientConfig::default()
+        };
+
+        let mut silent = MllpClient::connect_with_config(addr, config.clone()).unwrap();
+        assert!(matches!(silent.send(b"MSH|"), Err(MllpError::Timeout(Timeout::FirstByte))));
+        let mut stalled = MllpClient::connect_with_config(addr, config).unwrap();
+        assert!(matches!(stalled.send(b"MSH|"), Err(MllpError::Timeout(Timeout::InterByte))));
+        drop((silent, stalled));
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn it_records_timeline() {
+        let (addr, handler) = receiver(vec![None, Some(MllpCodec::ack().to_vec())]);
+        let timeline = Arc::new(Timeline::new());
+        let config = MllpClientConfig {
+            event_sink: Some(timeline.clone()),
+            ..quick_config(1)
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+        client.send(b"MSH|").unwrap();
+        drop(client);
+        handler.join().unwrap();
+
+        // the message is sent again on a new connection
+        let connections = timeline.connections();
+        assert_eq!(connections.len(), 2);
+        let kinds = |i: usize| connections[i].entries.iter().map(|entry| entry.kind.clone()).collect::<Vec<EventKind>>();
+        assert_eq!(kinds(0), vec![
+            EventKind::Connected,
+            EventKind::MessageSent { bytes: 4 },
+            EventKind::AckTimeout,
+            EventKind::Disconnected,
+        ]);
+        assert_eq!(kinds(1), vec![
+            EventKind::Connected,
+            EventKind::Retry { attempt: 1 },
+            EventKind::MessageSent { bytes: 4 },
+            EventKind::AckReceived,
+            EventKind::Disconnected,
+        ]);
+    }
+
+    #[test]
+    fn it_reconnects_in_keep_open_mode() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            // ACK one message per connection, then close it
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut decoder = MllpDecoder::new();
+                decoder.read_frame(&mut stream).unwrap();
+                stream.write_all(&MllpCodec::ack()).unwrap();
+            }
+        });
+        let timeline = Arc::new(Timeline::new());
+        let config = MllpClientConfig {
+            keep_open: true,
+            reconnect_backoff: Duration::from_millis(10),
+            event_sink: Some(timeline.clone()),
+            ..quick_config(0)
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Commit);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Commit);
+        handler.join().unwrap();
+
+        let connections = timeline.connections();
+        let kinds: Vec<EventKind> = connections[0].entries.iter().map(|entry| entry.kind.clone()).collect();
+        assert!(matches!(kinds[3], EventKind::ConnectionLost { .. }));
+        assert_eq!(kinds[4], EventKind::Reconnecting { attempt: 1 });
+        assert_eq!(connections[1].entries[0].kind, EventKind::Connected);
+    }
+
+    #[test]
+    fn it_gives_up_reconnecting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = MllpClientConfig {
+            keep_open: true,
+            max_reconnect_attempts: 2,
+            reconnect_backoff: Duration::from_millis(10),
+            ..quick_config(0)
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+        drop(listener);
+
+        assert!(matches!(client.send(b"MSH|"), Err(MllpError::Io(_))));
+    }
+
+    #[test]
+    fn it_reconnects_per_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gave_up = Arc::new(Mutex::new(None));
+        let notified = gave_up.clone();
+        let policy = NotifyGiveUp::new(FixedBackoff::new(Duration::from_millis(20), 3), move |attempts, _: &std::io::Error| {
+            *notified.lock().unwrap() = Some(attempts);
+        });
+        let config = MllpClientConfig {
+            keep_open: true,
+            reconnect_policy: Some(Arc::new(policy)),
+            ..quick_config(0)
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+        drop(listener);
+
+        let start = Instant::now();
+        assert!(matches!(client.send(b"MSH|"), Err(MllpError::Io(_))));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(*gave_up.lock().unwrap(), Some(3));
+    }
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<DeadLetter>>);
+
+    impl DeadLetterSink for RecordingSink {
+        fn dead_letter(&self, letter: &DeadLetter) -> std::io::Result<()> {
+            self.0.lock().unwrap().push(letter.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_dead_letters_naked_messages() {
+        let nak = MllpCodec::nak().to_vec();
+        let (addr, handler) = receiver(vec![Some(nak.clone()), Some(nak)]);
+        let sink = Arc::new(RecordingSink::default());
+        let config = MllpClientConfig {
+            dead_letter: Some(sink.clone()),
+            ..quick_config(1)
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+
+        assert!(matches!(client.send(b"MSH|"), Err(MllpError::Nak)));
+        drop(client);
+        handler.join().unwrap();
+
+        let letters = sink.0.lock().unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].payload, b"MSH|");
+        assert_eq!(letters[0].reason, DeadLetterReason::Nak);
+        assert_eq!(letters[0].attempts, 2);
+    }
+
+    #[test]
+    fn it_enables_tcp_keepalive() {
+        let (addr, handler) = receiver(vec![]);
+        let config = MllpClientConfig {
+            tcp_keepalive: Some(Duration::from_secs(60)),
+            ..quick_config(0)
+        };
+        let client = MllpClient::connect_with_config(addr, config).unwrap();
+
+        assert!(client.connection.stream.socket().unwrap().keepalive().unwrap());
+        drop(client);
+        handler.join().unwrap();
+    }
+
+    fn unreachable_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn it_connects_to_first_reachable_endpoint() {
+        let (addr, handler) = receiver(vec![Some(MllpCodec::ack().to_vec())]);
+        let mut client = MllpClient::connect_failover(&[unreachable_addr(), addr], quick_config(0)).unwrap();
+
+        assert_eq!(client.active_endpoint(), 1);
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Commit);
+        drop(client);
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn it_fails_over_after_repeated_naks() {
+        let nak = MllpCodec::nak().to_vec();
+        let (primary, primary_handler) = receiver(vec![Some(nak.clone()), Some(nak)]);
+        let (secondary, secondary_handler) = receiver(vec![Some(MllpCodec::ack().to_vec())]);
+        let mut client = MllpClient::connect_failover(&[primary, secondary], quick_config(1)).unwrap();
+
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Commit);
+        assert_eq!(client.active_endpoint(), 1);
+        drop(client);
+        assert_eq!(primary_handler.join().unwrap(), 2);
+        assert_eq!(secondary_handler.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn it_counts_attempts_over_all_endpoints() {
+        let nak = MllpCodec::nak().to_vec();
+        let (primary, primary_handler) = receiver(vec![Some(nak.clone()), Some(nak.clone())]);
+        let (secondary, secondary_handler) = receiver(vec![Some(nak.clone()), Some(nak.clone()), Some(nak)]);
+        let sink = Arc::new(RecordingSink::default());
+        let config = MllpClientConfig {
+            dead_letter: Some(sink.clone()),
+            ..quick_config(1)
+        };
+        let mut client = MllpClient::connect_failover(&[primary, secondary], config).unwrap();
+
+        assert!(matches!(client.send(b"MSH|"), Err(MllpError::Nak)));
+        assert!(matches!(client.send_batch(&[b"MSH|"]).as_slice(), [Err(MllpError::Nak)]));
+        drop(client);
+        primary_handler.join().unwrap();
+        secondary_handler.join().unwrap();
+
+        let letters = sink.0.lock().unwrap();
+        assert_eq!(letters.iter().map(|letter| letter.attempts).collect::<Vec<_>>(), [4, 1]);
+    }
+
+    #[test]
+    fn it_fails_back_after_recovery_period() {
+        let primary = unreachable_addr();
+        let (secondary, secondary_handler) = receiver(vec![]);
+        let config = MllpClientConfig {
+            failback_after: Some(Duration::ZERO),
+            ..quick_config(0)
+        };
+        let mut client = MllpClient::connect_failover(&[primary, secondary], config).unwrap();
+        assert_eq!(client.active_endpoint(), 1);
+
+        let (_, primary_handler) = receiver_on(primary, vec![Some(MllpCodec::ack().to_vec())]);
+        assert_eq!(client.send(b"MSH|").unwrap(), Ack::Commit);
+        assert_eq!(client.active_endpoint(), 0);
+        drop(client);
+        primary_handler.join().unwrap();
+        secondary_handler.join().unwrap();
+    }
+
+    /// Resolver advertising the next port of `ports` on each lookup, with a TTL of zero.
+    struct ChangingResolver {
+        ports: Mutex<Vec<u16>>,
+    }
+
+    impl SrvResolver for ChangingResolver {
+        fn lookup_srv(&self, _name: &str) -> std::io::Result<SrvLookup> {
+            let port = self.ports.lock().unwrap().remove(0);
+            Ok(SrvLookup {
+                records: vec![SrvRecord {
+                    priority: 10,
+                    weight: 0,
+                    port,
+                    target: "127.0.0.1".to_owned(),
+                }],
+                ttl: Duration::ZERO,
+            })
+        }
+    }
+
+    #[test]
+    fn it_follows_srv_record_changes() {
+        let (first, first_handler) = receiver(vec![Some(MllpCodec::ack().to_vec())]);
+        let (second, second_handler) = receiver(vec![Some(MllpCodec::ack().to_vec())]);
+        let resolver = ChangingResolver {
+            ports: Mutex::new(vec![first.port(), first.port(), second.port()]),
+        };
+        let destination = SrvDestination::new("_hl7._tcp.lab", Arc::new(resolver));
+        let mut client = MllpClient::connect_srv(destination, quick_config(0)).unwrap();
+
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Commit);
+        assert_eq!(client.peer_addr(), first);
+        assert_eq!(client.send(b"MSH|2").unwrap(), Ack::Commit);
+        assert_eq!(client.peer_addr(), second);
+        drop(client);
+        assert_eq!(first_handler.join().unwrap(), 1);
+        assert_eq!(second_handler.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn it_connects_from_configured_source_port() {
+        let (addr, handler) = receiver(vec![]);
+        let port = unreachable_addr().port();
+        let config = MllpClientConfig {
+            bind_addr: Some("127.0.0.1".parse().unwrap()),
+            source_ports: Some(port..=port),
+            ..quick_config(0)
+        };
+        let client = MllpClient::connect_with_config(addr, config).unwrap();
+
+        assert_eq!(client.local_addr(), SocketAddr::from(([127, 0, 0, 1], port)));
+        drop(client);
+        handler.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn it_connects_through_configured_interface() {
+        let (addr, handler) = receiver(vec![]);
+        let config = MllpClientConfig {
+            bind_interface: Some("lo".into()),
+            ..quick_config(0)
+        };
+        let client = MllpClient::connect_with_config(addr, config).unwrap();
+        assert!(client.local_addr().ip().is_loopback());
+        drop(client);
+        handler.join().unwrap();
+
+        let config = MllpClientConfig {
+            bind_interface: Some("mllp-missing0".into()),
+            ..quick_config(0)
+        };
+        assert!(MllpClient::connect_with_config(addr, config).is_err());
+    }
+
+    #[test]
+    fn it_waits_for_frames_of_ack_mode() {
+        let both = [&MllpCodec::ack()[..], &MllpCodec::encode(b"MSA|AA")].concat();
+        let (addr, handler) = receiver(vec![Some(both.clone()), Some(both), None]);
+        let mode_config = |mode: AckMode| MllpClientConfig {
+            ack_mode: Some(mode),
+            ..quick_config(0)
+        };
+        let mut client = MllpClient::connect_with_config(addr, mode_config(AckMode::Both)).unwrap();
+
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSA|AA".to_vec()));
+        client.config.ack_mode = Some(AckMode::TransportOnly);
+        assert_eq!(client.send(b"MSH|2").unwrap(), Ack::Commit);
+        client.config.ack_mode = Some(AckMode::None);
+        assert_eq!(client.send(b"MSH|3").unwrap(), Ack::None);
+        drop(client);
+        assert_eq!(handler.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn it_skips_receiver_banner() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"220 LIS ready\r\n").unwrap();
+            let mut decoder = MllpDecoder::new();
+            while decoder.read_frame(&mut stream).is_ok() {
+                stream.write_all(&MllpCodec::ack()).unwrap();
+            }
+        });
+        let config = MllpClientConfig {
+            skip_banner: true,
+            ..quick_config(0)
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Commit);
+        assert_eq!(client.send(b"MSH|2").unwrap(), Ack::Commit);
+    }
+
+    #[test]
+    fn it_pipelines_batches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            // answers once the three messages are in, which one-at-a-time sending never gets to
+            for _ in 0..3 {
+                decoder.read_frame(&mut stream).unwrap();
+            }
+            let responses = [&MllpCodec::ack()[..], &MllpCodec::nak(), &MllpCodec::encode(b"MSA|AA")].concat();
+            stream.write_all(&responses).unwrap();
+            while decoder.read_frame(&mut stream).is_ok() {}
+        });
+        let mut client = MllpClient::connect_with_config(addr, quick_config(0)).unwrap();
+
+        let results = client.send_batch(&[b"MSH|1", b"MSH|2", b"MSH|3"]);
+        assert_eq!(results[0].as_ref().unwrap(), &Ack::Commit);
+        assert!(matches!(results[1], Err(MllpError::Nak)));
+        assert_eq!(results[2].as_ref().unwrap(), &Ack::Application(b"MSA|AA".to_vec()));
+
+        let results = client.send_batch(&[b"MSH|4", b"MSH|5"]);
+        assert!(matches!(results[0], Err(MllpError::AckTimeout)));
+        assert!(matches!(&results[1], Err(MllpError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionAborted));
+    }
+
+    #[test]
+    fn it_fails_the_message_a_batch_could_not_write() {
+        let listener = MemoryListener::new();
+        let config = MllpClientConfig {
+            max_in_flight: 2,
+            ..quick_config(0)
+        };
+        let mut client = MllpClient::connect_memory(listener.connector(), config).unwrap();
+        let mut stream = listener.accept().unwrap();
+        let mut decoder = MllpDecoder::new();
+        let peer = thread::spawn(move || {
+            decoder.read_frame(&mut stream).unwrap();
+            decoder.read_frame(&mut stream).unwrap();
+            // the third message cannot be written once the first one is acknowledged
+            stream.shutdown(Shutdown::Read).unwrap();
+            stream.write_all(&MllpCodec::ack()).unwrap();
+
+            let mut stream = listener.accept().unwrap();
+            decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::ack()).unwrap();
+        });
+
+        let results = client.send_batch(&[b"MSH|1", b"MSH|2", b"MSH|3"]);
+        assert_eq!(results[0].as_ref().unwrap(), &Ack::Commit);
+        assert!(matches!(&results[1], Err(MllpError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionAborted));
+        assert!(matches!(&results[2], Err(MllpError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe));
+        // on a new connection
+        assert_eq!(client.send(b"MSH|4").unwrap(), Ack::Commit);
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn it_splits_into_reader_and_writer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&MllpCodec::encode(b"MSH|^~\\&|ADT|||||ADT^A01|1")).unwrap();
+            let mut decoder = MllpDecoder::new();
+            let received = [decoder.read_frame(&mut stream).unwrap(), decoder.read_frame(&mut stream).unwrap()];
+            stream.write_all(&MllpCodec::ack()).unwrap();
+            received
+        });
+
+        let (mut reader, mut writer) = MllpClient::connect(addr).unwrap().into_split().unwrap();
+        let receiving = thread::spawn(move || [reader.read_frame().unwrap(), reader.read_frame().unwrap()]);
+        writer.write_frame(b"MSH|^~\\&|EHR|||||ORU^R01|2").unwrap();
+        writer.write_frame(&[ACK]).unwrap();
+
+        assert_eq!(peer.join().unwrap(), [b"MSH|^~\\&|EHR|||||ORU^R01|2".to_vec(), vec![ACK]]);
+        assert_eq!(receiving.join().unwrap(), [b"MSH|^~\\&|ADT|||||ADT^A01|1".to_vec(), vec![ACK]]);
+
+        let listener = MemoryListener::new();
+        let client = MllpClient::connect_memory(listener.connector(), MllpClientConfig::default()).unwrap();
+        assert_eq!(client.into_split().err().map(|e| e.kind()), Some(std::io::ErrorKind::Unsupported));
+    }
+
+    #[test]
+    fn it_records_stats_per_destination() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            while let Ok(message) = decoder.read_frame(&mut stream) {
+                let answer = if message.ends_with(b"|2") { MllpCodec::nak() } else { MllpCodec::ack() };
+                if message.ends_with(b"|3") {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                stream.write_all(&answer).unwrap();
+            }
+        });
+
+        let mut client = MllpClient::connect_with_config(addr, MllpClientConfig { max_retries: 0, ..MllpClientConfig::default() }).unwrap();
+        assert!(client.stats().is_empty());
+        client.send(b"MSH|^~\\&|EHR|||||ORU^R01|1").unwrap();
+        client.send(b"MSH|^~\\&|EHR|||||ORU^R01|2").unwrap_err();
+        client.send_batch(&[b"MSH|^~\\&|EHR|||||ORU^R01|3".as_slice()])[0].as_ref().unwrap();
+
+        let stats = client.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].peer_addr, addr);
+        let stats = &stats[0].stats;
+        assert_eq!((stats.messages, stats.bytes, stats.errors), (3, 78, 1));
+        // the latency of the batch message runs until its acknowledgement
+        assert!(stats.ack_latency.is_some_and(|latency| latency.p50 <= latency.max && latency.max >= Duration::from_millis(50)));
+        assert!(stats.last_activity.is_some());
+    }
+}
@@ -0,0 +1,73 @@
+//! Timeouts across clock jumps.
+//!
+//! Timeouts and schedules are measured with [`Instant`], the monotonic clock, so NTP corrections
+//! and manual changes of the system clock do not affect them; [`SystemTime`](std::time::SystemTime)
+//! is only used to timestamp events and records.
+//!
+//! The monotonic clock still moves on while a virtual machine is paused or migrated, and after
+//! a long pause every idle timeout would fire at once. Blocking calls are therefore given a
+//! timeout, and when one of them returns much later than that, the excess is taken for a stall
+//! of the whole process and is not counted against the connections.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Lateness of a blocking call not taken for a stall, to allow for scheduling delays.
+const STALL_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// Time the process was stalled during a blocking call that started at `started` and was given
+/// `timeout`, or zero if it returned in time.
+pub(crate) fn stalled(started: Instant, timeout: Duration) -> Duration {
+    stalled_between(started, Instant::now(), timeout)
+}
+
+fn stalled_between(started: Instant, returned: Instant, timeout: Duration) -> Duration {
+    let late = returned.saturating_duration_since(started).saturating_sub(timeout);
+    if late > STALL_TOLERANCE {
+        late
+    } else {
+        Duration::ZERO
+    }
+}
+
+/// HL7 `YYYYMMDDHHMMSS` timestamp of `time`, in UTC.
+pub(crate) fn hl7_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // civil date of a count of days since 1970-01-01, after Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::clock::stalled_between;
+
+    #[test]
+    fn it_ignores_calls_returning_in_time() {
+        let started = Instant::now();
+        let timeout = Duration::from_millis(100);
+
+        assert_eq!(stalled_between(started, started + Duration::from_millis(50), timeout), Duration::ZERO);
+        assert_eq!(stalled_between(started, started + Duration::from_millis(600), timeout), Duration::ZERO);
+    }
+
+    #[test]
+    fn it_measures_stalls_past_timeout() {
+        let started = Instant::now();
+        let timeout = Duration::from_millis(100);
+
+        let stalled = stalled_between(started, started + Duration::from_secs(3600), timeout);
+        assert_eq!(stalled, Duration::from_secs(3600) - timeout);
+    }
+}
@@ -0,0 +1,277 @@
+//! Coordination of receivers running as several instances.
+//!
+//! When a receiver is scaled horizontally, connections from one sender may land on different
+//! instances. Any per-sender state (expected sequence number, last time a sender was heard from,
+//! message counters) must then live in a store shared by all instances, the same way the
+//! [`MessageLedger`](crate::ledger::MessageLedger) shares the dedup window.
+//!
+//! [`ClusterStore`] is that store: a map of named `u64` values supporting the atomic operations
+//! needed to keep such state consistent. [`InMemoryClusterStore`] is the single-instance
+//! implementation; multi-instance deployments implement the trait on top of Redis (`INCRBY`,
+//! a `WATCH`/`MULTI` transaction or a Lua script for the compare-and-swap) or an SQL table.
+//!
+//! [`SequenceChecker::with_cluster_store`] keeps the sequence numbers expected in the store, and
+//! the [`SenderTracker`] interceptor the messages received from each sender and when it was last
+//! heard from, to tell a silent sender whichever instance its connections land on.
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use mllp_rs::cluster::{InMemoryClusterStore, SenderTracker};
+//! use mllp_rs::sequence::SequenceChecker;
+//! use mllp_rs::server::MllpServerConfig;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let store = Arc::new(InMemoryClusterStore::new());
+//! let senders = Arc::new(SenderTracker::new(store.clone()));
+//! let config = MllpServerConfig {
+//!     interceptors: vec![senders.clone(), Arc::new(SequenceChecker::with_cluster_store(store))],
+//!     ..MllpServerConfig::default()
+//! };
+//!
+//! // from a monitoring thread
+//! if senders.is_silent("LAB|NORTH", Duration::from_secs(600))? {
+//!     eprintln!("nothing from the lab for 10 minutes");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`SequenceChecker::with_cluster_store`]: crate::sequence::SequenceChecker::with_cluster_store
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::handler::AckDecision;
+use crate::interceptor::{Interceptor, Next};
+
+/// Shared map of named counters and timestamps.
+///
+/// Errors from the underlying store are reported as [`io::Error`]s.
+pub trait ClusterStore: Send + Sync {
+    /// Returns the value of `key`, or `None` if it was never set.
+    fn get(&self, key: &str) -> io::Result<Option<u64>>;
+
+    /// Sets `key` to `value`.
+    fn set(&self, key: &str, value: u64) -> io::Result<()>;
+
+    /// Atomically adds `delta` to `key` (absent keys count as 0), returning the new value.
+    fn increment(&self, key: &str, delta: u64) -> io::Result<u64>;
+
+    /// Atomically sets `key` to `new` if its value is `current` (`None` meaning absent).
+    /// Returns whether the value was replaced.
+    fn compare_and_swap(&self, key: &str, current: Option<u64>, new: u64) -> io::Result<bool>;
+}
+
+impl fmt::Debug for dyn ClusterStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClusterStore")
+    }
+}
+
+impl<S: ClusterStore + ?Sized> ClusterStore for Arc<S> {
+    fn get(&self, key: &str) -> io::Result<Option<u64>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: &str, value: u64) -> io::Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn increment(&self, key: &str, delta: u64) -> io::Result<u64> {
+        (**self).increment(key, delta)
+    }
+
+    fn compare_and_swap(&self, key: &str, current: Option<u64>, new: u64) -> io::Result<bool> {
+        (**self).compare_and_swap(key, current, new)
+    }
+}
+
+/// Store local to the current process, for receivers running as a single instance.
+/// ```
+/// use mllp_rs::cluster::{ClusterStore, InMemoryClusterStore};
+///
+/// let store = InMemoryClusterStore::new();
+/// assert_eq!(store.increment("LAB:received", 1).unwrap(), 1);
+/// assert!(store.compare_and_swap("LAB:sequence", None, 1).unwrap());
+/// ```
+#[derive(Default)]
+pub struct InMemoryClusterStore {
+    values: Mutex<HashMap<String, u64>>,
+}
+
+impl InMemoryClusterStore {
+    pub fn new() -> Self {
+        InMemoryClusterStore::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, u64>> {
+        self.values.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ClusterStore for InMemoryClusterStore {
+    fn get(&self, key: &str) -> io::Result<Option<u64>> {
+        Ok(self.lock().get(key).copied())
+    }
+
+    fn set(&self, key: &str, value: u64) -> io::Result<()> {
+        self.lock().insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    fn increment(&self, key: &str, delta: u64) -> io::Result<u64> {
+        let mut values = self.lock();
+        let value = values.entry(key.to_owned()).or_insert(0);
+        *value = value.saturating_add(delta);
+
+        Ok(*value)
+    }
+
+    fn compare_and_swap(&self, key: &str, current: Option<u64>, new: u64) -> io::Result<bool> {
+        let mut values = self.lock();
+        if values.get(key).copied() != current {
+            return Ok(false);
+        }
+        values.insert(key.to_owned(), new);
+
+        Ok(true)
+    }
+}
+
+/// Interceptor counting, for each sender told apart by MSH-3 and MSH-4, the messages received and
+/// refused, and keeping when it was last heard from, in a [`ClusterStore`].
+///
+/// The store being shared, the figures are those of every instance of the receiver. Failing to
+/// update them never fails the message.
+#[derive(Debug)]
+pub struct SenderTracker {
+    store: Arc<dyn ClusterStore>,
+}
+
+impl SenderTracker {
+    pub fn new(store: Arc<dyn ClusterStore>) -> Self {
+        SenderTracker { store }
+    }
+
+    /// Messages received from `sender`, its MSH-3 and MSH-4 as `LAB|NORTH`.
+    pub fn received(&self, sender: &str) -> io::Result<u64> {
+        Ok(self.store.get(&key(sender, "received"))?.unwrap_or(0))
+    }
+
+    /// Messages from `sender` the handler answered with a NAK.
+    pub fn refused(&self, sender: &str) -> io::Result<u64> {
+        Ok(self.store.get(&key(sender, "refused"))?.unwrap_or(0))
+    }
+
+    /// When a message was last received from `sender`, `None` if none was.
+    pub fn last_heard(&self, sender: &str) -> io::Result<Option<SystemTime>> {
+        Ok(self.store.get(&key(sender, "last_heard"))?.map(|millis| UNIX_EPOCH + Duration::from_millis(millis)))
+    }
+
+    /// Whether nothing was received from `sender` for `max_silence`, or ever.
+    pub fn is_silent(&self, sender: &str, max_silence: Duration) -> io::Result<bool> {
+        Ok(self.last_heard(sender)?.is_none_or(|heard| heard.elapsed().unwrap_or_default() > max_silence))
+    }
+
+    fn heard(&self, sender: &str) -> io::Result<()> {
+        let key = key(sender, "last_heard");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        // another instance may have heard from the sender later, with a clock ahead
+        loop {
+            let current = self.store.get(&key)?;
+            if current.is_some_and(|heard| heard >= now) || self.store.compare_and_swap(&key, current, now)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Interceptor for SenderTracker {
+    fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+        let Some(sender) = crate::sender(message) else {
+            return next(message);
+        };
+
+        // the figures are best effort and never fail the message
+        let _ = self.store.increment(&key(&sender, "received"), 1);
+        let _ = self.heard(&sender);
+        let response = next(message);
+        if response == AckDecision::CommitNak {
+            let _ = self.store.increment(&key(&sender, "refused"), 1);
+        }
+
+        response
+    }
+}
+
+/// Key of the value `name` of `sender`, as `LAB|NORTH:received`.
+fn key(sender: &str, name: &str) -> String {
+    format!("{}:{}", sender, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::cluster::{ClusterStore, InMemoryClusterStore, SenderTracker};
+    use crate::handler::AckDecision;
+    use crate::interceptor::Interceptor;
+
+    #[test]
+    fn it_increments_counters() {
+        let store = Arc::new(InMemoryClusterStore::new());
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        store.increment("count", 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handler in handlers {
+            handler.join().unwrap();
+        }
+        assert_eq!(store.get("count").unwrap(), Some(400));
+    }
+
+    #[test]
+    fn it_swaps_only_expected_value() {
+        let store = InMemoryClusterStore::new();
+
+        assert!(store.compare_and_swap("seq", None, 1).unwrap());
+        assert!(!store.compare_and_swap("seq", None, 2).unwrap());
+        assert!(!store.compare_and_swap("seq", Some(2), 3).unwrap());
+        assert!(store.compare_and_swap("seq", Some(1), 2).unwrap());
+        assert_eq!(store.get("seq").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn it_tracks_senders_across_receivers() {
+        let store = Arc::new(InMemoryClusterStore::new());
+        let north = SenderTracker::new(store.clone());
+        let south = SenderTracker::new(store.clone());
+        let message = b"MSH|^~\\&|LAB|NORTH|EHR|SOUTH|20240131||ORU^R01|MSG1|P|2.5";
+
+        assert!(north.is_silent("LAB|NORTH", Duration::from_secs(60)).unwrap());
+        north.around(message, &|_: &[u8]| AckDecision::CommitAck);
+        south.around(message, &|_: &[u8]| AckDecision::CommitNak);
+        south.around(message, &|_: &[u8]| AckDecision::CommitAck);
+
+        for tracker in [&north, &south] {
+            assert_eq!(tracker.received("LAB|NORTH").unwrap(), 3);
+            assert_eq!(tracker.refused("LAB|NORTH").unwrap(), 1);
+            assert!(tracker.last_heard("LAB|NORTH").unwrap().is_some());
+            assert!(!tracker.is_silent("LAB|NORTH", Duration::from_secs(60)).unwrap());
+        }
+        assert_eq!(north.received("RIS|NORTH").unwrap(), 0);
+        assert!(north.is_silent("RIS|NORTH", Duration::from_secs(60)).unwrap());
+        thread::sleep(Duration::from_millis(20));
+        assert!(south.is_silent("LAB|NORTH", Duration::from_millis(10)).unwrap());
+    }
+}
@@ -0,0 +1,462 @@
+//! Framing of single messages, without any I/O.
+
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::{batch, ACK, CR, EB, NAK, SB};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MllpCodec { }
+
+impl MllpCodec {
+    pub fn encode(with: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Self::encode_into(with, &mut buf);
+        buf
+    }
+
+    /// Appends the frame of `with` to `buf`, growing it once at most, so that a buffer cleared
+    /// and reused between messages saves the allocation of large ones.
+    /// ```
+    /// use mllp_rs::MllpCodec;
+    ///
+    /// let mut scratch = Vec::new();
+    /// for payload in [&b"MSH|^~\\&|1"[..], b"MSH|^~\\&|2"] {
+    ///     scratch.clear();
+    ///     MllpCodec::encode_into(payload, &mut scratch);
+    ///     assert_eq!(scratch, MllpCodec::encode(payload));
+    /// }
+    /// ```
+    pub fn encode_into(with: &[u8], buf: &mut Vec<u8>) {
+        buf.reserve(with.len() + 3);
+        buf.push(SB);
+        buf.extend_from_slice(with);
+        buf.push(EB);
+        buf.push(CR);
+    }
+
+    /// Payload of the frame `with`, which must be a whole frame; shorter input is an error.
+    pub fn decode(with: &[u8]) -> Result<&[u8], MllpSyntaxError> {
+        match with {
+            [SB, hl7 @ .., EB, CR] => Ok(hl7),
+            _ => Err(MllpSyntaxError),
+        }
+    }
+
+    /// Same as [`MllpCodec::decode`], failing with where the frame goes wrong: the offset of the
+    /// offending byte, the byte found there and the bytes around it.
+    /// ```
+    /// use mllp_rs::MllpCodec;
+    ///
+    /// let error = MllpCodec::decode_verbose(b"\x0bMSH|^~\\&|LAB\x1c").unwrap_err();
+    /// assert_eq!((error.offset, error.found, error.expected), (14, None, "<CR>"));
+    /// assert_eq!(error.to_string(), "Expected <CR> at offset 14, found end of input, after 0b 4d 53 48 7c 5e 7e 5c 26 7c 4c 41 42 1c");
+    /// ```
+    pub fn decode_verbose(with: &[u8]) -> Result<&[u8], FrameError> {
+        if let Ok(hl7) = Self::decode(with) {
+            return Ok(hl7);
+        }
+        let (offset, expected) = match with.first() {
+            Some(&SB) => match with.windows(2).position(|w| w == [EB, CR]) {
+                Some(end) => (end + 2, "end of input after <EB><CR>"),
+                None if with.ends_with(&[EB]) => (with.len(), "<CR>"),
+                None => (with.len(), "<EB><CR>"),
+            },
+            _ => (0, "<SB>"),
+        };
+        let context_start = offset.saturating_sub(FrameError::CONTEXT);
+
+        Err(FrameError {
+            offset,
+            found: with.get(offset).copied(),
+            expected,
+            context_start,
+            context: with[context_start..with.len().min(offset + FrameError::CONTEXT)].to_vec(),
+        })
+    }
+
+    /// Same as [`MllpCodec::decode`], but salvages the payload of a frame cut short, without its
+    /// `<EB><CR>` as a sender crashing leaves it, marked [`LossyFrame::Truncated`]. Fails if
+    /// `with` does not start with `<SB>`, or goes on after `<EB><CR>`.
+    /// ```
+    /// use mllp_rs::{LossyFrame, MllpCodec};
+    ///
+    /// let frame = MllpCodec::encode(b"MSH|^~\\&|LAB\rOBX|1|TX|||Potassi");
+    /// assert_eq!(MllpCodec::decode_lossy(&frame).unwrap(), LossyFrame::Complete(b"MSH|^~\\&|LAB\rOBX|1|TX|||Potassi"));
+    /// assert_eq!(MllpCodec::decode_lossy(&frame[..19]).unwrap(), LossyFrame::Truncated(b"MSH|^~\\&|LAB\rOBX|1"));
+    /// ```
+    pub fn decode_lossy(with: &[u8]) -> Result<LossyFrame<'_>, MllpSyntaxError> {
+        if let Ok(hl7) = Self::decode(with) {
+            return Ok(LossyFrame::Complete(hl7));
+        }
+        match with {
+            [SB, rest @ ..] if !rest.windows(2).any(|w| w == [EB, CR]) => {
+                Ok(LossyFrame::Truncated(rest.strip_suffix(&[EB]).unwrap_or(rest)))
+            }
+            _ => Err(MllpSyntaxError),
+        }
+    }
+
+    /// Payload of the first complete frame of `buf`, and the bytes after it, `None` if `buf`
+    /// ends before the frame does.
+    ///
+    /// Fails if `buf` does not start with `<SB>`. Unlike [`MllpDecoder`](crate::MllpDecoder),
+    /// nothing is buffered or copied, for callers keeping the received bytes themselves:
+    /// ```
+    /// use mllp_rs::MllpCodec;
+    ///
+    /// let buf = b"\x0bMSH|^~\\&|1\x1c\x0d\x0bMSH|^~";
+    /// let (payload, rest) = MllpCodec::decode_first(buf).unwrap().unwrap();
+    /// assert_eq!(payload, b"MSH|^~\\&|1");
+    /// assert_eq!(MllpCodec::decode_first(rest).unwrap(), None);
+    /// ```
+    #[allow(clippy::type_complexity)]
+    pub fn decode_first(buf: &[u8]) -> Result<Option<(&[u8], &[u8])>, MllpSyntaxError> {
+        match buf.first() {
+            None => return Ok(None),
+            Some(&SB) => {}
+            Some(_) => return Err(MllpSyntaxError),
+        }
+
+        Ok(buf.windows(2).position(|w| w == [EB, CR]).map(|end| (&buf[1..end], &buf[end + 2..])))
+    }
+
+    /// Whether `buf` holds a complete frame, possibly after bytes which are not part of one.
+    pub fn contains_complete_frame(buf: &[u8]) -> bool {
+        Self::frame_len_hint(buf).is_some()
+    }
+
+    /// Number of bytes up to the end of the first complete frame of `buf`, `<CR>` and any bytes
+    /// before `<SB>` included, `None` if more must be read first.
+    /// ```
+    /// use mllp_rs::MllpCodec;
+    ///
+    /// assert_eq!(MllpCodec::frame_len_hint(b"\x0bMSH|^~\\&|"), None);
+    /// assert_eq!(MllpCodec::frame_len_hint(b"\x0bMSH|^~\\&|\x1c\x0d\x0bMSH"), Some(12));
+    /// ```
+    pub fn frame_len_hint(buf: &[u8]) -> Option<usize> {
+        let start = buf.iter().position(|b| *b == SB)?;
+        let end = buf[start..].windows(2).position(|w| w == [EB, CR])?;
+        Some(start + end + 2)
+    }
+
+    /// Creates an MLLP ACK.
+    /// ```
+    /// use mllp_rs::MllpCodec;
+    ///
+    /// let ack = MllpCodec::ack();
+    /// ```
+    pub fn ack() -> [u8;4] {
+        [SB, ACK, EB, CR]
+    }
+
+    /// Creates an MLLP NAK (Negative ACK).
+    /// ```
+    /// use mllp_rs::MllpCodec;
+    ///
+    /// let nak = MllpCodec::nak();
+    /// ```
+    pub fn nak() -> [u8;4] {
+        [SB, NAK, EB, CR]
+    }
+
+    pub fn is_ack(with: &[u8]) -> bool {
+        with == Self::ack()
+    }
+
+    pub fn is_nak(with: &[u8]) -> bool {
+        with == Self::nak()
+    }
+
+    /// Frames `messages` together as an HL7 batch, see [`batch::assemble`].
+    pub fn encode_batch(messages: &[&[u8]]) -> Vec<u8> {
+        Self::encode(&batch::assemble(messages))
+    }
+
+    /// Handles the `<SB>` and `<EB>` bytes found in `payload` according to `policy`, so that
+    /// they cannot be taken for the bounds of a frame once it is encoded. Binary data, such as a
+    /// PDF carried in OBX-5 without base64 encoding, may otherwise end a frame early and merge
+    /// what follows with the next message.
+    ///
+    /// `<CR>` is left as is: it separates the segments, and only ends a frame after an `<EB>`.
+    /// A payload without these bytes is returned as is.
+    /// ```
+    /// use mllp_rs::{MllpCodec, SanitizePolicy};
+    ///
+    /// let payload = b"MSH|^~\\&|LAB\rOBX|1|ED|PDF||%PDF\x1c\x0d";
+    /// let escaped = MllpCodec::sanitize(payload, SanitizePolicy::Escape).unwrap();
+    /// assert_eq!(*escaped, *b"MSH|^~\\&|LAB\rOBX|1|ED|PDF||%PDF\\X1C\\\x0d");
+    /// assert!(MllpCodec::sanitize(payload, SanitizePolicy::Error).is_err());
+    /// ```
+    pub fn sanitize(payload: &[u8], policy: SanitizePolicy) -> Result<Cow<'_, [u8]>, ReservedByte> {
+        let reserved = |b: &u8| *b == SB || *b == EB;
+        let Some(offset) = payload.iter().position(reserved) else {
+            return Ok(Cow::Borrowed(payload));
+        };
+
+        match policy {
+            SanitizePolicy::Strip => Ok(Cow::Owned(payload.iter().copied().filter(|b| !reserved(b)).collect())),
+            SanitizePolicy::Escape => {
+                // MSH-2 holds the encoding characters, the escape character third
+                let escape = match payload.strip_prefix(b"MSH") {
+                    Some([_, _, _, escape, ..]) => *escape,
+                    _ => b'\\',
+                };
+                let mut escaped = Vec::with_capacity(payload.len() + 8);
+                for b in payload {
+                    match reserved(b) {
+                        true => escaped.extend([escape, b'X', HEX[(b >> 4) as usize], HEX[(b & 15) as usize], escape]),
+                        false => escaped.push(*b),
+                    }
+                }
+                Ok(Cow::Owned(escaped))
+            }
+            SanitizePolicy::Error => Err(ReservedByte { offset, byte: payload[offset] }),
+        }
+    }
+}
+
+/// Framing of the messages on the wire, MLLP with [`MllpCodec`].
+///
+/// Set in the `codec` field of the client and server configurations, another implementation
+/// swaps the framing, to HLLP or to custom delimiters, keeping the rest of the transport.
+/// Commit acknowledgements are the payloads `<ACK>` and `<NAK>`, framed by the codec.
+pub trait LowerLayerCodec: fmt::Debug + Send + Sync {
+    /// Appends the frame of `payload` to `buf`.
+    fn encode_into(&self, payload: &[u8], buf: &mut Vec<u8>);
+
+    /// Payload of the first complete frame of `buf`, and the bytes after it, `None` if `buf`
+    /// ends before the frame does. Fails if `buf` does not start with a frame.
+    #[allow(clippy::type_complexity)]
+    fn decode_first<'a>(&self, buf: &'a [u8]) -> Result<Option<(&'a [u8], &'a [u8])>, MllpSyntaxError>;
+
+    /// Offset of the first byte of `buf` which may start a frame, where decoding starts again
+    /// after bytes which are not a frame.
+    fn frame_start(&self, buf: &[u8]) -> Option<usize>;
+
+    /// Frame of `payload`.
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(payload, &mut buf);
+        buf
+    }
+}
+
+impl LowerLayerCodec for MllpCodec {
+    fn encode_into(&self, payload: &[u8], buf: &mut Vec<u8>) {
+        MllpCodec::encode_into(payload, buf)
+    }
+
+    fn decode_first<'a>(&self, buf: &'a [u8]) -> Result<Option<(&'a [u8], &'a [u8])>, MllpSyntaxError> {
+        MllpCodec::decode_first(buf)
+    }
+
+    fn frame_start(&self, buf: &[u8]) -> Option<usize> {
+        buf.iter().position(|b| *b == SB)
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+/// What [`MllpCodec::sanitize`] does with the `<SB>` and `<EB>` bytes of a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizePolicy {
+    /// Removes them.
+    Strip,
+    /// Replaces them with the HL7 hexadecimal escape sequence, `\X0B\` or `\X1C\`, written with
+    /// the escape character of MSH-2.
+    Escape,
+    /// Fails with a [`ReservedByte`] error.
+    Error,
+}
+
+/// Byte of a payload which would be taken for a bound of the frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedByte {
+    pub offset: usize,
+    pub byte: u8,
+}
+
+impl fmt::Display for ReservedByte {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reserved byte 0x{:02x} at offset {}", self.byte, self.offset)
+    }
+}
+
+impl core::error::Error for ReservedByte { }
+
+/// Payload decoded by [`MllpCodec::decode_lossy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LossyFrame<'a> {
+    Complete(&'a [u8]),
+    /// The frame ended before its `<EB><CR>`: the payload is what was received of it.
+    Truncated(&'a [u8]),
+}
+
+impl<'a> LossyFrame<'a> {
+    pub fn payload(&self) -> &'a [u8] {
+        match self {
+            LossyFrame::Complete(payload) | LossyFrame::Truncated(payload) => payload,
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, LossyFrame::Truncated(_))
+    }
+}
+
+/// Where a frame fails to decode, from [`MllpCodec::decode_verbose`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameError {
+    /// Offset of the offending byte, the length of the frame if it ends early.
+    pub offset: usize,
+    /// Byte at `offset`, `None` at the end of the frame.
+    pub found: Option<u8>,
+    /// What was expected at `offset`.
+    pub expected: &'static str,
+    /// Offset of the first byte of `context`.
+    pub context_start: usize,
+    /// Bytes of the frame around `offset`, up to 16 before and after it.
+    pub context: Vec<u8>,
+}
+
+impl FrameError {
+    const CONTEXT: usize = 16;
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected {} at offset {}, found ", self.expected, self.offset)?;
+        match self.found {
+            Some(byte) => write!(f, "0x{:02x}", byte)?,
+            None => write!(f, "end of input")?,
+        }
+        let split = self.offset - self.context_start;
+        for (label, bytes) in [("after", &self.context[..split]), ("before", &self.context[split..])] {
+            if !bytes.is_empty() {
+                write!(f, ", {}", label)?;
+                for byte in bytes {
+                    write!(f, " {:02x}", byte)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for FrameError { }
+
+#[derive(Debug)]
+pub struct MllpSyntaxError;
+
+impl fmt::Display for MllpSyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected bytes <SB>...<EB><CR>")
+    }
+}
+
+impl core::error::Error for MllpSyntaxError { }
+
+#[cfg(test)]
+mod tests {
+    use crate::{LossyFrame, MllpCodec, MllpDecoder, ReservedByte, SanitizePolicy, CR, EB, SB};
+
+    #[test]
+    fn encode_and_decode_same_message() {
+        let data = "MSH|^~\\&|ZIS|1^AHospital|||200405141144||¶ADT^A01|20041104082400|P|2.3|||AL|NE|||8859/15|¶EVN|A01|20041104082400.0000+0100|20041104082400¶PID||\"\"|10||Vries^Danny^D.^^de||19951202|M|||Rembrandlaan^7^Leiden^^7301TH^\"\"^^P||\"\"|\"\"||\"\"|||||||\"\"|\"\"¶PV1||I|3w^301^\"\"^01|S|||100^van den Berg^^A.S.^^\"\"^dr|\"\"||9||||H||||20041104082400.0000+0100";
+        let encoded_data = MllpCodec::encode(data.as_bytes());
+        let decoded_data = MllpCodec::decode(encoded_data.as_slice());
+
+        assert!(decoded_data.is_ok());
+        assert_eq!(decoded_data.unwrap(), data.as_bytes());
+    }
+
+    #[test]
+    fn it_decodes_the_first_frame() {
+        let mut buf = MllpCodec::encode(b"first");
+        buf.extend(MllpCodec::ack());
+        buf.extend(&MllpCodec::encode(b"third")[..3]);
+
+        let (first, rest) = MllpCodec::decode_first(&buf).unwrap().unwrap();
+        assert_eq!(first, b"first");
+        let (ack, rest) = MllpCodec::decode_first(rest).unwrap().unwrap();
+        assert!(MllpCodec::is_ack(&MllpCodec::encode(ack)));
+        assert_eq!(MllpCodec::decode_first(rest).unwrap(), None);
+        assert_eq!(MllpCodec::decode_first(b"").unwrap(), None);
+        assert!(MllpCodec::decode_first(b"junk\x0b").is_err());
+    }
+
+    #[test]
+    fn it_finds_frame_boundaries() {
+        let frame = MllpCodec::encode(b"MSH|1");
+        for len in 0..frame.len() {
+            assert!(!MllpCodec::contains_complete_frame(&frame[..len]));
+            assert!(MllpCodec::decode(&frame[..len]).is_err());
+        }
+        assert!(MllpCodec::contains_complete_frame(&frame));
+
+        let buf = [&b"junk"[..], &frame, &frame[..2]].concat();
+        assert_eq!(MllpCodec::frame_len_hint(&buf), Some(4 + frame.len()));
+        assert_eq!(MllpCodec::frame_len_hint(&[SB, EB, CR]), Some(3));
+        assert_eq!(MllpCodec::decode(&[SB, EB, CR]).unwrap(), b"");
+    }
+
+    #[test]
+    fn it_reports_where_frames_go_wrong() {
+        let frame = MllpCodec::encode(b"MSH|^~\\&|LAB|NORTH|EHR|SOUTH|20240131");
+        assert_eq!(MllpCodec::decode_verbose(&frame).unwrap(), &frame[1..frame.len() - 2]);
+
+        let error = MllpCodec::decode_verbose(b"MSH|1").unwrap_err();
+        assert_eq!((error.offset, error.found, error.expected), (0, Some(b'M'), "<SB>"));
+        assert_eq!(error.to_string(), "Expected <SB> at offset 0, found 0x4d, before 4d 53 48 7c 31");
+
+        let truncated = MllpCodec::decode_verbose(&frame[..frame.len() - 2]).unwrap_err();
+        assert_eq!((truncated.offset, truncated.found, truncated.expected), (frame.len() - 2, None, "<EB><CR>"));
+        assert_eq!(truncated.context_start, frame.len() - 18);
+        assert_eq!(truncated.context, &frame[frame.len() - 18..frame.len() - 2]);
+
+        let trailing = [&frame[..], b"junk"].concat();
+        let error = MllpCodec::decode_verbose(&trailing).unwrap_err();
+        assert_eq!((error.offset, error.found), (frame.len(), Some(b'j')));
+        assert_eq!(MllpCodec::decode_verbose(b"").unwrap_err().found, None);
+    }
+
+    #[test]
+    fn it_salvages_truncated_frames() {
+        let frame = MllpCodec::encode(b"MSH|1");
+        assert_eq!(MllpCodec::decode_lossy(&frame).unwrap(), LossyFrame::Complete(b"MSH|1"));
+        assert_eq!(MllpCodec::decode_lossy(&frame[..5]).unwrap(), LossyFrame::Truncated(b"MSH|"));
+        let cut = MllpCodec::decode_lossy(&frame[..7]).unwrap();
+        assert!(cut.is_truncated());
+        assert_eq!(cut.payload(), b"MSH|1");
+        assert_eq!(MllpCodec::decode_lossy(&[SB]).unwrap(), LossyFrame::Truncated(b""));
+
+        assert!(MllpCodec::decode_lossy(b"MSH|1").is_err());
+        assert!(MllpCodec::decode_lossy(&[&frame[..], b"MSH"].concat()).is_err());
+    }
+
+    #[test]
+    fn it_creates_ack() {
+        let ack = MllpCodec::ack();
+        assert!(MllpCodec::is_ack(&ack));
+    }
+
+    #[test]
+    fn it_creates_nak() {
+        let nak = MllpCodec::nak();
+        assert!(MllpCodec::is_nak(&nak));
+    }
+
+    #[test]
+    fn it_sanitizes_reserved_bytes() {
+        let payload = b"MSH|^~#&|LAB\rOBX|1|ED|PDF||\x0b%PDF\x1c\rPID|1";
+        let mut decoder = MllpDecoder::new();
+        decoder.extend(&MllpCodec::encode(&MllpCodec::sanitize(payload, SanitizePolicy::Strip).unwrap()));
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"MSH|^~#&|LAB\rOBX|1|ED|PDF||%PDF\rPID|1");
+
+        let escaped = MllpCodec::sanitize(payload, SanitizePolicy::Escape).unwrap();
+        assert_eq!(*escaped, *b"MSH|^~#&|LAB\rOBX|1|ED|PDF||#X0B#%PDF#X1C#\rPID|1");
+        assert_eq!(MllpCodec::sanitize(payload, SanitizePolicy::Error), Err(ReservedByte { offset: 27, byte: 0x0b }));
+        assert!(matches!(MllpCodec::sanitize(b"PID|1", SanitizePolicy::Error), Ok(std::borrow::Cow::Borrowed(_))));
+    }
+}
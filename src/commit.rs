@@ -0,0 +1,185 @@
+//! MLLP Release 2 commit acknowledgement state machine.
+//!
+//! MLLP Release 2 makes the delivery reliable by having the receiver answer each block with a
+//! commit acknowledgement once it has safely stored it: `<SB><ACK><EB><CR>`, or
+//! `<SB><NAK><EB><CR>` if it could not. The sender must not send the next block before the
+//! acknowledgement of the previous one arrives, and sends the block again on a NAK or when no
+//! acknowledgement comes.
+//!
+//! [`CommitSession`] enforces these rules for the sending side of a connection, whatever the
+//! transport: it hands out the frames to write, and is given the frames received and the
+//! acknowledgement timeouts. A peer or a caller breaking the rules gets a
+//! [`ProtocolViolation`].
+//! ```
+//! use mllp_rs::{MllpCodec, MllpError};
+//! use mllp_rs::commit::{CommitOutcome, CommitSession, ProtocolViolation};
+//!
+//! let mut session = CommitSession::new(1);
+//! let frame = session.send(b"MSH|1").unwrap();
+//! assert_eq!(frame, MllpCodec::encode(b"MSH|1"));
+//! assert!(matches!(session.send(b"MSH|2"), Err(MllpError::Protocol(ProtocolViolation::BlockInFlight))));
+//!
+//! // the receiver could not store the block, send it again
+//! assert_eq!(session.receive(&MllpCodec::nak()).unwrap(), CommitOutcome::Retransmit(frame));
+//! assert_eq!(session.receive(&MllpCodec::ack()).unwrap(), CommitOutcome::Committed);
+//! assert!(session.send(b"MSH|2").is_ok());
+//! ```
+
+use std::fmt;
+use crate::{MllpCodec, MllpError};
+
+/// Breach of the commit acknowledgement rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    /// A block was sent before the previous one was acknowledged.
+    BlockInFlight,
+    /// An acknowledgement or a timeout came while no block was waiting for one.
+    NoBlockInFlight,
+    /// The peer answered a block with a frame other than a commit ACK or NAK.
+    NotCommitAck,
+}
+
+impl fmt::Display for ProtocolViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolViolation::BlockInFlight => write!(f, "Block sent before the previous one was acknowledged"),
+            ProtocolViolation::NoBlockInFlight => write!(f, "Acknowledgement without a block waiting for it"),
+            ProtocolViolation::NotCommitAck => write!(f, "Expected a commit ACK or NAK"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolViolation {}
+
+/// What to do after receiving an acknowledgement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommitOutcome {
+    /// The block was committed, the next one may be sent.
+    Committed,
+    /// The block was negatively acknowledged: write this frame again.
+    Retransmit(Vec<u8>),
+}
+
+/// Sending side of an MLLP Release 2 connection.
+#[derive(Debug, Clone)]
+pub struct CommitSession {
+    max_retransmissions: u32,
+    in_flight: Option<InFlight>,
+}
+
+#[derive(Debug, Clone)]
+struct InFlight {
+    frame: Vec<u8>,
+    retransmissions: u32,
+}
+
+impl CommitSession {
+    /// Creates a session sending each block at most `max_retransmissions` more times after a NAK
+    /// or a timeout.
+    pub fn new(max_retransmissions: u32) -> Self {
+        CommitSession {
+            max_retransmissions,
+            in_flight: None,
+        }
+    }
+
+    /// Whether a block is waiting for its acknowledgement.
+    pub fn is_awaiting_commit(&self) -> bool {
+        self.in_flight.is_some()
+    }
+
+    /// Starts the delivery of `payload`, returning the frame to write.
+    pub fn send(&mut self, payload: &[u8]) -> Result<Vec<u8>, MllpError> {
+        if self.in_flight.is_some() {
+            return Err(ProtocolViolation::BlockInFlight.into());
+        }
+        let frame = MllpCodec::encode(payload);
+        self.in_flight = Some(InFlight {
+            frame: frame.clone(),
+            retransmissions: 0,
+        });
+
+        Ok(frame)
+    }
+
+    /// Handles `frame`, received from the peer.
+    ///
+    /// Once the retransmissions are used up, a NAK returns [`MllpError::Nak`] and the block is
+    /// abandoned.
+    pub fn receive(&mut self, frame: &[u8]) -> Result<CommitOutcome, MllpError> {
+        if self.in_flight.is_none() {
+            return Err(ProtocolViolation::NoBlockInFlight.into());
+        }
+
+        if MllpCodec::is_ack(frame) {
+            self.in_flight = None;
+            Ok(CommitOutcome::Committed)
+        } else if MllpCodec::is_nak(frame) {
+            self.retransmit(MllpError::Nak).map(CommitOutcome::Retransmit)
+        } else {
+            Err(ProtocolViolation::NotCommitAck.into())
+        }
+    }
+
+    /// Handles the expiry of the acknowledgement timeout, returning the frame to write again.
+    ///
+    /// Once the retransmissions are used up, returns [`MllpError::AckTimeout`] and the block is
+    /// abandoned.
+    pub fn timed_out(&mut self) -> Result<Vec<u8>, MllpError> {
+        if self.in_flight.is_none() {
+            return Err(ProtocolViolation::NoBlockInFlight.into());
+        }
+
+        self.retransmit(MllpError::AckTimeout)
+    }
+
+    fn retransmit(&mut self, exhausted: MllpError) -> Result<Vec<u8>, MllpError> {
+        let Some(in_flight) = self.in_flight.as_mut() else {
+            return Err(ProtocolViolation::NoBlockInFlight.into());
+        };
+        if in_flight.retransmissions >= self.max_retransmissions {
+            self.in_flight = None;
+            return Err(exhausted);
+        }
+        in_flight.retransmissions += 1;
+
+        Ok(in_flight.frame.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::commit::{CommitOutcome, CommitSession, ProtocolViolation};
+    use crate::{MllpCodec, MllpError};
+
+    #[test]
+    fn it_abandons_block_after_retransmissions() {
+        let mut session = CommitSession::new(2);
+        let frame = session.send(b"MSH|1").unwrap();
+
+        assert_eq!(session.timed_out().unwrap(), frame);
+        assert_eq!(session.receive(&MllpCodec::nak()).unwrap(), CommitOutcome::Retransmit(frame));
+        assert!(matches!(session.receive(&MllpCodec::nak()), Err(MllpError::Nak)));
+        assert!(!session.is_awaiting_commit());
+        assert!(session.send(b"MSH|2").is_ok());
+    }
+
+    fn violation<T>(result: Result<T, MllpError>) -> Option<ProtocolViolation> {
+        match result {
+            Err(MllpError::Protocol(violation)) => Some(violation),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn it_reports_protocol_violations() {
+        let mut session = CommitSession::new(0);
+
+        assert_eq!(violation(session.receive(&MllpCodec::ack())), Some(ProtocolViolation::NoBlockInFlight));
+        assert_eq!(violation(session.timed_out()), Some(ProtocolViolation::NoBlockInFlight));
+        session.send(b"MSH|1").unwrap();
+        assert_eq!(violation(session.send(b"MSH|2")), Some(ProtocolViolation::BlockInFlight));
+        assert_eq!(violation(session.receive(&MllpCodec::encode(b"MSA|AA"))), Some(ProtocolViolation::NotCommitAck));
+        assert!(matches!(session.timed_out(), Err(MllpError::AckTimeout)));
+    }
+}
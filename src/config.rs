@@ -0,0 +1,613 @@
+//! Builders of the client and server configurations, checking the settings as a whole.
+//!
+//! [`MllpClientConfig`] and [`MllpServerConfig`] can be written as struct literals, but a
+//! builder reads better with many settings, and its `build` method rejects settings which
+//! would only fail later, on the first connection or message: a zero timeout, an empty source
+//! port range, TLS with a worker pool.
+//! ```
+//! use std::time::Duration;
+//! use mllp_rs::client::MllpClientConfig;
+//! use mllp_rs::AckMode;
+//!
+//! let config = MllpClientConfig::builder()
+//!     .ack_timeout(Some(Duration::from_secs(10)))
+//!     .connect_timeout(Duration::from_secs(5))
+//!     .ack_mode(AckMode::ApplicationOnly)
+//!     .max_frame_size(1 << 20)
+//!     .build()
+//!     .unwrap();
+//!
+//! let invalid = MllpClientConfig::builder().max_in_flight(0).build();
+//! assert_eq!(invalid.unwrap_err().to_string(), "Invalid max_in_flight: must be at least 1");
+//! ```
+
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::audit::AuditSink;
+use crate::capture::PayloadCapture;
+use crate::client::MllpClientConfig;
+use crate::dead_letter::DeadLetterSink;
+use crate::event::EventSink;
+use crate::filter::{ConnectionFilter, IpRange};
+use crate::interceptor::Interceptor;
+use crate::journal::FrameJournal;
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::rate_limit::{RateLimit, Throttle};
+use crate::reconnect::ReconnectPolicy;
+use crate::sequence::SequenceNumbers;
+use crate::server::{
+    FdBudget, MalformedFramePolicy, MllpServerConfig, OverCapacityPolicy, RateLimitPolicy, ReloadableSettings, SinkPolicy, WriteCoalescing,
+};
+use crate::sink::MessageSink;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsAcceptor, TlsConnector};
+use crate::{AckMode, LowerLayerCodec};
+
+/// Smallest frame, `<SB><EB><CR>`.
+const MIN_FRAME_SIZE: usize = 3;
+
+/// Setting rejected by the `build` method of a builder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Name of the setting, that of its field in the configuration.
+    pub field: &'static str,
+    /// What is wrong with it.
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {}: {}", self.field, self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError { }
+
+/// Fails when `timeout` is zero, which the sockets refuse as a read or write timeout.
+fn non_zero(field: &'static str, timeout: Option<Duration>) -> Result<(), ConfigError> {
+    match timeout {
+        Some(timeout) if timeout.is_zero() => Err(ConfigError { field, reason: "must not be zero" }),
+        _ => Ok(()),
+    }
+}
+
+fn frame_size(max_frame_size: Option<usize>) -> Result<(), ConfigError> {
+    match max_frame_size {
+        Some(max) if max < MIN_FRAME_SIZE => Err(ConfigError {
+            field: "max_frame_size",
+            reason: "must be at least 3, the size of an empty frame",
+        }),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn rate_limit(field: &'static str, limit: Option<RateLimit>) -> Result<(), ConfigError> {
+    match limit {
+        Some(limit) if limit.per_second > 0.0 => Ok(()),
+        Some(_) => Err(ConfigError { field, reason: "must allow a positive rate" }),
+        None => Ok(()),
+    }
+}
+
+/// Checks settings changed while the server runs, as [`MllpServerConfigBuilder::build`] does.
+pub(crate) fn check_reloadable(settings: &ReloadableSettings) -> Result<(), ConfigError> {
+    frame_size(settings.max_frame_size)?;
+    rate_limit("connection_rate_limit", settings.connection_rate_limit)?;
+    rate_limit("global_rate_limit", settings.global_rate_limit)
+}
+
+/// Builder of an [`MllpClientConfig`], made with [`MllpClientConfig::builder`]. Settings not
+/// set keep their default.
+#[derive(Debug, Clone, Default)]
+pub struct MllpClientConfigBuilder {
+    config: MllpClientConfig,
+}
+
+impl MllpClientConfigBuilder {
+    /// Sets [`MllpClientConfig::ack_timeout`], `None` waiting forever.
+    pub fn ack_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.ack_timeout = timeout;
+        self
+    }
+
+    /// Sets [`MllpClientConfig::connect_timeout`].
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::first_byte_timeout`].
+    pub fn first_byte_timeout(mut self, timeout: Duration) -> Self {
+        self.config.first_byte_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::inter_byte_timeout`].
+    pub fn inter_byte_timeout(mut self, timeout: Duration) -> Self {
+        self.config.inter_byte_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::write_timeout`].
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::max_retries`] and [`MllpClientConfig::retry_backoff`].
+    pub fn retries(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.config.max_retries = max_retries;
+        self.config.retry_backoff = backoff;
+        self
+    }
+
+    /// Sets [`MllpClientConfig::failback_after`].
+    pub fn failback_after(mut self, delay: Duration) -> Self {
+        self.config.failback_after = Some(delay);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::keep_open`].
+    pub fn keep_open(mut self, keep_open: bool) -> Self {
+        self.config.keep_open = keep_open;
+        self
+    }
+
+    /// Sets [`MllpClientConfig::tcp_keepalive`].
+    pub fn tcp_keepalive(mut self, time: Duration) -> Self {
+        self.config.tcp_keepalive = Some(time);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::max_reconnect_attempts`] and
+    /// [`MllpClientConfig::reconnect_backoff`].
+    pub fn reconnects(mut self, max_attempts: u32, backoff: Duration) -> Self {
+        self.config.max_reconnect_attempts = max_attempts;
+        self.config.reconnect_backoff = backoff;
+        self
+    }
+
+    /// Sets [`MllpClientConfig::reconnect_policy`].
+    pub fn reconnect_policy(mut self, policy: Arc<dyn ReconnectPolicy>) -> Self {
+        self.config.reconnect_policy = Some(policy);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::event_sink`].
+    pub fn event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.config.event_sink = Some(sink);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::capture`].
+    pub fn capture(mut self, capture: Arc<PayloadCapture>) -> Self {
+        self.config.capture = Some(capture);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::journal`].
+    pub fn journal(mut self, journal: Arc<FrameJournal>) -> Self {
+        self.config.journal = Some(journal);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::dead_letter`].
+    pub fn dead_letter(mut self, sink: Arc<dyn DeadLetterSink>) -> Self {
+        self.config.dead_letter = Some(sink);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::audit`].
+    pub fn audit(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.config.audit = Some(sink);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::bind_addr`].
+    pub fn bind_addr(mut self, addr: IpAddr) -> Self {
+        self.config.bind_addr = Some(addr);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::source_ports`].
+    pub fn source_ports(mut self, ports: RangeInclusive<u16>) -> Self {
+        self.config.source_ports = Some(ports);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::bind_interface`].
+    pub fn bind_interface(mut self, interface: String) -> Self {
+        self.config.bind_interface = Some(interface);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::ack_mode`].
+    pub fn ack_mode(mut self, mode: AckMode) -> Self {
+        self.config.ack_mode = Some(mode);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::heartbeat_interval`].
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.config.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::heartbeat_payload`].
+    pub fn heartbeat_payload(mut self, payload: Vec<u8>) -> Self {
+        self.config.heartbeat_payload = payload;
+        self
+    }
+
+    /// Sets [`MllpClientConfig::max_frame_size`].
+    pub fn max_frame_size(mut self, max: usize) -> Self {
+        self.config.max_frame_size = Some(max);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::codec`].
+    pub fn codec(mut self, codec: Arc<dyn LowerLayerCodec>) -> Self {
+        self.config.codec = Some(codec);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::skip_banner`].
+    pub fn skip_banner(mut self, skip: bool) -> Self {
+        self.config.skip_banner = skip;
+        self
+    }
+
+    /// Sets [`MllpClientConfig::metrics`].
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::max_in_flight`].
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.config.max_in_flight = max;
+        self
+    }
+
+    /// Sets [`MllpClientConfig::sequence_numbers`].
+    pub fn sequence_numbers(mut self, numbers: Arc<SequenceNumbers>) -> Self {
+        self.config.sequence_numbers = Some(numbers);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::throttle`].
+    pub fn throttle(mut self, throttle: Arc<Throttle>) -> Self {
+        self.config.throttle = Some(throttle);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::proxy`].
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.config.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets [`MllpClientConfig::tls`].
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, connector: Arc<TlsConnector>) -> Self {
+        self.config.tls = Some(connector);
+        self
+    }
+
+    /// Checks the settings and returns the configuration.
+    pub fn build(self) -> Result<MllpClientConfig, ConfigError> {
+        let config = self.config;
+        non_zero("ack_timeout", config.ack_timeout)?;
+        non_zero("connect_timeout", config.connect_timeout)?;
+        non_zero("first_byte_timeout", config.first_byte_timeout)?;
+        non_zero("inter_byte_timeout", config.inter_byte_timeout)?;
+        non_zero("write_timeout", config.write_timeout)?;
+        frame_size(config.max_frame_size)?;
+        if config.max_in_flight == 0 {
+            return Err(ConfigError { field: "max_in_flight", reason: "must be at least 1" });
+        }
+        if config.source_ports.as_ref().is_some_and(|ports| ports.is_empty()) {
+            return Err(ConfigError { field: "source_ports", reason: "must not be empty" });
+        }
+        if config.bind_interface.as_ref().is_some_and(|interface| interface.is_empty()) {
+            return Err(ConfigError { field: "bind_interface", reason: "must not be empty" });
+        }
+
+        Ok(config)
+    }
+}
+
+/// Builder of an [`MllpServerConfig`], made with [`MllpServerConfig::builder`]. Settings not
+/// set keep their default.
+#[derive(Debug, Clone, Default)]
+pub struct MllpServerConfigBuilder {
+    config: MllpServerConfig,
+}
+
+impl MllpServerConfigBuilder {
+    /// Sets [`MllpServerConfig::idle_timeout`].
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::first_frame_timeout`].
+    pub fn first_frame_timeout(mut self, timeout: Duration) -> Self {
+        self.config.first_frame_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::first_byte_timeout`].
+    pub fn first_byte_timeout(mut self, timeout: Duration) -> Self {
+        self.config.first_byte_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::inter_byte_timeout`].
+    pub fn inter_byte_timeout(mut self, timeout: Duration) -> Self {
+        self.config.inter_byte_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::write_timeout`].
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::drain_timeout`].
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.config.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::capture`].
+    pub fn capture(mut self, capture: Arc<PayloadCapture>) -> Self {
+        self.config.capture = Some(capture);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::journal`].
+    pub fn journal(mut self, journal: Arc<FrameJournal>) -> Self {
+        self.config.journal = Some(journal);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::audit`].
+    pub fn audit(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.config.audit = Some(sink);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::message_sink`].
+    pub fn message_sink(mut self, sink: Arc<dyn MessageSink>) -> Self {
+        self.config.message_sink = Some(sink);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::sink_full_policy`].
+    pub fn sink_full_policy(mut self, policy: SinkPolicy) -> Self {
+        self.config.sink_full_policy = policy;
+        self
+    }
+
+    /// Sets [`MllpServerConfig::sink_failure_policy`].
+    pub fn sink_failure_policy(mut self, policy: SinkPolicy) -> Self {
+        self.config.sink_failure_policy = policy;
+        self
+    }
+
+    /// Adds an interceptor to [`MllpServerConfig::interceptors`], inside those added before.
+    pub fn interceptor(mut self, interceptor: Arc<dyn Interceptor>) -> Self {
+        self.config.interceptors.push(interceptor);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::auto_ack`].
+    pub fn auto_ack(mut self, auto_ack: bool) -> Self {
+        self.config.auto_ack = auto_ack;
+        self
+    }
+
+    /// Sets [`MllpServerConfig::ack_mode`].
+    pub fn ack_mode(mut self, mode: AckMode) -> Self {
+        self.config.ack_mode = Some(mode);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::heartbeat_payload`].
+    pub fn heartbeat_payload(mut self, payload: Vec<u8>) -> Self {
+        self.config.heartbeat_payload = Some(payload);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::max_frame_size`].
+    pub fn max_frame_size(mut self, max: usize) -> Self {
+        self.config.max_frame_size = Some(max);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::codec`].
+    pub fn codec(mut self, codec: Arc<dyn LowerLayerCodec>) -> Self {
+        self.config.codec = Some(codec);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::skip_banner`].
+    pub fn skip_banner(mut self, skip: bool) -> Self {
+        self.config.skip_banner = skip;
+        self
+    }
+
+    /// Sets [`MllpServerConfig::connection_rate_limit`].
+    pub fn connection_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.config.connection_rate_limit = Some(limit);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::global_rate_limit`].
+    pub fn global_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.config.global_rate_limit = Some(limit);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::malformed_frame_policy`].
+    pub fn malformed_frame_policy(mut self, policy: MalformedFramePolicy) -> Self {
+        self.config.malformed_frame_policy = policy;
+        self
+    }
+
+    /// Sets [`MllpServerConfig::rate_limit_policy`].
+    pub fn rate_limit_policy(mut self, policy: RateLimitPolicy) -> Self {
+        self.config.rate_limit_policy = policy;
+        self
+    }
+
+    /// Adds a range to [`MllpServerConfig::allowed_peers`].
+    pub fn allowed_peer(mut self, range: IpRange) -> Self {
+        self.config.allowed_peers.get_or_insert_with(Vec::new).push(range);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::connection_filter`].
+    pub fn connection_filter(mut self, filter: Arc<dyn ConnectionFilter>) -> Self {
+        self.config.connection_filter = Some(filter);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::max_connections`] and [`MllpServerConfig::over_capacity`].
+    pub fn max_connections(mut self, max: usize, over_capacity: OverCapacityPolicy) -> Self {
+        self.config.max_connections = Some(max);
+        self.config.over_capacity = over_capacity;
+        self
+    }
+
+    /// Sets [`MllpServerConfig::listen_backlog`].
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.config.listen_backlog = Some(backlog);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::dual_stack`].
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.config.dual_stack = Some(dual_stack);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::fd_budget`].
+    pub fn fd_budget(mut self, budget: FdBudget) -> Self {
+        self.config.fd_budget = Some(budget);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::event_sink`].
+    pub fn event_sink(mut self, sink: Arc<dyn EventSink>) -> Self {
+        self.config.event_sink = Some(sink);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::worker_threads`].
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.config.worker_threads = Some(threads);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::write_coalescing`].
+    pub fn write_coalescing(mut self, coalescing: WriteCoalescing) -> Self {
+        self.config.write_coalescing = Some(coalescing);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::metrics`].
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.config.metrics = Some(metrics);
+        self
+    }
+
+    /// Sets [`MllpServerConfig::tls`].
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, acceptor: Arc<TlsAcceptor>) -> Self {
+        self.config.tls = Some(acceptor);
+        self
+    }
+
+    /// Checks the settings and returns the configuration.
+    pub fn build(self) -> Result<MllpServerConfig, ConfigError> {
+        let config = self.config;
+        non_zero("idle_timeout", config.idle_timeout)?;
+        non_zero("first_frame_timeout", config.first_frame_timeout)?;
+        non_zero("first_byte_timeout", config.first_byte_timeout)?;
+        non_zero("inter_byte_timeout", config.inter_byte_timeout)?;
+        non_zero("write_timeout", config.write_timeout)?;
+        frame_size(config.max_frame_size)?;
+        rate_limit("connection_rate_limit", config.connection_rate_limit)?;
+        rate_limit("global_rate_limit", config.global_rate_limit)?;
+        if config.max_connections == Some(0) {
+            return Err(ConfigError { field: "max_connections", reason: "must be at least 1" });
+        }
+        if config.worker_threads == Some(0) {
+            return Err(ConfigError { field: "worker_threads", reason: "must be at least 1" });
+        }
+        if config.fd_budget.is_some_and(|budget| budget.reserve >= budget.max_fds) {
+            return Err(ConfigError { field: "fd_budget", reason: "must reserve fewer descriptors than the maximum" });
+        }
+        #[cfg(feature = "tls")]
+        if config.tls.is_some() && config.worker_threads.is_some() {
+            return Err(ConfigError { field: "tls", reason: "needs a thread per connection, not worker threads" });
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::client::MllpClientConfig;
+    use crate::config::ConfigError;
+    use crate::rate_limit::RateLimit;
+    use crate::server::{MllpServerConfig, OverCapacityPolicy};
+    use crate::AckMode;
+
+    #[test]
+    fn it_builds_client_configs() {
+        let config = MllpClientConfig::builder()
+            .ack_timeout(None)
+            .inter_byte_timeout(Duration::from_secs(1))
+            .ack_mode(AckMode::Both)
+            .build()
+            .unwrap();
+        assert_eq!(config.ack_timeout, None);
+        assert_eq!(config.inter_byte_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(config.max_in_flight, MllpClientConfig::default().max_in_flight);
+
+        let zero = MllpClientConfig::builder().write_timeout(Duration::ZERO).build();
+        assert_eq!(zero.unwrap_err(), ConfigError { field: "write_timeout", reason: "must not be zero" });
+        #[allow(clippy::reversed_empty_ranges)]
+        let ports = MllpClientConfig::builder().source_ports(2000..=1000).build();
+        assert_eq!(ports.unwrap_err().field, "source_ports");
+        assert_eq!(MllpClientConfig::builder().bind_interface(String::new()).build().unwrap_err().field, "bind_interface");
+        assert_eq!(MllpClientConfig::builder().max_frame_size(2).build().unwrap_err().field, "max_frame_size");
+    }
+
+    #[test]
+    fn it_builds_server_configs() {
+        let config = MllpServerConfig::builder()
+            .idle_timeout(Duration::from_secs(600))
+            .max_connections(100, OverCapacityPolicy::Reject)
+            .allowed_peer("10.0.0.0/8".parse().unwrap())
+            .allowed_peer("192.168.0.0/16".parse().unwrap())
+            .build()
+            .unwrap();
+        assert_eq!(config.max_connections, Some(100));
+        assert_eq!(config.allowed_peers.unwrap().len(), 2);
+
+        let limit = RateLimit { per_second: 0.0, burst: 10 };
+        assert_eq!(MllpServerConfig::builder().global_rate_limit(limit).build().unwrap_err().field, "global_rate_limit");
+        assert_eq!(MllpServerConfig::builder().worker_threads(0).build().unwrap_err().field, "worker_threads");
+    }
+}
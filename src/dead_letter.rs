@@ -0,0 +1,176 @@
+//! Dead-letter handling of permanently failed messages.
+//!
+//! Once the retries of a message are used up without an acknowledgement, or the receiver keeps
+//! answering NAK, the client hands the message to the [`DeadLetterSink`] set in
+//! [`MllpClientConfig::dead_letter`](crate::client::MllpClientConfig), together with what went
+//! wrong, so that operators can look into it and reprocess it later.
+//! [`DirectoryDeadLetterSink`] keeps dead letters as files in a directory.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::event::{format_rfc3339, json_string};
+use crate::spool::Metadata;
+
+/// Why a message was dead-lettered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterReason {
+    /// No acknowledgement was received, retries included.
+    AckTimeout,
+    /// The receiver answered NAK, retries included.
+    Nak,
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeadLetterReason::AckTimeout => write!(f, "ack_timeout"),
+            DeadLetterReason::Nak => write!(f, "nak"),
+        }
+    }
+}
+
+/// A message which could not be delivered, with the failure details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    pub payload: Vec<u8>,
+    pub reason: DeadLetterReason,
+    /// Receiver the last delivery attempt was made to.
+    pub peer_addr: SocketAddr,
+    pub time: SystemTime,
+    /// Transmissions of the message, the first one included, to all the receivers tried.
+    pub attempts: u32,
+    /// Metadata the message was [spooled](crate::spool::Spool::push_with_metadata) with.
+    pub metadata: Metadata,
+}
+
+/// Destination of dead letters.
+pub trait DeadLetterSink: Send + Sync {
+    /// Stores `letter`. An error means the message was not stored, and is still the caller's
+    /// responsibility.
+    fn dead_letter(&self, letter: &DeadLetter) -> io::Result<()>;
+}
+
+impl<S: DeadLetterSink + ?Sized> DeadLetterSink for Arc<S> {
+    fn dead_letter(&self, letter: &DeadLetter) -> io::Result<()> {
+        (**self).dead_letter(letter)
+    }
+}
+
+impl fmt::Debug for dyn DeadLetterSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeadLetterSink")
+    }
+}
+
+/// Sink writing each dead letter as two files in a directory: `<name>.hl7` holding the payload,
+/// and `<name>.json` holding the failure details, and the metadata of the message if it has
+/// any. `<name>` starts with the time of the failure, so a directory listing shows the dead
+/// letters in order.
+#[derive(Debug)]
+pub struct DirectoryDeadLetterSink {
+    dir: PathBuf,
+    written: AtomicU64,
+}
+
+impl DirectoryDeadLetterSink {
+    /// Creates a sink writing to `dir`, creating the directory if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        Ok(DirectoryDeadLetterSink {
+            dir,
+            written: AtomicU64::new(0),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl DeadLetterSink for DirectoryDeadLetterSink {
+    fn dead_letter(&self, letter: &DeadLetter) -> io::Result<()> {
+        let millis = letter.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let sequence = self.written.fetch_add(1, Ordering::Relaxed) + 1;
+        let name = format!("{}-{:06}", millis, sequence);
+
+        let mut payload = File::create(self.dir.join(format!("{}.hl7", name)))?;
+        payload.write_all(&letter.payload)?;
+        payload.sync_all()?;
+
+        let mut details = format!(
+            "{{\"time\":\"{}\",\"reason\":\"{}\",\"peer_addr\":{},\"attempts\":{},\"bytes\":{}",
+            format_rfc3339(letter.time),
+            letter.reason,
+            json_string(&letter.peer_addr.to_string()),
+            letter.attempts,
+            letter.payload.len()
+        );
+        if !letter.metadata.is_empty() {
+            let fields: Vec<_> = letter
+                .metadata
+                .iter()
+                .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+                .collect();
+            details.push_str(&format!(",\"metadata\":{{{}}}", fields.join(",")));
+        }
+        details.push_str("}\n");
+        let mut file = File::create(self.dir.join(format!("{}.json", name)))?;
+        file.write_all(details.as_bytes())?;
+        file.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::dead_letter::{DeadLetter, DeadLetterReason, DeadLetterSink, DirectoryDeadLetterSink};
+    use crate::spool::Metadata;
+
+    #[test]
+    fn it_writes_payload_and_details() {
+        let dir = env::temp_dir().join(format!("mllp-rs-dead-letter-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let sink = DirectoryDeadLetterSink::new(&dir).unwrap();
+
+        sink.dead_letter(&DeadLetter {
+            payload: b"MSH|1".to_vec(),
+            reason: DeadLetterReason::Nak,
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 2575)),
+            time: UNIX_EPOCH + Duration::from_millis(1500),
+            attempts: 4,
+            metadata: Metadata::new(),
+        })
+        .unwrap();
+        sink.dead_letter(&DeadLetter {
+            payload: b"MSH|2".to_vec(),
+            reason: DeadLetterReason::AckTimeout,
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 2575)),
+            time: UNIX_EPOCH + Duration::from_millis(1500),
+            attempts: 1,
+            metadata: Metadata::from([("tenant".to_owned(), "north".to_owned()), ("order".to_owned(), "A-42".to_owned())]),
+        })
+        .unwrap();
+
+        assert_eq!(fs::read(dir.join("1500-000001.hl7")).unwrap(), b"MSH|1");
+        assert_eq!(
+            fs::read_to_string(dir.join("1500-000001.json")).unwrap(),
+            "{\"time\":\"1970-01-01T00:00:01.500Z\",\"reason\":\"nak\",\"peer_addr\":\"127.0.0.1:2575\",\"attempts\":4,\"bytes\":5}\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("1500-000002.json")).unwrap(),
+            "{\"time\":\"1970-01-01T00:00:01.500Z\",\"reason\":\"ack_timeout\",\"peer_addr\":\"127.0.0.1:2575\",\"attempts\":1,\"bytes\":5,\"metadata\":{\"order\":\"A-42\",\"tenant\":\"north\"}}\n"
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+}
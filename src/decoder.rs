@@ -0,0 +1,322 @@
+//! Streaming decoder, turning a byte stream into MLLP frames.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Read};
+#[cfg(feature = "std")]
+use crate::MllpError;
+use crate::{LowerLayerCodec, MllpCodec, MllpSyntaxError, CR, EB, SB};
+
+/// Incremental MLLP decoder, or decoder of the framing of another [`LowerLayerCodec`].
+///
+/// Bytes are fed as they come off the wire with [`MllpDecoder::extend`], and complete frames are
+/// taken out with [`MllpDecoder::next_frame`]. Bytes of an incomplete frame are kept until the
+/// rest of it arrives.
+/// ```
+/// use mllp_rs::MllpDecoder;
+///
+/// let mut decoder = MllpDecoder::new();
+/// decoder.extend(b"\x0bMSH|^~\\&|");
+/// assert!(decoder.next_frame().is_none());
+///
+/// decoder.extend(b"\x1c\x0d");
+/// assert_eq!(decoder.next_frame().unwrap().unwrap(), b"MSH|^~\\&|");
+/// ```
+#[derive(Debug, Default)]
+pub struct MllpDecoder {
+    buf: Vec<u8>,
+    /// MLLP if `None`.
+    codec: Option<Arc<dyn LowerLayerCodec>>,
+    max_frame_size: Option<usize>,
+    /// The rest of a frame over the maximum size is being discarded.
+    oversized: bool,
+    /// Bytes of the MLLP frame at the start of the buffer already searched for its end, so that
+    /// a large frame arriving in chunks is not searched again from the start with each one.
+    scanned: usize,
+}
+
+impl MllpDecoder {
+    pub fn new() -> Self {
+        MllpDecoder::default()
+    }
+
+    /// Decoder of frames of at most `max` bytes, `<SB>`, `<EB>` and `<CR>` included. A larger
+    /// frame is discarded, as soon as it is known to be larger, and reported as a
+    /// [`MllpSyntaxError`], so that a peer cannot make the buffer grow without bound.
+    pub fn with_max_frame_size(max: usize) -> Self {
+        MllpDecoder {
+            max_frame_size: Some(max),
+            ..MllpDecoder::default()
+        }
+    }
+
+    /// Decoder of the frames of `codec`.
+    pub fn with_codec(codec: Arc<dyn LowerLayerCodec>) -> Self {
+        MllpDecoder {
+            codec: Some(codec),
+            ..MllpDecoder::default()
+        }
+    }
+
+    /// Decoder of the frames of `codec`, MLLP if `None`, of at most `max_frame_size` bytes.
+    #[cfg(feature = "std")]
+    pub(crate) fn framed(codec: Option<Arc<dyn LowerLayerCodec>>, max_frame_size: Option<usize>) -> Self {
+        MllpDecoder {
+            codec,
+            max_frame_size,
+            ..MllpDecoder::default()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn set_max_frame_size(&mut self, max_frame_size: Option<usize>) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Appends bytes received from the peer.
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Number of buffered bytes not yet returned as a frame.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Discards the bytes of the incomplete frame buffered, as when the connection is closed, and
+    /// returns how many there were.
+    pub fn clear(&mut self) -> usize {
+        let partial = self.buf.len();
+        self.buf.clear();
+        self.oversized = false;
+        self.scanned = 0;
+        partial
+    }
+
+    /// Takes the next complete frame out of the buffer and returns its payload.
+    ///
+    /// Returns `None` if more bytes are needed. If the buffer does not start with `<SB>`, the
+    /// bytes up to the next `<SB>` are discarded and a [`MllpSyntaxError`] is returned.
+    pub fn next_frame(&mut self) -> Option<Result<Vec<u8>, MllpSyntaxError>> {
+        self.take_frame(None)
+    }
+
+    /// Same as [`MllpDecoder::next_frame`], appending the bytes taken out of the buffer to `raw`,
+    /// as they were received: the frame, or the bytes discarded as not being one.
+    #[cfg(feature = "std")]
+    pub(crate) fn next_raw_frame(&mut self, raw: &mut Vec<u8>) -> Option<Result<Vec<u8>, MllpSyntaxError>> {
+        self.take_frame(Some(raw))
+    }
+
+    fn take_frame(&mut self, mut raw: Option<&mut Vec<u8>>) -> Option<Result<Vec<u8>, MllpSyntaxError>> {
+        let codec = self.codec.as_deref().unwrap_or(&MllpCodec {});
+        // bytes up to the start of the next frame, the one at the start of the buffer excluded
+        let next_start = |buf: &[u8]| buf.get(1..).and_then(|rest| codec.frame_start(rest)).map_or(buf.len(), |start| start + 1);
+
+        if self.oversized {
+            match codec.frame_start(&self.buf) {
+                Some(start) => {
+                    take(&mut self.buf, start, raw.as_deref_mut());
+                    self.oversized = false;
+                    self.scanned = 0;
+                }
+                None => {
+                    let len = self.buf.len();
+                    take(&mut self.buf, len, raw);
+                    return None;
+                }
+            }
+        }
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        let too_large = |size: usize| self.max_frame_size.is_some_and(|max| size > max);
+        if self.codec.is_none() && self.buf[0] == SB && !too_large(self.buf.len()) {
+            // the <EB> of the bytes scanned before may be followed by a <CR> received since
+            let from = self.scanned.saturating_sub(1);
+            let ended = self.buf[from..].windows(2).any(|w| w == [EB, CR]);
+            self.scanned = self.buf.len();
+            if !ended {
+                return None;
+            }
+        }
+        let (payload, len) = match codec.decode_first(&self.buf) {
+            Ok(Some((_, rest))) if too_large(self.buf.len() - rest.len()) => (None, self.buf.len() - rest.len()),
+            Ok(Some((payload, rest))) => (Some(payload.to_vec()), self.buf.len() - rest.len()),
+            Ok(None) if too_large(self.buf.len()) => {
+                let len = next_start(&self.buf);
+                self.oversized = len == self.buf.len();
+                (None, len)
+            }
+            Ok(None) => return None,
+            Err(_) => (None, next_start(&self.buf)),
+        };
+        take(&mut self.buf, len, raw);
+        self.scanned = 0;
+
+        Some(payload.ok_or(MllpSyntaxError))
+    }
+
+    /// Takes the complete frames out of the buffer, as [`MllpDecoder::next_frame`] does.
+    ///
+    /// Convenient where bytes come in chunks of their own, as the messages of a WebSocket in a
+    /// browser do:
+    /// ```
+    /// use mllp_rs::MllpDecoder;
+    ///
+    /// let mut decoder = MllpDecoder::new();
+    /// decoder.extend(b"\x0bMSH|^~\\&|LAB\x1c\x0d\x0bMSH|^~");
+    /// let frames: Vec<_> = decoder.frames().collect();
+    /// assert_eq!(frames.len(), 1);
+    /// assert_eq!(decoder.buffered(), 7);
+    /// ```
+    pub fn frames(&mut self) -> Frames<'_> {
+        Frames { decoder: self }
+    }
+
+    /// Reads from `reader` until a complete frame is available, and returns its payload.
+    ///
+    /// An end of stream before the frame is complete is reported as a
+    /// [`MllpError::ConnectionClosed`] error, rather than a truncated payload, and the bytes of the
+    /// incomplete frame are discarded.
+    #[cfg(feature = "std")]
+    pub fn read_frame<R: Read>(&mut self, reader: &mut R) -> Result<Vec<u8>, MllpError> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            if let Some(frame) = self.next_frame() {
+                return Ok(frame?);
+            }
+
+            match reader.read(&mut chunk) {
+                Ok(0) => return Err(MllpError::ConnectionClosed { partial_bytes: self.clear() }),
+                Ok(n) => self.extend(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Takes the first `len` bytes out of `buf`, appending them to `raw` if any.
+fn take(buf: &mut Vec<u8>, len: usize, raw: Option<&mut Vec<u8>>) {
+    let taken = buf.drain(..len);
+    if let Some(raw) = raw {
+        raw.extend(taken);
+    }
+}
+
+/// Iterator over the complete frames of an [`MllpDecoder`], returned by [`MllpDecoder::frames`].
+#[derive(Debug)]
+pub struct Frames<'a> {
+    decoder: &'a mut MllpDecoder,
+}
+
+impl Iterator for Frames<'_> {
+    type Item = Result<Vec<u8>, MllpSyntaxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.decoder.next_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "std")]
+    use crate::MllpError;
+    use crate::{MllpCodec, MllpDecoder};
+
+    #[test]
+    fn it_decodes_frames_split_across_reads() {
+        let mut decoder = MllpDecoder::new();
+        let mut bytes = MllpCodec::encode(b"first");
+        bytes.extend(MllpCodec::encode(b"second"));
+
+        decoder.extend(&bytes[..4]);
+        assert!(decoder.next_frame().is_none());
+        decoder.extend(&bytes[4..]);
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"first");
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"second");
+        assert!(decoder.next_frame().is_none());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn it_decodes_large_frames_in_chunks() {
+        let payload = [b'x'; 1 << 20];
+        let mut bytes = MllpCodec::encode(&payload);
+        bytes.extend(MllpCodec::encode(b"next"));
+        let mut decoder = MllpDecoder::new();
+
+        // the <EB> and the <CR> of the large frame in two chunks
+        let (large, next) = bytes.split_at(payload.len() + 2);
+        for chunk in large.chunks(4096) {
+            decoder.extend(chunk);
+            assert!(decoder.next_frame().is_none());
+        }
+        decoder.extend(next);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), payload);
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"next");
+    }
+
+    #[test]
+    fn it_skips_bytes_before_start_block() {
+        let mut decoder = MllpDecoder::new();
+        decoder.extend(b"junk");
+        decoder.extend(&MllpCodec::encode(b"message"));
+
+        assert!(decoder.next_frame().unwrap().is_err());
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"message");
+    }
+
+    #[test]
+    fn it_discards_frames_over_the_maximum_size() {
+        let mut decoder = MllpDecoder::with_max_frame_size(8);
+        decoder.extend(&MllpCodec::encode(b"long message"));
+        decoder.extend(&MllpCodec::encode(b"short"));
+        assert!(decoder.next_frame().unwrap().is_err());
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"short");
+
+        // discarded as soon as it is too large, up to its end
+        let frame = MllpCodec::encode(b"long message");
+        decoder.extend(&frame[..10]);
+        assert!(decoder.next_frame().unwrap().is_err());
+        decoder.extend(&frame[10..14]);
+        assert!(decoder.next_frame().is_none());
+        decoder.extend(&frame[14..]);
+        decoder.extend(&MllpCodec::encode(b"short"));
+        assert_eq!(decoder.next_frame().unwrap().unwrap(), b"short");
+        assert!(decoder.next_frame().is_none());
+    }
+
+    #[test]
+    fn it_iterates_over_complete_frames() {
+        let mut decoder = MllpDecoder::new();
+        decoder.extend(b"junk");
+        decoder.extend(&MllpCodec::encode(b"first"));
+        decoder.extend(&MllpCodec::ack()[..2]);
+
+        let frames: Vec<_> = decoder.frames().collect();
+        assert!(frames[0].is_err());
+        assert_eq!(frames[1].as_deref().unwrap(), b"first");
+        assert_eq!(frames.len(), 2);
+
+        decoder.extend(&MllpCodec::ack()[2..]);
+        assert!(MllpCodec::is_ack(&MllpCodec::encode(&decoder.frames().next().unwrap().unwrap())));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn it_reports_eof_in_the_middle_of_a_frame() {
+        let mut decoder = MllpDecoder::new();
+        let mut reader: &[u8] = b"\x0bMSH";
+        assert!(matches!(decoder.read_frame(&mut reader), Err(MllpError::ConnectionClosed { partial_bytes: 4 })));
+        assert_eq!(decoder.buffered(), 0);
+
+        let mut reader: &[u8] = b"";
+        assert!(matches!(decoder.read_frame(&mut reader), Err(MllpError::ConnectionClosed { partial_bytes: 0 })));
+    }
+}
@@ -0,0 +1,221 @@
+//! Detection of retransmitted messages, by their control ID.
+//!
+//! A sender which gets no acknowledgement, because it was lost or late, sends the message again,
+//! and the receiver handles it twice: an order placed twice, a result filed twice. A
+//! [`Deduplicator`] set in
+//! [`MllpServerConfig::interceptors`](crate::server::MllpServerConfig::interceptors) records
+//! the MSH-10 control IDs of the messages handled recently, for each sending application, MSH-3,
+//! in a [`MessageLedger`]. A message seen already is answered with an `AA` acknowledgement,
+//! without reaching the handler.
+//! ```
+//! use std::sync::Arc;
+//! use mllp_rs::dedup::Deduplicator;
+//! use mllp_rs::server::MllpServerConfig;
+//!
+//! let config = MllpServerConfig {
+//!     interceptors: vec![Arc::new(Deduplicator::new(10_000))],
+//!     ..MllpServerConfig::default()
+//! };
+//! ```
+//!
+//! Messages without a control ID are always handled, and so are messages the handler refused,
+//! with a NAK or an `AE` or `AR` acknowledgement, since the sender is expected to send them
+//! again. A copy arriving while the first one is still being handled, on another connection, is
+//! answered with a NAK, so that it is sent again once the first one is answered.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use crate::handler::AckDecision;
+use crate::interceptor::{Interceptor, Next};
+use crate::ledger::{InMemoryLedger, MessageLedger};
+
+/// Interceptor answering the retransmissions of the messages recorded in its ledger.
+#[derive(Debug)]
+pub struct Deduplicator {
+    ledger: Box<dyn MessageLedger>,
+    /// IDs of the messages being handled.
+    handling: Mutex<HashSet<String>>,
+    duplicates: AtomicU64,
+}
+
+impl Deduplicator {
+    /// Keeps the last `capacity` messages handled in an [`InMemoryLedger`].
+    pub fn new(capacity: usize) -> Self {
+        Deduplicator::with_ledger(Box::new(InMemoryLedger::new(capacity)))
+    }
+
+    /// Keeps the messages handled in `ledger`, which receivers running as several instances
+    /// share.
+    pub fn with_ledger(ledger: Box<dyn MessageLedger>) -> Self {
+        Deduplicator {
+            ledger,
+            handling: Mutex::new(HashSet::new()),
+            duplicates: AtomicU64::new(0),
+        }
+    }
+
+    /// Number of retransmissions answered without reaching the handler.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    fn handling(&self) -> std::sync::MutexGuard<'_, HashSet<String>> {
+        self.handling.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Interceptor for Deduplicator {
+    fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+        let Some(id) = id(message) else {
+            return next(message);
+        };
+
+        if !self.handling().insert(id.clone()) {
+            return AckDecision::CommitNak;
+        }
+        match self.ledger.record(&id) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.handling().remove(&id);
+                self.duplicates.fetch_add(1, Ordering::Relaxed);
+                return AckDecision::ApplicationAck(crate::acknowledgement(message, "AA", None));
+            }
+            // sent again once the ledger is reachable, rather than risking a duplicate
+            Err(_) => {
+                self.handling().remove(&id);
+                return AckDecision::CommitNak;
+            }
+        }
+
+        let response = next(message);
+        if refused(&response) {
+            // the retransmission is then handled again, which is also the fallback of a ledger
+            // not reachable
+            let _ = self.ledger.forget(&id);
+        }
+        self.handling().remove(&id);
+
+        response
+    }
+}
+
+/// MSH-3 sending application and MSH-10 control ID of `message`, if it has a control ID.
+fn id(message: &[u8]) -> Option<String> {
+    let separator = *message.strip_prefix(b"MSH")?.first()?;
+    let segment = message.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let fields: Vec<&[u8]> = segment.split(|b| *b == separator).collect();
+    let control_id = fields.get(9).filter(|control_id| !control_id.is_empty())?;
+    let application = fields.get(2).copied().unwrap_or_default();
+
+    Some(format!("{}|{}", String::from_utf8_lossy(application), String::from_utf8_lossy(control_id)))
+}
+
+/// Whether `response` refuses the message: a NAK, or an `AE`, `AR`, `CE` or `CR` acknowledgement.
+fn refused(response: &AckDecision) -> bool {
+    match response {
+        AckDecision::CommitNak => true,
+        AckDecision::ApplicationAck(ack) => {
+            let code = ack
+                .split(|b| *b == b'\r' || *b == b'\n')
+                .find(|segment| segment.starts_with(b"MSA"))
+                .and_then(|msa| msa.get(4..6));
+            matches!(code, Some(b"AE" | b"AR" | b"CE" | b"CR"))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use crate::dedup::Deduplicator;
+    use crate::handler::AckDecision;
+    use crate::interceptor::Interceptor;
+    use crate::ledger::InMemoryLedger;
+
+    fn message(sender: &str, control_id: &str) -> Vec<u8> {
+        format!("MSH|^~\\&|{}|NORTH|LIS|SOUTH|20240131||ORM^O01|{}|P|2.5", sender, control_id).into_bytes()
+    }
+
+    /// MSA-1 and MSA-2 of an application acknowledgement.
+    fn acknowledged(response: AckDecision) -> String {
+        match response {
+            AckDecision::ApplicationAck(ack) => {
+                let ack = String::from_utf8(ack).unwrap();
+                let msa: Vec<&str> = ack.split('\r').nth(1).unwrap().split('|').collect();
+                format!("{}|{}", msa[1], msa[2])
+            }
+            response => panic!("{:?}", response),
+        }
+    }
+
+    #[test]
+    fn it_answers_retransmissions_without_the_handler() {
+        let dedup = Deduplicator::new(2);
+        let handled = Cell::new(0);
+        let handler = |message: &[u8]| {
+            handled.set(handled.get() + 1);
+            match message.ends_with(b"NAK|P|2.5") {
+                true => AckDecision::CommitNak,
+                false => AckDecision::ApplicationAck(b"MSH|^~\\&|LIS\rMSA|AA|ORD".to_vec()),
+            }
+        };
+
+        dedup.around(&message("EHR", "ORD1"), &handler);
+        assert_eq!(acknowledged(dedup.around(&message("EHR", "ORD1"), &handler)), "AA|ORD1");
+        assert_eq!(handled.get(), 1);
+        assert_eq!(dedup.duplicates(), 1);
+
+        // same control ID from another application, refused message, no control ID
+        dedup.around(&message("RIS", "ORD1"), &handler);
+        dedup.around(&message("EHR", "NAK"), &handler);
+        dedup.around(&message("EHR", "NAK"), &handler);
+        dedup.around(b"MSH|^~\\&|EHR", &handler);
+        dedup.around(b"MSH|^~\\&|EHR", &handler);
+        assert_eq!(handled.get(), 6);
+
+        // the oldest ones are forgotten first
+        dedup.around(&message("EHR", "ORD2"), &handler);
+        dedup.around(&message("EHR", "ORD3"), &handler);
+        dedup.around(&message("RIS", "ORD1"), &handler);
+        dedup.around(&message("EHR", "ORD3"), &handler);
+        assert_eq!(handled.get(), 9);
+        assert_eq!(dedup.duplicates(), 2);
+    }
+
+    #[test]
+    fn it_shares_the_window_between_receivers() {
+        let ledger = Arc::new(InMemoryLedger::new(10));
+        let north = Deduplicator::with_ledger(Box::new(ledger.clone()));
+        let south = Deduplicator::with_ledger(Box::new(ledger.clone()));
+        let handled = Cell::new(0);
+        let handler = |_: &[u8]| {
+            handled.set(handled.get() + 1);
+            AckDecision::CommitAck
+        };
+
+        north.around(&message("EHR", "ORD1"), &handler);
+        assert_eq!(acknowledged(south.around(&message("EHR", "ORD1"), &handler)), "AA|ORD1");
+        south.around(&message("EHR", "ORD2"), &handler);
+        assert_eq!(acknowledged(north.around(&message("EHR", "ORD2"), &handler)), "AA|ORD2");
+        assert_eq!(handled.get(), 2);
+        assert_eq!(ledger.len(), 2);
+    }
+
+    #[test]
+    fn it_handles_again_messages_refused() {
+        let dedup = Deduplicator::new(10);
+        let handled = Cell::new(0);
+        let handler = |_: &[u8]| {
+            handled.set(handled.get() + 1);
+            AckDecision::ApplicationAck(b"MSH|^~\\&|LIS\rMSA|AE|ORD1".to_vec())
+        };
+
+        dedup.around(&message("EHR", "ORD1"), &handler);
+        dedup.around(&message("EHR", "ORD1"), &handler);
+        assert_eq!(handled.get(), 2);
+        assert_eq!(dedup.duplicates(), 0);
+    }
+}
@@ -0,0 +1,448 @@
+//! Discovery of destinations through DNS SRV records.
+//!
+//! In dynamic environments (Kubernetes headless services, Consul DNS), receivers are published as
+//! SRV records such as `_hl7._tcp.lab.example.com`, instead of living at a fixed host and port.
+//! An [`SrvDestination`] looks such a name up, orders the targets by priority and weight as
+//! described in RFC 2782, and looks the name up again once the TTL of the records has expired.
+//! ```no_run
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use mllp_rs::client::{MllpClient, MllpClientConfig};
+//! use mllp_rs::discovery::{DnsResolver, SrvDestination};
+//!
+//! # fn main() -> Result<(), mllp_rs::MllpError> {
+//! let resolver = DnsResolver::from_system_config(Duration::from_secs(2))?;
+//! let destination = SrvDestination::new("_hl7._tcp.lab.example.com", Arc::new(resolver));
+//! let mut client = MllpClient::connect_srv(destination, MllpClientConfig::default())?;
+//! client.send(b"MSH|^~\\&|...")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::random;
+
+/// DNS resource record type of SRV records.
+const TYPE_SRV: u16 = 33;
+/// DNS class of Internet records.
+const CLASS_IN: u16 = 1;
+/// Port of DNS servers.
+const DNS_PORT: u16 = 53;
+/// When a lookup fails and earlier results are still used, how long to wait before looking up
+/// again.
+const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// A DNS SRV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    /// Targets with the lowest priority are used first.
+    pub priority: u16,
+    /// Relative share of the connections among the targets of a same priority.
+    pub weight: u16,
+    pub port: u16,
+    /// Host name of the target.
+    pub target: String,
+}
+
+/// Result of an SRV lookup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvLookup {
+    pub records: Vec<SrvRecord>,
+    /// How long the records may be cached.
+    pub ttl: Duration,
+}
+
+/// Source of SRV records.
+pub trait SrvResolver: Send + Sync {
+    /// Looks up the SRV records of `name`.
+    fn lookup_srv(&self, name: &str) -> io::Result<SrvLookup>;
+}
+
+impl<R: SrvResolver + ?Sized> SrvResolver for Arc<R> {
+    fn lookup_srv(&self, name: &str) -> io::Result<SrvLookup> {
+        (**self).lookup_srv(name)
+    }
+}
+
+impl fmt::Debug for dyn SrvResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SrvResolver")
+    }
+}
+
+/// Resolver sending SRV queries over UDP to DNS servers.
+///
+/// Servers are asked in order, each one being given `timeout` to answer. Truncated answers are
+/// used as they are, without retrying over TCP.
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    nameservers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl DnsResolver {
+    pub fn new(nameservers: Vec<SocketAddr>, timeout: Duration) -> Self {
+        DnsResolver { nameservers, timeout }
+    }
+
+    /// Creates a resolver asking the `nameserver`s of `/etc/resolv.conf`.
+    pub fn from_system_config(timeout: Duration) -> io::Result<Self> {
+        let nameservers = parse_resolv_conf(&fs::read_to_string("/etc/resolv.conf")?);
+        if nameservers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf"));
+        }
+
+        Ok(Self::new(nameservers, timeout))
+    }
+
+    pub fn nameservers(&self) -> &[SocketAddr] {
+        &self.nameservers
+    }
+
+    fn query(&self, nameserver: SocketAddr, name: &str) -> io::Result<SrvLookup> {
+        let bind_addr: IpAddr = match nameserver {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((bind_addr, 0))?;
+        socket.connect(nameserver)?;
+
+        let id = random() as u16;
+        socket.send(&build_query(id, name)?)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut response = [0u8; 4096];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"));
+            }
+            socket.set_read_timeout(Some(remaining))?;
+
+            let len = match socket.recv(&mut response) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out"));
+                }
+                Err(e) => return Err(e),
+            };
+            // answers to an earlier query are skipped
+            if len >= 2 && u16::from_be_bytes([response[0], response[1]]) == id {
+                return parse_response(&response[..len]);
+            }
+        }
+    }
+}
+
+impl SrvResolver for DnsResolver {
+    fn lookup_srv(&self, name: &str) -> io::Result<SrvLookup> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no nameserver configured");
+        for nameserver in &self.nameservers {
+            match self.query(*nameserver, name) {
+                Ok(lookup) => return Ok(lookup),
+                // the name does not exist, other servers would tell the same
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Destination published as SRV records.
+#[derive(Debug)]
+pub struct SrvDestination {
+    name: String,
+    resolver: Arc<dyn SrvResolver>,
+    endpoints: Vec<Vec<SocketAddr>>,
+    expires_at: Option<Instant>,
+}
+
+impl SrvDestination {
+    /// Creates a destination for the SRV records of `name`. Nothing is looked up until
+    /// [`SrvDestination::endpoints`] is called.
+    pub fn new<S: Into<String>>(name: S, resolver: Arc<dyn SrvResolver>) -> Self {
+        SrvDestination {
+            name: name.into(),
+            resolver,
+            endpoints: Vec::new(),
+            expires_at: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether the records must be looked up again.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_none_or(|expires_at| Instant::now() >= expires_at)
+    }
+
+    /// Returns the addresses of the targets, in order of preference, one entry per target.
+    ///
+    /// The records are looked up when they have expired. If that lookup fails, the targets found
+    /// by the previous lookup are returned, if any, so that a DNS outage does not stop the
+    /// delivery to receivers which are still up. The lookup is then tried again 10 seconds later.
+    pub fn endpoints(&mut self) -> io::Result<&[Vec<SocketAddr>]> {
+        if self.is_expired() {
+            match self.lookup() {
+                Ok((endpoints, ttl)) => {
+                    self.endpoints = endpoints;
+                    self.expires_at = Some(Instant::now() + ttl);
+                }
+                Err(e) if self.endpoints.is_empty() => return Err(e),
+                Err(_) => self.expires_at = Some(Instant::now() + RETRY_AFTER),
+            }
+        }
+
+        Ok(&self.endpoints)
+    }
+
+    fn lookup(&self) -> io::Result<(Vec<Vec<SocketAddr>>, Duration)> {
+        let lookup = self.resolver.lookup_srv(&self.name)?;
+
+        let mut last_error = None;
+        let mut endpoints = Vec::new();
+        for record in order_by_preference(lookup.records, &mut random) {
+            match (record.target.as_str(), record.port).to_socket_addrs() {
+                Ok(addrs) => endpoints.push(addrs.collect()),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        if endpoints.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no target for {}", self.name))
+            }));
+        }
+
+        Ok((endpoints, lookup.ttl))
+    }
+}
+
+/// Orders `records` as described in RFC 2782: by increasing priority and, within a priority, in
+/// a random order where each record has a chance to come first proportional to its weight.
+fn order_by_preference<F: FnMut() -> u64>(mut records: Vec<SrvRecord>, random: &mut F) -> Vec<SrvRecord> {
+    // a target of "." means that the service is not available
+    records.retain(|record| !record.target.is_empty() && record.target != ".");
+    // zero weights are placed first, so that they are only picked when drawing 0
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let same_priority = records.iter().take_while(|record| record.priority == priority).count();
+
+        let total: u64 = records[..same_priority].iter().map(|record| record.weight as u64).sum();
+        let draw = random() % (total + 1);
+        let mut running = 0;
+        let picked = records[..same_priority]
+            .iter()
+            .position(|record| {
+                running += record.weight as u64;
+                running >= draw
+            })
+            .unwrap_or(0);
+
+        ordered.push(records.remove(picked));
+    }
+
+    ordered
+}
+
+fn parse_resolv_conf(conf: &str) -> Vec<SocketAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("nameserver") {
+                return None;
+            }
+            // scoped IPv6 addresses (fe80::1%eth0) are not supported
+            let ip: IpAddr = words.next()?.parse().ok()?;
+            Some(SocketAddr::new(ip, DNS_PORT))
+        })
+        .collect()
+}
+
+fn build_query(id: u16, name: &str) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid DNS name {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+fn invalid_response() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid DNS response")
+}
+
+fn parse_response(response: &[u8]) -> io::Result<SrvLookup> {
+    let read_u16 = |pos: usize| -> io::Result<u16> {
+        response.get(pos..pos + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).ok_or_else(invalid_response)
+    };
+
+    let flags = read_u16(2)?;
+    match flags & 0x000f {
+        0 => {}
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "no such DNS name")),
+        rcode => return Err(io::Error::other(format!("DNS server answered with error {}", rcode))),
+    }
+    let questions = read_u16(4)?;
+    let answers = read_u16(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(response, pos)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = read_name(response, pos)?.1;
+        let record_type = read_u16(pos)?;
+        let record_ttl = (read_u16(pos + 4)? as u32) << 16 | read_u16(pos + 6)? as u32;
+        let data_len = read_u16(pos + 8)? as usize;
+        let data = pos + 10;
+        pos = data + data_len;
+
+        if record_type == TYPE_SRV {
+            records.push(SrvRecord {
+                priority: read_u16(data)?,
+                weight: read_u16(data + 2)?,
+                port: read_u16(data + 4)?,
+                target: read_name(response, data + 6)?.0,
+            });
+            ttl = ttl.min(record_ttl);
+        }
+    }
+
+    if records.is_empty() {
+        ttl = 0;
+    }
+    Ok(SrvLookup {
+        records,
+        ttl: Duration::from_secs(ttl as u64),
+    })
+}
+
+/// Reads the possibly compressed name at `pos`, returning it with the position after it.
+fn read_name(message: &[u8], mut pos: usize) -> io::Result<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // compression pointers must go backwards, this bounds the number of jumps
+    let mut jumps = 0;
+
+    loop {
+        let len = *message.get(pos).ok_or_else(invalid_response)? as usize;
+        match len {
+            0 => break,
+            len if len & 0xc0 == 0xc0 => {
+                let low = *message.get(pos + 1).ok_or_else(invalid_response)? as usize;
+                end.get_or_insert(pos + 2);
+                jumps += 1;
+                if jumps > message.len() {
+                    return Err(invalid_response());
+                }
+                pos = (len & 0x3f) << 8 | low;
+            }
+            len => {
+                let label = message.get(pos + 1..pos + 1 + len).ok_or_else(invalid_response)?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+    }
+
+    Ok((labels.join("."), end.unwrap_or(pos + 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use crate::discovery::{build_query, order_by_preference, parse_resolv_conf, parse_response, SrvRecord};
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 2575,
+            target: target.to_owned(),
+        }
+    }
+
+    #[test]
+    fn it_orders_records_by_priority_and_weight() {
+        let records = vec![record(20, 0, "backup"), record(10, 1, "light"), record(10, 9, "heavy")];
+
+        let mut draws = [5, 0, 0].into_iter();
+        let ordered = order_by_preference(records.clone(), &mut || draws.next().unwrap());
+        let targets: Vec<_> = ordered.iter().map(|record| record.target.as_str()).collect();
+        assert_eq!(targets, ["heavy", "light", "backup"]);
+
+        let mut draws = [1, 0, 0].into_iter();
+        let ordered = order_by_preference(records, &mut || draws.next().unwrap());
+        assert_eq!(ordered[0].target, "light");
+    }
+
+    #[test]
+    fn it_parses_srv_answers() {
+        let query = build_query(0x1234, "_hl7._tcp.lab").unwrap();
+        let mut response = query.clone();
+        // response flags, one answer
+        response[2..4].copy_from_slice(&[0x81, 0x80]);
+        response[6..8].copy_from_slice(&[0, 1]);
+        // name compressed as a pointer to the question, SRV, IN, TTL 300
+        response.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 1, 44]);
+        let mut data = vec![0, 10, 0, 5, 0x0a, 0x0f];
+        data.extend_from_slice(&[3, b'm', b'l', b'p', 0xc0, 22]);
+        response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        response.extend_from_slice(&data);
+
+        let lookup = parse_response(&response).unwrap();
+        assert_eq!(lookup.ttl, Duration::from_secs(300));
+        assert_eq!(lookup.records, vec![SrvRecord {
+            priority: 10,
+            weight: 5,
+            port: 2575,
+            target: "mlp.lab".to_owned(),
+        }]);
+    }
+
+    #[test]
+    fn it_reports_unknown_names() {
+        let mut response = build_query(1, "_hl7._tcp.lab").unwrap();
+        response[2..4].copy_from_slice(&[0x81, 0x83]);
+
+        assert_eq!(parse_response(&response).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn it_reads_nameservers() {
+        let conf = "# generated\nsearch svc.cluster.local\nnameserver 10.96.0.10\nnameserver ::1\n";
+
+        assert_eq!(parse_resolv_conf(conf), vec![
+            "10.96.0.10:53".parse::<SocketAddr>().unwrap(),
+            "[::1]:53".parse().unwrap(),
+        ]);
+    }
+}
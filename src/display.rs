@@ -0,0 +1,90 @@
+//! Readable rendering of frames, for logs and troubleshooting.
+
+use core::fmt;
+use crate::{ACK, CR, EB, NAK, SB};
+
+/// Line Feed, used by some senders as a segment separator.
+const LF: u8 = 10u8;
+
+/// Frame, or any bytes off the wire, rendered with its control characters spelled out.
+///
+/// `<SB>`, `<EB>`, `<CR>`, `<ACK>` and `<NAK>` are written by name, segment separators are
+/// followed by a new line, other control characters are written as `<0x..>` and bytes which are
+/// not UTF-8 as `\x..`. [`fmt::Debug`] renders the same as [`fmt::Display`], so that frames are
+/// readable in assertions too.
+/// ```
+/// use mllp_rs::{FrameDisplay, MllpCodec};
+///
+/// let frame = MllpCodec::encode(b"MSH|^~\\&|LAB\rPID|1");
+/// assert_eq!(FrameDisplay(&frame).to_string(), "<SB>MSH|^~\\&|LAB<CR>\nPID|1<EB><CR>");
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FrameDisplay<'a>(pub &'a [u8]);
+
+impl fmt::Display for FrameDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut previous = None;
+        // a segment separator is followed by a new line, after the LF of a CR LF pair
+        let mut separator = false;
+        for chunk in self.0.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                let byte = u8::try_from(c).ok();
+                if separator && byte != Some(LF) {
+                    f.write_str("\n")?;
+                }
+                separator = false;
+                match byte {
+                    Some(SB) => f.write_str("<SB>")?,
+                    Some(EB) => f.write_str("<EB>")?,
+                    Some(ACK) => f.write_str("<ACK>")?,
+                    Some(NAK) => f.write_str("<NAK>")?,
+                    // the CR ending a frame is no segment separator
+                    Some(CR) if previous == Some(EB) => f.write_str("<CR>")?,
+                    Some(CR) => {
+                        f.write_str("<CR>")?;
+                        separator = true;
+                    }
+                    Some(LF) => f.write_str("<LF>\n")?,
+                    _ if c.is_control() => write!(f, "<0x{:02x}>", c as u32)?,
+                    _ => write!(f, "{}", c)?,
+                }
+                previous = byte;
+            }
+            for byte in chunk.invalid() {
+                if separator {
+                    f.write_str("\n")?;
+                    separator = false;
+                }
+                write!(f, "\\x{:02x}", byte)?;
+                previous = Some(*byte);
+            }
+        }
+        if separator {
+            f.write_str("\n")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for FrameDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FrameDisplay, MllpCodec};
+
+    #[test]
+    fn it_spells_out_control_characters() {
+        assert_eq!(FrameDisplay(&MllpCodec::ack()).to_string(), "<SB><ACK><EB><CR>");
+        assert_eq!(FrameDisplay(&MllpCodec::nak()).to_string(), "<SB><NAK><EB><CR>");
+        assert_eq!(
+            format!("{:?}", FrameDisplay(b"\x0bMSH|1\r\nPID|\x07\xff\x1c\r")),
+            "<SB>MSH|1<CR><LF>\nPID|<0x07>\\xff<EB><CR>"
+        );
+        assert_eq!(FrameDisplay("é".as_bytes()).to_string(), "é");
+    }
+}
@@ -0,0 +1,249 @@
+//! Links on which both sides send messages over the same connection.
+//!
+//! On such links, the frames received are a mix of messages sent by the peer and
+//! acknowledgements of the messages sent to it. [`DuplexLink`] reads them on a thread of its own
+//! and tells them apart: commit ACKs and NAKs, and application acknowledgements, which hold an
+//! MSA segment, go to the message waiting for them, and the other frames to the handler, whose
+//! answer is written back.
+//! ```no_run
+//! use mllp_rs::client::MllpClient;
+//! use mllp_rs::duplex::DuplexLink;
+//! use mllp_rs::handler::AckDecision;
+//!
+//! # fn main() -> Result<(), mllp_rs::MllpError> {
+//! let client = MllpClient::connect("127.0.0.1:5000")?;
+//! let link = DuplexLink::new(client, |admission: &[u8]| {
+//!     println!("{}", String::from_utf8_lossy(admission));
+//!     AckDecision::CommitAck
+//! })?;
+//! link.send(b"MSH|^~\\&|EHR|||||ADT^A08|42|P|2.5")?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::client::{Ack, MllpClient, MllpReader, MllpWriter};
+use crate::handler::{AckDecision, MllpHandler};
+use crate::{MllpError, ACK, NAK};
+
+/// Message sent and waiting for its acknowledgement: its number, its control ID, and where to
+/// send its outcome.
+type Pending = (u64, Option<String>, mpsc::Sender<Result<Ack, MllpError>>);
+
+/// Messages waiting for their acknowledgements.
+#[derive(Debug, Default)]
+struct Tracker {
+    state: Mutex<TrackerState>,
+    next: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    /// Oldest first.
+    pending: VecDeque<Pending>,
+    /// The connection was closed, nothing is to be received any more.
+    closed: bool,
+}
+
+impl Tracker {
+    /// Hands `frame`, an acknowledgement, to the message it answers: the one whose control ID
+    /// is in its MSA-2, or else the oldest one.
+    fn acknowledge(&self, frame: Vec<u8>) {
+        let acknowledged = match frame.as_slice() {
+            [ACK] | [NAK] => None,
+            frame => crate::acknowledged_id(frame),
+        };
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let index = state.pending.iter().position(|(_, control_id, _)| acknowledged.is_some() && *control_id == acknowledged).unwrap_or(0);
+        let Some((_, _, reply)) = state.pending.remove(index) else {
+            return;
+        };
+        let outcome = match frame.as_slice() {
+            [ACK] => Ok(Ack::Commit),
+            [NAK] => Err(MllpError::Nak),
+            _ => Ok(Ack::Application(frame)),
+        };
+        // the sender may have stopped waiting
+        let _ = reply.send(outcome);
+    }
+
+    /// Waits for the acknowledgement of the message `control_id`, unless the connection is
+    /// closed. Returns its number.
+    fn wait(&self, control_id: Option<String>, reply: mpsc::Sender<Result<Ack, MllpError>>) -> Option<u64> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.closed {
+            return None;
+        }
+        let number = self.next.fetch_add(1, Ordering::Relaxed);
+        state.pending.push_back((number, control_id, reply));
+        Some(number)
+    }
+
+    /// Fails every message waiting, the connection being closed.
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.closed = true;
+        // dropping the senders fails the messages waiting
+        state.pending.clear();
+    }
+
+    fn forget(&self, number: u64) {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).pending.retain(|(pending, _, _)| *pending != number);
+    }
+}
+
+/// Connection on which both sides send messages, see the [module documentation](self).
+///
+/// Messages are sent from any thread with [`DuplexLink::send`], which waits for the
+/// acknowledgement up to the [`ack_timeout`](crate::client::MllpClientConfig::ack_timeout) of
+/// the client, without retrying. Several messages may wait at once.
+pub struct DuplexLink {
+    writer: Arc<Mutex<MllpWriter>>,
+    tracker: Arc<Tracker>,
+    ack_timeout: Option<Duration>,
+    peer_addr: SocketAddr,
+}
+
+impl DuplexLink {
+    /// Splits `client` and starts reading, the messages received going to `handler`.
+    ///
+    /// Fails for connections which cannot be split, see [`MllpClient::into_split`].
+    pub fn new<H: MllpHandler + 'static>(client: MllpClient, handler: H) -> io::Result<Self> {
+        let ack_timeout = client.config().ack_timeout;
+        let peer_addr = client.peer_addr();
+        let (reader, writer) = client.into_split()?;
+        let writer = Arc::new(Mutex::new(writer));
+        let tracker = Arc::new(Tracker::default());
+
+        let receiving = (writer.clone(), tracker.clone());
+        thread::Builder::new()
+            .name("mllp-duplex".to_owned())
+            .spawn(move || receive(reader, handler, receiving.0, receiving.1))?;
+
+        Ok(DuplexLink { writer, tracker, ack_timeout, peer_addr })
+    }
+
+    /// Sends `payload` and waits for its acknowledgement, a NAK being a [`MllpError::Nak`]
+    /// error. The messages received meanwhile are handled.
+    pub fn send(&self, payload: &[u8]) -> Result<Ack, MllpError> {
+        let (reply, outcome) = mpsc::channel();
+        let number = {
+            let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            // waiting before the frame is written, for an acknowledgement quicker than this thread
+            let Some(number) = self.tracker.wait(crate::control_id(payload), reply) else {
+                return Err(MllpError::ConnectionClosed { partial_bytes: 0 });
+            };
+            if let Err(e) = writer.write_frame(payload) {
+                self.tracker.forget(number);
+                return Err(e);
+            }
+            number
+        };
+
+        let received = match self.ack_timeout {
+            Some(timeout) => outcome.recv_timeout(timeout),
+            None => outcome.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(outcome) => outcome,
+            Err(RecvTimeoutError::Timeout) => {
+                self.tracker.forget(number);
+                Err(MllpError::AckTimeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(MllpError::ConnectionClosed { partial_bytes: 0 }),
+        }
+    }
+
+    /// Number of messages waiting for their acknowledgement.
+    pub fn pending(&self) -> usize {
+        self.tracker.state.lock().unwrap_or_else(|e| e.into_inner()).pending.len()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Shuts down writing. The link ends once the peer closes the connection in turn.
+    pub fn close(&self) -> io::Result<()> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner()).shutdown()
+    }
+}
+
+/// Reads the frames of `reader` until the connection is closed, acknowledgements going to
+/// `tracker` and messages to `handler`.
+fn receive<H: MllpHandler>(mut reader: MllpReader, handler: H, writer: Arc<Mutex<MllpWriter>>, tracker: Arc<Tracker>) {
+    loop {
+        let frame = match reader.read_frame() {
+            Ok(frame) => frame,
+            // an invalid frame is skipped, the next one may be fine
+            Err(MllpError::Syntax(_)) => continue,
+            Err(_) => break,
+        };
+        if matches!(frame.as_slice(), [ACK] | [NAK]) || crate::acknowledged_id(&frame).is_some() {
+            tracker.acknowledge(frame);
+            continue;
+        }
+
+        let answer = match handler.on_message(&frame) {
+            AckDecision::CommitAck => vec![ACK],
+            AckDecision::CommitNak => vec![NAK],
+            AckDecision::ApplicationAck(payload) => payload,
+            AckDecision::None => continue,
+        };
+        if writer.lock().unwrap_or_else(|e| e.into_inner()).write_frame(&answer).is_err() {
+            break;
+        }
+    }
+    tracker.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::client::{Ack, MllpClient};
+    use crate::duplex::DuplexLink;
+    use crate::handler::AckDecision;
+    use crate::{MllpCodec, MllpDecoder, MllpError, ACK};
+
+    #[test]
+    fn it_routes_acknowledgements_and_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            let first = decoder.read_frame(&mut stream).unwrap();
+            // a message of its own before acknowledging
+            stream.write_all(&MllpCodec::encode(b"MSH|^~\\&|ADT|||||ADT^A01|A1|P|2.5")).unwrap();
+            let answer = decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::encode(b"MSH|^~\\&|ADT|||||ACK|A2|P|2.5\rMSA|AA|E1")).unwrap();
+            let second = decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::nak()).unwrap();
+            (first, answer, second)
+        });
+
+        let link = DuplexLink::new(MllpClient::connect(addr).unwrap(), |message: &[u8]| {
+            assert!(message.ends_with(b"ADT^A01|A1|P|2.5"));
+            AckDecision::CommitAck
+        })
+        .unwrap();
+        let ack = link.send(b"MSH|^~\\&|EHR|||||ADT^A08|E1|P|2.5").unwrap();
+        assert_eq!(ack, Ack::Application(b"MSH|^~\\&|ADT|||||ACK|A2|P|2.5\rMSA|AA|E1".to_vec()));
+        assert!(matches!(link.send(b"MSH|^~\\&|EHR|||||ADT^A08|E2|P|2.5"), Err(MllpError::Nak)));
+        assert_eq!(link.pending(), 0);
+
+        let (first, answer, second) = peer.join().unwrap();
+        assert_eq!((first.as_slice(), answer.as_slice()), (&b"MSH|^~\\&|EHR|||||ADT^A08|E1|P|2.5"[..], &[ACK][..]));
+        assert!(second.ends_with(b"|E2|P|2.5"));
+        assert!(matches!(link.send(b"MSH|^~\\&|EHR|||||ADT^A08|E3|P|2.5"), Err(MllpError::ConnectionClosed { .. }) | Err(MllpError::Io(_))));
+    }
+}
@@ -0,0 +1,108 @@
+use std::{fmt, io};
+use crate::commit::ProtocolViolation;
+use crate::MllpSyntaxError;
+
+/// Errors returned by the MLLP client and server.
+#[derive(Debug)]
+pub enum MllpError {
+    /// The underlying connection failed.
+    Io(io::Error),
+    /// The peer sent bytes that are not a valid MLLP frame.
+    Syntax(MllpSyntaxError),
+    /// No acknowledgement was received within the configured timeout, and all retries were used.
+    AckTimeout,
+    /// The receiver answered with a NAK, and all retries were used.
+    Nak,
+    /// The peer or the caller broke the rules of commit acknowledgement.
+    Protocol(ProtocolViolation),
+    /// A timeout of the connection expired, which was then not used any further.
+    Timeout(Timeout),
+    /// The peer closed the connection before a frame was received, `partial_bytes` of an
+    /// incomplete frame being discarded, 0 if it closed between frames.
+    ConnectionClosed { partial_bytes: usize },
+}
+
+/// Timeouts of a connection, besides the acknowledgement timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Timeout {
+    /// The connection could not be established in time.
+    Connect,
+    /// The peer sent nothing in time: after a message, on the client, or after the connection
+    /// was accepted, on the server.
+    FirstByte,
+    /// The peer stopped sending in the middle of a frame.
+    InterByte,
+    /// The peer did not read what was written in time.
+    Write,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timeout::Connect => write!(f, "Timed out connecting"),
+            Timeout::FirstByte => write!(f, "Timed out waiting for the first byte"),
+            Timeout::InterByte => write!(f, "Timed out in the middle of a frame"),
+            Timeout::Write => write!(f, "Timed out writing"),
+        }
+    }
+}
+
+impl std::error::Error for Timeout { }
+
+impl From<Timeout> for io::Error {
+    /// An [`io::ErrorKind::TimedOut`] error, turned back into [`MllpError::Timeout`] by `into`.
+    fn from(timeout: Timeout) -> Self {
+        io::Error::new(io::ErrorKind::TimedOut, timeout)
+    }
+}
+
+impl fmt::Display for MllpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MllpError::Io(e) => write!(f, "I/O error: {}", e),
+            MllpError::Syntax(e) => write!(f, "Syntax error: {}", e),
+            MllpError::AckTimeout => write!(f, "Timed out waiting for an acknowledgement"),
+            MllpError::Nak => write!(f, "Message was negatively acknowledged"),
+            MllpError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            MllpError::Timeout(timeout) => write!(f, "{}", timeout),
+            MllpError::ConnectionClosed { partial_bytes: 0 } => write!(f, "Connection closed by the peer"),
+            MllpError::ConnectionClosed { partial_bytes } => {
+                write!(f, "Connection closed by the peer in the middle of a frame, {} bytes discarded", partial_bytes)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MllpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MllpError::Io(e) => Some(e),
+            MllpError::Syntax(e) => Some(e),
+            MllpError::Protocol(e) => Some(e),
+            MllpError::Timeout(timeout) => Some(timeout),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MllpError {
+    fn from(e: io::Error) -> Self {
+        match e.get_ref().and_then(|inner| inner.downcast_ref::<Timeout>()) {
+            Some(timeout) => MllpError::Timeout(*timeout),
+            None => MllpError::Io(e),
+        }
+    }
+}
+
+impl From<MllpSyntaxError> for MllpError {
+    fn from(e: MllpSyntaxError) -> Self {
+        MllpError::Syntax(e)
+    }
+}
+
+impl From<ProtocolViolation> for MllpError {
+    fn from(e: ProtocolViolation) -> Self {
+        MllpError::Protocol(e)
+    }
+}
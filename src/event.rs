@@ -0,0 +1,366 @@
+//! Protocol events reported by the client and the server.
+//!
+//! An [`EventSink`] set in [`MllpClientConfig::event_sink`](crate::client::MllpClientConfig) is
+//! told about everything happening on the connections: frames sent and received,
+//! acknowledgements, retransmissions, timeouts and errors. A sink set in
+//! [`MllpServerConfig::event_sink`](crate::server::MllpServerConfig) is told about the resource
+//! decisions of the server. [`Timeline`](crate::timeline::Timeline)
+//! is a sink recording these events to render them as a sequence diagram, and [`JsonLinesSink`]
+//! writes them as a JSON-lines stream, ready to be ingested by log collectors.
+
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::Timeout;
+
+/// Something that happened on a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum EventKind {
+    /// The connection was established.
+    Connected,
+    /// The connection was closed by this side.
+    Disconnected,
+    /// The connection was found closed by the peer, or failed.
+    ConnectionLost { message: String },
+    /// Reconnection attempt number `attempt`, starting at 1.
+    Reconnecting { attempt: u32 },
+    /// A message of `bytes` bytes (payload length) was sent.
+    MessageSent { bytes: usize },
+    /// A message is about to be retransmitted, `attempt` starting at 1.
+    Retry { attempt: u32 },
+    /// A commit ACK was received.
+    AckReceived,
+    /// A commit NAK was received.
+    NakReceived,
+    /// An application acknowledgement of `bytes` bytes was received.
+    ApplicationAckReceived { bytes: usize },
+    /// No acknowledgement was received in time.
+    AckTimeout,
+    /// The connection failed or the peer sent an invalid frame.
+    Error { message: String },
+    /// The server stopped accepting connections, `open_fds` descriptors being open. Both
+    /// addresses of the event are the listening address.
+    AcceptPaused { open_fds: usize },
+    /// The server accepts connections again after [`EventKind::AcceptPaused`].
+    AcceptResumed,
+    /// The server closed this connection right after accepting it, its peer not being
+    /// [allowed](crate::server::MllpServerConfig::allowed_peers).
+    ConnectionRejected,
+    /// The server is closing this idle connection to free its descriptor.
+    ConnectionShed,
+    /// The server is closing this connection, which sent no frame within the
+    /// [first frame timeout](crate::server::MllpServerConfig::first_frame_timeout).
+    FirstFrameTimeout,
+    /// A timeout of the connection other than the acknowledgement timeout expired, and the
+    /// connection is given up.
+    TimedOut { timeout: Timeout },
+    /// The peer closed the connection in the middle of a frame, whose `partial_bytes` received
+    /// were discarded.
+    ClosedMidFrame { partial_bytes: usize },
+}
+
+/// An [`EventKind`] with the time it happened and the connection it happened on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Event {
+    pub time: SystemTime,
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    pub kind: EventKind,
+}
+
+/// Receiver of protocol events.
+///
+/// Sinks are called synchronously on the connection's thread, so they should return quickly.
+pub trait EventSink: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl<S: EventSink + ?Sized> EventSink for Arc<S> {
+    fn on_event(&self, event: &Event) {
+        (**self).on_event(event)
+    }
+}
+
+impl fmt::Debug for dyn EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EventSink")
+    }
+}
+
+/// Sink writing each event as one line of JSON.
+///
+/// The writer can be a file, a socket or anything implementing [`Write`]; it is flushed after
+/// each event. Write errors are ignored, so that a failing log destination never disrupts the
+/// connections. A line looks like this:
+/// ```text
+/// {"time":"2026-10-14T19:00:38.123Z","local_addr":"10.0.0.2:50112","peer_addr":"10.0.0.9:2575","event":"message_sent","bytes":1234}
+/// ```
+/// ```no_run
+/// use std::sync::Arc;
+/// use mllp_rs::client::MllpClientConfig;
+/// use mllp_rs::event::JsonLinesSink;
+///
+/// # fn main() -> std::io::Result<()> {
+/// let config = MllpClientConfig {
+///     event_sink: Some(Arc::new(JsonLinesSink::append("/var/log/mllp/events.jsonl")?)),
+///     ..MllpClientConfig::default()
+/// };
+/// # Ok(())
+/// # }
+/// ```
+pub struct JsonLinesSink<W: Write + Send> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl JsonLinesSink<File> {
+    /// Opens `path` for appending, creating the file if needed.
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(OpenOptions::new().create(true).append(true).open(path)?))
+    }
+}
+
+impl<W: Write + Send> EventSink for JsonLinesSink<W> {
+    fn on_event(&self, event: &Event) {
+        let line = to_json_line(event);
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writer.write_all(line.as_bytes()).and_then(|_| writer.flush());
+    }
+}
+
+/// Serializes `event` as a JSON object followed by a newline.
+fn to_json_line(event: &Event) -> String {
+    let mut line = format!(
+        "{{\"time\":\"{}\",\"local_addr\":\"{}\",\"peer_addr\":\"{}\",\"event\":",
+        format_rfc3339(event.time),
+        event.local_addr,
+        event.peer_addr
+    );
+
+    let _ = match &event.kind {
+        EventKind::Connected => write!(line, "\"connected\""),
+        EventKind::Disconnected => write!(line, "\"disconnected\""),
+        EventKind::ConnectionLost { message } => {
+            write!(line, "\"connection_lost\",\"message\":{}", json_string(message))
+        }
+        EventKind::Reconnecting { attempt } => write!(line, "\"reconnecting\",\"attempt\":{}", attempt),
+        EventKind::MessageSent { bytes } => write!(line, "\"message_sent\",\"bytes\":{}", bytes),
+        EventKind::Retry { attempt } => write!(line, "\"retry\",\"attempt\":{}", attempt),
+        EventKind::AckReceived => write!(line, "\"ack_received\""),
+        EventKind::NakReceived => write!(line, "\"nak_received\""),
+        EventKind::ApplicationAckReceived { bytes } => {
+            write!(line, "\"application_ack_received\",\"bytes\":{}", bytes)
+        }
+        EventKind::AckTimeout => write!(line, "\"ack_timeout\""),
+        EventKind::Error { message } => write!(line, "\"error\",\"message\":{}", json_string(message)),
+        EventKind::AcceptPaused { open_fds } => write!(line, "\"accept_paused\",\"open_fds\":{}", open_fds),
+        EventKind::AcceptResumed => write!(line, "\"accept_resumed\""),
+        EventKind::ConnectionRejected => write!(line, "\"connection_rejected\""),
+        EventKind::ConnectionShed => write!(line, "\"connection_shed\""),
+        EventKind::FirstFrameTimeout => write!(line, "\"first_frame_timeout\""),
+        EventKind::TimedOut { timeout } => write!(line, "\"timed_out\",\"timeout\":\"{}\"", timeout_name(*timeout)),
+        EventKind::ClosedMidFrame { partial_bytes } => {
+            write!(line, "\"closed_mid_frame\",\"partial_bytes\":{}", partial_bytes)
+        }
+    };
+
+    line.push_str("}\n");
+    line
+}
+
+/// Name of `timeout` in the JSON lines.
+fn timeout_name(timeout: Timeout) -> &'static str {
+    match timeout {
+        Timeout::Connect => "connect",
+        Timeout::FirstByte => "first_byte",
+        Timeout::InterByte => "inter_byte",
+        Timeout::Write => "write",
+    }
+}
+
+/// `text` as a JSON string, quotes included. Shared with the command line tools, it is not part
+/// of the supported API.
+#[doc(hidden)]
+pub fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with millisecond precision.
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parses a UTC time such as `2024-01-31T08:00:00.250Z`, as formatted by [`format_rfc3339`], the
+/// fraction of a second being optional.
+#[cfg(feature = "archive")]
+pub(crate) fn parse_rfc3339(text: &str) -> Option<SystemTime> {
+    let (date, time) = text.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<u32>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(str::parse::<u64>);
+    let (hours, minutes, secs) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hours > 23 || minutes > 59 || secs > 60 {
+        return None;
+    }
+    let nanos = match fraction {
+        "" => 0,
+        fraction if fraction.len() <= 9 => fraction.parse::<u32>().ok()? * 10u32.pow(9 - fraction.len() as u32),
+        _ => return None,
+    };
+
+    let days = u64::try_from(days_from_civil(year as i64, month, day)).ok()?;
+    let secs = days * 86400 + hours * 3600 + minutes * 60 + secs;
+    Some(UNIX_EPOCH + std::time::Duration::new(secs, nanos))
+}
+
+/// Converts a (year, month, day) date to days since 1970-01-01, the inverse of [`civil_from_days`].
+#[cfg(feature = "archive")]
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date, using Howard Hinnant's algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, UNIX_EPOCH};
+    #[cfg(feature = "archive")]
+    use crate::event::parse_rfc3339;
+    use crate::event::{format_rfc3339, Event, EventKind, EventSink, JsonLinesSink};
+
+    #[test]
+    fn it_formats_timestamps() {
+        assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::from_millis(1_709_208_000_250)),
+            "2024-02-29T12:00:00.250Z"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn it_parses_timestamps() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_208_000_250);
+        assert_eq!(parse_rfc3339(&format_rfc3339(time)), Some(time));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+        assert_eq!(parse_rfc3339("2024-02-29T12:00:00+01:00"), None);
+        assert_eq!(parse_rfc3339("2024-13-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn it_writes_one_json_line_per_event() {
+        let sink = JsonLinesSink::new(Vec::new());
+        let event = |kind| Event {
+            time: UNIX_EPOCH,
+            local_addr: SocketAddr::from(([127, 0, 0, 1], 1000)),
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 2575)),
+            kind,
+        };
+        sink.on_event(&event(EventKind::MessageSent { bytes: 42 }));
+        sink.on_event(&event(EventKind::Error { message: "bad \"frame\"\n".to_owned() }));
+
+        let output = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"time":"1970-01-01T00:00:00.000Z","local_addr":"127.0.0.1:1000","peer_addr":"127.0.0.1:2575","event":"message_sent","bytes":42}"#
+        );
+        assert!(lines[1].ends_with(r#""event":"error","message":"bad \"frame\"\n"}"#));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn it_implements_serde() {
+        use serde::de::value::{Error, StrDeserializer};
+        use serde::de::{DeserializeOwned, IntoDeserializer};
+        use serde::{Deserialize, Serialize};
+        use crate::client::Ack;
+        use crate::handler::{AckDecision, ReceivedFrame};
+        use crate::spool::DeliveryStatus;
+        use crate::Timeout;
+
+        fn serde<T: Serialize + DeserializeOwned>() {}
+        serde::<Event>();
+        serde::<Ack>();
+        serde::<AckDecision>();
+        serde::<DeliveryStatus>();
+        fn serialize<T: Serialize>() {}
+        serialize::<ReceivedFrame<'_>>();
+
+        let name: StrDeserializer<'_, Error> = "InterByte".into_deserializer();
+        assert_eq!(Timeout::deserialize(name), Ok(Timeout::InterByte));
+        let name: StrDeserializer<'_, Error> = "Commit".into_deserializer();
+        assert_eq!(Ack::deserialize(name), Ok(Ack::Commit));
+    }
+}
@@ -0,0 +1,296 @@
+//! C bindings, with the `ffi` feature, for applications written in C or C++.
+//!
+//! The functions are declared in `include/mllp.h`. Build the library to link against with
+//! `cargo rustc --release --features ffi --crate-type staticlib`, or `cdylib` for a shared one.
+//!
+//! Every function returns [`MLLP_OK`] or another status code, and hands its results out through
+//! pointer arguments. Buffers returned by the library are released with [`mllp_buffer_free`],
+//! decoders with [`mllp_decoder_free`] and clients with [`mllp_client_free`]. A decoder or a
+//! client may be moved to another thread, but not used by two threads at once.
+//! ```c
+//! mllp_client *client;
+//! if (mllp_client_connect("127.0.0.1:2575", 10000, 0, &client) == MLLP_OK) {
+//!     uint8_t *ack;
+//!     size_t ack_len;
+//!     int status = mllp_client_send(client, message, message_len, &ack, &ack_len);
+//!     if (status == MLLP_OK && ack != NULL) {
+//!         /* application ACK in ack[0..ack_len] */
+//!         mllp_buffer_free(ack, ack_len);
+//!     }
+//!     mllp_client_free(client);
+//! }
+//! ```
+
+use std::ffi::{c_char, c_int, CStr};
+use std::ptr;
+use std::slice;
+use std::time::Duration;
+use crate::client::{Ack, MllpClient, MllpClientConfig};
+use crate::{MllpCodec, MllpDecoder, MllpError};
+
+/// Success. A decoder returned a frame, a client got an acknowledgement.
+pub const MLLP_OK: c_int = 0;
+/// The decoder needs more bytes for a complete frame.
+pub const MLLP_INCOMPLETE: c_int = 1;
+/// A null pointer, or an address which is not valid UTF-8.
+pub const MLLP_ERR_ARGUMENT: c_int = -1;
+/// The connection failed, or the address could not be resolved or reached.
+pub const MLLP_ERR_IO: c_int = -2;
+/// Bytes which are not a valid MLLP frame.
+pub const MLLP_ERR_SYNTAX: c_int = -3;
+/// No acknowledgement within the timeout, after all retries, or another timeout of the
+/// connection.
+pub const MLLP_ERR_TIMEOUT: c_int = -4;
+/// The receiver answered with a NAK, after all retries.
+pub const MLLP_ERR_NAK: c_int = -5;
+/// The peer broke the rules of commit acknowledgement.
+pub const MLLP_ERR_PROTOCOL: c_int = -6;
+
+/// Wraps `payload` in an MLLP frame, `<SB>payload<EB><CR>`.
+///
+/// # Safety
+///
+/// `payload` points to `len` readable bytes, or is null with a `len` of 0. `out` and `out_len`
+/// are valid for writes. The frame written to `out` is released with [`mllp_buffer_free`].
+#[no_mangle]
+pub unsafe extern "C" fn mllp_encode(payload: *const u8, len: usize, out: *mut *mut u8, out_len: *mut usize) -> c_int {
+    let Some(payload) = bytes(payload, len) else {
+        return MLLP_ERR_ARGUMENT;
+    };
+    if out.is_null() || out_len.is_null() {
+        return MLLP_ERR_ARGUMENT;
+    }
+
+    hand_out(MllpCodec::encode(payload), out, out_len);
+    MLLP_OK
+}
+
+/// Releases a buffer returned by the library.
+///
+/// # Safety
+///
+/// `buffer` and `len` were returned together by a function of this module, and the buffer was
+/// not released already. A null `buffer` is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn mllp_buffer_free(buffer: *mut u8, len: usize) {
+    if !buffer.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer, len)));
+    }
+}
+
+/// Creates a streaming decoder, released with [`mllp_decoder_free`].
+#[no_mangle]
+pub extern "C" fn mllp_decoder_new() -> *mut MllpDecoder {
+    Box::into_raw(Box::new(MllpDecoder::new()))
+}
+
+/// Appends bytes received from the peer to `decoder`.
+///
+/// # Safety
+///
+/// `decoder` was returned by [`mllp_decoder_new`] and not released. `data` points to `len`
+/// readable bytes, or is null with a `len` of 0.
+#[no_mangle]
+pub unsafe extern "C" fn mllp_decoder_extend(decoder: *mut MllpDecoder, data: *const u8, len: usize) -> c_int {
+    match (decoder.as_mut(), bytes(data, len)) {
+        (Some(decoder), Some(data)) => {
+            decoder.extend(data);
+            MLLP_OK
+        }
+        _ => MLLP_ERR_ARGUMENT,
+    }
+}
+
+/// Takes the next complete frame out of `decoder`, and writes its payload to `out`.
+///
+/// Returns [`MLLP_INCOMPLETE`] if more bytes are needed, and [`MLLP_ERR_SYNTAX`] if bytes were
+/// discarded before the start of a frame; the next call goes on with the following frame.
+///
+/// # Safety
+///
+/// `decoder` was returned by [`mllp_decoder_new`] and not released. `out` and `out_len` are
+/// valid for writes. The payload written to `out` is released with [`mllp_buffer_free`].
+#[no_mangle]
+pub unsafe extern "C" fn mllp_decoder_next_frame(decoder: *mut MllpDecoder, out: *mut *mut u8, out_len: *mut usize) -> c_int {
+    let Some(decoder) = decoder.as_mut() else {
+        return MLLP_ERR_ARGUMENT;
+    };
+    if out.is_null() || out_len.is_null() {
+        return MLLP_ERR_ARGUMENT;
+    }
+
+    match decoder.next_frame() {
+        Some(Ok(payload)) => {
+            hand_out(payload, out, out_len);
+            MLLP_OK
+        }
+        Some(Err(_)) => MLLP_ERR_SYNTAX,
+        None => MLLP_INCOMPLETE,
+    }
+}
+
+/// Releases a decoder and the bytes it still buffers.
+///
+/// # Safety
+///
+/// `decoder` was returned by [`mllp_decoder_new`] and not released already. A null `decoder` is
+/// ignored.
+#[no_mangle]
+pub unsafe extern "C" fn mllp_decoder_free(decoder: *mut MllpDecoder) {
+    if !decoder.is_null() {
+        drop(Box::from_raw(decoder));
+    }
+}
+
+/// Connects to `addr`, a `host:port` string, and writes the client to `client`.
+///
+/// Acknowledgements are waited for `ack_timeout_ms` milliseconds, or forever with 0, and a
+/// message is sent again up to `max_retries` times after a timeout or a NAK. The client is
+/// released with [`mllp_client_free`].
+///
+/// # Safety
+///
+/// `addr` is a null-terminated string. `client` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mllp_client_connect(addr: *const c_char, ack_timeout_ms: u64, max_retries: u32, client: *mut *mut MllpClient) -> c_int {
+    if addr.is_null() || client.is_null() {
+        return MLLP_ERR_ARGUMENT;
+    }
+    let Ok(addr) = CStr::from_ptr(addr).to_str() else {
+        return MLLP_ERR_ARGUMENT;
+    };
+
+    let config = MllpClientConfig {
+        ack_timeout: (ack_timeout_ms > 0).then(|| Duration::from_millis(ack_timeout_ms)),
+        max_retries,
+        ..MllpClientConfig::default()
+    };
+    match MllpClient::connect_with_config(addr, config) {
+        Ok(connected) => {
+            *client = Box::into_raw(Box::new(connected));
+            MLLP_OK
+        }
+        Err(_) => MLLP_ERR_IO,
+    }
+}
+
+/// Sends `payload` and waits for its acknowledgement.
+///
+/// On [`MLLP_OK`], `ack` is null for a commit ACK, and holds the payload of the acknowledgement
+/// otherwise, usually an HL7 ACK message, released with [`mllp_buffer_free`].
+///
+/// # Safety
+///
+/// `client` was returned by [`mllp_client_connect`] and not released. `payload` points to `len`
+/// readable bytes. `ack` and `ack_len` are valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mllp_client_send(client: *mut MllpClient, payload: *const u8, len: usize, ack: *mut *mut u8, ack_len: *mut usize) -> c_int {
+    let (Some(client), Some(payload)) = (client.as_mut(), bytes(payload, len)) else {
+        return MLLP_ERR_ARGUMENT;
+    };
+    if ack.is_null() || ack_len.is_null() {
+        return MLLP_ERR_ARGUMENT;
+    }
+
+    match client.send(payload) {
+        Ok(Ack::Application(payload)) => {
+            hand_out(payload, ack, ack_len);
+            MLLP_OK
+        }
+        Ok(Ack::Commit | Ack::None) => {
+            *ack = ptr::null_mut();
+            *ack_len = 0;
+            MLLP_OK
+        }
+        Err(e) => status(&e),
+    }
+}
+
+/// Closes the connection of a client and releases it.
+///
+/// # Safety
+///
+/// `client` was returned by [`mllp_client_connect`] and not released already. A null `client`
+/// is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn mllp_client_free(client: *mut MllpClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Bytes at `data`, empty when it is null with a `len` of 0, `None` when it is null otherwise.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    match data.is_null() {
+        true => (len == 0).then_some(&[]),
+        false => Some(slice::from_raw_parts(data, len)),
+    }
+}
+
+/// Hands `bytes` over to the caller, who releases them with [`mllp_buffer_free`].
+unsafe fn hand_out(bytes: Vec<u8>, out: *mut *mut u8, out_len: *mut usize) {
+    *out_len = bytes.len();
+    *out = Box::into_raw(bytes.into_boxed_slice()).cast();
+}
+
+fn status(e: &MllpError) -> c_int {
+    match e {
+        MllpError::Io(_) | MllpError::ConnectionClosed { .. } => MLLP_ERR_IO,
+        MllpError::Syntax(_) => MLLP_ERR_SYNTAX,
+        MllpError::AckTimeout | MllpError::Timeout(_) => MLLP_ERR_TIMEOUT,
+        MllpError::Nak => MLLP_ERR_NAK,
+        MllpError::Protocol(_) => MLLP_ERR_PROTOCOL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::ptr;
+    use std::slice;
+    use crate::ffi::*;
+    use crate::handler::AckDecision;
+    use crate::testing::MockMllpServer;
+
+    #[test]
+    fn it_encodes_and_decodes_over_the_c_interface() {
+        unsafe {
+            let (mut frame, mut frame_len) = (ptr::null_mut(), 0);
+            assert_eq!(mllp_encode(b"MSH|^~\\&|".as_ptr(), 9, &mut frame, &mut frame_len), MLLP_OK);
+
+            let decoder = mllp_decoder_new();
+            let (mut payload, mut payload_len) = (ptr::null_mut(), 0);
+            assert_eq!(mllp_decoder_extend(decoder, frame, 4), MLLP_OK);
+            assert_eq!(mllp_decoder_next_frame(decoder, &mut payload, &mut payload_len), MLLP_INCOMPLETE);
+            assert_eq!(mllp_decoder_extend(decoder, frame.add(4), frame_len - 4), MLLP_OK);
+            assert_eq!(mllp_decoder_next_frame(decoder, &mut payload, &mut payload_len), MLLP_OK);
+            assert_eq!(slice::from_raw_parts(payload, payload_len), b"MSH|^~\\&|");
+
+            mllp_buffer_free(payload, payload_len);
+            mllp_buffer_free(frame, frame_len);
+            mllp_decoder_free(decoder);
+            assert_eq!(mllp_decoder_extend(ptr::null_mut(), ptr::null(), 0), MLLP_ERR_ARGUMENT);
+        }
+    }
+
+    #[test]
+    fn it_sends_over_the_c_interface() {
+        let server = MockMllpServer::start([AckDecision::ApplicationAck(b"MSA|AA".to_vec()), AckDecision::CommitNak]).unwrap();
+        let addr = CString::new(server.local_addr().to_string()).unwrap();
+
+        unsafe {
+            let mut client = ptr::null_mut();
+            assert_eq!(mllp_client_connect(addr.as_ptr(), 5000, 0, &mut client), MLLP_OK);
+
+            let (mut ack, mut ack_len) = (ptr::null_mut(), 0);
+            assert_eq!(mllp_client_send(client, b"first".as_ptr(), 5, &mut ack, &mut ack_len), MLLP_OK);
+            assert_eq!(slice::from_raw_parts(ack, ack_len), b"MSA|AA");
+            mllp_buffer_free(ack, ack_len);
+
+            assert_eq!(mllp_client_send(client, b"second".as_ptr(), 6, &mut ack, &mut ack_len), MLLP_ERR_NAK);
+            assert_eq!(mllp_client_send(client, b"third".as_ptr(), 5, &mut ack, &mut ack_len), MLLP_OK);
+            assert!(ack.is_null());
+            mllp_client_free(client);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Filtering of the connections accepted by the server.
+//!
+//! [`MllpServerConfig::allowed_peers`](crate::server::MllpServerConfig::allowed_peers) lists the
+//! [`IpRange`]s connections are accepted from, and
+//! [`MllpServerConfig::connection_filter`](crate::server::MllpServerConfig::connection_filter)
+//! takes a [`ConnectionFilter`] for any other rule. Connections failing either are closed right
+//! after being accepted, before anything is read from them, and reported as
+//! [`EventKind::ConnectionRejected`](crate::event::EventKind::ConnectionRejected).
+//! ```
+//! use std::net::SocketAddr;
+//! use std::sync::Arc;
+//! use mllp_rs::server::MllpServerConfig;
+//!
+//! let config = MllpServerConfig {
+//!     allowed_peers: Some(vec!["10.20.0.0/16".parse().unwrap(), "192.168.1.12".parse().unwrap()]),
+//!     // privileged source ports only
+//!     connection_filter: Some(Arc::new(|peer_addr: SocketAddr| peer_addr.port() < 1024)),
+//!     ..MllpServerConfig::default()
+//! };
+//! ```
+//!
+//! Connections over a Unix domain socket have no IP address, and are not filtered.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Decides whether to keep a connection, from the address of its peer.
+pub trait ConnectionFilter: Send + Sync {
+    /// Returns `false` to close the connection from `peer_addr`.
+    fn accept(&self, peer_addr: SocketAddr) -> bool;
+}
+
+impl<F> ConnectionFilter for F
+where
+    F: Fn(SocketAddr) -> bool + Send + Sync,
+{
+    fn accept(&self, peer_addr: SocketAddr) -> bool {
+        self(peer_addr)
+    }
+}
+
+impl fmt::Debug for dyn ConnectionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ConnectionFilter")
+    }
+}
+
+/// Range of IP addresses in CIDR notation, such as `10.20.0.0/16` or `fd00::/8`.
+///
+/// An address without a prefix length is a range of its own. IPv4 addresses mapped to IPv6,
+/// as peers of a dual-stack listener are seen, are matched as IPv4 addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    /// Range of the addresses sharing the first `prefix_len` bits of `addr`. The remaining bits
+    /// of `addr` are ignored.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self, InvalidIpRange> {
+        let addr = match addr {
+            IpAddr::V4(ip) if prefix_len <= 32 => IpAddr::V4((u32::from(ip) & mask(prefix_len, 32) as u32).into()),
+            IpAddr::V6(ip) if prefix_len <= 128 => IpAddr::V6((u128::from(ip) & mask(prefix_len, 128)).into()),
+            _ => return Err(InvalidIpRange),
+        };
+
+        Ok(IpRange { addr, prefix_len })
+    }
+
+    /// First address of the range.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => u32::from(ip) & mask(self.prefix_len, 32) as u32 == u32::from(range),
+            (IpAddr::V6(range), IpAddr::V6(ip)) => u128::from(ip) & mask(self.prefix_len, 128) == u128::from(range),
+            _ => false,
+        }
+    }
+}
+
+/// Mask of the first `prefix_len` bits of an address of `bits` bits.
+fn mask(prefix_len: u8, bits: u32) -> u128 {
+    match prefix_len {
+        0 => 0,
+        len => (u128::MAX << (128 - len as u32)) >> (128 - bits),
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| InvalidIpRange)?;
+        let prefix_len = match (prefix_len, addr) {
+            (Some(prefix_len), _) => prefix_len.parse().map_err(|_| InvalidIpRange)?,
+            (None, IpAddr::V4(_)) => 32,
+            (None, IpAddr::V6(_)) => 128,
+        };
+
+        IpRange::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Error returned for an IP range which is not an address, optionally followed by `/` and a
+/// prefix length no longer than the address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidIpRange;
+
+impl fmt::Display for InvalidIpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected an IP range such as 10.20.0.0/16")
+    }
+}
+
+impl std::error::Error for InvalidIpRange {}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use crate::filter::{InvalidIpRange, IpRange};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn it_matches_addresses_in_range() {
+        let range: IpRange = "10.20.30.40/16".parse().unwrap();
+        assert_eq!(range.to_string(), "10.20.0.0/16");
+        assert!(range.contains(ip("10.20.255.1")));
+        assert!(!range.contains(ip("10.21.0.1")));
+        assert!(range.contains(ip("::ffff:10.20.0.9")));
+
+        let single: IpRange = "192.168.1.12".parse().unwrap();
+        assert!(single.contains(ip("192.168.1.12")));
+        assert!(!single.contains(ip("192.168.1.13")));
+
+        let v6: IpRange = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(!v6.contains(ip("10.20.0.1")));
+
+        assert!("0.0.0.0/0".parse::<IpRange>().unwrap().contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn it_rejects_invalid_ranges() {
+        assert_eq!("10.0.0.0/33".parse::<IpRange>(), Err(InvalidIpRange));
+        assert_eq!("fd00::/129".parse::<IpRange>(), Err(InvalidIpRange));
+        assert_eq!("lab.example.org/24".parse::<IpRange>(), Err(InvalidIpRange));
+        assert_eq!("10.0.0.0/".parse::<IpRange>(), Err(InvalidIpRange));
+    }
+}
@@ -0,0 +1,163 @@
+//! Handling of the messages received by the server.
+//!
+//! An [`MllpHandler`] is called with the payload of each received message, and returns an
+//! [`AckDecision`] telling the server what to write back: an MLLP commit acknowledgement, which
+//! only says the message was received, an HL7 application acknowledgement built by the handler,
+//! or nothing. Closures taking the payload and returning an `AckDecision` are handlers.
+//!
+//! Handlers routing or auditing messages by source implement [`MllpHandler::on_frame`] too, which
+//! is given the [`ReceivedFrame`]: the payload with the address of the sender, the connection it
+//! came on and when it was received.
+//! ```
+//! use mllp_rs::handler::{AckDecision, MllpHandler};
+//!
+//! /// Lab results go to a queue, the rest is refused.
+//! struct LabResults;
+//!
+//! impl MllpHandler for LabResults {
+//!     fn on_message(&self, message: &[u8]) -> AckDecision {
+//!         if message.windows(7).any(|field| field == b"|ORU^R0") {
+//!             AckDecision::CommitAck
+//!         } else {
+//!             AckDecision::CommitNak
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use crate::server::Session;
+use crate::{LowerLayerCodec, MllpCodec, ACK, NAK};
+
+/// What the server writes back for a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AckDecision {
+    /// MLLP commit acknowledgement, `<SB><ACK><EB><CR>`.
+    CommitAck,
+    /// MLLP negative commit acknowledgement, `<SB><NAK><EB><CR>`.
+    CommitNak,
+    /// Application acknowledgement, usually an HL7 ACK message. Holds the payload, framed by the
+    /// server.
+    ApplicationAck(Vec<u8>),
+    /// Nothing is written back.
+    None,
+}
+
+impl AckDecision {
+    /// Frame to write back, if any.
+    pub fn to_frame(&self) -> Option<Vec<u8>> {
+        self.encode_with(&MllpCodec {})
+    }
+
+    /// Frame to write back, if any, framed by `codec`.
+    pub fn encode_with(&self, codec: &dyn LowerLayerCodec) -> Option<Vec<u8>> {
+        match self {
+            AckDecision::CommitAck => Some(codec.encode(&[ACK])),
+            AckDecision::CommitNak => Some(codec.encode(&[NAK])),
+            AckDecision::ApplicationAck(payload) => Some(codec.encode(payload)),
+            AckDecision::None => None,
+        }
+    }
+}
+
+/// A message received by an [`MllpServer`](crate::server::MllpServer), and where and when it
+/// was received.
+///
+/// With the `serde` feature, it serializes without its session, identified by `connection_id`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ReceivedFrame<'a> {
+    /// Payload of the frame, as passed on by the [interceptors](crate::interceptor).
+    pub payload: &'a [u8],
+    pub peer_addr: SocketAddr,
+    pub received_at: SystemTime,
+    /// Identifier of the connection, unique among the connections accepted by the server.
+    pub connection_id: u64,
+    /// Length of the frame on the wire, `<SB>` to `<CR>`.
+    pub len: usize,
+    /// Connection the frame was received on.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub session: &'a Session,
+}
+
+/// Handler of the messages received by an [`MllpServer`](crate::server::MllpServer).
+///
+/// It is shared by the connections, and may be called from several threads at once.
+pub trait MllpHandler: Send + Sync {
+    /// Handles the payload of a received message.
+    fn on_message(&self, message: &[u8]) -> AckDecision;
+
+    /// Handles a received message along with where and when it was received. Calls
+    /// [`MllpHandler::on_message`] with the payload, unless overridden.
+    fn on_frame(&self, frame: &ReceivedFrame<'_>) -> AckDecision {
+        self.on_message(frame.payload)
+    }
+}
+
+impl<F> MllpHandler for F
+where
+    F: Fn(&[u8]) -> AckDecision + Send + Sync,
+{
+    fn on_message(&self, message: &[u8]) -> AckDecision {
+        self(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use crate::client::MllpClient;
+    use crate::handler::{AckDecision, MllpHandler, ReceivedFrame};
+    use crate::server::{MllpServer, MllpServerConfig};
+    use crate::MllpCodec;
+
+    #[test]
+    fn it_frames_decisions() {
+        assert_eq!(AckDecision::CommitAck.to_frame(), Some(MllpCodec::ack().to_vec()));
+        assert_eq!(AckDecision::CommitNak.to_frame(), Some(MllpCodec::nak().to_vec()));
+        assert_eq!(AckDecision::ApplicationAck(b"MSA|AA".to_vec()).to_frame(), Some(MllpCodec::encode(b"MSA|AA")));
+        assert_eq!(AckDecision::None.to_frame(), None);
+    }
+
+    /// Counts the messages of each connection.
+    #[derive(Clone, Default)]
+    struct PerConnection(Arc<Mutex<HashMap<u64, (SocketAddr, usize)>>>);
+
+    impl MllpHandler for PerConnection {
+        fn on_message(&self, _: &[u8]) -> AckDecision {
+            AckDecision::CommitNak
+        }
+
+        fn on_frame(&self, frame: &ReceivedFrame<'_>) -> AckDecision {
+            assert_eq!(frame.len, frame.payload.len() + 3);
+            let mut connections = self.0.lock().unwrap();
+            connections.entry(frame.connection_id).or_insert((frame.peer_addr, 0)).1 += 1;
+            AckDecision::CommitAck
+        }
+    }
+
+    #[test]
+    fn it_passes_received_frames_to_handlers() {
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig::default()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let handler = PerConnection::default();
+        let serving = handler.clone();
+        thread::spawn(move || server.serve(serving));
+
+        let mut first = MllpClient::connect(addr).unwrap();
+        let mut second = MllpClient::connect(addr).unwrap();
+        first.send(b"MSH|1").unwrap();
+        first.send(b"MSH|2").unwrap();
+        second.send(b"MSH|3").unwrap();
+
+        let connections = handler.0.lock().unwrap();
+        let mut counts: Vec<_> = connections.values().map(|(peer_addr, count)| (peer_addr.ip(), *count)).collect();
+        counts.sort();
+        assert_eq!(counts, vec![(addr.ip(), 1), (addr.ip(), 2)]);
+    }
+}
@@ -0,0 +1,391 @@
+//! Parsed HL7 v2 messages, for handlers and senders working with segments and fields rather
+//! than bytes.
+//!
+//! [`Message::parse`] reads the separators declared in MSH and splits a payload into segments,
+//! borrowing it. An [`Hl7Handler`] is given the parsed message and answers with an
+//! [`Acknowledgement`], which [`MllpServer::serve_hl7`] turns into an HL7 ACK built from the MSH
+//! of the message: sending and receiving applications swapped, and the control ID in MSA-2.
+//! [`MllpClient::send_hl7`] reads the acknowledgement of a message back from its MSA.
+//! ```no_run
+//! use mllp_rs::hl7::{Acknowledgement, Message};
+//! use mllp_rs::server::{MllpServer, MllpServerConfig};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let server = MllpServer::bind("0.0.0.0:2575", MllpServerConfig::default())?;
+//! server.serve_hl7(|message: &Message<'_>| match message.get("PID-3.1") {
+//!     Some(_) => Acknowledgement::Accept,
+//!     None => Acknowledgement::Reject("No patient identifier".into()),
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Payloads which are not HL7 messages, without an MSH segment, are answered with a NAK.
+//!
+//! [`MllpServer::serve_hl7`]: crate::server::MllpServer::serve_hl7
+//! [`MllpClient::send_hl7`]: crate::client::MllpClient::send_hl7
+
+use std::fmt;
+use std::str;
+use std::time::SystemTime;
+use crate::client::Ack;
+use crate::clock;
+use crate::handler::{AckDecision, MllpHandler};
+
+/// Separators declared in MSH-1 and MSH-2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Separators {
+    pub field: u8,
+    pub component: u8,
+    pub repetition: u8,
+    pub escape: u8,
+    pub subcomponent: u8,
+}
+
+impl Default for Separators {
+    /// The usual `|^~\&`.
+    fn default() -> Self {
+        Separators { field: b'|', component: b'^', repetition: b'~', escape: b'\\', subcomponent: b'&' }
+    }
+}
+
+/// Why a payload could not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The payload does not start with an MSH segment.
+    MissingHeader,
+    /// MSH-2 does not hold the component, repetition and escape characters.
+    InvalidEncodingCharacters,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingHeader => write!(f, "Payload does not start with an MSH segment"),
+            ParseError::InvalidEncodingCharacters => write!(f, "Invalid encoding characters in MSH-2"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError { }
+
+/// HL7 v2 message, borrowing its payload.
+#[derive(Debug, Clone)]
+pub struct Message<'a> {
+    payload: &'a [u8],
+    separators: Separators,
+    segments: Vec<&'a [u8]>,
+}
+
+impl<'a> Message<'a> {
+    /// Parses `payload`, whose segments are separated by `<CR>`, `<LF>`, or both.
+    pub fn parse(payload: &'a [u8]) -> Result<Self, ParseError> {
+        let header = payload.strip_prefix(b"MSH").ok_or(ParseError::MissingHeader)?;
+        let field = *header.first().ok_or(ParseError::MissingHeader)?;
+        let encoding = header[1..].split(|b| *b == field || *b == b'\r' || *b == b'\n').next().unwrap_or_default();
+        let [component, repetition, escape, ..] = *encoding else {
+            return Err(ParseError::InvalidEncodingCharacters);
+        };
+        let separators = Separators {
+            field,
+            component,
+            repetition,
+            escape,
+            subcomponent: encoding.get(3).copied().unwrap_or(b'&'),
+        };
+        let segments = payload.split(|b| *b == b'\r' || *b == b'\n').filter(|segment| !segment.is_empty()).collect();
+
+        Ok(Message { payload, separators, segments })
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    pub fn separators(&self) -> Separators {
+        self.separators
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + '_ {
+        self.segments.iter().map(|line| Segment { line, separators: self.separators })
+    }
+
+    /// First segment named `name`, such as `PID`.
+    pub fn segment(&self, name: &str) -> Option<Segment<'a>> {
+        self.segments().find(|segment| segment.name().as_bytes() == name.as_bytes())
+    }
+
+    pub fn msh(&self) -> Segment<'a> {
+        Segment { line: self.segments[0], separators: self.separators }
+    }
+
+    /// MSH-9, such as `ORU^R01^ORU_R01`.
+    pub fn message_type(&self) -> Option<&'a str> {
+        self.msh().text(9)
+    }
+
+    /// MSH-10.
+    pub fn control_id(&self) -> Option<&'a str> {
+        self.msh().text(10)
+    }
+
+    /// MSH-12.
+    pub fn version(&self) -> Option<&'a str> {
+        self.msh().text(12)
+    }
+
+    /// Value at `path`, a segment name and a field number, and optionally a component number,
+    /// such as `PID-5` or `PID-5.1`, in the first repetition of the field of the first segment
+    /// of that name. `None` if the path is invalid or the value is empty.
+    pub fn get(&self, path: &str) -> Option<&'a [u8]> {
+        let (name, position) = path.split_once('-')?;
+        let (field, component) = match position.split_once('.') {
+            Some((field, component)) => (field.parse().ok()?, Some(component.parse().ok()?)),
+            None => (position.parse().ok()?, None),
+        };
+        let segment = self.segment(name)?;
+
+        match component {
+            Some(component) => segment.component(field, component),
+            None => segment.field(field),
+        }
+    }
+
+    /// Application acknowledgement of the message: an ACK with the sending and receiving
+    /// applications and facilities of the MSH swapped, the control ID, processing ID and version
+    /// of the message, and an MSA with the code and text of `acknowledgement`.
+    pub fn ack(&self, acknowledgement: &Acknowledgement) -> Vec<u8> {
+        let msh = self.msh();
+        let field = |index| msh.field(index).unwrap_or_default();
+        let separator = self.separators.field;
+        let timestamp = clock::hl7_timestamp(SystemTime::now());
+        let trigger = msh.component(9, 2).unwrap_or_default();
+        let message_type = [&b"ACK"[..], trigger].join(&self.separators.component);
+        let text = acknowledgement.text().map(|text| self.escape(text.as_bytes())).unwrap_or_default();
+
+        let header: [&[u8]; 12] = [
+            b"MSH", field(2), field(5), field(6), field(3), field(4), timestamp.as_bytes(), b"", &message_type,
+            field(10), field(11), field(12),
+        ];
+        let msa: [&[u8]; 4] = [b"MSA", acknowledgement.code().as_bytes(), field(10), &text];
+
+        [header.join(&separator), msa.join(&separator)].join(&b'\r')
+    }
+
+    /// `text` with the separators escaped, as `\F\`, `\S\`, `\R\`, `\E\` and `\T\`.
+    fn escape(&self, text: &[u8]) -> Vec<u8> {
+        let Separators { field, component, repetition, escape, subcomponent } = self.separators;
+        let mut escaped = Vec::with_capacity(text.len());
+        for &b in text {
+            let code = match b {
+                _ if b == field => b'F',
+                _ if b == component => b'S',
+                _ if b == repetition => b'R',
+                _ if b == escape => b'E',
+                _ if b == subcomponent => b'T',
+                _ => {
+                    escaped.push(b);
+                    continue;
+                }
+            };
+            escaped.extend_from_slice(&[escape, code, escape]);
+        }
+
+        escaped
+    }
+}
+
+/// Segment of a [`Message`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    line: &'a [u8],
+    separators: Separators,
+}
+
+impl<'a> Segment<'a> {
+    /// Name of the segment, such as `PID`.
+    pub fn name(&self) -> &'a str {
+        str::from_utf8(self.line.get(..3).unwrap_or(self.line)).unwrap_or_default()
+    }
+
+    /// Field `index`, numbered as in HL7: in MSH, MSH-1 is the field separator itself and MSH-2
+    /// the encoding characters. `None` if the field is empty.
+    pub fn field(&self, index: usize) -> Option<&'a [u8]> {
+        let is_header = self.line.starts_with(b"MSH");
+        let field = match (is_header, index) {
+            (_, 0) => self.line.get(..3),
+            (true, 1) => self.line.get(3..4),
+            (true, index) => self.line.split(|b| *b == self.separators.field).nth(index - 1),
+            (false, index) => self.line.split(|b| *b == self.separators.field).nth(index),
+        };
+
+        field.filter(|field| !field.is_empty())
+    }
+
+    /// Component `component` of the first repetition of field `field`, both numbered from 1.
+    pub fn component(&self, field: usize, component: usize) -> Option<&'a [u8]> {
+        let repetition = self.field(field)?.split(|b| *b == self.separators.repetition).next()?;
+        repetition.split(|b| *b == self.separators.component).nth(component.checked_sub(1)?).filter(|component| !component.is_empty())
+    }
+
+    /// Field `index` as text, `None` if it is empty or not UTF-8.
+    pub fn text(&self, index: usize) -> Option<&'a str> {
+        str::from_utf8(self.field(index)?).ok()
+    }
+}
+
+/// Answer to a message, MSA-1 and MSA-3 of its acknowledgement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Acknowledgement {
+    /// `AA`, or `CA` in enhanced mode.
+    Accept,
+    /// `AE`, or `CE`: the message could not be processed, and may be sent again. Holds the text
+    /// of MSA-3.
+    Error(String),
+    /// `AR`, or `CR`: the message was refused, and should not be sent again. Holds the text of
+    /// MSA-3.
+    Reject(String),
+}
+
+impl Acknowledgement {
+    /// Acknowledgment code of MSA-1, in original mode.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Acknowledgement::Accept => "AA",
+            Acknowledgement::Error(_) => "AE",
+            Acknowledgement::Reject(_) => "AR",
+        }
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Acknowledgement::Accept => None,
+            Acknowledgement::Error(text) | Acknowledgement::Reject(text) => Some(text),
+        }
+    }
+
+    /// Answer given by the acknowledgement `ack`. A commit ACK, or nothing awaited, accepts the
+    /// message; an application acknowledgement without a readable MSA is an error.
+    pub fn from_ack(ack: &Ack) -> Self {
+        let Ack::Application(payload) = ack else {
+            return Acknowledgement::Accept;
+        };
+        let msa = Message::parse(payload).ok().and_then(|message| {
+            let msa = message.segment("MSA")?;
+            let text = String::from_utf8_lossy(msa.field(3).unwrap_or_default()).into_owned();
+            Some((msa.field(1)?.to_vec(), text))
+        });
+
+        match msa {
+            Some((code, _)) if code == b"AA" || code == b"CA" => Acknowledgement::Accept,
+            Some((code, text)) if code == b"AR" || code == b"CR" => Acknowledgement::Reject(text),
+            Some((_, text)) => Acknowledgement::Error(text),
+            None => Acknowledgement::Error("No MSA segment in the acknowledgement".into()),
+        }
+    }
+}
+
+/// Handler of parsed messages, served by [`MllpServer::serve_hl7`].
+///
+/// Closures taking a [`Message`] and returning an [`Acknowledgement`] are handlers.
+///
+/// [`MllpServer::serve_hl7`]: crate::server::MllpServer::serve_hl7
+pub trait Hl7Handler: Send + Sync {
+    fn on_message(&self, message: &Message<'_>) -> Acknowledgement;
+}
+
+impl<F> Hl7Handler for F
+where
+    F: Fn(&Message<'_>) -> Acknowledgement + Send + Sync,
+{
+    fn on_message(&self, message: &Message<'_>) -> Acknowledgement {
+        self(message)
+    }
+}
+
+/// [`MllpHandler`] parsing the payloads for an [`Hl7Handler`], and answering with the ACK built
+/// by [`Message::ack`], or a NAK for payloads which are not HL7 messages.
+#[derive(Debug)]
+pub struct Hl7Adapter<H> {
+    handler: H,
+}
+
+impl<H: Hl7Handler> Hl7Adapter<H> {
+    pub fn new(handler: H) -> Self {
+        Hl7Adapter { handler }
+    }
+}
+
+impl<H: Hl7Handler> MllpHandler for Hl7Adapter<H> {
+    fn on_message(&self, payload: &[u8]) -> AckDecision {
+        match Message::parse(payload) {
+            Ok(message) => AckDecision::ApplicationAck(message.ack(&self.handler.on_message(&message))),
+            Err(_) => AckDecision::CommitNak,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::client::{Ack, MllpClient, MllpClientConfig};
+    use crate::hl7::{Acknowledgement, Message, ParseError};
+    use crate::server::{MllpServer, MllpServerConfig};
+
+    const ORU: &[u8] = b"MSH|^~\\&|LAB|NORTH|EHR|SOUTH|20240131||ORU^R01^ORU_R01|MSG1|P|2.5\rPID|1||12345^^^HOSP~67890||Doe^John\r\nOBX|1|NM|GLU||5.4";
+
+    #[test]
+    fn it_parses_messages() {
+        let message = Message::parse(ORU).unwrap();
+        assert_eq!(message.segments().map(|segment| segment.name()).collect::<Vec<_>>(), ["MSH", "PID", "OBX"]);
+        assert_eq!((message.message_type(), message.control_id(), message.version()), (Some("ORU^R01^ORU_R01"), Some("MSG1"), Some("2.5")));
+        assert_eq!(message.msh().field(1), Some(&b"|"[..]));
+        assert_eq!(message.msh().field(2), Some(&b"^~\\&"[..]));
+        assert_eq!(message.get("PID-3.1"), Some(&b"12345"[..]));
+        assert_eq!(message.get("PID-5"), Some(&b"Doe^John"[..]));
+        assert_eq!(message.get("OBX-5"), Some(&b"5.4"[..]));
+        assert_eq!(message.get("PID-4"), None);
+        assert_eq!(message.get("ZZZ-1"), None);
+
+        assert_eq!(Message::parse(b"PID|1").unwrap_err(), ParseError::MissingHeader);
+        assert_eq!(Message::parse(b"MSH|^").unwrap_err(), ParseError::InvalidEncodingCharacters);
+    }
+
+    #[test]
+    fn it_builds_acknowledgements_from_the_header() {
+        let message = Message::parse(ORU).unwrap();
+        let ack = message.ack(&Acknowledgement::Reject("Unknown|patient".into()));
+        let parsed = Message::parse(&ack).unwrap();
+
+        assert_eq!(parsed.msh().field(3), Some(&b"EHR"[..]));
+        assert_eq!(parsed.msh().field(5), Some(&b"LAB"[..]));
+        assert_eq!(parsed.message_type(), Some("ACK^R01"));
+        assert_eq!(parsed.get("MSA-2"), Some(&b"MSG1"[..]));
+        assert_eq!(parsed.get("MSA-3"), Some(&b"Unknown\\F\\patient"[..]));
+        assert_eq!(Acknowledgement::from_ack(&Ack::Application(ack)), Acknowledgement::Reject("Unknown\\F\\patient".into()));
+        assert_eq!(Acknowledgement::from_ack(&Ack::Commit), Acknowledgement::Accept);
+    }
+
+    #[test]
+    fn it_serves_parsed_messages() {
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig::default()).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            server.serve_hl7(|message: &Message<'_>| match message.get("PID-3.1") {
+                Some(_) => Acknowledgement::Accept,
+                None => Acknowledgement::Error("No patient identifier".into()),
+            })
+        });
+
+        let config = MllpClientConfig {
+            max_retries: 0,
+            ..MllpClientConfig::default()
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+        assert_eq!(client.send_hl7(&Message::parse(ORU).unwrap()).unwrap(), Acknowledgement::Accept);
+        let anonymous = Message::parse(b"MSH|^~\\&|LAB||EHR||20240131||ADT^A01|MSG2|P|2.5\rPID|1").unwrap();
+        assert_eq!(client.send_hl7(&anonymous).unwrap(), Acknowledgement::Error("No patient identifier".into()));
+        assert!(client.send(b"not HL7").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Interceptors wrapping the server's message handler.
+//!
+//! An [`Interceptor`] is called with each received message before the handler, and decides what
+//! happens next: it can pass the message on unchanged, pass on a modified copy (de-identified,
+//! normalized), answer in place of the handler (rejecting a message failing a schema check), or
+//! observe the acknowledgement decision (logging, metrics). Interceptors set in
+//! [`MllpServerConfig::interceptors`](crate::server::MllpServerConfig::interceptors) are chained
+//! in order, the first one being the outermost.
+//! ```
+//! use std::sync::Arc;
+//! use mllp_rs::handler::AckDecision;
+//! use mllp_rs::interceptor::{Interceptor, Next};
+//! use mllp_rs::server::MllpServerConfig;
+//!
+//! /// Rejects the messages which are not HL7 v2.
+//! struct RequireMsh;
+//!
+//! impl Interceptor for RequireMsh {
+//!     fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+//!         if message.starts_with(b"MSH|") {
+//!             next(message)
+//!         } else {
+//!             AckDecision::CommitNak
+//!         }
+//!     }
+//! }
+//!
+//! let config = MllpServerConfig {
+//!     interceptors: vec![Arc::new(RequireMsh)],
+//!     ..MllpServerConfig::default()
+//! };
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+use crate::handler::{AckDecision, MllpHandler, ReceivedFrame};
+
+/// Rest of the chain: the following interceptors, then the handler.
+pub type Next<'a> = &'a dyn Fn(&[u8]) -> AckDecision;
+
+/// Step of the chain of interceptors around the message handler.
+pub trait Interceptor: Send + Sync {
+    /// Handles `message`, usually by calling `next` with it or a modified copy, and returns what
+    /// to write back to the sender.
+    fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision;
+}
+
+impl<I: Interceptor + ?Sized> Interceptor for Arc<I> {
+    fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+        (**self).around(message, next)
+    }
+}
+
+impl fmt::Debug for dyn Interceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Interceptor")
+    }
+}
+
+/// Runs the payload of `frame` through `interceptors`, then `handler`.
+pub(crate) fn intercept<H>(interceptors: &[Arc<dyn Interceptor>], frame: &ReceivedFrame<'_>, handler: &H) -> AckDecision
+where
+    H: MllpHandler + ?Sized,
+{
+    match interceptors.split_first() {
+        Some((first, rest)) => first.around(frame.payload, &|payload| intercept(rest, &ReceivedFrame { payload, ..*frame }, handler)),
+        None => handler.on_frame(frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+    use crate::handler::{AckDecision, ReceivedFrame};
+    use crate::interceptor::{intercept, Interceptor, Next};
+    use crate::server::Session;
+
+    /// Prefixes messages with its name, and records the responses going through it.
+    struct Tagging {
+        name: &'static str,
+        responses: Mutex<Vec<AckDecision>>,
+    }
+
+    impl Interceptor for Tagging {
+        fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+            let tagged = [self.name.as_bytes(), message].concat();
+            let response = next(&tagged);
+            self.responses.lock().unwrap().push(response.clone());
+            response
+        }
+    }
+
+    struct Rejecting;
+
+    impl Interceptor for Rejecting {
+        fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+            if message.ends_with(b"bad") {
+                AckDecision::CommitNak
+            } else {
+                next(message)
+            }
+        }
+    }
+
+    fn frame<'a>(payload: &'a [u8], session: &'a Session) -> ReceivedFrame<'a> {
+        ReceivedFrame {
+            payload,
+            peer_addr: session.peer_addr(),
+            received_at: SystemTime::now(),
+            connection_id: session.id(),
+            len: payload.len() + 3,
+            session,
+        }
+    }
+
+    #[test]
+    fn it_chains_interceptors_in_order() {
+        let outer = Arc::new(Tagging {
+            name: "outer:",
+            responses: Mutex::new(Vec::new()),
+        });
+        let inner = Arc::new(Tagging {
+            name: "inner:",
+            responses: Mutex::new(Vec::new()),
+        });
+        let interceptors: Vec<Arc<dyn Interceptor>> = vec![outer.clone(), inner, Arc::new(Rejecting)];
+        let echo = |message: &[u8]| AckDecision::ApplicationAck(message.to_vec());
+
+        let session = Session::detached("127.0.0.1:2575".parse().unwrap());
+        assert_eq!(intercept(&interceptors, &frame(b"MSH|", &session), &echo), AckDecision::ApplicationAck(b"inner:outer:MSH|".to_vec()));
+        assert_eq!(intercept(&interceptors, &frame(b"bad", &session), &echo), AckDecision::CommitNak);
+        assert_eq!(outer.responses.lock().unwrap()[1], AckDecision::CommitNak);
+    }
+}
@@ -0,0 +1,410 @@
+//! Journal of every frame sent and received, for an authoritative record of the traffic.
+//!
+//! Where a [capture](crate::capture) samples payloads, a [`FrameJournal`] set in
+//! [`MllpClientConfig::journal`](crate::client::MllpClientConfig::journal) or
+//! [`MllpServerConfig::journal`](crate::server::MllpServerConfig::journal) keeps every frame
+//! written or read on the connections, acknowledgements and retransmissions included, as it was on
+//! the wire, with when and with whom it was exchanged. The bytes received which are not a frame,
+//! such as a banner or a malformed frame, are kept as they were too. Frames are appended to rolling files in a
+//! directory, the oldest files being removed once [`JournalConfig::max_files`] are kept, and
+//! read back in order with [`JournalReader`].
+//! ```no_run
+//! use std::sync::Arc;
+//! use mllp_rs::client::MllpClientConfig;
+//! use mllp_rs::journal::{FrameJournal, JournalConfig, JournalReader};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let journal = FrameJournal::open("/var/lib/mllp/journal", JournalConfig::default())?;
+//! let config = MllpClientConfig {
+//!     journal: Some(Arc::new(journal)),
+//!     ..MllpClientConfig::default()
+//! };
+//!
+//! // what was sent at 02:13?
+//! for entry in JournalReader::open("/var/lib/mllp/journal")? {
+//!     let entry = entry?;
+//!     println!("{:?} {:?} {}", entry.time, entry.direction, String::from_utf8_lossy(&entry.frame));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Files are named `journal-<sequence>.mllp`. Each entry is the time in milliseconds since the
+//! epoch as a big-endian `u64`, the direction as a byte, 0 for inbound and 1 for outbound, the
+//! length of the peer address as a byte and the address as text, and the length of the frame as
+//! a big-endian `u32` and the frame. Entries are written as the frames go, without waiting for
+//! the disk: an entry cut short by a crash ends the reading with an
+//! [`io::ErrorKind::UnexpectedEof`] error.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::capture::Direction;
+
+/// Settings of a [`FrameJournal`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalConfig {
+    /// Once a file holds this many bytes, the next frames go to a new file.
+    pub max_file_bytes: u64,
+    /// Number of files kept, the current one included.
+    pub max_files: usize,
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            max_file_bytes: 64 * 1024 * 1024,
+            max_files: 16,
+        }
+    }
+}
+
+/// A frame read back from a journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub time: SystemTime,
+    pub direction: Direction,
+    /// Receiver of an outbound frame, sender of an inbound one.
+    pub peer_addr: SocketAddr,
+    /// Frame as it was on the wire, `<SB>` to `<CR>`, or bytes received which were not one.
+    pub frame: Vec<u8>,
+}
+
+/// Writer of frames to rolling files in a directory.
+#[derive(Debug)]
+pub struct FrameJournal {
+    dir: PathBuf,
+    config: JournalConfig,
+    state: Mutex<JournalState>,
+}
+
+#[derive(Debug)]
+struct JournalState {
+    file: File,
+    sequence: u64,
+    bytes: u64,
+}
+
+impl FrameJournal {
+    /// Opens the journal in `dir`, creating the directory if needed. Frames are appended to the
+    /// last file of a previous run.
+    pub fn open<P: AsRef<Path>>(dir: P, config: JournalConfig) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let sequence = journal_files(&dir)?.last().map_or(1, |(sequence, _)| *sequence);
+        let file = OpenOptions::new().create(true).append(true).open(file_path(&dir, sequence))?;
+        let bytes = file.metadata()?.len();
+
+        Ok(FrameJournal {
+            dir,
+            config,
+            state: Mutex::new(JournalState { file, sequence, bytes }),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Appends a frame written to, or read from, `peer_addr`.
+    pub fn record(&self, direction: Direction, peer_addr: SocketAddr, frame: &[u8]) -> io::Result<()> {
+        let mut entry = Vec::with_capacity(frame.len() + 64);
+        write_record(&mut entry, SystemTime::now(), direction, peer_addr, frame)?;
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.bytes > 0 && state.bytes + entry.len() as u64 > self.config.max_file_bytes {
+            self.roll(&mut state)?;
+        }
+        state.file.write_all(&entry)?;
+        state.bytes += entry.len() as u64;
+
+        Ok(())
+    }
+
+    /// Moves on to the next file, removing the oldest ones beyond [`JournalConfig::max_files`].
+    fn roll(&self, state: &mut JournalState) -> io::Result<()> {
+        let sequence = state.sequence + 1;
+        state.file = OpenOptions::new().create(true).append(true).open(file_path(&self.dir, sequence))?;
+        state.sequence = sequence;
+        state.bytes = 0;
+
+        let files = journal_files(&self.dir)?;
+        let excess = files.len().saturating_sub(self.config.max_files.max(1));
+        for (_, path) in &files[..excess] {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Iterator over the entries of the journal in a directory, oldest first.
+///
+/// After an error, the iteration ends.
+#[derive(Debug)]
+pub struct JournalReader {
+    files: std::vec::IntoIter<PathBuf>,
+    reader: Option<BufReader<File>>,
+    failed: bool,
+}
+
+impl JournalReader {
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let files: Vec<PathBuf> = journal_files(dir.as_ref())?.into_iter().map(|(_, path)| path).collect();
+        Ok(JournalReader {
+            files: files.into_iter(),
+            reader: None,
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for JournalReader {
+    type Item = io::Result<JournalEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => match File::open(self.files.next()?) {
+                    Ok(file) => self.reader.insert(BufReader::new(file)),
+                    // removed by the writer rolling over since the listing
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                },
+            };
+            match read_record(reader) {
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => self.reader = None,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Journal files of `dir`, by sequence number.
+fn journal_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let sequence = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("journal-")?.strip_suffix(".mllp")?.parse().ok());
+        if let Some(sequence) = sequence {
+            files.push((sequence, path));
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+fn file_path(dir: &Path, sequence: u64) -> PathBuf {
+    dir.join(format!("journal-{:06}.mllp", sequence))
+}
+
+/// Writes an entry in the binary layout of the journal, shared by the
+/// [length-prefixed archives](crate::archive::LengthPrefixed).
+pub(crate) fn write_record(
+    writer: &mut dyn Write,
+    time: SystemTime,
+    direction: Direction,
+    peer_addr: SocketAddr,
+    bytes: &[u8],
+) -> io::Result<()> {
+    let peer_addr = peer_addr.to_string();
+    let len = u32::try_from(bytes.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record longer than 4 GiB"))?;
+    let millis = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+
+    writer.write_all(&millis.to_be_bytes())?;
+    writer.write_all(&[match direction {
+        Direction::Inbound => 0,
+        Direction::Outbound => 1,
+    }])?;
+    // at most 47 bytes, for an IPv6 address with a scope and a port
+    writer.write_all(&[peer_addr.len() as u8])?;
+    writer.write_all(peer_addr.as_bytes())?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Reads an entry written by [`write_record`], or returns `None` at the end of the stream.
+pub(crate) fn read_record(reader: &mut dyn BufRead) -> io::Result<Option<JournalEntry>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut millis = [0u8; 8];
+    reader.read_exact(&mut millis)?;
+
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let direction = match header[0] {
+        0 => Direction::Inbound,
+        1 => Direction::Outbound,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid record direction")),
+    };
+    let mut peer_addr = vec![0u8; header[1] as usize];
+    reader.read_exact(&mut peer_addr)?;
+    let peer_addr = String::from_utf8(peer_addr)
+        .ok()
+        .and_then(|addr| addr.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid record peer address"))?;
+
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+
+    let time = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis));
+    Ok(Some(JournalEntry {
+        time,
+        direction,
+        peer_addr,
+        frame: bytes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use crate::capture::Direction;
+    use crate::client::{MllpClient, MllpClientConfig};
+    use crate::handler::AckDecision;
+    use crate::journal::{FrameJournal, JournalConfig, JournalReader};
+    use crate::server::{MllpServer, MllpServerConfig};
+    use crate::{MllpCodec, MllpDecoder};
+
+    fn journal_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("mllp-rs-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([10, 20, 0, 5], 2575))
+    }
+
+    #[test]
+    fn it_rolls_over_and_reads_back_in_order() {
+        let dir = journal_dir("roll");
+        let config = JournalConfig {
+            max_file_bytes: 100,
+            max_files: 3,
+        };
+        let journal = FrameJournal::open(&dir, config).unwrap();
+        for n in 0..10 {
+            journal.record(Direction::Outbound, peer(), &MllpCodec::encode(format!("MSH|{:02}", n).as_bytes())).unwrap();
+            journal.record(Direction::Inbound, peer(), &MllpCodec::ack()).unwrap();
+        }
+        drop(journal);
+        // a restart appends to the last file
+        FrameJournal::open(&dir, config).unwrap().record(Direction::Outbound, peer(), &MllpCodec::encode(b"MSH|10")).unwrap();
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        let entries: Vec<_> = JournalReader::open(&dir).unwrap().map(Result::unwrap).collect();
+        let last = entries.last().unwrap();
+        assert_eq!((last.direction, last.peer_addr), (Direction::Outbound, peer()));
+        assert_eq!(last.frame, MllpCodec::encode(b"MSH|10"));
+        let sent: Vec<_> = entries.iter().filter(|entry| entry.direction == Direction::Outbound).map(|entry| entry.frame[5..7].to_vec()).collect();
+        assert!(sent.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(sent.len() < 11);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_stops_at_a_truncated_entry() {
+        let dir = journal_dir("truncated");
+        let journal = FrameJournal::open(&dir, JournalConfig::default()).unwrap();
+        journal.record(Direction::Inbound, peer(), &MllpCodec::encode(b"MSH|1")).unwrap();
+        journal.record(Direction::Inbound, peer(), &MllpCodec::encode(b"MSH|2")).unwrap();
+        drop(journal);
+        let path = dir.join("journal-000001.mllp");
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let mut reader = JournalReader::open(&dir).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().frame, MllpCodec::encode(b"MSH|1"));
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(reader.next().is_none());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_journals_frames_on_both_sides() {
+        let (client_dir, server_dir) = (journal_dir("client"), journal_dir("server"));
+        let server_journal = Arc::new(FrameJournal::open(&server_dir, JournalConfig::default()).unwrap());
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            journal: Some(server_journal),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+
+        let client_journal = Arc::new(FrameJournal::open(&client_dir, JournalConfig::default()).unwrap());
+        let mut client = MllpClient::connect_with_config(addr, MllpClientConfig {
+            journal: Some(client_journal),
+            ..MllpClientConfig::default()
+        })
+        .unwrap();
+        client.send(b"MSH|1").unwrap();
+        drop(client);
+
+        let frames = |dir| -> Vec<_> {
+            JournalReader::open(dir).unwrap().map(|entry| entry.map(|entry| (entry.direction, entry.frame)).unwrap()).collect()
+        };
+        let ack = MllpCodec::ack().to_vec();
+        assert_eq!(frames(&client_dir), vec![(Direction::Outbound, MllpCodec::encode(b"MSH|1")), (Direction::Inbound, ack.clone())]);
+        assert_eq!(frames(&server_dir), vec![(Direction::Inbound, MllpCodec::encode(b"MSH|1")), (Direction::Outbound, ack)]);
+        let _ = fs::remove_dir_all(client_dir);
+        let _ = fs::remove_dir_all(server_dir);
+    }
+
+    #[test]
+    fn it_journals_the_bytes_received_as_they_were() {
+        let dir = journal_dir("raw");
+        let journal = Arc::new(FrameJournal::open(&dir, JournalConfig::default()).unwrap());
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            journal: Some(journal),
+            skip_banner: true,
+            heartbeat_payload: Some(b"HB".to_vec()),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let sent = [b"HELLO\r\n".to_vec(), MllpCodec::encode(b"HB"), MllpCodec::encode(b"MSH|1")];
+        stream.write_all(&sent.concat()).unwrap();
+        MllpDecoder::new().read_frame(&mut stream).unwrap();
+        drop(stream);
+
+        let received: Vec<_> = JournalReader::open(&dir)
+            .unwrap()
+            .map(Result::unwrap)
+            .filter(|entry| entry.direction == Direction::Inbound)
+            .map(|entry| entry.frame)
+            .collect();
+        assert_eq!(received, sent);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
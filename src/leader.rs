@@ -0,0 +1,166 @@
+//! Active/standby coordination of sender instances.
+//!
+//! Two sender instances reading the same outbound queue must not both send, or every message
+//! would be delivered twice. A [`LeaderLock`] makes sure only one of them is active at a time: the
+//! instance holding the lock sends, the other one waits in [`wait_for_leadership`] and takes over
+//! when the lock is released, or when the active instance dies.
+//! [`SpoolingClient::leader_lock`](crate::spool::SpoolingClient::leader_lock) sets a lock on a
+//! spool shared by the instances, which only the holder drains.
+//!
+//! [`FileLock`] uses an OS file lock, which is released automatically when the holding process
+//! exits; it suits instances sharing a filesystem. Instances on separate hosts implement the
+//! trait on top of an external store, e.g. a Redis key set with `SET <key> <instance> NX PX <ttl>`
+//! and renewed by the leader while it runs.
+
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Exclusive lock held by the active sender instance.
+pub trait LeaderLock: Send + Sync {
+    /// Tries to take the lock without blocking. Returns `true` if this instance holds the lock,
+    /// including when it already held it.
+    ///
+    /// The holder calls it again before each piece of work, such as each message sent, so that
+    /// a lock with an expiry renews it, and is found lost once it expired.
+    fn try_acquire(&self) -> io::Result<bool>;
+
+    /// Releases the lock, letting a standby instance take over.
+    fn release(&self) -> io::Result<()>;
+}
+
+impl<L: LeaderLock + ?Sized> LeaderLock for Arc<L> {
+    fn try_acquire(&self) -> io::Result<bool> {
+        (**self).try_acquire()
+    }
+
+    fn release(&self) -> io::Result<()> {
+        (**self).release()
+    }
+}
+
+/// Blocks until `lock` is acquired, trying again every `poll_interval`.
+pub fn wait_for_leadership<L: LeaderLock + ?Sized>(lock: &L, poll_interval: Duration) -> io::Result<()> {
+    while !lock.try_acquire()? {
+        thread::sleep(poll_interval);
+    }
+
+    Ok(())
+}
+
+/// Lock on a file, shared by instances running on the same host or on a shared filesystem.
+/// ```no_run
+/// use std::time::Duration;
+/// use mllp_rs::leader::{wait_for_leadership, FileLock};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let lock = FileLock::new("/var/spool/mllp/sender.lock");
+/// wait_for_leadership(&lock, Duration::from_secs(5))?;
+/// // this instance is now the active one, drain the queue
+/// # Ok(())
+/// # }
+/// ```
+pub struct FileLock {
+    path: PathBuf,
+    held: Mutex<Option<File>>,
+}
+
+impl FileLock {
+    /// Creates a lock on the file at `path`. The file is created when the lock is first acquired.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileLock {
+            path: path.as_ref().to_owned(),
+            held: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<File>> {
+        self.held.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl LeaderLock for FileLock {
+    fn try_acquire(&self) -> io::Result<bool> {
+        let mut held = self.lock();
+        if held.is_some() {
+            return Ok(true);
+        }
+
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(&self.path)?;
+        match file.try_lock() {
+            Ok(()) => {
+                *held = Some(file);
+                Ok(true)
+            }
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    fn release(&self) -> io::Result<()> {
+        match self.lock().take() {
+            Some(file) => file.unlock(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::leader::{wait_for_leadership, FileLock, LeaderLock};
+
+    fn lock_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("mllp-rs-{}-{}.lock", name, std::process::id()))
+    }
+
+    #[test]
+    fn only_one_instance_holds_the_lock() {
+        let path = lock_path("exclusive");
+        let active = FileLock::new(&path);
+        let standby = FileLock::new(&path);
+
+        assert!(active.try_acquire().unwrap());
+        assert!(active.try_acquire().unwrap());
+        assert!(!standby.try_acquire().unwrap());
+
+        active.release().unwrap();
+        assert!(standby.try_acquire().unwrap());
+        assert!(!active.try_acquire().unwrap());
+
+        standby.release().unwrap();
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn standby_takes_over_when_lock_is_released() {
+        let path = lock_path("takeover");
+        let active = Arc::new(FileLock::new(&path));
+        assert!(active.try_acquire().unwrap());
+
+        let standby = thread::spawn({
+            let path = path.clone();
+            move || {
+                let standby = FileLock::new(path);
+                wait_for_leadership(&standby, Duration::from_millis(10)).unwrap();
+                standby.release().unwrap();
+            }
+        });
+        thread::sleep(Duration::from_millis(50));
+        active.release().unwrap();
+
+        standby.join().unwrap();
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,220 @@
+//! Unique message-ID ledger.
+//!
+//! A ledger remembers which message IDs (typically the MSH-10 control ID, possibly prefixed with
+//! the sending application) have been seen recently, so that retransmitted messages can be
+//! recognised as duplicates. A [`Deduplicator`](crate::dedup::Deduplicator) keeps its window in
+//! one.
+//!
+//! [`MessageLedger`] is the extension point. [`InMemoryLedger`] keeps the window in the current
+//! process, which is fine for a single receiver. When several receivers run behind a load
+//! balancer, implement the trait on top of a shared store so they all use one window:
+//! - Redis: `record` maps to `SET <id> 1 NX EX <ttl>`, `contains` to `EXISTS`, `forget` to `DEL`.
+//! - SQL: `record` maps to an `INSERT` on a table with a unique constraint on the ID, a
+//!   constraint violation meaning the ID is a duplicate.
+//!
+//! ```
+//! use std::collections::HashSet;
+//! use std::io;
+//! use std::sync::Mutex;
+//! use mllp_rs::dedup::Deduplicator;
+//! use mllp_rs::ledger::MessageLedger;
+//!
+//! /// Stand-in for a client of an external store.
+//! struct SharedLedger {
+//!     ids: Mutex<HashSet<String>>,
+//! }
+//!
+//! impl MessageLedger for SharedLedger {
+//!     fn record(&self, id: &str) -> io::Result<bool> {
+//!         Ok(self.ids.lock().unwrap().insert(id.to_owned()))
+//!     }
+//!
+//!     fn contains(&self, id: &str) -> io::Result<bool> {
+//!         Ok(self.ids.lock().unwrap().contains(id))
+//!     }
+//!
+//!     fn forget(&self, id: &str) -> io::Result<()> {
+//!         self.ids.lock().unwrap().remove(id);
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let dedup = Deduplicator::with_ledger(Box::new(SharedLedger { ids: Mutex::default() }));
+//! ```
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+/// Storage for the IDs of recently seen messages.
+///
+/// Implementations must be safe to share between connections, hence the `Send + Sync` bound.
+/// Errors from the underlying store are reported as [`io::Error`]s.
+pub trait MessageLedger: Send + Sync {
+    /// Records `id`. Returns `true` if the ID was new, `false` if it was already in the ledger.
+    ///
+    /// The check and the insertion must happen atomically, otherwise two receivers could both
+    /// accept the same message.
+    fn record(&self, id: &str) -> io::Result<bool>;
+
+    /// Returns whether `id` is in the ledger.
+    fn contains(&self, id: &str) -> io::Result<bool>;
+
+    /// Removes `id` from the ledger, so that a retransmission of the message is accepted again.
+    fn forget(&self, id: &str) -> io::Result<()>;
+}
+
+impl fmt::Debug for dyn MessageLedger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MessageLedger")
+    }
+}
+
+impl<L: MessageLedger + ?Sized> MessageLedger for Arc<L> {
+    fn record(&self, id: &str) -> io::Result<bool> {
+        (**self).record(id)
+    }
+
+    fn contains(&self, id: &str) -> io::Result<bool> {
+        (**self).contains(id)
+    }
+
+    fn forget(&self, id: &str) -> io::Result<()> {
+        (**self).forget(id)
+    }
+}
+
+/// Process-local ledger keeping the last `capacity` IDs.
+///
+/// When the ledger is full, recording a new ID evicts the oldest one.
+/// ```
+/// use mllp_rs::ledger::{InMemoryLedger, MessageLedger};
+///
+/// let ledger = InMemoryLedger::new(1000);
+/// assert!(ledger.record("MSG00001").unwrap());
+/// assert!(!ledger.record("MSG00001").unwrap());
+/// ```
+pub struct InMemoryLedger {
+    capacity: usize,
+    window: Mutex<Window>,
+}
+
+#[derive(Default)]
+struct Window {
+    order: VecDeque<String>,
+    ids: HashSet<String>,
+}
+
+impl InMemoryLedger {
+    pub fn new(capacity: usize) -> Self {
+        InMemoryLedger {
+            capacity,
+            window: Mutex::new(Window::default()),
+        }
+    }
+
+    /// Number of IDs currently in the ledger.
+    pub fn len(&self) -> usize {
+        self.lock().ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Window> {
+        // The window is always left consistent, so a poisoned lock is still usable.
+        self.window.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl MessageLedger for InMemoryLedger {
+    fn record(&self, id: &str) -> io::Result<bool> {
+        if self.capacity == 0 {
+            return Ok(true);
+        }
+
+        let mut window = self.lock();
+        if window.ids.contains(id) {
+            return Ok(false);
+        }
+
+        while window.order.len() >= self.capacity {
+            if let Some(oldest) = window.order.pop_front() {
+                window.ids.remove(&oldest);
+            }
+        }
+        window.order.push_back(id.to_owned());
+        window.ids.insert(id.to_owned());
+
+        Ok(true)
+    }
+
+    fn contains(&self, id: &str) -> io::Result<bool> {
+        Ok(self.lock().ids.contains(id))
+    }
+
+    fn forget(&self, id: &str) -> io::Result<()> {
+        let mut window = self.lock();
+        if window.ids.remove(id) {
+            window.order.retain(|known| known != id);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use crate::ledger::{InMemoryLedger, MessageLedger};
+
+    #[test]
+    fn it_detects_duplicates() {
+        let ledger = InMemoryLedger::new(10);
+
+        assert!(ledger.record("1").unwrap());
+        assert!(ledger.record("2").unwrap());
+        assert!(!ledger.record("1").unwrap());
+        assert_eq!(ledger.len(), 2);
+    }
+
+    #[test]
+    fn it_evicts_oldest_id_when_full() {
+        let ledger = InMemoryLedger::new(2);
+
+        ledger.record("1").unwrap();
+        ledger.record("2").unwrap();
+        ledger.record("3").unwrap();
+
+        assert!(!ledger.contains("1").unwrap());
+        assert!(ledger.contains("2").unwrap());
+        assert!(ledger.contains("3").unwrap());
+    }
+
+    #[test]
+    fn it_accepts_forgotten_id_again() {
+        let ledger = InMemoryLedger::new(10);
+
+        ledger.record("1").unwrap();
+        ledger.forget("1").unwrap();
+
+        assert!(ledger.record("1").unwrap());
+    }
+
+    #[test]
+    fn shared_ledger_accepts_id_once() {
+        let ledger = Arc::new(InMemoryLedger::new(10));
+        let handlers: Vec<_> = (0..4)
+            .map(|_| {
+                let ledger = ledger.clone();
+                thread::spawn(move || ledger.record("1").unwrap())
+            })
+            .collect();
+
+        let accepted = handlers.into_iter().map(|h| h.join().unwrap()).filter(|new| *new).count();
+        assert_eq!(accepted, 1);
+    }
+}
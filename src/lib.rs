@@ -9,6 +9,7 @@
 //! - SB is the Start Block Character, 0x0B.
 //! - EB is the End Block Character, 0x1C.
 //! - CR is the Carriage Return Character, 0x0D.
+//!
 //! This is called the Block Format.
 //!
 //! MLLP contains 2 other formats, the Commit Acknowledgement
@@ -20,33 +21,153 @@
 //! # Quick start
 //!
 //! Client side code might look like this:
-//! ```
+//! ```no_run
 //! use std::io::prelude::*;
 //! use std::net::TcpStream;
 //! use mllp_rs::MllpCodec;
 //!
+//! # fn main() -> std::io::Result<()> {
 //! // Client side
 //! let mut stream = TcpStream::connect("127.0.0.1:5000")?;
-//! let _ = stream.write(MllpCodec::encode("MSH|^~\&|WIR|||36|20200514123930||VXU^V04^VXU_V04|43|P|2.5.1|||ER".as_bytes()).as_bytes());
+//! let _ = stream.write(MllpCodec::encode("MSH|^~\\&|WIR|||36|20200514123930||VXU^V04^VXU_V04|43|P|2.5.1|||ER".as_bytes()).as_slice());
+//! # Ok(())
+//! # }
 //! ```
 //!
 //! Server side code might look like this:
-//! ```
+//! ```no_run
 //! use std::io::prelude::*;
 //! use std::net::TcpListener;
 //! use mllp_rs::MllpCodec;
 //!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let addr = "127.0.0.1:5000";
 //! let mut listener = TcpListener::bind(addr).unwrap();
 //! for stream in listener.incoming() {
 //!     let mut buf: Vec<u8> = vec![];
 //!     let _ = stream?.read_to_end(&mut buf);
 //!     let decoded_data = String::from_utf8_lossy(MllpCodec::decode(buf.as_slice())?);
 //! }
+//! # Ok(())
+//! # }
 //! ```
+//!
+//! # `no_std`
+//!
+//! Without the default `std` feature, only the framing is built, over `core` and `alloc`:
+//! [`MllpCodec`], [`MllpDecoder`] fed with [`MllpDecoder::extend`], [`FrameDisplay`],
+//! [`batch`] and [`charset`], for devices without an operating system and for
+//! `wasm32-unknown-unknown`, such as a browser tool decoding the frames received over a
+//! WebSocket. Every other feature needs `std`.
 
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
 extern crate core;
 
-use std::fmt;
+#[cfg(all(test, feature = "std"))]
+mod api;
+
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "std")]
+pub mod audit;
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod capture;
+pub mod charset;
+#[cfg(feature = "std")]
+pub mod client;
+mod codec;
+#[cfg(feature = "std")]
+mod clock;
+#[cfg(feature = "unstable")]
+pub mod cluster;
+#[cfg(feature = "std")]
+pub mod commit;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod dead_letter;
+#[cfg(feature = "std")]
+pub mod dedup;
+mod decoder;
+#[cfg(feature = "std")]
+pub mod discovery;
+mod display;
+#[cfg(feature = "std")]
+pub mod duplex;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "std")]
+pub mod event;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod handler;
+#[cfg(feature = "hl7")]
+pub mod hl7;
+#[cfg(feature = "std")]
+pub mod interceptor;
+#[cfg(feature = "std")]
+pub mod journal;
+#[cfg(feature = "unstable")]
+pub mod leader;
+#[cfg(feature = "std")]
+pub mod ledger;
+#[cfg(feature = "std")]
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod pool;
+#[cfg(feature = "std")]
+pub mod proxy;
+#[cfg(feature = "std")]
+pub mod rate_limit;
+#[cfg(feature = "std")]
+pub mod reconnect;
+#[cfg(feature = "std")]
+pub mod sequence;
+#[cfg(feature = "std")]
+pub mod server;
+#[cfg(feature = "std")]
+pub mod sink;
+#[cfg(feature = "std")]
+pub mod spool;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "futures")]
+pub mod stream;
+#[cfg(feature = "std")]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod timeline;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "std")]
+mod trace;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod tuning;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+#[cfg(feature = "std")]
+use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::hash::{BuildHasher, Hasher};
+#[cfg(feature = "std")]
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+pub use codec::{FrameError, LossyFrame, LowerLayerCodec, MllpCodec, MllpSyntaxError, ReservedByte, SanitizePolicy};
+pub use decoder::{Frames, MllpDecoder};
+pub use display::FrameDisplay;
+#[cfg(feature = "std")]
+pub use error::{MllpError, Timeout};
 
 /// Start Block
 const SB: u8 = 11u8;
@@ -58,76 +179,102 @@ const ACK: u8 = 6u8;
 /// Negative ACK
 const NAK: u8 = 15u8;
 
-pub struct MllpCodec { }
+/// Address given to both ends of a connection over a Unix domain socket or in memory, which
+/// have none.
+#[cfg(feature = "std")]
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
 
-impl MllpCodec {
-    pub fn encode(with: &[u8]) -> Vec<u8> {
-        let mut buf: Vec<u8> = vec![];
+/// Acknowledgement frames exchanged for each message, agreed with the trading partner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckMode {
+    /// Only the MLLP commit acknowledgement, `<SB><ACK><EB><CR>` or `<SB><NAK><EB><CR>`.
+    TransportOnly,
+    /// Only the application acknowledgement, usually an HL7 ACK message.
+    ApplicationOnly,
+    /// A commit acknowledgement, then an application acknowledgement.
+    Both,
+    /// No acknowledgement at all.
+    None,
+}
 
-        buf.push(SB);
-        buf.extend(with.iter());
-        buf.push(EB);
-        buf.push(CR);
+/// MSH-10 message control ID of an HL7 v2 message.
+#[cfg(feature = "std")]
+fn control_id(payload: &[u8]) -> Option<String> {
+    let separator = *payload.strip_prefix(b"MSH")?.first()?;
+    let segment = payload.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let control_id = segment.split(|b| *b == separator).nth(9)?;
 
-        buf
-    }
+    (!control_id.is_empty()).then(|| String::from_utf8_lossy(control_id).into_owned())
+}
 
-    pub fn decode(with: &[u8]) -> Result<&[u8], MllpSyntaxError> {
-        assert!(with.len() >= 4);
+/// MSH-3 sending application and MSH-4 sending facility of an HL7 v2 message, as `LAB|NORTH`.
+#[cfg(feature = "std")]
+fn sender(payload: &[u8]) -> Option<String> {
+    let separator = *payload.strip_prefix(b"MSH")?.first()?;
+    let segment = payload.split(|b| *b == b'\r' || *b == b'\n').next()?;
+    let fields: Vec<&[u8]> = segment.split(|b| *b == separator).collect();
+    let field = |index: usize| String::from_utf8_lossy(fields.get(index).copied().unwrap_or_default()).into_owned();
 
-        let sb = with[0];
-        let hl7 = &with[1..with.len() - 2];
-        let eb = with[with.len() - 2];
-        let cr = with[with.len() - 1];
+    Some(format!("{}|{}", field(2), field(3)))
+}
 
-        if sb == SB && eb == EB && cr == CR {
-            Ok(hl7)
-        } else {
-            Err(MllpSyntaxError)
-        }
-    }
+/// HL7 ACK answering `message`, an HL7 v2 message, with the acknowledgment `code`, and `expected`
+/// in MSA-4 if set.
+#[cfg(feature = "std")]
+fn acknowledgement(message: &[u8], code: &str, expected: Option<i64>) -> Vec<u8> {
+    let separator = message[3];
+    let msh = message.split(|b| *b == b'\r' || *b == b'\n').next().unwrap_or_default();
+    let fields: Vec<&[u8]> = msh.split(|b| *b == separator).collect();
+    let field = |index: usize| fields.get(index).copied().unwrap_or_default();
+    let timestamp = clock::hl7_timestamp(std::time::SystemTime::now());
 
-    /// Creates an MLLP ACK.
-    /// ```
-    /// use mllp_rs::MllpCodec;
-    ///
-    /// let ack = MllpCodec::ack();
-    /// ```
-    pub fn ack() -> [u8;4] {
-        [SB, ACK, EB, CR]
-    }
+    let msh: [&[u8]; 12] = [b"MSH", field(1), field(4), field(5), field(2), field(3), timestamp.as_bytes(), b"", b"ACK", field(9), field(10), field(11)];
+    let expected = expected.map(|expected| expected.to_string()).unwrap_or_default();
+    let msa: [&[u8]; 5] = [b"MSA", code.as_bytes(), field(9), b"", expected.as_bytes()];
+    let msa = match expected.is_empty() {
+        true => &msa[..3],
+        false => &msa[..],
+    };
 
-    /// Creates an MLLP NAK (Negative ACK).
-    /// ```
-    /// use mllp_rs::MllpCodec;
-    ///
-    /// let nak = MllpCodec::nak();
-    /// ```
-    pub fn nak() -> [u8;4] {
-        [SB, NAK, EB, CR]
-    }
+    [msh.join(&separator), msa.join(&separator)].join(&b'\r')
+}
 
-    pub fn is_ack(with: &[u8]) -> bool {
-        with == Self::ack()
-    }
+/// MSA-2 control ID of the message an application acknowledgement answers.
+#[cfg(feature = "std")]
+fn acknowledged_id(ack: &[u8]) -> Option<String> {
+    let separator = *ack.strip_prefix(b"MSH")?.first()?;
+    let msa = ack.split(|b| *b == b'\r' || *b == b'\n').find(|segment| segment.starts_with(b"MSA"))?;
+    let control_id = msa.split(|b| *b == separator).nth(2)?;
 
-    pub fn is_nak(with: &[u8]) -> bool {
-        with == Self::nak()
-    }
+    (!control_id.is_empty()).then(|| String::from_utf8_lossy(control_id).into_owned())
 }
 
-#[derive(Debug)]
-pub struct MllpSyntaxError;
+/// Standard base64 encoding, with padding.
+#[cfg(feature = "std")]
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
 
-impl fmt::Display for MllpSyntaxError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Expected bytes <SB>...<EB><CR>")
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char),
+                false => encoded.push('='),
+            }
+        }
     }
+
+    encoded
 }
 
-impl std::error::Error for MllpSyntaxError { }
+/// Random number, good enough to spread load, not for cryptography.
+#[cfg(feature = "std")]
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use std::io::{Read, Write};
     use std::net::{SocketAddr, TcpListener, TcpStream};
@@ -136,20 +283,10 @@ mod tests {
     use std::time::Duration;
     use crate::MllpCodec;
 
-    #[test]
-    fn encode_and_decode_same_message() {
-        let data = "MSH|^~\\&|ZIS|1^AHospital|||200405141144||¶ADT^A01|20041104082400|P|2.3|||AL|NE|||8859/15|¶EVN|A01|20041104082400.0000+0100|20041104082400¶PID||\"\"|10||Vries^Danny^D.^^de||19951202|M|||Rembrandlaan^7^Leiden^^7301TH^\"\"^^P||\"\"|\"\"||\"\"|||||||\"\"|\"\"¶PV1||I|3w^301^\"\"^01|S|||100^van den Berg^^A.S.^^\"\"^dr|\"\"||9||||H||||20041104082400.0000+0100";
-        let encoded_data = MllpCodec::encode(data.as_bytes());
-        let decoded_data = MllpCodec::decode(encoded_data.as_slice());
-
-        assert!(decoded_data.is_ok());
-        assert_eq!(decoded_data.unwrap(), data.as_bytes());
-    }
-
     #[test]
     fn listen_and_receive_mllp_packet() {
         let data = "MSH|^~\\&|ZIS|1^AHospital|||200405141144||¶ADT^A01|20041104082400|P|2.3|||AL|NE|||8859/15|¶EVN|A01|20041104082400.0000+0100|20041104082400¶PID||\"\"|10||Vries^Danny^D.^^de||19951202|M|||Rembrandlaan^7^Leiden^^7301TH^\"\"^^P||\"\"|\"\"||\"\"|||||||\"\"|\"\"¶PV1||I|3w^301^\"\"^01|S|||100^van den Berg^^A.S.^^\"\"^dr|\"\"||9||||H||||20041104082400.0000+0100";
-        let original_data = data.clone();
+        let original_data = data;
         let addr = "127.0.0.1:5000";
         let (tx, rx) = mpsc::channel();
 
@@ -157,13 +294,12 @@ mod tests {
             let listener = TcpListener::bind(addr).unwrap();
             tx.send(true).unwrap();
 
-            for stream in listener.incoming() {
+            if let Some(stream) = listener.incoming().next() {
                 assert!(stream.is_ok());
                 let mut buf: Vec<u8> = vec![];
                 let _ = stream.unwrap().read_to_end(&mut buf);
                 let decoded_data = String::from_utf8_lossy(MllpCodec::decode(buf.as_slice()).unwrap());
                 assert_eq!(decoded_data, data);
-                break;
             }
             // close the socket server
             drop(listener);
@@ -183,16 +319,4 @@ mod tests {
         handler2.join().expect("TODO: panic message server");
         handler.join().expect("TODO: panic message listener");
     }
-
-    #[test]
-    fn it_creates_ack() {
-        let ack = MllpCodec::ack();
-        assert!(MllpCodec::is_ack(&ack));
-    }
-
-    #[test]
-    fn it_creates_nak() {
-        let nak = MllpCodec::nak();
-        assert!(MllpCodec::is_nak(&nak));
-    }
 }
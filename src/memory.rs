@@ -0,0 +1,257 @@
+//! In-memory transport: connections between a client and a server of the same process, neither
+//! opening a socket.
+//!
+//! [`duplex`] returns two connected [`DuplexStream`]s: what is written to one is read from the
+//! other. They are blocking `Read` and `Write` streams with read timeouts and shutdowns, like
+//! `TcpStream`s. A [`MemoryListener`] accepts the connections made with its [`MemoryConnector`],
+//! and serves an [`MllpServer`](crate::server::MllpServer) reached by an
+//! [`MllpClient`](crate::client::MllpClient).
+//!
+//! In-memory connections have no address, and are given the unspecified address `0.0.0.0`, like
+//! those over a Unix domain socket. Bytes written are buffered until read, without limit.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Returns two connected in-memory streams.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+    let (first, second) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+
+    (DuplexStream::new(first.clone(), second.clone()), DuplexStream::new(second, first))
+}
+
+/// Bytes going one way, from a stream to its peer.
+#[derive(Debug, Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PipeState {
+    bytes: VecDeque<u8>,
+    /// No more bytes go through: the writing side shut down, or the reading side.
+    closed: bool,
+}
+
+impl Pipe {
+    fn lock(&self) -> MutexGuard<'_, PipeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.readable.notify_all();
+    }
+}
+
+/// One end of an in-memory connection, made with [`duplex`].
+///
+/// Reading blocks until the peer writes, shuts down writing or is dropped, or until the read
+/// timeout, after which an [`io::ErrorKind::WouldBlock`] error is returned. Writing to a peer
+/// which shut down reading, or was dropped, fails with [`io::ErrorKind::BrokenPipe`].
+#[derive(Debug)]
+pub struct DuplexStream {
+    incoming: Arc<Pipe>,
+    outgoing: Arc<Pipe>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+impl DuplexStream {
+    fn new(incoming: Arc<Pipe>, outgoing: Arc<Pipe>) -> Self {
+        DuplexStream {
+            incoming,
+            outgoing,
+            read_timeout: Mutex::new(None),
+        }
+    }
+
+    /// Sets how long reading waits for bytes, `None` waiting forever.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::ZERO) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero read timeout"));
+        }
+        *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Shuts down reading, writing or both. The peer reads the end of the stream once writing is
+    /// shut down.
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            self.incoming.close();
+        }
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.outgoing.close();
+        }
+        Ok(())
+    }
+
+    /// Whether bytes may still be read: some are waiting, or the peer may write more.
+    pub(crate) fn is_open(&self) -> bool {
+        let incoming = self.incoming.lock();
+        !incoming.closed || !incoming.bytes.is_empty()
+    }
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.read_timeout()?.map(|timeout| Instant::now() + timeout);
+        let mut incoming = self.incoming.lock();
+
+        while incoming.bytes.is_empty() && !incoming.closed {
+            incoming = match deadline {
+                Some(deadline) => {
+                    let Some(remaining) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) else {
+                        return Err(io::Error::from(io::ErrorKind::WouldBlock));
+                    };
+                    self.incoming.readable.wait_timeout(incoming, remaining).unwrap_or_else(|e| e.into_inner()).0
+                }
+                None => self.incoming.readable.wait(incoming).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+
+        let len = buf.len().min(incoming.bytes.len());
+        for (byte, read) in buf.iter_mut().zip(incoming.bytes.drain(..len)) {
+            *byte = read;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut outgoing = self.outgoing.lock();
+        if outgoing.closed {
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        outgoing.bytes.extend(buf);
+        drop(outgoing);
+        self.outgoing.readable.notify_all();
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+/// Listener of in-memory connections, made with its [`MemoryConnector`].
+#[derive(Debug)]
+pub struct MemoryListener {
+    connector: MemoryConnector,
+    incoming: Mutex<mpsc::Receiver<DuplexStream>>,
+}
+
+impl MemoryListener {
+    pub fn new() -> Self {
+        let (sender, incoming) = mpsc::channel();
+
+        MemoryListener {
+            connector: MemoryConnector { sender },
+            incoming: Mutex::new(incoming),
+        }
+    }
+
+    /// Returns a handle opening connections to this listener.
+    pub fn connector(&self) -> MemoryConnector {
+        self.connector.clone()
+    }
+
+    /// Waits for a connection and returns its end of it.
+    pub fn accept(&self) -> io::Result<DuplexStream> {
+        let incoming = self.incoming.lock().unwrap_or_else(|e| e.into_inner());
+        // the listener holds a connector of its own, the channel is never disconnected
+        incoming.recv().map_err(|_| io::Error::from(io::ErrorKind::ConnectionAborted))
+    }
+}
+
+impl Default for MemoryListener {
+    fn default() -> Self {
+        MemoryListener::new()
+    }
+}
+
+/// Handle opening connections to a [`MemoryListener`].
+#[derive(Debug, Clone)]
+pub struct MemoryConnector {
+    sender: mpsc::Sender<DuplexStream>,
+}
+
+impl MemoryConnector {
+    /// Opens a connection, accepted by the listener, and returns this end of it. Fails with
+    /// [`io::ErrorKind::ConnectionRefused`] once the listener is dropped.
+    pub fn connect(&self) -> io::Result<DuplexStream> {
+        let (stream, peer) = duplex();
+        self.sender.send(peer).map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::net::Shutdown;
+    use std::thread;
+    use std::time::Duration;
+    use crate::memory::{duplex, MemoryListener};
+
+    #[test]
+    fn it_carries_bytes_both_ways() {
+        let (mut left, mut right) = duplex();
+        left.write_all(b"MSH|1").unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(right.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"MSH|1");
+
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            left.read_to_end(&mut received).unwrap();
+            received
+        });
+        right.write_all(b"ACK").unwrap();
+        right.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(reader.join().unwrap(), b"ACK");
+        assert_eq!(right.write(b"late").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn it_times_out_reading() {
+        let (mut left, _right) = duplex();
+        left.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        assert_eq!(left.read(&mut [0u8; 4]).unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn it_refuses_connections_once_the_listener_is_dropped() {
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+        let mut client = connector.connect().unwrap();
+        let mut accepted = listener.accept().unwrap();
+        client.write_all(b"MSH|").unwrap();
+        let mut buf = [0u8; 4];
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"MSH|");
+
+        drop(listener);
+        assert_eq!(connector.connect().unwrap_err().kind(), ErrorKind::ConnectionRefused);
+    }
+}
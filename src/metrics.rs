@@ -0,0 +1,311 @@
+//! Counters and histograms of the client and server connections.
+//!
+//! [`MllpClientConfig::metrics`](crate::client::MllpClientConfig::metrics) and
+//! [`MllpServerConfig::metrics`](crate::server::MllpServerConfig::metrics) take a [`Metrics`]
+//! implementation, called on the connection's thread each time a [`Counter`] is incremented or
+//! a [`Histogram`] observes a value. With the `prometheus` feature, [`PrometheusMetrics`] keeps
+//! them in memory and renders them in the Prometheus text format.
+#![cfg_attr(feature = "prometheus", doc = r#"```no_run
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::thread;
+use mllp_rs::metrics::PrometheusMetrics;
+use mllp_rs::server::MllpServerConfig;
+
+# fn main() -> std::io::Result<()> {
+let metrics = Arc::new(PrometheusMetrics::new());
+let exporter = metrics.clone();
+let listener = TcpListener::bind("0.0.0.0:9102")?;
+thread::spawn(move || exporter.serve(listener));
+
+let config = MllpServerConfig {
+    metrics: Some(metrics),
+    ..MllpServerConfig::default()
+};
+# Ok(())
+# }
+```"#)]
+
+use std::fmt;
+#[cfg(feature = "prometheus")]
+use std::fmt::Write as _;
+#[cfg(feature = "prometheus")]
+use std::io::{self, Write};
+#[cfg(feature = "prometheus")]
+use std::net::TcpListener;
+#[cfg(feature = "prometheus")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "prometheus")]
+use std::sync::Mutex;
+#[cfg(feature = "prometheus")]
+use std::time::Duration;
+
+/// Count of occurrences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Counter {
+    /// A message was written by the client, retransmissions included.
+    MessagesSent,
+    /// A message was received by the server.
+    MessagesReceived,
+    /// A commit ACK was received by the client.
+    AcksReceived,
+    /// A commit NAK was received by the client.
+    NaksReceived,
+    /// A commit ACK was written by the server.
+    AcksSent,
+    /// A commit NAK was written by the server.
+    NaksSent,
+    /// Bytes were received outside of a frame.
+    DecodeErrors,
+    /// A connection was opened by the client, or accepted by the server.
+    ConnectionsOpened,
+    /// A connection was closed, by either side. Open connections are the opened ones minus the
+    /// closed ones.
+    ConnectionsClosed,
+    /// A connection was refused by the server, for its peer or for being over capacity.
+    ConnectionsRejected,
+}
+
+/// Distribution of values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Histogram {
+    /// Size in bytes of the frames of the messages sent or received, framing included.
+    FrameSize,
+    /// Seconds between writing a message and receiving its acknowledgement, on the client.
+    AckLatency,
+}
+
+/// Receiver of the metrics.
+///
+/// Called synchronously on the connection's thread, so it should return quickly.
+pub trait Metrics: Send + Sync {
+    fn increment(&self, counter: Counter);
+
+    fn observe(&self, histogram: Histogram, value: f64);
+}
+
+impl fmt::Debug for dyn Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Metrics")
+    }
+}
+
+#[cfg(feature = "prometheus")]
+const COUNTERS: [(Counter, &str, &str); 10] = [
+    (Counter::MessagesSent, "mllp_messages_sent_total", "Messages written by the client."),
+    (Counter::MessagesReceived, "mllp_messages_received_total", "Messages received by the server."),
+    (Counter::AcksReceived, "mllp_acks_received_total", "Commit ACKs received by the client."),
+    (Counter::NaksReceived, "mllp_naks_received_total", "Commit NAKs received by the client."),
+    (Counter::AcksSent, "mllp_acks_sent_total", "Commit ACKs written by the server."),
+    (Counter::NaksSent, "mllp_naks_sent_total", "Commit NAKs written by the server."),
+    (Counter::DecodeErrors, "mllp_decode_errors_total", "Bytes received outside of a frame."),
+    (Counter::ConnectionsOpened, "mllp_connections_opened_total", "Connections opened or accepted."),
+    (Counter::ConnectionsClosed, "mllp_connections_closed_total", "Connections closed."),
+    (Counter::ConnectionsRejected, "mllp_connections_rejected_total", "Connections refused by the server."),
+];
+
+#[cfg(feature = "prometheus")]
+const FRAME_SIZE_BUCKETS: [f64; 7] = [256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0];
+#[cfg(feature = "prometheus")]
+const ACK_LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// [`Metrics`] kept in memory, for a Prometheus server to scrape.
+///
+/// Besides the counters and histograms, `mllp_open_connections` gauges the connections open.
+#[cfg(feature = "prometheus")]
+#[derive(Debug)]
+pub struct PrometheusMetrics {
+    counters: [AtomicU64; COUNTERS.len()],
+    frame_size: Mutex<Buckets>,
+    ack_latency: Mutex<Buckets>,
+}
+
+/// Cumulative counts of a histogram.
+#[cfg(feature = "prometheus")]
+#[derive(Debug)]
+struct Buckets {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[cfg(feature = "prometheus")]
+impl Buckets {
+    fn new(bounds: &'static [f64]) -> Self {
+        Buckets {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, self.sum, name, self.count);
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        PrometheusMetrics {
+            counters: Default::default(),
+            frame_size: Mutex::new(Buckets::new(&FRAME_SIZE_BUCKETS)),
+            ack_latency: Mutex::new(Buckets::new(&ACK_LATENCY_BUCKETS)),
+        }
+    }
+
+    pub fn get(&self, counter: Counter) -> u64 {
+        COUNTERS
+            .iter()
+            .position(|(known, _, _)| *known == counter)
+            .map_or(0, |index| self.counters[index].load(Ordering::Relaxed))
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for ((_, name, help), value) in COUNTERS.iter().zip(&self.counters) {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value.load(Ordering::Relaxed));
+        }
+        let open = self.get(Counter::ConnectionsOpened).saturating_sub(self.get(Counter::ConnectionsClosed));
+        let _ = writeln!(out, "# HELP mllp_open_connections Connections open.\n# TYPE mllp_open_connections gauge");
+        let _ = writeln!(out, "mllp_open_connections {}", open);
+
+        self.frame_size.lock().unwrap_or_else(|e| e.into_inner()).render(
+            &mut out,
+            "mllp_frame_size_bytes",
+            "Size of the frames sent or received.",
+        );
+        self.ack_latency.lock().unwrap_or_else(|e| e.into_inner()).render(
+            &mut out,
+            "mllp_ack_latency_seconds",
+            "Time from writing a message to receiving its acknowledgement.",
+        );
+        out
+    }
+
+    /// Answers each HTTP request made on `listener` with the metrics, whatever its path, one
+    /// connection at a time. Only returns if accepting fails.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (mut stream, _) = crate::server::accept_retrying(|| listener.accept())?;
+            let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+            // a failing scrape does not stop the exporter
+            let _ = crate::proxy::read_head(&mut stream).and_then(|_| {
+                let body = self.render();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            });
+        }
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        PrometheusMetrics::new()
+    }
+}
+
+#[cfg(feature = "prometheus")]
+impl Metrics for PrometheusMetrics {
+    fn increment(&self, counter: Counter) {
+        if let Some(index) = COUNTERS.iter().position(|(known, _, _)| *known == counter) {
+            self.counters[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        let buckets = match histogram {
+            Histogram::FrameSize => &self.frame_size,
+            Histogram::AckLatency => &self.ack_latency,
+        };
+        buckets.lock().unwrap_or_else(|e| e.into_inner()).observe(value);
+    }
+}
+
+#[cfg(all(test, feature = "prometheus"))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use crate::client::{MllpClient, MllpClientConfig};
+    use crate::handler::AckDecision;
+    use crate::metrics::{Counter, PrometheusMetrics};
+    use crate::server::{MllpServer, MllpServerConfig};
+
+    #[test]
+    fn it_counts_messages_on_both_sides() {
+        let client_metrics = Arc::new(PrometheusMetrics::new());
+        let server_metrics = Arc::new(PrometheusMetrics::new());
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            metrics: Some(server_metrics.clone()),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+
+        let mut client = MllpClient::connect_with_config(addr, MllpClientConfig {
+            metrics: Some(client_metrics.clone()),
+            ..MllpClientConfig::default()
+        })
+        .unwrap();
+        client.send(b"MSH|1").unwrap();
+        client.send(b"MSH|2").unwrap();
+        drop(client);
+
+        assert_eq!(client_metrics.get(Counter::MessagesSent), 2);
+        assert_eq!(client_metrics.get(Counter::AcksReceived), 2);
+        assert_eq!(client_metrics.get(Counter::ConnectionsClosed), 1);
+        assert_eq!(server_metrics.get(Counter::MessagesReceived), 2);
+        assert_eq!(server_metrics.get(Counter::AcksSent), 2);
+        let rendered = client_metrics.render();
+        assert!(rendered.contains("mllp_open_connections 0\n"), "{}", rendered);
+        assert!(rendered.contains("mllp_frame_size_bytes_bucket{le=\"256\"} 2\n"), "{}", rendered);
+        assert!(rendered.contains("mllp_ack_latency_seconds_count 2\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn it_serves_metrics_over_http() {
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let exporter = metrics.clone();
+        thread::spawn(move || exporter.serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&metrics.render()));
+    }
+}
@@ -0,0 +1,635 @@
+//! Pool of persistent outbound connections.
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::client::{Ack, MllpClient, MllpClientConfig};
+#[cfg(feature = "tls")]
+use crate::tls::HandshakeStats;
+use crate::MllpError;
+
+/// Thread-safe pool keeping up to `max_connections` persistent connections per destination.
+///
+/// Connections are checked out with [`MllpConnectionPool::get`] and go back to the pool when the
+/// [`PooledConnection`] is dropped. Connections closed by the peer are detected on checkout and
+/// replaced by new ones. When all the connections of a destination are in use, `get` blocks until
+/// one is returned.
+///
+/// For bursty feeds, [`MllpConnectionPool::keep_warm`] has spare connections opened ahead of
+/// time, so the first messages of a burst do not wait for connections to be set up.
+///
+/// A pool created with [`MllpConnectionPool::with_adaptive_limit`] adjusts, for each
+/// destination, how many messages may be in flight at once, from the ACK latency and the NAKs
+/// observed, so a slow receiver gets fewer concurrent messages.
+/// ```no_run
+/// use mllp_rs::pool::MllpConnectionPool;
+/// use mllp_rs::client::MllpClientConfig;
+///
+/// # fn main() -> Result<(), mllp_rs::MllpError> {
+/// let pool = MllpConnectionPool::new(4, MllpClientConfig::default());
+/// let ack = pool.send("lab.example.org:2575", b"MSH|^~\\&|...")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MllpConnectionPool {
+    max_connections: usize,
+    config: MllpClientConfig,
+    adaptive: Option<AdaptiveLimit>,
+    /// Shared with the threads opening connections ahead of time.
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    destinations: Mutex<HashMap<String, Destination>>,
+    returned: Condvar,
+}
+
+#[derive(Default)]
+struct Destination {
+    idle: Vec<MllpClient>,
+    /// Connections open to the destination, idle or checked out.
+    open: usize,
+    /// Replaces the configuration of the pool for this destination.
+    config: Option<MllpClientConfig>,
+    /// Idle connections to keep ready, see [`MllpConnectionPool::keep_warm`].
+    min_idle: usize,
+    /// Connections being opened ahead of time, counted in `open`.
+    warming: usize,
+    /// Connections checked out, counted in `open`.
+    checked_out: usize,
+    /// In-flight limit, in adaptive mode.
+    limit: Option<LimitState>,
+}
+
+/// Settings of the adaptive in-flight limit of an [`MllpConnectionPool`].
+///
+/// The limit follows AIMD (additive increase, multiplicative decrease), as TCP congestion
+/// control does: each message acknowledged within `target_latency` raises the limit by
+/// `1 / limit`, that is by one once a full window of messages went through, up to
+/// `max_connections`. A message acknowledged later, answered with a NAK or not acknowledged at
+/// all multiplies the limit by `backoff`, at most once per `target_latency` for the messages
+/// already in flight not to decrease it again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveLimit {
+    /// ACK latency, retransmissions included, above which the receiver is considered
+    /// overloaded.
+    pub target_latency: Duration,
+    /// In-flight limit of a destination before any message is acknowledged.
+    pub initial_limit: usize,
+    /// Lowest in-flight limit, at least 1.
+    pub min_limit: usize,
+    /// Factor applied to the limit on overload, between 0 and 1.
+    pub backoff: f64,
+}
+
+impl Default for AdaptiveLimit {
+    fn default() -> Self {
+        AdaptiveLimit {
+            target_latency: Duration::from_millis(500),
+            initial_limit: 1,
+            min_limit: 1,
+            backoff: 0.5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LimitState {
+    limit: f64,
+    decreased_at: Option<Instant>,
+}
+
+impl LimitState {
+    fn new(settings: &AdaptiveLimit, max: usize) -> Self {
+        LimitState {
+            limit: settings.initial_limit.clamp(settings.min_limit.max(1), max) as f64,
+            decreased_at: None,
+        }
+    }
+
+    /// In-flight limit, between the minimum and `max`.
+    fn get(&self, settings: &AdaptiveLimit, max: usize) -> usize {
+        (self.limit as usize).clamp(settings.min_limit.max(1).min(max), max)
+    }
+
+    fn on_ack(&mut self, latency: Duration, settings: &AdaptiveLimit, max: usize) {
+        if latency > settings.target_latency {
+            self.on_overload(settings);
+        } else {
+            self.limit = (self.limit + 1.0 / self.limit).min(max as f64);
+        }
+    }
+
+    fn on_overload(&mut self, settings: &AdaptiveLimit) {
+        if self.decreased_at.is_some_and(|at| at.elapsed() < settings.target_latency) {
+            return;
+        }
+        self.limit = (self.limit * settings.backoff).max(settings.min_limit.max(1) as f64);
+        self.decreased_at = Some(Instant::now());
+    }
+}
+
+impl MllpConnectionPool {
+    /// Creates an empty pool. Connections are opened with `config` as they are needed.
+    pub fn new(max_connections: usize, config: MllpClientConfig) -> Self {
+        MllpConnectionPool {
+            max_connections: max_connections.max(1),
+            config,
+            adaptive: None,
+            shared: Arc::new(Shared {
+                destinations: Mutex::new(HashMap::new()),
+                returned: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Creates an empty pool adapting the in-flight limit of each destination, up to
+    /// `max_connections`, to the receiver's ACK latency and NAKs.
+    pub fn with_adaptive_limit(max_connections: usize, config: MllpClientConfig, adaptive: AdaptiveLimit) -> Self {
+        MllpConnectionPool {
+            adaptive: Some(adaptive),
+            ..Self::new(max_connections, config)
+        }
+    }
+
+    /// Opens the connections to `destination` with `config` instead of the configuration of the
+    /// pool, e.g. to set the [source address](MllpClientConfig::bind_addr) a firewall expects
+    /// for it. Connections already open are kept.
+    pub fn configure(&self, destination: &str, config: MllpClientConfig) {
+        self.lock().entry(destination.to_owned()).or_default().config = Some(config);
+    }
+
+    /// Keeps `min_idle` idle connections to `destination` ready, within the limit of
+    /// `max_connections`, and opens them right away.
+    ///
+    /// The connections are opened in the background: as connections are checked out, new ones
+    /// are opened ahead of time while the in-flight count approaches capacity, and once the
+    /// burst is over the returned connections are kept idle. Connections closed by the peer
+    /// while idle are only replaced at the next checkout.
+    pub fn keep_warm(&self, destination: &str, min_idle: usize) {
+        let mut destinations = self.lock();
+        let entry = destinations.entry(destination.to_owned()).or_default();
+        entry.min_idle = min_idle;
+        self.connect_ahead(destination, entry);
+    }
+
+    /// Checks out a connection to `destination`, a `host:port` address.
+    pub fn get(&self, destination: &str) -> Result<PooledConnection<'_>, MllpError> {
+        let mut destinations = self.lock();
+
+        loop {
+            let entry = destinations.entry(destination.to_owned()).or_default();
+            if entry.checked_out >= self.limit_of(entry) {
+                destinations = self.shared.returned.wait(destinations).unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+
+            if let Some(client) = entry.idle.pop() {
+                if client.is_connected() {
+                    entry.checked_out += 1;
+                    self.connect_ahead(destination, entry);
+                    return Ok(self.wrap(destination, client));
+                }
+                entry.open -= 1;
+                continue;
+            }
+
+            if entry.open < self.max_connections {
+                entry.open += 1;
+                entry.checked_out += 1;
+                let config = self.destination_config(entry);
+                self.connect_ahead(destination, entry);
+                drop(destinations);
+
+                return match MllpClient::connect_with_config(destination, config) {
+                    Ok(client) => Ok(self.wrap(destination, client)),
+                    Err(e) => {
+                        self.discard(destination);
+                        Err(e.into())
+                    }
+                };
+            }
+
+            destinations = self.shared.returned.wait(destinations).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Opens, in the background, the connections missing for `entry` to have its minimum of idle
+    /// connections.
+    fn connect_ahead(&self, destination: &str, entry: &mut Destination) {
+        while entry.idle.len() + entry.warming < entry.min_idle && entry.open < self.max_connections {
+            entry.open += 1;
+            entry.warming += 1;
+
+            let shared = self.shared.clone();
+            let destination = destination.to_owned();
+            let config = self.destination_config(entry);
+            thread::spawn(move || {
+                let connected = MllpClient::connect_with_config(destination.as_str(), config);
+                if let Some(entry) = shared.lock().get_mut(&destination) {
+                    entry.warming -= 1;
+                    match connected {
+                        Ok(client) => entry.idle.push(client),
+                        Err(_) => entry.open -= 1,
+                    }
+                }
+                shared.returned.notify_all();
+            });
+        }
+    }
+
+    fn destination_config(&self, entry: &Destination) -> MllpClientConfig {
+        entry.config.clone().unwrap_or_else(|| self.config.clone())
+    }
+
+    /// Sends `payload` on a pooled connection to `destination`.
+    pub fn send(&self, destination: &str, payload: &[u8]) -> Result<Ack, MllpError> {
+        self.get(destination)?.send(payload)
+    }
+
+    /// How many messages may currently be in flight to `destination`: `max_connections`, or the
+    /// adaptive limit.
+    pub fn in_flight_limit(&self, destination: &str) -> usize {
+        let mut destinations = self.lock();
+        let entry = destinations.entry(destination.to_owned()).or_default();
+        self.limit_of(entry)
+    }
+
+    /// Number of connections currently open to `destination`, idle or checked out.
+    pub fn open_connections(&self, destination: &str) -> usize {
+        self.lock().get(destination).map_or(0, |entry| entry.open)
+    }
+
+    /// Counts of the TLS handshakes made to `destination`, `None` when its connections are not
+    /// made over TLS. Give each destination its own [`TlsConnector`](crate::tls::TlsConnector)
+    /// with [`MllpConnectionPool::configure`] for the counts not to include other destinations.
+    #[cfg(feature = "tls")]
+    pub fn tls_handshakes(&self, destination: &str) -> Option<HandshakeStats> {
+        let destinations = self.lock();
+        let config = destinations.get(destination).and_then(|entry| entry.config.as_ref()).unwrap_or(&self.config);
+        config.tls.as_ref().map(|connector| connector.handshakes())
+    }
+
+    fn limit_of(&self, entry: &mut Destination) -> usize {
+        match &self.adaptive {
+            Some(settings) => {
+                let state = entry.limit.get_or_insert_with(|| LimitState::new(settings, self.max_connections));
+                state.get(settings, self.max_connections)
+            }
+            None => self.max_connections,
+        }
+    }
+
+    /// Adjusts the adaptive limit of `destination` to the outcome of a message.
+    fn record(&self, destination: &str, latency: Duration, result: &Result<Ack, MllpError>) {
+        let Some(settings) = &self.adaptive else {
+            return;
+        };
+        if let Some(state) = self.lock().get_mut(destination).and_then(|entry| entry.limit.as_mut()) {
+            match result {
+                Ok(_) => state.on_ack(latency, settings, self.max_connections),
+                Err(MllpError::Nak | MllpError::AckTimeout) => state.on_overload(settings),
+                Err(_) => return,
+            }
+        }
+        self.shared.returned.notify_all();
+    }
+
+    fn wrap(&self, destination: &str, client: MllpClient) -> PooledConnection<'_> {
+        PooledConnection {
+            pool: self,
+            destination: destination.to_owned(),
+            client: Some(client),
+            broken: false,
+        }
+    }
+
+    fn discard(&self, destination: &str) {
+        if let Some(entry) = self.lock().get_mut(destination) {
+            entry.open -= 1;
+            entry.checked_out -= 1;
+        }
+        self.shared.returned.notify_all();
+    }
+
+    fn give_back(&self, destination: &str, client: MllpClient) {
+        if let Some(entry) = self.lock().get_mut(destination) {
+            entry.checked_out -= 1;
+            entry.idle.push(client);
+        }
+        self.shared.returned.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Destination>> {
+        self.shared.lock()
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Destination>> {
+        self.destinations.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Connection checked out of an [`MllpConnectionPool`], returned to it when dropped.
+///
+/// Messages are sent through the connection's own methods, which feed the adaptive in-flight
+/// limit; the client is otherwise only lent for reading. A connection on which a message failed
+/// with an I/O error, or timed out waiting for an acknowledgement which may still come, is
+/// closed instead of being returned.
+pub struct PooledConnection<'a> {
+    pool: &'a MllpConnectionPool,
+    destination: String,
+    client: Option<MllpClient>,
+    broken: bool,
+}
+
+impl PooledConnection<'_> {
+    pub fn send(&mut self, payload: &[u8]) -> Result<Ack, MllpError> {
+        let start = Instant::now();
+        let result = self.client_mut().send(payload);
+        self.pool.record(&self.destination, start.elapsed(), &result);
+        self.check(&result);
+
+        result
+    }
+
+    /// Sends `payloads` pipelined, like [`MllpClient::send_batch`]. The messages overlapping,
+    /// each is recorded with an equal share of the batch's time.
+    pub fn send_batch(&mut self, payloads: &[&[u8]]) -> Vec<Result<Ack, MllpError>> {
+        let start = Instant::now();
+        let results = self.client_mut().send_batch(payloads);
+        let latency = start.elapsed() / results.len().max(1) as u32;
+        for result in &results {
+            self.pool.record(&self.destination, latency, result);
+            self.check(result);
+        }
+
+        results
+    }
+
+    /// Sends a heartbeat if one is due, like [`MllpClient::heartbeat`].
+    pub fn heartbeat(&mut self) -> Result<bool, MllpError> {
+        let result = self.client_mut().heartbeat();
+        self.check(&result);
+
+        result
+    }
+
+    /// Marks the connection broken if `result` leaves it in doubt.
+    fn check<T>(&mut self, result: &Result<T, MllpError>) {
+        if let Err(MllpError::Io(_) | MllpError::Timeout(_) | MllpError::AckTimeout | MllpError::ConnectionClosed { .. }) = result {
+            self.broken = true;
+        }
+    }
+
+    fn client_mut(&mut self) -> &mut MllpClient {
+        self.client.as_mut().expect("connection is only taken on drop")
+    }
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = MllpClient;
+
+    fn deref(&self) -> &MllpClient {
+        self.client.as_ref().expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        match self.client.take() {
+            Some(client) if !self.broken => self.pool.give_back(&self.destination, client),
+            _ => self.pool.discard(&self.destination),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+    use crate::client::{Ack, MllpClientConfig};
+    use crate::pool::{AdaptiveLimit, MllpConnectionPool};
+    use crate::{MllpCodec, MllpDecoder, MllpError};
+
+    /// Spawns a receiver ACKing every message, closing each connection after `per_connection`
+    /// messages. Returns its address and the number of accepted connections.
+    fn receiver(per_connection: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+
+        let counter = accepted.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                thread::spawn(move || {
+                    let mut decoder = MllpDecoder::new();
+                    for _ in 0..per_connection {
+                        if decoder.read_frame(&mut stream).is_err() {
+                            break;
+                        }
+                        stream.write_all(&MllpCodec::ack()).unwrap();
+                    }
+                });
+            }
+        });
+
+        (addr, accepted)
+    }
+
+    fn config() -> MllpClientConfig {
+        MllpClientConfig {
+            ack_timeout: Some(Duration::from_secs(1)),
+            max_retries: 0,
+            ..MllpClientConfig::default()
+        }
+    }
+
+    #[test]
+    fn it_reuses_connections() {
+        let (addr, accepted) = receiver(usize::MAX);
+        let pool = MllpConnectionPool::new(2, config());
+
+        for _ in 0..5 {
+            assert_eq!(pool.send(&addr, b"MSH|").unwrap(), Ack::Commit);
+        }
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.open_connections(&addr), 1);
+    }
+
+    #[test]
+    fn it_replaces_closed_connections() {
+        let (addr, accepted) = receiver(1);
+        let pool = MllpConnectionPool::new(1, config());
+
+        assert_eq!(pool.send(&addr, b"MSH|").unwrap(), Ack::Commit);
+        // let the receiver close the connection
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pool.send(&addr, b"MSH|").unwrap(), Ack::Commit);
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.open_connections(&addr), 1);
+    }
+
+    #[test]
+    fn it_caps_connections_per_destination() {
+        let (addr, accepted) = receiver(usize::MAX);
+        let pool = Arc::new(MllpConnectionPool::new(2, config()));
+
+        let handlers: Vec<_> = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                let addr = addr.clone();
+                thread::spawn(move || {
+                    let mut connection = pool.get(&addr).unwrap();
+                    thread::sleep(Duration::from_millis(10));
+                    connection.send(b"MSH|").unwrap()
+                })
+            })
+            .collect();
+
+        for handler in handlers {
+            assert_eq!(handler.join().unwrap(), Ack::Commit);
+        }
+        assert!(accepted.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn it_wakes_waiters_of_the_destination_returned_to() {
+        let (x, _) = receiver(usize::MAX);
+        let (y, _) = receiver(usize::MAX);
+        let pool = Arc::new(MllpConnectionPool::new(1, config()));
+        let to_x = pool.get(&x).unwrap();
+        let _to_y = pool.get(&y).unwrap();
+
+        let (checked_out, waited) = mpsc::channel();
+        for destination in [y, x] {
+            let (pool, checked_out) = (pool.clone(), checked_out.clone());
+            thread::spawn(move || {
+                let _connection = pool.get(&destination).unwrap();
+                checked_out.send(destination).unwrap();
+            });
+            // waiting on Y first, then on X
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        drop(to_x);
+        assert!(waited.recv_timeout(Duration::from_secs(3)).is_ok());
+    }
+
+    #[test]
+    fn it_discards_connections_timing_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            let _ = decoder.read_frame(&mut stream);
+            // too late
+            thread::sleep(Duration::from_millis(200));
+            let _ = stream.write_all(&MllpCodec::ack());
+            let _ = decoder.read_frame(&mut stream);
+        });
+        let pool = MllpConnectionPool::new(1, MllpClientConfig { ack_timeout: Some(Duration::from_millis(100)), ..config() });
+
+        assert!(matches!(pool.send(&addr, b"MSH|"), Err(MllpError::AckTimeout)));
+        assert_eq!(pool.open_connections(&addr), 0);
+    }
+
+    #[test]
+    fn it_records_and_discards_batches() {
+        let (addr, _) = receiver(usize::MAX);
+        let adaptive = AdaptiveLimit {
+            target_latency: Duration::from_secs(10),
+            ..AdaptiveLimit::default()
+        };
+        let pool = MllpConnectionPool::with_adaptive_limit(4, config(), adaptive);
+
+        let results = pool.get(&addr).unwrap().send_batch(&[b"MSH|1", b"MSH|2"]);
+        assert!(results.iter().all(|result| matches!(result, Ok(Ack::Commit))));
+        assert_eq!(pool.in_flight_limit(&addr), 2);
+        assert_eq!(pool.open_connections(&addr), 1);
+
+        let (addr, _) = receiver(1);
+        let results = pool.get(&addr).unwrap().send_batch(&[b"MSH|1", b"MSH|2"]);
+        assert!(matches!(results[0], Ok(Ack::Commit)));
+        assert!(results[1].is_err());
+        assert_eq!(pool.open_connections(&addr), 0);
+    }
+
+    #[test]
+    fn it_uses_destination_config() {
+        let (addr, _) = receiver(usize::MAX);
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let pool = MllpConnectionPool::new(1, config());
+        pool.configure(&addr, MllpClientConfig {
+            source_ports: Some(port..=port),
+            ..config()
+        });
+
+        assert_eq!(pool.get(&addr).unwrap().local_addr().port(), port);
+    }
+
+    #[test]
+    fn it_opens_connections_ahead() {
+        let (addr, accepted) = receiver(usize::MAX);
+        let pool = MllpConnectionPool::new(3, config());
+        let wait_for = |count: usize| {
+            for _ in 0..100 {
+                if accepted.load(Ordering::SeqCst) >= count {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        pool.keep_warm(&addr, 2);
+        wait_for(2);
+        assert_eq!(pool.open_connections(&addr), 2);
+
+        let first = pool.get(&addr).unwrap();
+        let _second = pool.get(&addr).unwrap();
+        wait_for(3);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        // capacity reached
+        assert_eq!(pool.open_connections(&addr), 3);
+        drop(first);
+        assert_eq!(pool.send(&addr, b"MSH|").unwrap(), Ack::Commit);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn it_adapts_in_flight_limit() {
+        let (addr, _) = receiver(usize::MAX);
+        let adaptive = AdaptiveLimit {
+            target_latency: Duration::ZERO,
+            ..AdaptiveLimit::default()
+        };
+        let pool = MllpConnectionPool::with_adaptive_limit(4, config(), adaptive);
+
+        assert_eq!(pool.in_flight_limit(&addr), 1);
+        pool.record(&addr, Duration::ZERO, &Ok(Ack::Commit));
+        pool.record(&addr, Duration::ZERO, &Ok(Ack::Commit));
+        assert_eq!(pool.in_flight_limit(&addr), 2);
+        for _ in 0..10 {
+            pool.record(&addr, Duration::ZERO, &Ok(Ack::Commit));
+        }
+        assert_eq!(pool.in_flight_limit(&addr), 4);
+
+        pool.record(&addr, Duration::ZERO, &Err(MllpError::Nak));
+        assert_eq!(pool.in_flight_limit(&addr), 2);
+        // a slow ACK counts as overload
+        assert_eq!(pool.send(&addr, b"MSH|").unwrap(), Ack::Commit);
+        assert_eq!(pool.in_flight_limit(&addr), 1);
+    }
+}
@@ -0,0 +1,313 @@
+//! Connections through a SOCKS5 or HTTP proxy.
+//!
+//! Networks where outbound connections must go through a proxy, such as hospital DMZs, are
+//! reached by setting [`MllpClientConfig::proxy`](crate::client::MllpClientConfig::proxy). The
+//! client connects to the proxy, has it open a tunnel to the receiver, then makes the TLS
+//! session if any and speaks MLLP through the tunnel.
+//! ```no_run
+//! use mllp_rs::client::{MllpClient, MllpClientConfig};
+//! use mllp_rs::proxy::{Credentials, Proxy};
+//!
+//! # fn main() -> Result<(), mllp_rs::MllpError> {
+//! let config = MllpClientConfig {
+//!     proxy: Some(Proxy::Socks5 {
+//!         addr: "10.0.0.1:1080".parse().unwrap(),
+//!         credentials: Some(Credentials::new("lab", "secret")),
+//!     }),
+//!     ..MllpClientConfig::default()
+//! };
+//! let mut client = MllpClient::connect_with_config("10.20.0.5:2575", config)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The receiver's address is resolved by the client, and the proxy is asked for a tunnel to the
+//! IP address.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use crate::base64;
+
+/// Longest HTTP head of a proxy response.
+const MAX_HEAD: usize = 8 * 1024;
+
+/// Proxy the connections are made through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Proxy {
+    /// SOCKS5 proxy, with username and password authentication if credentials are given.
+    Socks5 {
+        addr: SocketAddr,
+        credentials: Option<Credentials>,
+    },
+    /// HTTP proxy tunnelling with the `CONNECT` method, with basic authentication if credentials
+    /// are given.
+    HttpConnect {
+        addr: SocketAddr,
+        credentials: Option<Credentials>,
+    },
+}
+
+/// Username and password to authenticate to a proxy.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Self {
+        Credentials {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials").field("username", &self.username).finish_non_exhaustive()
+    }
+}
+
+impl Proxy {
+    pub fn addr(&self) -> SocketAddr {
+        match self {
+            Proxy::Socks5 { addr, .. } | Proxy::HttpConnect { addr, .. } => *addr,
+        }
+    }
+
+    /// Has the proxy connected to through `stream` open a tunnel to `target`.
+    pub(crate) fn tunnel(&self, stream: &mut TcpStream, target: SocketAddr) -> io::Result<()> {
+        match self {
+            Proxy::Socks5 { credentials, .. } => socks5(stream, target, credentials.as_ref()),
+            Proxy::HttpConnect { credentials, .. } => http_connect(stream, target, credentials.as_ref()),
+        }
+    }
+}
+
+fn socks5<S: Read + Write>(stream: &mut S, target: SocketAddr, credentials: Option<&Credentials>) -> io::Result<()> {
+    // no authentication, or username and password
+    let methods: &[u8] = match credentials {
+        Some(_) => &[0x00, 0x02],
+        None => &[0x00],
+    };
+    stream.write_all(&[&[0x05, methods.len() as u8], methods].concat())?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+
+    match (reply, credentials) {
+        ([0x05, 0x00], _) => {}
+        ([0x05, 0x02], Some(credentials)) => {
+            let (username, password) = (credentials.username.as_bytes(), credentials.password.as_bytes());
+            if username.len() > 255 || password.len() > 255 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5 credentials longer than 255 bytes"));
+            }
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request)?;
+            stream.read_exact(&mut reply)?;
+            if reply[1] != 0x00 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 proxy refused the credentials"));
+            }
+        }
+        ([0x05, _], _) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 proxy requires authentication")),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not a SOCKS5 proxy")),
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(0x01);
+            request.extend(ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(0x04);
+            request.extend(ip.octets());
+        }
+    }
+    request.extend(target.port().to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    match reply[1] {
+        0x00 => {}
+        0x05 => return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "receiver refused the proxy connection")),
+        code => return Err(io::Error::other(format!("SOCKS5 proxy failed to connect, error {}", code))),
+    }
+    // address the proxy connected from, and its port
+    let bound = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid SOCKS5 address type")),
+    };
+    stream.read_exact(&mut vec![0u8; bound + 2])
+}
+
+fn http_connect<S: Read + Write>(stream: &mut S, target: SocketAddr, credentials: Option<&Credentials>) -> io::Result<()> {
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some(credentials) = credentials {
+        let token = base64(format!("{}:{}", credentials.username, credentials.password).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    let head = read_head(stream)?;
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some("407") => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("proxy refused: {}", status_line))),
+        _ => Err(io::Error::other(format!("proxy refused: {}", status_line))),
+    }
+}
+
+/// Reads an HTTP head, up to the empty line. Reads a byte at a time, not to consume the bytes
+/// following it.
+pub(crate) fn read_head<S: Read>(stream: &mut S) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP head too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+
+    String::from_utf8(head).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "HTTP head not UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+    use std::time::Duration;
+    use crate::client::{Ack, MllpClient, MllpClientConfig};
+    use crate::proxy::{read_head, Credentials, Proxy};
+    use crate::{MllpCodec, MllpDecoder};
+
+    /// Receiver acknowledging one message.
+    fn receiver() -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let message = MllpDecoder::new().read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::ack()).unwrap();
+            message
+        });
+        (addr, handler)
+    }
+
+    /// Proxy reading the tunnel request with `handshake`, which returns the target, then relaying
+    /// the bytes of one exchange.
+    fn proxy<F>(handshake: F) -> (SocketAddr, thread::JoinHandle<SocketAddr>)
+    where
+        F: FnOnce(&mut std::net::TcpStream) -> SocketAddr + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let target = handshake(&mut client);
+            let mut receiver = std::net::TcpStream::connect(target).unwrap();
+            let mut upstream = client.try_clone().unwrap();
+            let mut receiver_writer = receiver.try_clone().unwrap();
+            thread::spawn(move || io::copy(&mut upstream, &mut receiver_writer));
+            let _ = io::copy(&mut receiver, &mut client);
+            target
+        });
+        (addr, handler)
+    }
+
+    fn send_through(proxy: Proxy) -> Ack {
+        let (receiver_addr, receiver) = receiver();
+        let config = MllpClientConfig {
+            ack_timeout: Some(Duration::from_secs(1)),
+            proxy: Some(proxy),
+            ..MllpClientConfig::default()
+        };
+        let mut client = MllpClient::connect_with_config(receiver_addr, config).unwrap();
+        let ack = client.send(b"MSH|1").unwrap();
+        assert_eq!(receiver.join().unwrap(), b"MSH|1");
+        ack
+    }
+
+    #[test]
+    fn it_connects_through_socks5_proxy() {
+        let (addr, proxy) = proxy(|client| {
+            let mut greeting = [0u8; 4];
+            client.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [0x05, 0x02, 0x00, 0x02]);
+            client.write_all(&[0x05, 0x02]).unwrap();
+            let mut auth = [0u8; 12];
+            client.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, b"\x01\x03lab\x06secret");
+            client.write_all(&[0x01, 0x00]).unwrap();
+
+            let mut request = [0u8; 10];
+            client.read_exact(&mut request).unwrap();
+            assert_eq!(request[..4], [0x05, 0x01, 0x00, 0x01]);
+            client.write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0]).unwrap();
+            let ip: [u8; 4] = request[4..8].try_into().unwrap();
+            SocketAddr::from((ip, u16::from_be_bytes([request[8], request[9]])))
+        });
+
+        let proxy_config = Proxy::Socks5 { addr, credentials: Some(Credentials::new("lab", "secret")) };
+        assert_eq!(send_through(proxy_config), Ack::Commit);
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn it_connects_through_http_proxy() {
+        let (addr, proxy) = proxy(|client| {
+            let head = read_head(client).unwrap();
+            let target = head.strip_prefix("CONNECT ").unwrap().split(' ').next().unwrap().parse().unwrap();
+            // "lab:secret"
+            assert!(head.contains("Proxy-Authorization: Basic bGFiOnNlY3JldA==\r\n"));
+            client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            target
+        });
+
+        let proxy_config = Proxy::HttpConnect { addr, credentials: Some(Credentials::new("lab", "secret")) };
+        assert_eq!(send_through(proxy_config), Ack::Commit);
+        proxy.join().unwrap();
+    }
+
+    #[test]
+    fn it_reports_refused_tunnels() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            read_head(&mut client).unwrap();
+            client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").unwrap();
+        });
+        let config = MllpClientConfig {
+            proxy: Some(Proxy::HttpConnect { addr, credentials: None }),
+            ..MllpClientConfig::default()
+        };
+
+        let Err(error) = MllpClient::connect_with_config("127.0.0.1:2575", config) else {
+            panic!("connected through a refusing proxy");
+        };
+        assert!(error.to_string().contains("407"), "{}", error);
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn it_hides_passwords() {
+        assert_eq!(format!("{:?}", Credentials::new("lab", "secret")), "Credentials { username: \"lab\", .. }");
+    }
+}
@@ -0,0 +1,214 @@
+//! Token bucket rate limiting.
+//!
+//! A [`TokenBucket`] holds up to [`RateLimit::burst`] tokens and is refilled at
+//! [`RateLimit::per_second`] tokens per second. Each message takes one token, so a sender can go
+//! as fast as it likes for `burst` messages, and is then held to the sustained rate.
+//!
+//! On the sending side, a [`Throttle`] set in
+//! [`MllpClientConfig::throttle`](crate::client::MllpClientConfig::throttle) holds a client to a
+//! number of messages and of bytes per second, so that a backfill does not overwhelm a receiver
+//! which NAKs under load. Its limits can be changed while the client sends:
+//! ```
+//! use std::sync::Arc;
+//! use mllp_rs::client::MllpClientConfig;
+//! use mllp_rs::rate_limit::{RateLimit, Throttle};
+//!
+//! let throttle = Arc::new(Throttle::default());
+//! throttle.set_message_rate(Some(RateLimit { per_second: 50.0, burst: 10 })).unwrap();
+//! let config = MllpClientConfig {
+//!     throttle: Some(throttle.clone()),
+//!     ..MllpClientConfig::default()
+//! };
+//! // at night
+//! throttle.set_message_rate(Some(RateLimit { per_second: 500.0, burst: 100 })).unwrap();
+//! ```
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::config::{self, ConfigError};
+
+/// Longest a [`Throttle`] sleeps before looking at its limits again.
+const MAX_THROTTLE_SLEEP: Duration = Duration::from_millis(100);
+
+/// Sustained rate and burst size of a [`TokenBucket`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// Messages allowed per second, on average. Must be positive.
+    pub per_second: f64,
+    /// Messages allowed in a row before the sustained rate applies.
+    pub burst: u32,
+}
+
+/// Token bucket, shareable between threads.
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            state: Mutex::new(BucketState {
+                tokens: limit.burst as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Takes a token if one is available, or returns how long until one is.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        self.try_take(1.0)
+    }
+
+    /// Takes `tokens` if available, or returns how long until they are. More tokens than the
+    /// burst are taken from a full bucket, which then owes them.
+    fn try_take(&self, tokens: f64) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        state.refilled_at = now;
+
+        let needed = tokens.min(self.limit.burst.max(1) as f64);
+        if state.tokens >= needed {
+            state.tokens -= tokens;
+            Ok(())
+        } else {
+            let wait = (needed - state.tokens) / self.limit.per_second;
+            Err(Duration::try_from_secs_f64(wait).unwrap_or(Duration::MAX))
+        }
+    }
+
+    /// Gives back a token taken for a message that was not sent after all.
+    pub(crate) fn release(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.tokens = (state.tokens + 1.0).min(self.limit.burst as f64);
+    }
+
+    /// Takes a token, sleeping until one is available.
+    pub fn acquire(&self) {
+        while let Err(wait) = self.try_acquire() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Limits of the messages and of the bytes sent per second, shared by the clients it is set on
+/// and adjustable while they send. Both are unlimited by default.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    messages: RwLock<Option<Arc<TokenBucket>>>,
+    bytes: RwLock<Option<Arc<TokenBucket>>>,
+}
+
+impl Throttle {
+    pub fn message_rate(&self) -> Option<RateLimit> {
+        self.messages.read().unwrap_or_else(|e| e.into_inner()).as_ref().map(|bucket| bucket.limit())
+    }
+
+    /// Rate of the bytes of the payloads, whose burst is usually the size of the largest
+    /// message at least.
+    pub fn byte_rate(&self) -> Option<RateLimit> {
+        self.bytes.read().unwrap_or_else(|e| e.into_inner()).as_ref().map(|bucket| bucket.limit())
+    }
+
+    /// Sets the limit of the messages per second, starting with a full burst. `None` lifts it.
+    pub fn set_message_rate(&self, limit: Option<RateLimit>) -> Result<(), ConfigError> {
+        config::rate_limit("message_rate", limit)?;
+        *self.messages.write().unwrap_or_else(|e| e.into_inner()) = limit.map(|limit| Arc::new(TokenBucket::new(limit)));
+        Ok(())
+    }
+
+    /// Sets the limit of the bytes per second, starting with a full burst. `None` lifts it.
+    pub fn set_byte_rate(&self, limit: Option<RateLimit>) -> Result<(), ConfigError> {
+        config::rate_limit("byte_rate", limit)?;
+        *self.bytes.write().unwrap_or_else(|e| e.into_inner()) = limit.map(|limit| Arc::new(TokenBucket::new(limit)));
+        Ok(())
+    }
+
+    /// Sleeps until a message of `bytes` bytes may be sent.
+    pub fn acquire(&self, bytes: usize) {
+        Self::take(&self.messages, 1.0);
+        Self::take(&self.bytes, bytes as f64);
+    }
+
+    /// Takes `tokens` from the bucket in `bucket`, looking at it again while sleeping, in case
+    /// its limit changes.
+    fn take(bucket: &RwLock<Option<Arc<TokenBucket>>>, tokens: f64) {
+        loop {
+            let Some(current) = bucket.read().unwrap_or_else(|e| e.into_inner()).clone() else {
+                return;
+            };
+            match current.try_take(tokens) {
+                Ok(()) => return,
+                Err(wait) => thread::sleep(wait.min(MAX_THROTTLE_SLEEP)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::rate_limit::{RateLimit, Throttle, TokenBucket};
+
+    #[test]
+    fn it_allows_bursts_then_sustained_rate() {
+        let bucket = TokenBucket::new(RateLimit {
+            per_second: 20.0,
+            burst: 3,
+        });
+
+        for _ in 0..3 {
+            assert!(bucket.try_acquire().is_ok());
+        }
+        let wait = bucket.try_acquire().unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(50));
+
+        let start = Instant::now();
+        bucket.acquire();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn it_throttles_messages_and_bytes() {
+        let throttle = Throttle::default();
+        let start = Instant::now();
+        for _ in 0..100 {
+            throttle.acquire(1000);
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        throttle.set_byte_rate(Some(RateLimit { per_second: 10_000.0, burst: 500 })).unwrap();
+        let start = Instant::now();
+        throttle.acquire(1000);
+        throttle.acquire(100);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(500), "{:?}", elapsed);
+
+        throttle.set_byte_rate(None).unwrap();
+        throttle.set_message_rate(Some(RateLimit { per_second: 1000.0, burst: 1 })).unwrap();
+        let start = Instant::now();
+        for _ in 0..21 {
+            throttle.acquire(1000);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(throttle.message_rate(), Some(RateLimit { per_second: 1000.0, burst: 1 }));
+        assert_eq!(throttle.set_message_rate(Some(RateLimit { per_second: 0.0, burst: 1 })).unwrap_err().field, "message_rate");
+    }
+}
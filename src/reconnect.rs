@@ -0,0 +1,185 @@
+//! When a client in keep-open mode reconnects, and when it gives up.
+//!
+//! A [`ReconnectPolicy`] set in
+//! [`MllpClientConfig::reconnect_policy`](crate::client::MllpClientConfig::reconnect_policy)
+//! tells how long to wait before each reconnection attempt, and is told when the client gave up.
+//! [`FixedBackoff`] waits the same delay between attempts, [`ExponentialBackoff`] doubles it
+//! with each attempt, and [`JitteredBackoff`] waits a random part of the doubled delay, so that
+//! clients cut off together do not all come back at the same time. Without a policy, the client
+//! backs off exponentially from
+//! [`MllpClientConfig::reconnect_backoff`](crate::client::MllpClientConfig::reconnect_backoff).
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use mllp_rs::client::MllpClientConfig;
+//! use mllp_rs::reconnect::{FixedBackoff, NotifyGiveUp};
+//!
+//! // what the site asked for: every 30 seconds, for 10 minutes
+//! let policy = NotifyGiveUp::new(FixedBackoff::new(Duration::from_secs(30), 20), |attempts, e: &std::io::Error| {
+//!     eprintln!("lab interface down after {} attempts: {}", attempts, e);
+//! });
+//! let config = MllpClientConfig {
+//!     keep_open: true,
+//!     reconnect_policy: Some(Arc::new(policy)),
+//!     ..MllpClientConfig::default()
+//! };
+//! ```
+
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::random;
+
+/// Timing of the reconnection attempts of a client.
+pub trait ReconnectPolicy: Send + Sync {
+    /// Delay before reconnection attempt `attempt`, counted from 0, or `None` to give up.
+    fn delay(&self, attempt: u32) -> Option<Duration>;
+
+    /// Called when the client gives up reconnecting, after `attempts` attempts, the last one
+    /// failing with `error`.
+    fn on_give_up(&self, attempts: u32, error: &io::Error) {
+        let _ = (attempts, error);
+    }
+}
+
+impl<P: ReconnectPolicy + ?Sized> ReconnectPolicy for Arc<P> {
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        (**self).delay(attempt)
+    }
+
+    fn on_give_up(&self, attempts: u32, error: &io::Error) {
+        (**self).on_give_up(attempts, error)
+    }
+}
+
+impl fmt::Debug for dyn ReconnectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ReconnectPolicy")
+    }
+}
+
+/// Attempts `delay` apart, the first one right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedBackoff {
+    pub delay: Duration,
+    pub max_attempts: u32,
+}
+
+impl FixedBackoff {
+    pub fn new(delay: Duration, max_attempts: u32) -> Self {
+        FixedBackoff { delay, max_attempts }
+    }
+}
+
+impl ReconnectPolicy for FixedBackoff {
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        match attempt {
+            attempt if attempt >= self.max_attempts => None,
+            0 => Some(Duration::ZERO),
+            _ => Some(self.delay),
+        }
+    }
+}
+
+/// First attempt right away, the second one `initial` later, the delay doubling with each
+/// further attempt up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExponentialBackoff {
+    pub initial: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32,
+}
+
+impl ExponentialBackoff {
+    pub fn new(initial: Duration, max_delay: Duration, max_attempts: u32) -> Self {
+        ExponentialBackoff { initial, max_delay, max_attempts }
+    }
+}
+
+impl ReconnectPolicy for ExponentialBackoff {
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        match attempt {
+            attempt if attempt >= self.max_attempts => None,
+            0 => Some(Duration::ZERO),
+            attempt => Some(self.initial.saturating_mul(2u32.saturating_pow(attempt - 1)).min(self.max_delay)),
+        }
+    }
+}
+
+/// Same as [`ExponentialBackoff`], each delay being drawn at random between none and the
+/// exponential delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JitteredBackoff(pub ExponentialBackoff);
+
+impl JitteredBackoff {
+    pub fn new(initial: Duration, max_delay: Duration, max_attempts: u32) -> Self {
+        JitteredBackoff(ExponentialBackoff::new(initial, max_delay, max_attempts))
+    }
+}
+
+impl ReconnectPolicy for JitteredBackoff {
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        let delay = self.0.delay(attempt)?;
+        let nanos = u64::try_from(delay.as_nanos()).unwrap_or(u64::MAX);
+        Some(Duration::from_nanos(random() % nanos.saturating_add(1)))
+    }
+}
+
+/// Policy `P` calling `F` with the number of attempts and the last error when giving up.
+pub struct NotifyGiveUp<P, F> {
+    policy: P,
+    callback: F,
+}
+
+impl<P, F> NotifyGiveUp<P, F>
+where
+    P: ReconnectPolicy,
+    F: Fn(u32, &io::Error) + Send + Sync,
+{
+    pub fn new(policy: P, callback: F) -> Self {
+        NotifyGiveUp { policy, callback }
+    }
+}
+
+impl<P, F> ReconnectPolicy for NotifyGiveUp<P, F>
+where
+    P: ReconnectPolicy,
+    F: Fn(u32, &io::Error) + Send + Sync,
+{
+    fn delay(&self, attempt: u32) -> Option<Duration> {
+        self.policy.delay(attempt)
+    }
+
+    fn on_give_up(&self, attempts: u32, error: &io::Error) {
+        self.policy.on_give_up(attempts, error);
+        (self.callback)(attempts, error)
+    }
+}
+
+impl<P: fmt::Debug, F> fmt::Debug for NotifyGiveUp<P, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NotifyGiveUp").field("policy", &self.policy).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::reconnect::{ExponentialBackoff, FixedBackoff, JitteredBackoff, ReconnectPolicy};
+
+    #[test]
+    fn it_times_reconnection_attempts() {
+        let delays = |policy: &dyn ReconnectPolicy| (0..6).map(|attempt| policy.delay(attempt).map(|delay| delay.as_secs())).collect::<Vec<_>>();
+        assert_eq!(delays(&FixedBackoff::new(Duration::from_secs(5), 3)), [Some(0), Some(5), Some(5), None, None, None]);
+        assert_eq!(
+            delays(&ExponentialBackoff::new(Duration::from_secs(1), Duration::from_secs(6), 5)),
+            [Some(0), Some(1), Some(2), Some(4), Some(6), None]
+        );
+
+        let jittered = JitteredBackoff::new(Duration::from_secs(1), Duration::from_secs(60), 10);
+        assert_eq!(jittered.delay(0), Some(Duration::ZERO));
+        assert!((0..20).all(|_| jittered.delay(4).unwrap() <= Duration::from_secs(8)));
+        assert_eq!(jittered.delay(10), None);
+    }
+}
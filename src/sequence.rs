@@ -0,0 +1,484 @@
+//! HL7 sequence number protocol, MSH-13, for in-order delivery without loss nor duplicates.
+//!
+//! The sender numbers its messages in MSH-13, and the receiver keeps the number it expects next.
+//! Both numbers are kept in files by [`SequenceNumbers`], so that they survive restarts.
+//!
+//! On the sending side, [`MllpClientConfig::sequence_numbers`] stamps each message with the next
+//! number. The number moves on once the message is acknowledged, to the expected number of the
+//! acknowledgement, MSA-4, if it holds one; a message failing keeps its number for the next one
+//! sent, usually the same message again.
+//!
+//! On the receiving side, [`SequenceChecker`] is an interceptor comparing MSH-13 to the number
+//! expected from the sender, told apart by MSH-3 and MSH-4. The expected message is handled, and
+//! the answer of the handler gets the next expected number in MSA-4. A message received already is acknowledged again without being handled, and
+//! a message past the expected one is rejected with `AR`, MSA-4 telling which one to send again.
+//! MSH-13 set to `-1` asks for the expected number without being handled, and `0` or no number
+//! turns the protocol off for the message.
+//! ```no_run
+//! use std::sync::Arc;
+//! use mllp_rs::client::MllpClientConfig;
+//! use mllp_rs::sequence::{SequenceChecker, SequenceNumbers};
+//! use mllp_rs::server::MllpServerConfig;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let sender = MllpClientConfig {
+//!     sequence_numbers: Some(Arc::new(SequenceNumbers::open("/var/lib/lab/next.seq")?)),
+//!     ..MllpClientConfig::default()
+//! };
+//! let receiver = MllpServerConfig {
+//!     interceptors: vec![Arc::new(SequenceChecker::open("/var/lib/ehr/expected")?)],
+//!     ..MllpServerConfig::default()
+//! };
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`MllpClientConfig::sequence_numbers`]: crate::client::MllpClientConfig::sequence_numbers
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use crate::client::Ack;
+#[cfg(feature = "unstable")]
+use crate::cluster::ClusterStore;
+use crate::spool::sync_dir;
+use crate::handler::AckDecision;
+use crate::interceptor::{Interceptor, Next};
+
+/// Sequence number kept in a file: the next one to send, or the next one expected.
+#[derive(Debug)]
+pub struct SequenceNumbers {
+    path: PathBuf,
+    next: Mutex<i64>,
+}
+
+impl SequenceNumbers {
+    /// Opens the file at `path`, starting from 1 if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let next = match fs::read_to_string(&path) {
+            Ok(text) => text.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid sequence number"))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e),
+        };
+
+        Ok(SequenceNumbers { path, next: Mutex::new(next) })
+    }
+
+    pub fn get(&self) -> i64 {
+        *self.next.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sets the number and writes it to the file.
+    pub fn set(&self, next: i64) -> io::Result<()> {
+        let mut current = self.next.lock().unwrap_or_else(|e| e.into_inner());
+        *current = next;
+        self.persist(next)
+    }
+
+    /// Writes `next` to a temporary file, then renames it, so that a crash leaves either number.
+    fn persist(&self, next: i64) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{}", next)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        // a bare file name has an empty parent, which is the current directory
+        match self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => sync_dir(dir),
+            None => sync_dir(Path::new(".")),
+        }
+    }
+
+    /// Stamps `payload` with the next number, plus `offset` for the messages sent after it
+    /// without waiting, returned with the stamped copy.
+    pub(crate) fn stamp(&self, payload: &[u8], offset: i64) -> Option<(i64, Vec<u8>)> {
+        let number = self.get() + offset;
+        let stamped = with_field(payload, b"MSH", 12, number.to_string().as_bytes())?;
+        Some((number, stamped))
+    }
+
+    /// Moves on after the message numbered `number` was acknowledged with `ack`.
+    pub(crate) fn acknowledged(&self, number: i64, ack: &Ack) {
+        let expected = match ack {
+            Ack::Application(payload) => expected_number(payload),
+            _ => None,
+        };
+        // the number moves on in memory even if it cannot be written
+        let _ = self.set(expected.unwrap_or(number + 1));
+    }
+}
+
+/// Interceptor of the receiving side of the sequence number protocol.
+///
+/// Each sender has its own expected number. Its messages are handled one at a time, in order,
+/// even when received on several connections, while those of the other senders go on.
+#[derive(Debug)]
+pub struct SequenceChecker {
+    store: Store,
+    /// Senders with a message being handled.
+    busy: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+/// Where the expected numbers are kept.
+#[derive(Debug)]
+enum Store {
+    Files(SequenceFiles),
+    #[cfg(feature = "unstable")]
+    Cluster(Arc<dyn ClusterStore>),
+}
+
+impl Store {
+    fn expected(&self, sender: &str) -> io::Result<i64> {
+        match self {
+            Store::Files(files) => Ok(files.open(sender)?.get()),
+            #[cfg(feature = "unstable")]
+            Store::Cluster(store) => Ok(store.get(&cluster_key(sender))?.map_or(1, |expected| expected as i64)),
+        }
+    }
+
+    /// Moves the number expected from `sender` past `number`, once it was handled.
+    fn handled(&self, sender: &str, number: i64) -> io::Result<()> {
+        match self {
+            Store::Files(files) => files.open(sender)?.set(number + 1),
+            #[cfg(feature = "unstable")]
+            Store::Cluster(store) => {
+                let key = cluster_key(sender);
+                let current = store.get(&key)?;
+                // moved on already by another instance handling the same message otherwise
+                if current.map_or(1, |expected| expected as i64) == number {
+                    store.compare_and_swap(&key, current, number as u64 + 1)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A file per sender in `dir`, opened as the sender is first heard from.
+#[derive(Debug)]
+struct SequenceFiles {
+    dir: PathBuf,
+    opened: Mutex<HashMap<String, Arc<SequenceNumbers>>>,
+}
+
+impl SequenceFiles {
+    fn open(&self, sender: &str) -> io::Result<Arc<SequenceNumbers>> {
+        let mut opened = self.opened.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(numbers) = opened.get(sender) {
+            return Ok(numbers.clone());
+        }
+        let numbers = Arc::new(SequenceNumbers::open(self.dir.join(file_name(sender)))?);
+        opened.insert(sender.to_owned(), numbers.clone());
+
+        Ok(numbers)
+    }
+}
+
+impl SequenceChecker {
+    /// Keeps the number expected from each sender in a file of `dir`, created if missing.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(SequenceChecker::with_store(Store::Files(SequenceFiles {
+            dir: dir.as_ref().to_path_buf(),
+            opened: Mutex::new(HashMap::new()),
+        })))
+    }
+
+    /// Keeps the number expected from each sender in `store`, shared by the instances of a
+    /// receiver. A message received by two instances at once may be handled by both; the
+    /// number still moves on once.
+    #[cfg(feature = "unstable")]
+    pub fn with_cluster_store(store: Arc<dyn ClusterStore>) -> Self {
+        SequenceChecker::with_store(Store::Cluster(store))
+    }
+
+    fn with_store(store: Store) -> Self {
+        SequenceChecker {
+            store,
+            busy: Mutex::new(HashSet::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Number of the next message expected from `sender`, its MSH-3 and MSH-4 as `LAB|NORTH`.
+    pub fn expected(&self, sender: &str) -> io::Result<i64> {
+        self.store.expected(sender)
+    }
+
+    /// Waits for the message of `sender` being handled, if any, then marks it busy until the
+    /// guard returned is dropped.
+    fn wait_turn(&self, sender: &str) -> Turn<'_> {
+        let mut busy = self.lock();
+        while busy.contains(sender) {
+            busy = self.released.wait(busy).unwrap_or_else(|e| e.into_inner());
+        }
+        busy.insert(sender.to_owned());
+
+        Turn { checker: self, sender: sender.to_owned() }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashSet<String>> {
+        self.busy.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Turn of a sender to have a message handled.
+struct Turn<'a> {
+    checker: &'a SequenceChecker,
+    sender: String,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        self.checker.lock().remove(&self.sender);
+        self.checker.released.notify_all();
+    }
+}
+
+impl Interceptor for SequenceChecker {
+    fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+        let number = match field(message, b"MSH", 12).and_then(|number| std::str::from_utf8(number).ok()?.trim().parse::<i64>().ok()) {
+            None | Some(0) => return next(message),
+            Some(number) => number,
+        };
+        let Some(sender) = crate::sender(message) else {
+            return next(message);
+        };
+
+        // the sender's next message waits for this one, not the other senders' messages
+        let _turn = self.wait_turn(&sender);
+        let Ok(expected) = self.store.expected(&sender) else {
+            return AckDecision::CommitNak;
+        };
+        if number == -1 || number < expected {
+            return acknowledgement(message, "AA", expected);
+        }
+        if number > expected {
+            return acknowledgement(message, "AR", expected);
+        }
+
+        let response = next(message);
+        if response == AckDecision::CommitNak {
+            return response;
+        }
+        // a file moves on in memory even if it cannot be written
+        let _ = self.store.handled(&sender, number);
+
+        match response {
+            AckDecision::ApplicationAck(ack) => {
+                let expected = (number + 1).to_string();
+                AckDecision::ApplicationAck(with_field(&ack, b"MSA", 4, expected.as_bytes()).unwrap_or(ack))
+            }
+            response => response,
+        }
+    }
+}
+
+/// Key of the number expected from `sender` in a [`ClusterStore`].
+#[cfg(feature = "unstable")]
+fn cluster_key(sender: &str) -> String {
+    format!("{}:sequence", sender)
+}
+
+/// Name of the file of the number expected from `sender`, its bytes other than letters, digits,
+/// `-` and `_` written as `%XX`.
+fn file_name(sender: &str) -> String {
+    let mut name = String::with_capacity(sender.len() + 4);
+    for byte in sender.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            byte => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name.push_str(".seq");
+
+    name
+}
+
+/// Application ACK of `message` with the acknowledgment `code`, and `expected` in MSA-4.
+fn acknowledgement(message: &[u8], code: &str, expected: i64) -> AckDecision {
+    AckDecision::ApplicationAck(crate::acknowledgement(message, code, Some(expected)))
+}
+
+/// MSA-4 expected sequence number of an acknowledgement.
+fn expected_number(ack: &[u8]) -> Option<i64> {
+    std::str::from_utf8(field(ack, b"MSA", 4)?).ok()?.trim().parse().ok()
+}
+
+/// Field `index` of the first `segment` of `payload`, counted as split on the field separator:
+/// MSH-13 is 12, MSA-4 is 4.
+fn field<'a>(payload: &'a [u8], segment: &[u8; 3], index: usize) -> Option<&'a [u8]> {
+    let separator = *payload.strip_prefix(b"MSH")?.first()?;
+    let line = payload.split(|b| *b == b'\r' || *b == b'\n').find(|line| line.starts_with(segment))?;
+
+    line.split(|b| *b == separator).nth(index).filter(|field| !field.is_empty())
+}
+
+/// Copy of `payload` with field `index` of its first `segment` set to `value`, added if the
+/// segment is shorter.
+fn with_field(payload: &[u8], segment: &[u8; 3], index: usize, value: &[u8]) -> Option<Vec<u8>> {
+    let separator = *payload.strip_prefix(b"MSH")?.first()?;
+    let start = payload
+        .split(|b| *b == b'\r' || *b == b'\n')
+        .scan(0, |offset, line| {
+            let start = *offset;
+            *offset += line.len() + 1;
+            Some((start, line))
+        })
+        .find(|(_, line)| line.starts_with(segment))?
+        .0;
+    let end = payload[start..].iter().position(|b| *b == b'\r' || *b == b'\n').map_or(payload.len(), |end| start + end);
+
+    let mut fields: Vec<&[u8]> = payload[start..end].split(|b| *b == separator).collect();
+    fields.resize(fields.len().max(index + 1), &[]);
+    fields[index] = value;
+
+    Some([&payload[..start], &fields.join(&separator), &payload[end..]].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::env;
+    use std::fs;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+    use crate::client::Ack;
+    use crate::handler::AckDecision;
+    use crate::interceptor::Interceptor;
+    use crate::sequence::{expected_number, field, file_name, SequenceChecker, SequenceNumbers};
+
+    fn path(name: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("mllp-sequence-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_dir_all(&path);
+        path
+    }
+
+    fn message(number: i64) -> Vec<u8> {
+        message_from("LAB", number)
+    }
+
+    fn message_from(application: &str, number: i64) -> Vec<u8> {
+        format!("MSH|^~\\&|{}|NORTH|EHR|SOUTH|20240131||ORU^R01|MSG{}|P|2.5|{}\rPID|1", application, number, number).into_bytes()
+    }
+
+    /// MSA-1 and MSA-4 of an application acknowledgement.
+    fn expected(response: AckDecision) -> (Vec<u8>, i64) {
+        match response {
+            AckDecision::ApplicationAck(ack) => (field(&ack, b"MSA", 1).unwrap().to_vec(), expected_number(&ack).unwrap()),
+            response => panic!("{:?}", response),
+        }
+    }
+
+    #[test]
+    fn it_stamps_and_persists_sequence_numbers() {
+        let path = path("sender");
+        let numbers = SequenceNumbers::open(&path).unwrap();
+        let (number, stamped) = numbers.stamp(b"MSH|^~\\&|LAB|NORTH|EHR|SOUTH|20240131||ORU^R01|MSG1|P|2.5\rPID|1", 0).unwrap();
+        assert_eq!(number, 1);
+        assert_eq!(stamped, b"MSH|^~\\&|LAB|NORTH|EHR|SOUTH|20240131||ORU^R01|MSG1|P|2.5|1\rPID|1");
+
+        numbers.acknowledged(1, &Ack::Commit);
+        assert_eq!(SequenceNumbers::open(&path).unwrap().get(), 2);
+        numbers.acknowledged(2, &Ack::Application(b"MSH|^~\\&|EHR\rMSA|AR|MSG2||7".to_vec()));
+        assert_eq!(SequenceNumbers::open(&path).unwrap().get(), 7);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_checks_sequence_numbers() {
+        let path = path("receiver");
+        let checker = SequenceChecker::open(&path).unwrap();
+        let handled = Cell::new(0);
+        let handler = |_: &[u8]| {
+            handled.set(handled.get() + 1);
+            AckDecision::ApplicationAck(b"MSH|^~\\&|EHR\rMSA|AA|MSG".to_vec())
+        };
+
+        assert_eq!(expected(checker.around(&message(-1), &handler)), (b"AA".to_vec(), 1));
+        assert_eq!(expected(checker.around(&message(1), &handler)), (b"AA".to_vec(), 2));
+        assert_eq!(expected(checker.around(&message(1), &handler)), (b"AA".to_vec(), 2));
+        assert_eq!(expected(checker.around(&message(3), &handler)), (b"AR".to_vec(), 2));
+        assert_eq!(handled.get(), 1);
+
+        assert_eq!(checker.around(&message(0), &|_: &[u8]| AckDecision::CommitAck), AckDecision::CommitAck);
+        assert_eq!(checker.around(&message(2), &|_: &[u8]| AckDecision::CommitNak), AckDecision::CommitNak);
+        assert_eq!(SequenceNumbers::open(path.join("LAB%7CNORTH.seq")).unwrap().get(), 2);
+        assert_eq!(SequenceChecker::open(&path).unwrap().expected("LAB|NORTH").unwrap(), 2);
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn it_expects_a_number_from_each_sender() {
+        let path = path("senders");
+        let checker = SequenceChecker::open(&path).unwrap();
+        let handler = |_: &[u8]| AckDecision::ApplicationAck(b"MSH|^~\\&|EHR\rMSA|AA|MSG".to_vec());
+
+        assert_eq!(expected(checker.around(&message_from("LAB", 1), &handler)), (b"AA".to_vec(), 2));
+        assert_eq!(expected(checker.around(&message_from("RIS", 1), &handler)), (b"AA".to_vec(), 2));
+        assert_eq!(expected(checker.around(&message_from("LAB", 2), &handler)), (b"AA".to_vec(), 3));
+        assert_eq!(checker.expected("LAB|NORTH").unwrap(), 3);
+        assert_eq!(checker.expected("RIS|NORTH").unwrap(), 2);
+        assert_eq!(checker.expected("ADT|NORTH").unwrap(), 1);
+        assert_eq!(file_name("LAB/../x|y"), "LAB%2F%2E%2E%2Fx%7Cy.seq");
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn it_handles_other_senders_meanwhile() {
+        let path = path("meanwhile");
+        let checker = Arc::new(SequenceChecker::open(&path).unwrap());
+        let (entered, in_handler) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+
+        let lab = checker.clone();
+        let handling = thread::spawn(move || {
+            lab.around(&message_from("LAB", 1), &|_: &[u8]| {
+                entered.send(()).unwrap();
+                released.recv().unwrap();
+                AckDecision::CommitAck
+            })
+        });
+        in_handler.recv().unwrap();
+
+        // another sender goes on, the same sender waits for its turn
+        assert_eq!(checker.around(&message_from("RIS", 1), &|_: &[u8]| AckDecision::CommitAck), AckDecision::CommitAck);
+        let waiting = checker.clone();
+        let next = thread::spawn(move || expected(waiting.around(&message_from("LAB", 1), &|_: &[u8]| AckDecision::CommitAck)));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!next.is_finished());
+
+        release.send(()).unwrap();
+        assert_eq!(handling.join().unwrap(), AckDecision::CommitAck);
+        assert_eq!(next.join().unwrap(), (b"AA".to_vec(), 2));
+        let _ = fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    #[cfg(feature = "unstable")]
+    fn it_shares_expected_numbers_through_a_cluster_store() {
+        use crate::cluster::InMemoryClusterStore;
+
+        let store = Arc::new(InMemoryClusterStore::new());
+        let north = SequenceChecker::with_cluster_store(store.clone());
+        let south = SequenceChecker::with_cluster_store(store.clone());
+        let handled = Cell::new(0);
+        let handler = |_: &[u8]| {
+            handled.set(handled.get() + 1);
+            AckDecision::ApplicationAck(b"MSH|^~\\&|EHR\rMSA|AA|MSG".to_vec())
+        };
+
+        assert_eq!(expected(north.around(&message(1), &handler)), (b"AA".to_vec(), 2));
+        assert_eq!(expected(south.around(&message(1), &handler)), (b"AA".to_vec(), 2));
+        assert_eq!(expected(south.around(&message(2), &handler)), (b"AA".to_vec(), 3));
+        assert_eq!(expected(north.around(&message(4), &handler)), (b"AR".to_vec(), 3));
+        assert_eq!(handled.get(), 2);
+        assert_eq!(north.expected("LAB|NORTH").unwrap(), 3);
+    }
+}
@@ -0,0 +1,2809 @@
+//! Blocking MLLP server.
+
+mod transport;
+mod worker_pool;
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use socket2::{Domain, Protocol, Socket, Type};
+use crate::audit::{AuditRecord, AuditSink, Disposition};
+use crate::capture::{Direction, PayloadCapture};
+use crate::clock;
+use crate::config::{self, ConfigError, MllpServerConfigBuilder};
+use crate::event::{Event, EventKind, EventSink};
+use crate::filter::{ConnectionFilter, IpRange};
+use crate::handler::{AckDecision, MllpHandler, ReceivedFrame};
+#[cfg(feature = "hl7")]
+use crate::hl7::{Hl7Adapter, Hl7Handler};
+use crate::interceptor::{intercept, Interceptor};
+use crate::journal::FrameJournal;
+use crate::metrics::{Counter, Histogram, Metrics};
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::sink::{MessageSink, SinkError};
+use crate::stats::{ConnectionStats, ListenerStats, Stats, StatsRecorder};
+use crate::memory::{MemoryConnector, MemoryListener};
+use crate::trace;
+use crate::transport::Transport;
+#[cfg(feature = "tls")]
+use rustls::pki_types::CertificateDer;
+#[cfg(feature = "tls")]
+use crate::tls::TlsAcceptor;
+use crate::{AckMode, LowerLayerCodec, MllpCodec, MllpDecoder, MllpSyntaxError, Timeout, ACK, NAK};
+use self::transport::{Listener, Stream};
+use self::worker_pool::WorkerPool;
+
+/// What the server does with a message received over a rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RateLimitPolicy {
+    /// Waits until the message is within the limit before handling it. Nothing more is read from
+    /// the connection meanwhile, so TCP flow control slows the sender down.
+    #[default]
+    Delay,
+    /// Answers NAK without calling the handler.
+    Nak,
+}
+
+/// What the server does with a message its [message sink](MllpServerConfig::message_sink) did
+/// not take.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SinkPolicy {
+    /// Answers as the handler decided, the message not being forwarded.
+    Drop,
+    /// Answers NAK, for the sender to send the message again.
+    #[default]
+    Nak,
+    /// Tries again for up to this long, nothing more being read from the connection meanwhile,
+    /// then answers NAK.
+    Retry(Duration),
+    /// Answers NAK, then closes the connection.
+    NakAndClose,
+}
+
+/// What the server does with a frame it cannot decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MalformedFramePolicy {
+    /// Answers NAK, unless commit acknowledgements are off in the
+    /// [acknowledgement mode](MllpServerConfig::ack_mode), and goes on reading the next frames.
+    #[default]
+    NakAndContinue,
+    /// Answers NAK as [`MalformedFramePolicy::NakAndContinue`] does, then closes the connection.
+    NakAndClose,
+    /// Closes the connection without answering.
+    Close,
+}
+
+/// What the server does with connections beyond [`MllpServerConfig::max_connections`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverCapacityPolicy {
+    /// Stops accepting until a connection closes. Further connections wait in the listen
+    /// backlog, and are refused by the system once it is full.
+    #[default]
+    Queue,
+    /// Accepts the connections and closes them right away.
+    Reject,
+}
+
+/// File descriptor budget of the process, see [`MllpServerConfig::fd_budget`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdBudget {
+    /// Descriptors the process may have open, usually the soft `RLIMIT_NOFILE` (`ulimit -n`).
+    pub max_fds: usize,
+    /// Descriptors to keep free for the rest of the process: files, outbound connections,
+    /// logs. Below that, the server stops accepting.
+    pub reserve: usize,
+}
+
+/// Coalescing of the responses of a connection, see [`MllpServerConfig::write_coalescing`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteCoalescing {
+    /// Longest a response waits for the following ones.
+    pub max_delay: Duration,
+    /// Responses are written once this many bytes are waiting.
+    pub max_bytes: usize,
+}
+
+impl Default for WriteCoalescing {
+    fn default() -> Self {
+        WriteCoalescing {
+            max_delay: Duration::from_millis(5),
+            max_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Delay before accepting again after the first failure to accept, doubling with each further
+/// failure up to [`MAX_ACCEPT_BACKOFF`].
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How often connection threads check whether the server is shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a message is offered again to a full or failing sink, see [`SinkPolicy::Retry`].
+const SINK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Listen backlog used when [`MllpServerConfig::listen_backlog`] is not set, the same as the
+/// standard library's.
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// Settings of an [`MllpServer`].
+#[derive(Debug, Clone, Default)]
+pub struct MllpServerConfig {
+    /// Connections on which nothing was received for this long are closed. `None` keeps them
+    /// open forever.
+    pub idle_timeout: Option<Duration>,
+    /// Capture of the received payloads.
+    pub capture: Option<Arc<PayloadCapture>>,
+    /// Journal of every frame read and written, the frames outside of the rate limits included.
+    pub journal: Option<Arc<FrameJournal>>,
+    /// Audit trail of the messages received, and of how they were answered.
+    pub audit: Option<Arc<dyn AuditSink>>,
+    /// Destination the messages are forwarded to once the handler decided, unless it answered
+    /// with a NAK. The NAKs of the sink policies are written only where those of the handler
+    /// would be, e.g. not in [automatic responder mode](MllpServerConfig::auto_ack).
+    pub message_sink: Option<Arc<dyn MessageSink>>,
+    /// What is done with the messages the message sink has no room for.
+    pub sink_full_policy: SinkPolicy,
+    /// What is done with the messages the message sink failed to take.
+    pub sink_failure_policy: SinkPolicy,
+    /// Chain of interceptors the messages go through before the handler, the first one being
+    /// the outermost. Messages rejected by a rate limit do not reach them.
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+    /// Automatic responder mode: each frame is answered with a commit ACK as soon as it is
+    /// decoded and within the rate limits, before the interceptors and the handler run. The
+    /// commit ACKs and NAKs decided by the handler are then not written, while its application
+    /// acknowledgements still are, after the commit ACK. For the senders stalling until the
+    /// transport acknowledgement comes back.
+    pub auto_ack: bool,
+    /// Acknowledgement frames written back, whatever the handler decides:
+    /// - [`AckMode::TransportOnly`] drops the application acknowledgements.
+    /// - [`AckMode::ApplicationOnly`] drops the commit ACKs and NAKs, including the NAKs of the
+    ///   rate limits and of the [malformed frames](MllpServerConfig::malformed_frame_policy).
+    /// - [`AckMode::Both`] answers each message with a commit ACK before the handler runs, as
+    ///   [`MllpServerConfig::auto_ack`] does, then writes the application acknowledgement.
+    /// - [`AckMode::None`] writes nothing back.
+    ///
+    /// `None` writes what the handler decides.
+    pub ack_mode: Option<AckMode>,
+    /// Connections not sending a complete frame within this delay after being accepted are
+    /// closed, for the port scanners and the clients which connect and never talk, whatever the
+    /// [idle timeout](MllpServerConfig::idle_timeout) of the quiet senders. `None` waits for the
+    /// idle timeout.
+    pub first_frame_timeout: Option<Duration>,
+    /// Connections not sending a single byte within this delay after being accepted are
+    /// closed. `None` waits for the first frame or idle timeout.
+    pub first_byte_timeout: Option<Duration>,
+    /// Connections stalling this long in the middle of a frame are closed, for the senders
+    /// which hang after a partial write and would otherwise hold the connection until the idle
+    /// timeout. `None` waits for the idle timeout.
+    pub inter_byte_timeout: Option<Duration>,
+    /// How long writing a response may wait for the sender to read, after which the connection
+    /// is closed. `None` waits forever.
+    pub write_timeout: Option<Duration>,
+    /// Largest frame accepted, `<SB>`, `<EB>` and `<CR>` included. A larger frame is discarded
+    /// without reaching the handler, as soon as it is known to be larger, and counts as bytes
+    /// outside of a frame. `None` accepts frames of any size.
+    pub max_frame_size: Option<usize>,
+    /// Payload of the heartbeats clients send on idle connections, such as the empty block of
+    /// [`MllpClientConfig::heartbeat_payload`](crate::client::MllpClientConfig::heartbeat_payload).
+    /// They are swallowed, neither handled nor answered. `None` handles every frame.
+    pub heartbeat_payload: Option<Vec<u8>>,
+    /// Framing of the messages and responses on the wire. `None` frames them with MLLP.
+    pub codec: Option<Arc<dyn LowerLayerCodec>>,
+    /// Ignores the bytes a client sends before its first frame, such as a text banner: they
+    /// are not answered with a NAK as [malformed frames](MllpServerConfig::malformed_frame_policy)
+    /// are.
+    pub skip_banner: bool,
+    /// What is done with the frames which cannot be decoded, past a skipped banner.
+    pub malformed_frame_policy: MalformedFramePolicy,
+    /// Limit of the messages handled on each connection.
+    pub connection_rate_limit: Option<RateLimit>,
+    /// Limit of the messages handled by the server, all connections together.
+    pub global_rate_limit: Option<RateLimit>,
+    /// What is done with the messages over a limit.
+    pub rate_limit_policy: RateLimitPolicy,
+    /// Ranges of the addresses connections are accepted from. Other connections are closed
+    /// right after being accepted, before anything is read. `None` accepts any address.
+    pub allowed_peers: Option<Vec<IpRange>>,
+    /// Called with the address of each connection from an allowed peer, the connection being
+    /// closed right away if it returns `false`.
+    pub connection_filter: Option<Arc<dyn ConnectionFilter>>,
+    /// Maximum number of connections handled at the same time. `None` is unlimited.
+    pub max_connections: Option<usize>,
+    /// What is done with the connections over [`MllpServerConfig::max_connections`].
+    pub over_capacity: OverCapacityPolicy,
+    /// Length of the queue of connections waiting to be accepted. The system may cap it, e.g.
+    /// to `net.core.somaxconn` on Linux. `None` uses 128.
+    pub listen_backlog: Option<u32>,
+    /// Whether a listener bound to an IPv6 address, such as `[::]:2575`, also accepts IPv4
+    /// connections, whose peers are then given their IPv4 address. `None` leaves the system
+    /// default, dual-stack on Linux but IPv6 only on Windows and the BSDs.
+    pub dual_stack: Option<bool>,
+    /// After a [shutdown](ShutdownHandle::shutdown), how long to wait for the messages being
+    /// received to complete. `None` waits until the connection goes idle.
+    pub drain_timeout: Option<Duration>,
+    /// When fewer than [`FdBudget::reserve`] descriptors are left, the server stops accepting
+    /// and closes idle connections, the longest idle first, until enough descriptors are free.
+    /// On Linux, all the descriptors of the process are counted; elsewhere, only the connections
+    /// of the server.
+    pub fd_budget: Option<FdBudget>,
+    /// Receiver of the server events: [`EventKind::AcceptPaused`], [`EventKind::AcceptResumed`],
+    /// [`EventKind::ConnectionRejected`], [`EventKind::ConnectionShed`],
+    /// [`EventKind::FirstFrameTimeout`] and [`EventKind::TimedOut`].
+    pub event_sink: Option<Arc<dyn EventSink>>,
+    /// Worker-pool mode: the connections are watched by a single thread, and their messages
+    /// handled by this many worker threads, instead of each connection having its own thread.
+    /// A handler that blocks, or a [`RateLimitPolicy::Delay`] wait, holds up its worker. `None`
+    /// uses a thread per connection.
+    pub worker_threads: Option<usize>,
+    /// Gathers the responses to the messages received together, still framed one by one, into
+    /// a single write, instead of a write per response. This cuts the system calls for senders
+    /// pipelining many small messages. Responses are never held for more input: they are
+    /// written once the messages received so far are handled, or earlier when the bounds are
+    /// reached. `None` writes each response right away.
+    pub write_coalescing: Option<WriteCoalescing>,
+    /// Receiver of the metrics of the connections and of the messages received.
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Accepts the connections over TLS, the handshake counting towards the
+    /// [first frame timeout](MllpServerConfig::first_frame_timeout), or else the
+    /// [idle timeout](MllpServerConfig::idle_timeout). Only with a thread per connection, over
+    /// TCP.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<TlsAcceptor>>,
+}
+
+impl MllpServerConfig {
+    /// Builder of a configuration, checking it when built.
+    pub fn builder() -> MllpServerConfigBuilder {
+        MllpServerConfigBuilder::default()
+    }
+}
+
+/// Server receiving MLLP framed messages, handling each connection on its own thread, or with
+/// a [pool of worker threads](MllpServerConfig::worker_threads).
+///
+/// The [handler](MllpHandler) is called with the payload of each received message, and decides
+/// what to write back to the sender.
+/// ```no_run
+/// use std::time::Duration;
+/// use mllp_rs::handler::AckDecision;
+/// use mllp_rs::server::{MllpServer, MllpServerConfig};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let config = MllpServerConfig {
+///     idle_timeout: Some(Duration::from_secs(600)),
+///     ..MllpServerConfig::default()
+/// };
+/// let server = MllpServer::bind("0.0.0.0:2575", config)?;
+/// server.serve(|message: &[u8]| {
+///     println!("{}", String::from_utf8_lossy(message));
+///     AckDecision::CommitAck
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MllpServer {
+    listener: Listener,
+    config: MllpServerConfig,
+    settings: SettingsHandle,
+    shutdown: ShutdownHandle,
+    registry: Arc<ConnectionRegistry>,
+}
+
+/// Handle stopping an [`MllpServer`], obtained with [`MllpServer::shutdown_handle`].
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    requested_at: Arc<OnceLock<Instant>>,
+    local_addr: SocketAddr,
+    /// Path of the Unix domain socket listened on, if any.
+    unix_path: Option<PathBuf>,
+    /// Connector of the in-memory listener listened on, if any.
+    memory: Option<MemoryConnector>,
+}
+
+impl ShutdownHandle {
+    /// Stops the server gracefully, without waiting for it to stop.
+    ///
+    /// No new connection is accepted. Connections between two messages are closed, while
+    /// connections in the middle of a message are closed once it is received, handled and
+    /// answered, or after [`MllpServerConfig::drain_timeout`]. [`MllpServer::serve`] then
+    /// returns.
+    pub fn shutdown(&self) {
+        if self.requested_at.set(Instant::now()).is_ok() {
+            // wake up the accept loop
+            #[cfg(unix)]
+            if let Some(path) = &self.unix_path {
+                let _ = UnixStream::connect(path);
+                return;
+            }
+            if let Some(connector) = &self.memory {
+                let _ = connector.connect();
+                return;
+            }
+            let _ = TcpStream::connect_timeout(&self.wake_addr(), Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.requested_at.get().is_some()
+    }
+
+    /// Whether the connections still draining must be closed now.
+    fn is_drain_over(&self, drain_timeout: Option<Duration>) -> bool {
+        match (self.requested_at.get(), drain_timeout) {
+            (Some(requested_at), Some(timeout)) => requested_at.elapsed() >= timeout,
+            _ => false,
+        }
+    }
+
+    fn wake_addr(&self) -> SocketAddr {
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        addr
+    }
+}
+
+/// Handle pausing and resuming the reading of connections of an [`MllpServer`], obtained with
+/// [`MllpServer::flow_control`].
+///
+/// Nothing is read from a paused connection, and the messages already received from it are not
+/// handled, so TCP flow control holds its sender back, while the other connections go on. The
+/// time a connection is paused does not count towards its idle timeout. Connections are told
+/// apart by the address of their peer, as in [events](crate::event::Event::peer_addr).
+/// ```no_run
+/// use mllp_rs::handler::AckDecision;
+/// use mllp_rs::server::{MllpServer, MllpServerConfig};
+///
+/// # fn main() -> std::io::Result<()> {
+/// # fn downstream_degraded() -> bool { false }
+/// let server = MllpServer::bind("0.0.0.0:2575", MllpServerConfig::default())?;
+/// let flow = server.flow_control();
+/// server.serve(move |message: &[u8]| {
+///     if downstream_degraded() {
+///         for peer_addr in flow.connections() {
+///             flow.pause(peer_addr);
+///         }
+///     }
+///     AckDecision::CommitAck
+/// })?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FlowControl {
+    registry: Arc<ConnectionRegistry>,
+}
+
+impl FlowControl {
+    /// Addresses of the peers of the open connections.
+    pub fn connections(&self) -> Vec<SocketAddr> {
+        self.registry.lock().iter().map(|state| state.peer_addr).collect()
+    }
+
+    /// Stops reading from the connections of `peer_addr`. Returns whether there was one.
+    pub fn pause(&self, peer_addr: SocketAddr) -> bool {
+        self.set_paused(peer_addr, true)
+    }
+
+    /// Reads again from the connections of `peer_addr`. Returns whether there was one.
+    pub fn resume(&self, peer_addr: SocketAddr) -> bool {
+        self.set_paused(peer_addr, false)
+    }
+
+    pub fn is_paused(&self, peer_addr: SocketAddr) -> bool {
+        self.registry.lock().iter().any(|state| state.peer_addr == peer_addr && state.is_paused())
+    }
+
+    fn set_paused(&self, peer_addr: SocketAddr, paused: bool) -> bool {
+        let connections = self.registry.lock();
+        let mut found = false;
+        for state in connections.iter().filter(|state| state.peer_addr == peer_addr) {
+            state.paused.store(paused, Ordering::Relaxed);
+            found = true;
+        }
+        found
+    }
+
+    /// Sessions of the open connections.
+    pub fn sessions(&self) -> Vec<Session> {
+        self.registry.lock().iter().map(|state| Session { state: state.clone() }).collect()
+    }
+}
+
+/// Handle of a connection accepted by an [`MllpServer`], passed to the handler in
+/// [`ReceivedFrame::session`].
+///
+/// It tells who the peer is and how the connection went so far, and lets the handler close the
+/// connection, for instance after a message from a peer it does not trust any more. A session
+/// may be kept and used from another thread; it outlives its connection.
+/// ```no_run
+/// use mllp_rs::handler::{AckDecision, MllpHandler, ReceivedFrame};
+///
+/// struct Limited;
+///
+/// impl MllpHandler for Limited {
+///     fn on_message(&self, _: &[u8]) -> AckDecision {
+///         AckDecision::CommitAck
+///     }
+///
+///     fn on_frame(&self, frame: &ReceivedFrame<'_>) -> AckDecision {
+///         if frame.session.messages_received() >= 1000 {
+///             frame.session.close();
+///         }
+///         self.on_message(frame.payload)
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Session {
+    state: Arc<ConnectionState>,
+}
+
+impl Session {
+    /// Session of no connection, for testing handlers.
+    pub fn detached(peer_addr: SocketAddr) -> Self {
+        Session { state: Arc::new(ConnectionState::new(0, peer_addr, Arc::default())) }
+    }
+
+    /// Identifier of the connection, the [`ReceivedFrame::connection_id`] of its messages.
+    pub fn id(&self) -> u64 {
+        self.state.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.state.peer_addr
+    }
+
+    /// Time since the connection was accepted.
+    pub fn uptime(&self) -> Duration {
+        self.state.accepted_at.elapsed()
+    }
+
+    /// Number of messages received, heartbeats left out, the message being handled included.
+    pub fn messages_received(&self) -> u64 {
+        self.state.messages.load(Ordering::Relaxed)
+    }
+
+    /// Number of commit ACKs written.
+    pub fn acks_sent(&self) -> u64 {
+        self.state.acks.load(Ordering::Relaxed)
+    }
+
+    /// Number of commit NAKs written, for refused, rate limited or undecodable messages.
+    pub fn naks_sent(&self) -> u64 {
+        self.state.naks.load(Ordering::Relaxed)
+    }
+
+    /// Statistics of the messages received, see [`stats`](crate::stats).
+    pub fn stats(&self) -> Stats {
+        self.state.stats.snapshot()
+    }
+
+    /// Certificate chain the peer presented in the TLS handshake, its own certificate first.
+    /// `None` without TLS or when the peer presented none, the server not asking for it.
+    #[cfg(feature = "tls")]
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.state.peer_certificates.get().map(Vec::as_slice)
+    }
+
+    /// Closes the connection once the response to the message being handled is written. The
+    /// messages received after it are not handled.
+    pub fn close(&self) {
+        self.state.closing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.state.is_closing()
+    }
+}
+
+impl PartialEq for Session {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl Eq for Session { }
+
+/// Settings of an [`MllpServer`] which can be changed while it runs, with its
+/// [`SettingsHandle`]. Each has the meaning of the field of [`MllpServerConfig`] of the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReloadableSettings {
+    /// Checked for the connections accepted after the change; the open connections are kept.
+    pub allowed_peers: Option<Vec<IpRange>>,
+    pub connection_rate_limit: Option<RateLimit>,
+    /// The global rate limit starts from a full bucket when changed.
+    pub global_rate_limit: Option<RateLimit>,
+    pub ack_mode: Option<AckMode>,
+    /// Checked for the frames whose first byte is received after the change.
+    pub max_frame_size: Option<usize>,
+}
+
+impl From<&MllpServerConfig> for ReloadableSettings {
+    fn from(config: &MllpServerConfig) -> Self {
+        ReloadableSettings {
+            allowed_peers: config.allowed_peers.clone(),
+            connection_rate_limit: config.connection_rate_limit,
+            global_rate_limit: config.global_rate_limit,
+            ack_mode: config.ack_mode,
+            max_frame_size: config.max_frame_size,
+        }
+    }
+}
+
+/// Handle changing the [reloadable settings](ReloadableSettings) of an [`MllpServer`] while it
+/// runs, without closing the listener nor the connections. Obtained with
+/// [`MllpServer::settings_handle`].
+///
+/// The connections pick the new settings up before handling the next messages received.
+/// ```no_run
+/// use mllp_rs::server::{MllpServer, MllpServerConfig};
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let server = MllpServer::bind("0.0.0.0:2575", MllpServerConfig::default())?;
+/// let settings = server.settings_handle();
+/// settings.update(|settings| settings.allowed_peers = Some(vec!["10.1.0.0/16".parse().unwrap()]))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SettingsHandle {
+    shared: Arc<SharedSettings>,
+}
+
+#[derive(Debug)]
+struct SharedSettings {
+    current: RwLock<ReloadableSettings>,
+    /// Bucket of the current global rate limit.
+    global_limit: RwLock<Option<Arc<TokenBucket>>>,
+    /// Number of changes, for the connections to notice them.
+    version: AtomicU64,
+}
+
+impl SettingsHandle {
+    fn new(config: &MllpServerConfig) -> Self {
+        SettingsHandle {
+            shared: Arc::new(SharedSettings {
+                current: RwLock::new(config.into()),
+                global_limit: RwLock::new(config.global_rate_limit.map(|limit| Arc::new(TokenBucket::new(limit)))),
+                version: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn get(&self) -> ReloadableSettings {
+        self.shared.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replaces the settings, if they pass the checks of [`MllpServerConfig::builder`].
+    pub fn set(&self, settings: ReloadableSettings) -> Result<(), ConfigError> {
+        config::check_reloadable(&settings)?;
+        let mut current = self.shared.current.write().unwrap_or_else(|e| e.into_inner());
+        if current.global_rate_limit != settings.global_rate_limit {
+            *self.shared.global_limit.write().unwrap_or_else(|e| e.into_inner()) =
+                settings.global_rate_limit.map(|limit| Arc::new(TokenBucket::new(limit)));
+        }
+        *current = settings;
+        self.shared.version.fetch_add(1, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Changes the settings with `change`, keeping them if the result does not pass the checks.
+    pub fn update<F: FnOnce(&mut ReloadableSettings)>(&self, change: F) -> Result<(), ConfigError> {
+        let mut settings = self.get();
+        change(&mut settings);
+        self.set(settings)
+    }
+
+    fn version(&self) -> u64 {
+        self.shared.version.load(Ordering::Acquire)
+    }
+
+    fn global_limit(&self) -> Option<Arc<TokenBucket>> {
+        self.shared.global_limit.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl MllpServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: MllpServerConfig) -> io::Result<Self> {
+        let backlog = listen_backlog(&config);
+
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address to bind to");
+        for addr in addr.to_socket_addrs()? {
+            match listen(addr, backlog, config.dual_stack) {
+                Ok(listener) => return Self::with_listener(Listener::Tcp(listener), None, config),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Listens on a Unix domain socket created at `path`, which must not exist yet.
+    ///
+    /// Such connections have no IP address: [`MllpServer::local_addr`] is the unspecified address
+    /// `0.0.0.0:0`, and the peers of the connections are given `0.0.0.0` with a port numbering
+    /// them, in events and in [`FlowControl`]. Access to the server is controlled with the
+    /// permissions of the socket file.
+    #[cfg(unix)]
+    pub fn bind_uds<P: AsRef<Path>>(path: P, config: MllpServerConfig) -> io::Result<Self> {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.bind(&socket2::SockAddr::unix(path.as_ref())?)?;
+        socket.listen(listen_backlog(&config))?;
+        let listener = Listener::Unix {
+            listener: OwnedFd::from(socket).into(),
+            next_port: 1.into(),
+        };
+
+        Self::with_listener(listener, Some(path.as_ref().to_owned()), config)
+    }
+
+    /// Listens on `listener`, for connections made in memory with its
+    /// [`MemoryConnector`](crate::memory::MemoryConnector), in tests.
+    ///
+    /// Such connections have no IP address, like those over a
+    /// [Unix domain socket](MllpServer::bind_uds). They have a thread each: setting
+    /// [`MllpServerConfig::worker_threads`] or [`MllpServerConfig::tls`] makes
+    /// [`MllpServer::serve`] fail.
+    pub fn bind_memory(listener: MemoryListener, config: MllpServerConfig) -> io::Result<Self> {
+        let listener = Listener::Memory {
+            listener,
+            next_port: 1.into(),
+        };
+
+        Self::with_listener(listener, None, config)
+    }
+
+    /// Serves `transport`, e.g. a serial link, as the only connection: [`MllpServer::serve`]
+    /// returns once it ends, and fails if called again. See the [`transport`](crate::transport)
+    /// module.
+    ///
+    /// Transports have no IP address, like the connections over a
+    /// [Unix domain socket](MllpServer::bind_uds). Setting [`MllpServerConfig::worker_threads`]
+    /// or [`MllpServerConfig::tls`] makes [`MllpServer::serve`] fail.
+    pub fn bind_transport<T: Transport + 'static>(transport: T, config: MllpServerConfig) -> io::Result<Self> {
+        let listener = Listener::Transport(Mutex::new(Some(Box::new(transport))));
+
+        Self::with_listener(listener, None, config)
+    }
+
+    fn with_listener(listener: Listener, unix_path: Option<PathBuf>, config: MllpServerConfig) -> io::Result<Self> {
+        let memory = match &listener {
+            Listener::Memory { listener, .. } => Some(listener.connector()),
+            _ => None,
+        };
+        let shutdown = ShutdownHandle {
+            requested_at: Arc::new(OnceLock::new()),
+            local_addr: listener.local_addr()?,
+            unix_path,
+            memory,
+        };
+        let registry = Arc::new(ConnectionRegistry::default());
+        let settings = SettingsHandle::new(&config);
+
+        Ok(MllpServer { listener, config, settings, shutdown, registry })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Configuration the server was bound with, the [reloadable settings](ReloadableSettings)
+    /// as they were then.
+    pub fn config(&self) -> &MllpServerConfig {
+        &self.config
+    }
+
+    /// Statistics of the listener and of its open connections, see [`stats`](crate::stats).
+    pub fn stats(&self) -> ListenerStats {
+        let connections = self.registry.lock().iter()
+            .map(|state| ConnectionStats { id: state.id, peer_addr: state.peer_addr, stats: state.stats.snapshot() })
+            .collect();
+        ListenerStats { total: self.registry.stats.snapshot(), connections }
+    }
+
+    /// Returns a handle to stop the server, e.g. from a signal handler or another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Returns a handle to change some settings while the server runs.
+    pub fn settings_handle(&self) -> SettingsHandle {
+        self.settings.clone()
+    }
+
+    /// Returns a handle to pause and resume reading from connections, e.g. from the handler.
+    pub fn flow_control(&self) -> FlowControl {
+        FlowControl {
+            registry: self.registry.clone(),
+        }
+    }
+
+    /// Accepts connections, spawning a thread for each connection.
+    ///
+    /// Accept errors about a single connection, such as a connection reset before it was
+    /// accepted, are ignored. Other errors, such as running out of file descriptors (`EMFILE`),
+    /// make the server wait before accepting again, from 5 milliseconds doubling up to 1 second,
+    /// while connections close; meanwhile new connections wait in the
+    /// [listen backlog](MllpServerConfig::listen_backlog). Only an error showing that the listener
+    /// itself is unusable is returned.
+    ///
+    /// Once [shut down](ShutdownHandle::shutdown), returns `Ok` after all the connections are
+    /// closed.
+    ///
+    /// Bytes received outside of a frame are discarded.
+    pub fn serve<H>(&self, handler: H) -> io::Result<()>
+    where
+        H: MllpHandler + 'static,
+    {
+        self.serve_shared(Arc::new(handler))
+    }
+
+    /// Same as [`MllpServer::serve`], with a handler of parsed messages answered with the
+    /// acknowledgements built from their MSH. See [`hl7`](crate::hl7).
+    #[cfg(feature = "hl7")]
+    pub fn serve_hl7<H>(&self, handler: H) -> io::Result<()>
+    where
+        H: Hl7Handler + 'static,
+    {
+        self.serve(Hl7Adapter::new(handler))
+    }
+
+    /// Same as [`MllpServer::serve`], with a handler shared with other servers.
+    fn serve_shared<H>(&self, handler: Arc<H>) -> io::Result<()>
+    where
+        H: MllpHandler + 'static,
+    {
+        #[cfg(feature = "tls")]
+        if self.config.tls.is_some() && (self.config.worker_threads.is_some() || !matches!(self.listener, Listener::Tcp(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "TLS needs a thread per connection, over TCP"));
+        }
+        if self.config.worker_threads.is_some() && matches!(self.listener, Listener::Memory { .. }) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "in-memory connections need a thread each"));
+        }
+        if let Listener::Transport(_) = &self.listener {
+            if self.config.worker_threads.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "transports need a thread each"));
+            }
+            // the only connection, on this thread
+            let (stream, peer_addr) = self.listener.accept()?;
+            let state = self.registry.register(peer_addr);
+            let mut session = ConnectionReader::new(self.config.clone(), self.settings.clone(), state.clone());
+            let result = handle_connection(stream, &mut session, &self.shutdown, &*handler);
+            self.registry.unregister(&state);
+            return result;
+        }
+        let slots = self.config.max_connections.map(|max| Arc::new(ConnectionSlots::new(max)));
+        let mut connections: Vec<JoinHandle<io::Result<()>>> = Vec::new();
+        let registry = self.registry.clone();
+        let pool = match self.config.worker_threads {
+            Some(threads) => Some(WorkerPool::start(
+                threads,
+                self.shutdown.clone(),
+                registry.clone(),
+                handler.clone(),
+            )?),
+            None => None,
+        };
+
+        loop {
+            if let Some(budget) = self.config.fd_budget {
+                self.wait_for_fds(budget, &registry);
+            }
+            let queued_slot = match &slots {
+                Some(slots) if self.config.over_capacity == OverCapacityPolicy::Queue => Some(slots.acquire()),
+                _ => None,
+            };
+            let (stream, peer_addr) = accept_retrying(|| self.listener.accept())?;
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            if !self.is_allowed(peer_addr) {
+                self.reject(peer_addr);
+                drop(stream);
+                continue;
+            }
+            let slot = match (&slots, queued_slot) {
+                (Some(slots), None) => match slots.try_acquire() {
+                    Some(slot) => Some(slot),
+                    None => {
+                        self.reject(peer_addr);
+                        drop(stream);
+                        continue;
+                    }
+                },
+                (_, slot) => slot,
+            };
+
+            let state = registry.register(peer_addr);
+            let mut session = ConnectionReader::new(self.config.clone(), self.settings.clone(), state.clone());
+            if let Some(pool) = &pool {
+                pool.add(stream, session, slot);
+                continue;
+            }
+
+            let handler = handler.clone();
+            let shutdown = self.shutdown.clone();
+            let registry = registry.clone();
+            connections.retain(|connection| !connection.is_finished());
+            connections.push(thread::spawn(move || {
+                let _slot = slot;
+                let result = handle_connection(stream, &mut session, &shutdown, &*handler);
+                registry.unregister(&state);
+                result
+            }));
+        }
+
+        for connection in connections {
+            let _ = connection.join();
+        }
+        if let Some(pool) = pool {
+            pool.join();
+        }
+
+        Ok(())
+    }
+}
+
+impl MllpServer {
+    /// Whether the connection from `peer_addr` passes the allowed peers and the connection
+    /// filter. Connections over a Unix domain socket always do.
+    fn is_allowed(&self, peer_addr: SocketAddr) -> bool {
+        if !matches!(self.listener, Listener::Tcp(_)) {
+            return true;
+        }
+        let allowed = match &self.settings.shared.current.read().unwrap_or_else(|e| e.into_inner()).allowed_peers {
+            Some(ranges) => ranges.iter().any(|range| range.contains(peer_addr.ip())),
+            None => true,
+        };
+
+        allowed && self.config.connection_filter.as_ref().is_none_or(|filter| filter.accept(peer_addr))
+    }
+
+    /// Waits until fewer than [`FdBudget::reserve`] descriptors of the budget are in use,
+    /// shedding idle connections meanwhile.
+    fn wait_for_fds(&self, budget: FdBudget, registry: &ConnectionRegistry) {
+        let mut paused = false;
+
+        loop {
+            let open_fds = open_fds().unwrap_or_else(|| registry.len() + 1);
+            let available = budget.max_fds.saturating_sub(open_fds);
+            if available >= budget.reserve || self.shutdown.is_shutdown() {
+                break;
+            }
+            if !paused {
+                paused = true;
+                self.emit(self.shutdown.local_addr, EventKind::AcceptPaused { open_fds });
+            }
+            for peer_addr in registry.shed_idle(budget.reserve - available) {
+                self.emit(peer_addr, EventKind::ConnectionShed);
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+
+        if paused {
+            self.emit(self.shutdown.local_addr, EventKind::AcceptResumed);
+        }
+    }
+
+    fn reject(&self, peer_addr: SocketAddr) {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.increment(Counter::ConnectionsRejected);
+        }
+        self.emit(peer_addr, EventKind::ConnectionRejected);
+    }
+
+    fn emit(&self, peer_addr: SocketAddr, kind: EventKind) {
+        trace::event(self.shutdown.local_addr, peer_addr, &kind);
+        if let Some(sink) = &self.config.event_sink {
+            sink.on_event(&Event {
+                time: SystemTime::now(),
+                local_addr: self.shutdown.local_addr,
+                peer_addr,
+                kind,
+            });
+        }
+    }
+}
+
+/// Servers listening on several addresses, each with its own configuration, serving the messages
+/// with one handler and shut down together.
+///
+/// Each address is bound with the configuration of its own listener, e.g. a port per trading
+/// partner with its own allowed peers and rate limits, usually derived from a common one:
+/// ```no_run
+/// use mllp_rs::handler::AckDecision;
+/// use mllp_rs::server::{MllpServerConfig, MllpServerGroup};
+///
+/// # fn main() -> std::io::Result<()> {
+/// let base = MllpServerConfig::default();
+/// let mut group = MllpServerGroup::new();
+/// group.bind("0.0.0.0:2575", base.clone())?;
+/// group.bind("0.0.0.0:2576", MllpServerConfig {
+///     allowed_peers: Some(vec!["10.20.0.0/16".parse().unwrap()]),
+///     ..base
+/// })?;
+/// group.serve(|_: &[u8]| AckDecision::CommitAck)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct MllpServerGroup {
+    servers: Vec<MllpServer>,
+}
+
+/// Handle stopping an [`MllpServerGroup`], obtained with [`MllpServerGroup::shutdown_handle`].
+#[derive(Debug, Clone)]
+pub struct GroupShutdownHandle {
+    handles: Vec<ShutdownHandle>,
+}
+
+impl GroupShutdownHandle {
+    /// Stops every server of the group, as [`ShutdownHandle::shutdown`] does.
+    pub fn shutdown(&self) {
+        for handle in &self.handles {
+            handle.shutdown();
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.handles.iter().all(ShutdownHandle::is_shutdown)
+    }
+}
+
+impl MllpServerGroup {
+    pub fn new() -> Self {
+        MllpServerGroup::default()
+    }
+
+    /// Binds `addr` with `config`, as [`MllpServer::bind`] does, and adds the server to the
+    /// group.
+    pub fn bind<A: ToSocketAddrs>(&mut self, addr: A, config: MllpServerConfig) -> io::Result<&MllpServer> {
+        let server = MllpServer::bind(addr, config)?;
+        Ok(self.add(server))
+    }
+
+    /// Adds a server bound already, e.g. on a Unix domain socket.
+    pub fn add(&mut self, server: MllpServer) -> &MllpServer {
+        self.servers.push(server);
+        &self.servers[self.servers.len() - 1]
+    }
+
+    pub fn servers(&self) -> &[MllpServer] {
+        &self.servers
+    }
+
+    /// Returns a handle to stop all the servers of the group.
+    pub fn shutdown_handle(&self) -> GroupShutdownHandle {
+        GroupShutdownHandle {
+            handles: self.servers.iter().map(MllpServer::shutdown_handle).collect(),
+        }
+    }
+
+    /// Serves the connections of every server with `handler`, each server accepting on its own
+    /// thread, the first one on the calling thread.
+    ///
+    /// Returns once every server stopped. A server failing, as [`MllpServer::serve`] does, shuts
+    /// the others down, and its error is returned.
+    pub fn serve<H>(self, handler: H) -> io::Result<()>
+    where
+        H: MllpHandler + 'static,
+    {
+        let handler = Arc::new(handler);
+        let shutdown = self.shutdown_handle();
+        let serve = move |server: MllpServer, handler: Arc<H>| {
+            let result = server.serve_shared(handler);
+            if result.is_err() {
+                shutdown.shutdown();
+            }
+            result
+        };
+
+        let mut servers = self.servers.into_iter();
+        let Some(first) = servers.next() else {
+            return Ok(());
+        };
+        let others: Vec<_> = servers
+            .map(|server| {
+                let handler = handler.clone();
+                let serve = serve.clone();
+                thread::spawn(move || serve(server, handler))
+            })
+            .collect();
+
+        let mut result = serve(first, handler);
+        for other in others {
+            let outcome = other.join().unwrap_or_else(|_| Err(io::Error::other("a server panicked")));
+            result = result.and(outcome);
+        }
+
+        result
+    }
+}
+
+/// Number of descriptors open in the process, where the system tells.
+fn open_fds() -> Option<usize> {
+    if cfg!(target_os = "linux") {
+        // minus the descriptor of the directory being read
+        std::fs::read_dir("/proc/self/fd").ok().map(|entries| entries.count().saturating_sub(1))
+    } else {
+        None
+    }
+}
+
+/// Connections being handled, for the server to pick those to shed.
+#[derive(Debug, Default)]
+struct ConnectionRegistry {
+    connections: Mutex<Vec<Arc<ConnectionState>>>,
+    /// Identifier of the last connection registered.
+    last_id: AtomicU64,
+    /// Statistics of every connection registered.
+    stats: Arc<StatsRecorder>,
+}
+
+#[derive(Debug)]
+struct ConnectionState {
+    id: u64,
+    peer_addr: SocketAddr,
+    /// When the last message was received, or `None` while a message is being received or
+    /// handled.
+    idle_since: Mutex<Option<Instant>>,
+    /// Set when the server wants the connection closed.
+    shed: AtomicBool,
+    /// Set while nothing must be read from the connection.
+    paused: AtomicBool,
+    /// Set when the application wants the connection closed.
+    closing: AtomicBool,
+    accepted_at: Instant,
+    messages: AtomicU64,
+    acks: AtomicU64,
+    naks: AtomicU64,
+    stats: StatsRecorder,
+    /// Statistics of the listener, which the connection adds to.
+    listener_stats: Arc<StatsRecorder>,
+    /// Certificate chain the peer presented in the TLS handshake.
+    #[cfg(feature = "tls")]
+    peer_certificates: OnceLock<Vec<CertificateDer<'static>>>,
+}
+
+impl ConnectionRegistry {
+    fn register(&self, peer_addr: SocketAddr) -> Arc<ConnectionState> {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let state = Arc::new(ConnectionState::new(id, peer_addr, self.stats.clone()));
+        self.lock().push(state.clone());
+
+        state
+    }
+
+    fn unregister(&self, state: &Arc<ConnectionState>) {
+        self.lock().retain(|registered| !Arc::ptr_eq(registered, state));
+    }
+
+    fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Asks the connections idle for the longest time to close, so that `count` connections are
+    /// being shed in total. Returns the addresses of the connections newly asked.
+    fn shed_idle(&self, count: usize) -> Vec<SocketAddr> {
+        let connections = self.lock();
+        let already_shed = connections.iter().filter(|state| state.shed.load(Ordering::Relaxed)).count();
+
+        let mut idle: Vec<(Instant, &Arc<ConnectionState>)> = connections
+            .iter()
+            .filter(|state| !state.shed.load(Ordering::Relaxed))
+            .filter_map(|state| Some((state.idle_since()?, state)))
+            .collect();
+        idle.sort_by_key(|(idle_since, _)| *idle_since);
+
+        idle.into_iter()
+            .take(count.saturating_sub(already_shed))
+            .map(|(_, state)| {
+                state.shed.store(true, Ordering::Relaxed);
+                state.peer_addr
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Arc<ConnectionState>>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl ConnectionState {
+    fn new(id: u64, peer_addr: SocketAddr, listener_stats: Arc<StatsRecorder>) -> Self {
+        ConnectionState {
+            id,
+            peer_addr,
+            idle_since: Mutex::new(None),
+            shed: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            closing: AtomicBool::new(false),
+            accepted_at: Instant::now(),
+            messages: AtomicU64::new(0),
+            acks: AtomicU64::new(0),
+            naks: AtomicU64::new(0),
+            stats: StatsRecorder::default(),
+            listener_stats,
+            #[cfg(feature = "tls")]
+            peer_certificates: OnceLock::new(),
+        }
+    }
+
+    fn idle_since(&self) -> Option<Instant> {
+        *self.idle_since.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn set_idle_since(&self, idle_since: Option<Instant>) {
+        *self.idle_since.lock().unwrap_or_else(|e| e.into_inner()) = idle_since;
+    }
+
+    fn is_shed(&self) -> bool {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+}
+
+/// Calls `accept` until it succeeds, backing off after errors which are not about a single
+/// connection. Returns the errors showing that the listener is unusable.
+pub(crate) fn accept_retrying<T, F>(mut accept: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+
+    loop {
+        match accept() {
+            Ok(accepted) => return Ok(accepted),
+            // the connection failed before it could be accepted
+            Err(e) if matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset | io::ErrorKind::Interrupted
+            ) => {}
+            // not a listening socket
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Err(e),
+            // out of file descriptors, memory or buffers, which closing connections will free
+            Err(_) => {
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+fn listen_backlog(config: &MllpServerConfig) -> i32 {
+    config.listen_backlog.map_or(DEFAULT_BACKLOG, |backlog| backlog.min(i32::MAX as u32) as i32)
+}
+
+fn listen(addr: SocketAddr, backlog: i32, dual_stack: Option<bool>) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let (SocketAddr::V6(_), Some(dual_stack)) = (addr, dual_stack) {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    // same as the standard library, so that a restarted server can bind right away
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+
+    Ok(socket.into())
+}
+
+/// Counter of the connections being handled, against [`MllpServerConfig::max_connections`].
+#[derive(Debug)]
+struct ConnectionSlots {
+    max: usize,
+    open: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// Place of a connection in [`ConnectionSlots`], given back when dropped.
+struct ConnectionSlot(Arc<ConnectionSlots>);
+
+impl ConnectionSlots {
+    fn new(max: usize) -> Self {
+        ConnectionSlots {
+            max,
+            open: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Takes a slot, waiting for one to be freed if needed.
+    fn acquire(self: &Arc<Self>) -> ConnectionSlot {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        while *open >= self.max {
+            open = self.freed.wait(open).unwrap_or_else(|e| e.into_inner());
+        }
+        *open += 1;
+
+        ConnectionSlot(self.clone())
+    }
+
+    fn try_acquire(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if *open >= self.max {
+            return None;
+        }
+        *open += 1;
+
+        Some(ConnectionSlot(self.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+fn handle_connection<H>(
+    stream: Stream,
+    session: &mut ConnectionReader,
+    shutdown: &ShutdownHandle,
+    handler: &H,
+) -> io::Result<()>
+where
+    H: MllpHandler,
+{
+    let _span = trace::connection(shutdown.local_addr, session.peer_addr);
+    let mut stream = secure(stream, session)?;
+    let config = &session.config;
+    let poll_interval = [config.idle_timeout, config.first_frame_timeout, config.first_byte_timeout, config.inter_byte_timeout]
+        .into_iter()
+        .flatten()
+        .fold(SHUTDOWN_POLL_INTERVAL, Duration::min);
+    stream.set_read_timeout(Some(poll_interval))?;
+    stream.set_write_timeout(session.config.write_timeout)?;
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        let paused = session.is_paused();
+        if !paused {
+            if let Err(e) = session.handle_frames(&mut stream, handler) {
+                return Err(session.write_error(shutdown.local_addr, e));
+            }
+        }
+        if session.should_close(shutdown) {
+            return stream.shutdown(Shutdown::Both);
+        }
+        if paused {
+            thread::sleep(poll_interval);
+            continue;
+        }
+
+        let started = Instant::now();
+        let read = stream.read(&mut chunk);
+        session.skip(clock::stalled(started, poll_interval));
+        match read {
+            Ok(0) => {
+                session.closed_by_peer(shutdown.local_addr);
+                // the responses are written already, the peer may still be reading them
+                let _ = stream.shutdown(Shutdown::Write);
+                return Ok(());
+            }
+            Ok(n) => session.received(&chunk[..n]),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Goes through the TLS handshake, if the server accepts TLS connections.
+#[cfg(feature = "tls")]
+fn secure(stream: Stream, session: &ConnectionReader) -> io::Result<Stream> {
+    let config = &session.config;
+    match (&config.tls, stream) {
+        (Some(acceptor), Stream::Tcp(stream)) => {
+            stream.set_read_timeout(config.first_frame_timeout.or(config.idle_timeout))?;
+            let stream = acceptor.accept(stream)?;
+            if let Some(certs) = stream.conn.peer_certificates() {
+                let _ = session.handle.state.peer_certificates.set(certs.iter().map(|cert| cert.clone().into_owned()).collect());
+            }
+            Ok(Stream::Tls(Box::new(stream)))
+        }
+        (_, stream) => Ok(stream),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn secure(stream: Stream, _: &ConnectionReader) -> io::Result<Stream> {
+    Ok(stream)
+}
+
+/// Receiving side of a connection, whichever thread reads it.
+struct ConnectionReader {
+    config: MllpServerConfig,
+    peer_addr: SocketAddr,
+    handle: Session,
+    settings: SettingsHandle,
+    /// Version of the settings last picked up, 0 for those of `config`.
+    settings_version: u64,
+    decoder: MllpDecoder,
+    connection_limit: Option<TokenBucket>,
+    last_received: Instant,
+    accepted_at: Instant,
+    /// Since when reading is [paused](FlowControl::pause).
+    paused_at: Option<Instant>,
+    /// A frame was received, any banner is over.
+    framed: bool,
+    /// A byte was received.
+    talked: bool,
+}
+
+impl ConnectionReader {
+    fn new(config: MllpServerConfig, settings: SettingsHandle, state: Arc<ConnectionState>) -> Self {
+        let session = ConnectionReader {
+            connection_limit: config.connection_rate_limit.map(TokenBucket::new),
+            decoder: MllpDecoder::framed(config.codec.clone(), config.max_frame_size),
+            config,
+            peer_addr: state.peer_addr,
+            handle: Session { state },
+            settings,
+            settings_version: 0,
+            last_received: Instant::now(),
+            accepted_at: Instant::now(),
+            paused_at: None,
+            framed: false,
+            talked: false,
+        };
+        session.increment(Counter::ConnectionsOpened);
+        session
+    }
+
+    /// Picks up the settings changed with the [`SettingsHandle`] since the last call.
+    fn reload(&mut self) {
+        let version = self.settings.version();
+        if version == self.settings_version {
+            return;
+        }
+        self.settings_version = version;
+        let settings = self.settings.get();
+        if settings.connection_rate_limit != self.config.connection_rate_limit {
+            self.connection_limit = settings.connection_rate_limit.map(TokenBucket::new);
+        }
+        self.decoder.set_max_frame_size(settings.max_frame_size);
+        self.config.allowed_peers = settings.allowed_peers;
+        self.config.connection_rate_limit = settings.connection_rate_limit;
+        self.config.global_rate_limit = settings.global_rate_limit;
+        self.config.ack_mode = settings.ack_mode;
+        self.config.max_frame_size = settings.max_frame_size;
+    }
+
+    fn received(&mut self, bytes: &[u8]) {
+        self.last_received = Instant::now();
+        self.talked = true;
+        self.decoder.extend(bytes);
+    }
+
+    /// The peer closed its side of the connection after the complete frames received, answered
+    /// already. The bytes of an incomplete frame are discarded rather than handled.
+    fn closed_by_peer(&mut self, local_addr: SocketAddr) {
+        let partial_bytes = self.decoder.clear();
+        if partial_bytes > 0 {
+            self.emit(local_addr, EventKind::ClosedMidFrame { partial_bytes });
+        }
+    }
+
+    fn respond<W: Write>(&self, stream: &mut CoalescedWrites<'_, W>, frame: &[u8]) -> io::Result<()> {
+        self.journal(Direction::Outbound, frame);
+        stream.write_frame(frame)
+    }
+
+    fn codec(&self) -> &dyn LowerLayerCodec {
+        self.config.codec.as_deref().unwrap_or(&MllpCodec {})
+    }
+
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        self.codec().encode(payload)
+    }
+
+    /// Records `payload` in the audit trail and the statistics, failed if its response could not
+    /// be `written`.
+    fn audit(&self, payload: &[u8], disposition: Disposition, written: &io::Result<()>, started: Instant) {
+        let state = &self.handle.state;
+        let failed = written.is_err() || disposition == Disposition::Naked;
+        let latency = Some(started.elapsed());
+        state.stats.record(payload.len(), latency, failed);
+        state.listener_stats.record(payload.len(), latency, failed);
+
+        let Some(sink) = &self.config.audit else { return };
+        let disposition = if written.is_ok() { disposition } else { Disposition::Failed };
+        let record = AuditRecord {
+            connection_id: Some(self.handle.state.id),
+            ..AuditRecord::new(Direction::Inbound, self.peer_addr, payload, disposition)
+        };
+        // auditing is best effort and never fails the connection
+        let _ = sink.record(&record);
+    }
+
+    /// Forwards the message of `frame` to the message sink, unless `decision` refuses it. Returns
+    /// the decision, turned into a NAK if the sink did not take the message and its policy says
+    /// so.
+    fn forward(&self, frame: &ReceivedFrame<'_>, decision: AckDecision) -> AckDecision {
+        let Some(sink) = &self.config.message_sink else { return decision };
+        if decision == AckDecision::CommitNak {
+            return decision;
+        }
+        let started = Instant::now();
+        loop {
+            let policy = match sink.forward(frame) {
+                Ok(()) => return decision,
+                Err(SinkError::Full) => self.config.sink_full_policy,
+                Err(SinkError::Failed(_)) => self.config.sink_failure_policy,
+            };
+            match policy {
+                SinkPolicy::Drop => return decision,
+                SinkPolicy::Retry(timeout) if started.elapsed() < timeout => thread::sleep(SINK_RETRY_INTERVAL.min(timeout)),
+                SinkPolicy::Nak | SinkPolicy::Retry(_) => return AckDecision::CommitNak,
+                SinkPolicy::NakAndClose => {
+                    self.handle.close();
+                    return AckDecision::CommitNak;
+                }
+            }
+        }
+    }
+
+    fn journal(&self, direction: Direction, frame: &[u8]) {
+        if let Some(journal) = &self.config.journal {
+            let _ = journal.record(direction, self.peer_addr, frame);
+        }
+    }
+
+    /// Takes the next frame out of the decoder, journaling its bytes as they were received,
+    /// those of malformed frames, banners and heartbeats included.
+    fn next_frame(&mut self) -> Option<Result<Vec<u8>, MllpSyntaxError>> {
+        let Some(journal) = &self.config.journal else {
+            return self.decoder.next_frame();
+        };
+        let mut raw = Vec::new();
+        let frame = self.decoder.next_raw_frame(&mut raw);
+        if !raw.is_empty() {
+            let _ = journal.record(Direction::Inbound, self.peer_addr, &raw);
+        }
+        frame
+    }
+
+    fn increment(&self, counter: Counter) {
+        let count = match counter {
+            Counter::MessagesReceived => Some(&self.handle.state.messages),
+            Counter::AcksSent => Some(&self.handle.state.acks),
+            Counter::NaksSent => Some(&self.handle.state.naks),
+            _ => None,
+        };
+        if let Some(count) = count {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(metrics) = &self.config.metrics {
+            metrics.increment(counter);
+        }
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        if let Some(metrics) = &self.config.metrics {
+            metrics.observe(histogram, value);
+        }
+    }
+
+    fn emit(&self, local_addr: SocketAddr, kind: EventKind) {
+        trace::event(local_addr, self.peer_addr, &kind);
+        if let Some(sink) = &self.config.event_sink {
+            sink.on_event(&Event {
+                time: SystemTime::now(),
+                local_addr,
+                peer_addr: self.peer_addr,
+                kind,
+            });
+        }
+    }
+
+    /// Error of a failed write of the responses, a write blocked past
+    /// [`MllpServerConfig::write_timeout`] being [`Timeout::Write`].
+    fn write_error(&self, local_addr: SocketAddr, e: io::Error) -> io::Error {
+        if !matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+            return e;
+        }
+        self.emit(local_addr, EventKind::TimedOut { timeout: Timeout::Write });
+        Timeout::Write.into()
+    }
+
+    /// Leaves `by` out of the idle and first frame timeouts: time the process was stalled, or
+    /// the connection paused.
+    fn skip(&mut self, by: Duration) {
+        self.last_received += by;
+        self.accepted_at += by;
+    }
+
+    /// Whether reading from the connection is paused, keeping track of how long it was.
+    fn is_paused(&mut self) -> bool {
+        let paused = self.handle.state.is_paused();
+        match (paused, self.paused_at) {
+            (true, None) => self.paused_at = Some(Instant::now()),
+            (false, Some(paused_at)) => {
+                self.paused_at = None;
+                self.skip(paused_at.elapsed());
+            }
+            _ => {}
+        }
+        paused
+    }
+
+    /// Handles the complete frames received, writing the responses to `stream`.
+    fn handle_frames<W, H>(&mut self, stream: &mut W, handler: &H) -> io::Result<()>
+    where
+        W: Write,
+        H: MllpHandler,
+    {
+        self.reload();
+        let global_limit = self.settings.global_limit();
+        let mut stream = CoalescedWrites::new(stream, self.config.write_coalescing);
+        let mode = self.config.ack_mode;
+        let commits = !matches!(mode, Some(AckMode::ApplicationOnly | AckMode::None));
+        let applications = !matches!(mode, Some(AckMode::TransportOnly | AckMode::None));
+        let auto_ack = commits && (self.config.auto_ack || mode == Some(AckMode::Both));
+
+        while !self.handle.is_closing() {
+            let Some(frame) = self.next_frame() else { break };
+            trace::frame_decoded(frame.as_ref().ok().map(|payload| payload.len() + 3));
+            let Ok(payload) = frame else {
+                self.increment(Counter::DecodeErrors);
+                if !self.framed && self.config.skip_banner {
+                    continue;
+                }
+                let policy = self.config.malformed_frame_policy;
+                if commits && policy != MalformedFramePolicy::Close {
+                    self.increment(Counter::NaksSent);
+                    self.respond(&mut stream, &self.encode(&[NAK]))?;
+                }
+                if policy != MalformedFramePolicy::NakAndContinue {
+                    self.handle.close();
+                }
+                continue;
+            };
+            self.framed = true;
+            if self.config.heartbeat_payload.as_ref() == Some(&payload) {
+                continue;
+            }
+            let received_at = SystemTime::now();
+            let started = Instant::now();
+            self.increment(Counter::MessagesReceived);
+            self.observe(Histogram::FrameSize, (payload.len() + 3) as f64);
+            let _span = trace::message(self.peer_addr, &payload);
+            if let Some(capture) = &self.config.capture {
+                let _ = capture.record(Direction::Inbound, self.peer_addr, &payload, false);
+            }
+            let buckets: Vec<&TokenBucket> = [self.connection_limit.as_ref(), global_limit.as_deref()]
+                .into_iter()
+                .flatten()
+                .collect();
+            let admitted = admit(&buckets, self.config.rate_limit_policy);
+            if !admitted {
+                let written = match commits {
+                    true => {
+                        self.increment(Counter::NaksSent);
+                        self.respond(&mut stream, &self.encode(&[NAK]))
+                    }
+                    false => Ok(()),
+                };
+                let disposition = if commits { Disposition::Naked } else { Disposition::Failed };
+                self.audit(&payload, disposition, &written, started);
+                written?;
+                continue;
+            }
+            if auto_ack {
+                self.increment(Counter::AcksSent);
+                self.respond(&mut stream, &self.encode(&[ACK]))?;
+            }
+            let frame = ReceivedFrame {
+                payload: &payload,
+                peer_addr: self.peer_addr,
+                received_at,
+                connection_id: self.handle.state.id,
+                len: payload.len() + 3,
+                session: &self.handle,
+            };
+            let decision = intercept(&self.config.interceptors, &frame, handler);
+            let decision = self.forward(&frame, decision);
+            let written = match decision {
+                AckDecision::CommitAck | AckDecision::CommitNak => commits && !auto_ack,
+                AckDecision::ApplicationAck(_) => applications,
+                AckDecision::None => false,
+            };
+            let response = decision.encode_with(self.codec()).filter(|_| written);
+            // what the sender gets back, the commit ACK written before the handler ran included
+            let disposition = match (&response, &decision) {
+                (Some(_), AckDecision::CommitNak) => Disposition::Naked,
+                (Some(_), _) => Disposition::Acked,
+                (None, _) if auto_ack => Disposition::Acked,
+                (None, _) => Disposition::Failed,
+            };
+            let written = match response {
+                Some(response) => {
+                    match decision {
+                        AckDecision::CommitAck => self.increment(Counter::AcksSent),
+                        AckDecision::CommitNak => self.increment(Counter::NaksSent),
+                        _ => {}
+                    }
+                    self.respond(&mut stream, &response)
+                }
+                None => Ok(()),
+            };
+            self.audit(&payload, disposition, &written, started);
+            written?;
+        }
+
+        stream.flush()
+    }
+
+    /// Whether the connection must be closed: closed by the handler, idle for too long, silent
+    /// since accepted, stalled in the middle of a frame, shed, or shut down with no message in
+    /// flight or after the drain timeout.
+    fn should_close(&self, shutdown: &ShutdownHandle) -> bool {
+        let in_flight = self.decoder.buffered() != 0;
+        self.handle.state.set_idle_since((!in_flight).then_some(self.last_received));
+
+        let idle = self.paused_at.is_none()
+            && self.config.idle_timeout.is_some_and(|idle| self.last_received.elapsed() >= idle);
+        let silent = !self.framed
+            && self.paused_at.is_none()
+            && self.config.first_frame_timeout.is_some_and(|timeout| self.accepted_at.elapsed() >= timeout);
+        if silent {
+            self.emit(shutdown.local_addr, EventKind::FirstFrameTimeout);
+        }
+        let timeout = match self.paused_at {
+            Some(_) => None,
+            None if !self.talked && self.config.first_byte_timeout.is_some_and(|timeout| self.accepted_at.elapsed() >= timeout) => {
+                Some(Timeout::FirstByte)
+            }
+            None if in_flight && self.config.inter_byte_timeout.is_some_and(|timeout| self.last_received.elapsed() >= timeout) => {
+                Some(Timeout::InterByte)
+            }
+            None => None,
+        };
+        if let Some(timeout) = timeout {
+            self.emit(shutdown.local_addr, EventKind::TimedOut { timeout });
+        }
+        self.handle.is_closing() || idle || silent || timeout.is_some() || (!in_flight && (shutdown.is_shutdown() || self.handle.state.is_shed()))
+            || shutdown.is_drain_over(self.config.drain_timeout)
+    }
+}
+
+impl Drop for ConnectionReader {
+    fn drop(&mut self) {
+        self.increment(Counter::ConnectionsClosed);
+    }
+}
+
+/// Writer of the responses of a connection, gathering them according to the
+/// [coalescing settings](WriteCoalescing).
+struct CoalescedWrites<'a, W> {
+    stream: &'a mut W,
+    coalescing: Option<WriteCoalescing>,
+    buf: Vec<u8>,
+    /// When the oldest response waiting was written to `buf`.
+    since: Option<Instant>,
+}
+
+impl<'a, W: Write> CoalescedWrites<'a, W> {
+    fn new(stream: &'a mut W, coalescing: Option<WriteCoalescing>) -> Self {
+        CoalescedWrites {
+            stream,
+            coalescing,
+            buf: Vec::new(),
+            since: None,
+        }
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let Some(coalescing) = self.coalescing else {
+            return self.stream.write_all(frame);
+        };
+
+        self.buf.extend_from_slice(frame);
+        let since = *self.since.get_or_insert_with(Instant::now);
+        if self.buf.len() >= coalescing.max_bytes || since.elapsed() >= coalescing.max_delay {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.since = None;
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let result = self.stream.write_all(&self.buf);
+        self.buf.clear();
+
+        result
+    }
+}
+
+/// Takes a token from each of `buckets` according to `policy`, returning whether the message may
+/// be handled. A refused message keeps no token.
+fn admit(buckets: &[&TokenBucket], policy: RateLimitPolicy) -> bool {
+    match policy {
+        RateLimitPolicy::Delay => {
+            buckets.iter().for_each(|bucket| bucket.acquire());
+            true
+        }
+        RateLimitPolicy::Nak => {
+            for (i, bucket) in buckets.iter().enumerate() {
+                if bucket.try_acquire().is_err() {
+                    buckets[..i].iter().for_each(|taken| taken.release());
+                    return false;
+                }
+            }
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use socket2::SockRef;
+    use crate::audit::{AuditRecord, AuditSink, Disposition};
+    use crate::client::{Ack, MllpClient, MllpClientConfig};
+    use crate::event::EventKind;
+    use crate::handler::{AckDecision, MllpHandler, ReceivedFrame};
+    use crate::interceptor::{Interceptor, Next};
+    use crate::metrics::{Counter, Histogram, Metrics};
+    use crate::timeline::Timeline;
+    use crate::rate_limit::{RateLimit, TokenBucket};
+    use crate::testing::{duplex, MemoryListener};
+    use crate::server::{
+        accept_retrying, admit, ConnectionRegistry, FdBudget, MalformedFramePolicy, MllpServer, MllpServerConfig, MllpServerGroup, OverCapacityPolicy,
+        RateLimitPolicy, ConnectionReader, SettingsHandle, ShutdownHandle, WriteCoalescing,
+    };
+    use crate::{AckMode, LowerLayerCodec, MllpCodec, MllpDecoder, MllpError, MllpSyntaxError, Timeout, ACK, NAK};
+
+    fn spawn_server(config: MllpServerConfig) -> SocketAddr {
+        let server = MllpServer::bind("127.0.0.1:0", config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+        addr
+    }
+
+    #[test]
+    fn it_answers_with_handler_response() {
+        let addr = spawn_server(MllpServerConfig::default());
+        let mut client = MllpClient::connect(addr).unwrap();
+
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+        assert_eq!(client.send(b"MSH|2").unwrap(), Ack::Application(b"MSH|2".to_vec()));
+    }
+
+    /// Frames between `<STX>` and `<ETX>`.
+    #[derive(Debug)]
+    struct StxEtx;
+
+    impl LowerLayerCodec for StxEtx {
+        fn encode_into(&self, payload: &[u8], buf: &mut Vec<u8>) {
+            buf.extend([&[2][..], payload, &[3]].concat());
+        }
+
+        fn decode_first<'a>(&self, buf: &'a [u8]) -> Result<Option<(&'a [u8], &'a [u8])>, MllpSyntaxError> {
+            match buf.first() {
+                None => Ok(None),
+                Some(2) => Ok(buf.iter().position(|b| *b == 3).map(|end| (&buf[1..end], &buf[end + 1..]))),
+                Some(_) => Err(MllpSyntaxError),
+            }
+        }
+
+        fn frame_start(&self, buf: &[u8]) -> Option<usize> {
+            buf.iter().position(|b| *b == 2)
+        }
+    }
+
+    #[test]
+    fn it_frames_with_another_codec() {
+        let addr = spawn_server(MllpServerConfig {
+            codec: Some(Arc::new(StxEtx)),
+            auto_ack: true,
+            ..MllpServerConfig::default()
+        });
+        let config = MllpClientConfig {
+            codec: Some(Arc::new(StxEtx)),
+            ..MllpClientConfig::default()
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Commit);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"junk\x02MSH|2\x03").unwrap();
+        let mut decoder = MllpDecoder::with_codec(Arc::new(StxEtx));
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), [NAK]);
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), [ACK]);
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), b"MSH|2");
+    }
+
+    #[test]
+    fn it_swallows_heartbeats() {
+        let addr = spawn_server(MllpServerConfig {
+            heartbeat_payload: Some(Vec::new()),
+            ..MllpServerConfig::default()
+        });
+        let config = MllpClientConfig {
+            heartbeat_interval: Some(Duration::from_millis(50)),
+            ..MllpClientConfig::default()
+        };
+        let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+        assert!(!client.heartbeat().unwrap());
+        thread::sleep(Duration::from_millis(60));
+        assert!(client.heartbeat().unwrap());
+        assert!(!client.heartbeat().unwrap());
+
+        // an answered heartbeat would be taken for the acknowledgement
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+    }
+
+    #[test]
+    fn it_closes_idle_connections() {
+        let addr = spawn_server(MllpServerConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..MllpServerConfig::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let start = Instant::now();
+
+        assert_eq!(stream.read(&mut [0u8; 16]).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn it_keeps_idle_connections_across_stalls() {
+        let registry = ConnectionRegistry::default();
+        let config = MllpServerConfig {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..MllpServerConfig::default()
+        };
+        let shutdown = ShutdownHandle {
+            requested_at: Arc::new(OnceLock::new()),
+            local_addr: "127.0.0.1:2575".parse().unwrap(),
+            unix_path: None,
+            memory: None,
+        };
+        let settings = SettingsHandle::new(&config);
+        let mut session = ConnectionReader::new(config, settings, registry.register("127.0.0.1:2575".parse().unwrap()));
+
+        // the process is paused, the monotonic clock moves on
+        thread::sleep(Duration::from_millis(150));
+        session.skip(Duration::from_millis(150));
+        assert!(!session.should_close(&shutdown));
+
+        thread::sleep(Duration::from_millis(150));
+        assert!(session.should_close(&shutdown));
+    }
+
+    #[test]
+    fn it_delays_messages_over_rate_limit() {
+        let addr = spawn_server(MllpServerConfig {
+            connection_rate_limit: Some(RateLimit {
+                per_second: 10.0,
+                burst: 1,
+            }),
+            ..MllpServerConfig::default()
+        });
+        let mut client = MllpClient::connect(addr).unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(180));
+    }
+
+    #[test]
+    fn it_naks_messages_over_global_rate_limit() {
+        let addr = spawn_server(MllpServerConfig {
+            global_rate_limit: Some(RateLimit {
+                per_second: 0.001,
+                burst: 2,
+            }),
+            rate_limit_policy: RateLimitPolicy::Nak,
+            ..MllpServerConfig::default()
+        });
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        let mut decoder = MllpDecoder::new();
+
+        first.write_all(&MllpCodec::encode(b"MSH|1")).unwrap();
+        assert_eq!(decoder.read_frame(&mut first).unwrap(), b"MSH|1");
+        second.write_all(&MllpCodec::encode(b"MSH|2")).unwrap();
+        assert_eq!(decoder.read_frame(&mut second).unwrap(), b"MSH|2");
+        first.write_all(&MllpCodec::encode(b"MSH|3")).unwrap();
+        assert!(MllpCodec::is_nak(&MllpCodec::encode(&decoder.read_frame(&mut first).unwrap())));
+    }
+
+    #[test]
+    fn it_keeps_the_connection_token_when_the_global_rate_limit_refuses() {
+        let connection = TokenBucket::new(RateLimit {
+            per_second: 0.001,
+            burst: 1,
+        });
+        let global = TokenBucket::new(RateLimit {
+            per_second: 0.001,
+            burst: 1,
+        });
+        global.try_acquire().unwrap();
+
+        assert!(!admit(&[&connection, &global], RateLimitPolicy::Nak));
+        assert!(connection.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn it_rejects_connections_over_capacity() {
+        let timeline = Arc::new(Timeline::new());
+        let metrics = Arc::new(CountingMetrics::default());
+        let addr = spawn_server(MllpServerConfig {
+            max_connections: Some(1),
+            over_capacity: OverCapacityPolicy::Reject,
+            event_sink: Some(timeline.clone()),
+            metrics: Some(metrics.clone()),
+            ..MllpServerConfig::default()
+        });
+        let mut client = MllpClient::connect(addr).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        rejected.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(rejected.read(&mut [0u8; 16]).unwrap(), 0);
+        let connections = timeline.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].peer_addr, rejected.local_addr().unwrap());
+        assert_eq!(connections[0].entries[0].kind, EventKind::ConnectionRejected);
+        assert_eq!(metrics.rejected.load(Ordering::SeqCst), 1);
+    }
+
+    #[derive(Default)]
+    struct CountingMetrics {
+        rejected: AtomicUsize,
+    }
+
+    impl Metrics for CountingMetrics {
+        fn increment(&self, counter: Counter) {
+            if counter == Counter::ConnectionsRejected {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        fn observe(&self, _histogram: Histogram, _value: f64) {}
+    }
+
+    #[test]
+    fn it_queues_connections_over_capacity() {
+        let addr = spawn_server(MllpServerConfig {
+            max_connections: Some(1),
+            listen_backlog: Some(4),
+            ..MllpServerConfig::default()
+        });
+        let mut client = MllpClient::connect(addr).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+
+        let mut queued = TcpStream::connect(addr).unwrap();
+        queued.write_all(&MllpCodec::encode(b"MSH|2")).unwrap();
+        queued.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(queued.read(&mut [0u8; 16]).is_err());
+
+        drop(client);
+        queued.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(MllpDecoder::new().read_frame(&mut queued).unwrap(), b"MSH|2");
+    }
+
+    #[test]
+    fn it_backs_off_on_accept_errors() {
+        // EMFILE twice, an aborted connection, then success
+        let mut results = vec![
+            Err(io::Error::from_raw_os_error(24)),
+            Err(io::Error::from_raw_os_error(24)),
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+            Ok(()),
+        ]
+        .into_iter();
+        let start = Instant::now();
+
+        assert!(accept_retrying(|| results.next().unwrap()).is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(15));
+        assert!(results.next().is_none());
+
+        let error = accept_retrying(|| Err::<(), _>(io::Error::from(io::ErrorKind::InvalidInput))).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn it_survives_connection_reset_storms() {
+        let addr = spawn_server(MllpServerConfig {
+            listen_backlog: Some(256),
+            ..MllpServerConfig::default()
+        });
+        for _ in 0..100 {
+            let stream = TcpStream::connect(addr).unwrap();
+            // closing with RST instead of FIN
+            SockRef::from(&stream).set_linger(Some(Duration::ZERO)).unwrap();
+        }
+
+        let mut client = MllpClient::connect(addr).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+    }
+
+    #[test]
+    fn it_shuts_down_after_in_flight_messages() {
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig::default()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let serving = thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let mut busy = TcpStream::connect(addr).unwrap();
+        let frame = MllpCodec::encode(b"MSH|1");
+        busy.write_all(&frame[..3]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        shutdown.shutdown();
+
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(idle.read(&mut [0u8; 16]).unwrap(), 0);
+        thread::sleep(Duration::from_millis(150));
+        busy.write_all(&frame[3..]).unwrap();
+        busy.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(MllpDecoder::new().read_frame(&mut busy).unwrap(), b"MSH|1");
+        assert_eq!(busy.read(&mut [0u8; 16]).unwrap(), 0);
+
+        assert!(serving.join().unwrap().is_ok());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn it_stops_draining_after_timeout() {
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            drain_timeout: Some(Duration::from_millis(100)),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let serving = thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(&MllpCodec::encode(b"MSH|1")[..3]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        shutdown.shutdown();
+
+        stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stalled.read(&mut [0u8; 16]).unwrap(), 0);
+        assert!(serving.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn it_sheds_longest_idle_connections() {
+        let registry = ConnectionRegistry::default();
+        let start = Instant::now();
+        let addrs: Vec<SocketAddr> = (1..=4).map(|port| SocketAddr::from(([10, 0, 0, 1], port))).collect();
+        let states: Vec<_> = addrs.iter().map(|addr| registry.register(*addr)).collect();
+        states[0].set_idle_since(Some(start + Duration::from_secs(3)));
+        states[1].set_idle_since(Some(start + Duration::from_secs(1)));
+        // receiving a message
+        states[2].set_idle_since(None);
+        states[3].set_idle_since(Some(start + Duration::from_secs(2)));
+
+        assert_eq!(registry.shed_idle(2), vec![addrs[1], addrs[3]]);
+        // the connections asked before count towards the total
+        assert_eq!(registry.shed_idle(2), vec![]);
+        assert_eq!(registry.shed_idle(3), vec![addrs[0]]);
+
+        registry.unregister(&states[1]);
+        assert_eq!(registry.len(), 3);
+    }
+
+    #[test]
+    fn it_pauses_accepting_when_out_of_descriptors() {
+        let timeline = Arc::new(Timeline::new());
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            fd_budget: Some(FdBudget {
+                max_fds: 1,
+                reserve: 1,
+            }),
+            event_sink: Some(timeline.clone()),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let serving = thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&MllpCodec::encode(b"MSH|1")).unwrap();
+        stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert!(stream.read(&mut [0u8; 16]).is_err());
+
+        shutdown.shutdown();
+        assert!(serving.join().unwrap().is_ok());
+        let connections = timeline.connections();
+        let kinds: Vec<_> = connections[0].entries.iter().map(|entry| entry.kind.clone()).collect();
+        assert!(matches!(kinds[0], EventKind::AcceptPaused { .. }));
+        assert_eq!(kinds[1..], [EventKind::AcceptResumed]);
+    }
+
+    #[test]
+    fn it_pauses_reading_from_a_connection() {
+        for worker_threads in [None, Some(2)] {
+            let config = MllpServerConfig {
+                worker_threads,
+                ..MllpServerConfig::default()
+            };
+            let server = MllpServer::bind("127.0.0.1:0", config).unwrap();
+            let addr = server.local_addr().unwrap();
+            let flow = server.flow_control();
+            thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+            let mut paused = TcpStream::connect(addr).unwrap();
+            let mut other = MllpClient::connect(addr).unwrap();
+            while flow.connections().len() < 2 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert!(flow.pause(paused.local_addr().unwrap()));
+            assert!(flow.is_paused(paused.local_addr().unwrap()));
+
+            paused.write_all(&MllpCodec::encode(b"MSH|1")).unwrap();
+            paused.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+            assert!(paused.read(&mut [0u8; 16]).is_err());
+            assert_eq!(other.send(b"MSH|2").unwrap(), Ack::Application(b"MSH|2".to_vec()));
+
+            assert!(flow.resume(paused.local_addr().unwrap()));
+            paused.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut response = [0u8; 8];
+            paused.read_exact(&mut response).unwrap();
+            assert_eq!(response, MllpCodec::encode(b"MSH|1")[..]);
+        }
+    }
+
+    #[test]
+    fn it_lets_handlers_close_their_session() {
+        struct Closing;
+
+        impl MllpHandler for Closing {
+            fn on_message(&self, _: &[u8]) -> AckDecision {
+                AckDecision::CommitAck
+            }
+
+            fn on_frame(&self, frame: &ReceivedFrame<'_>) -> AckDecision {
+                if frame.payload == b"MSH|close" {
+                    frame.session.close();
+                }
+                let session = frame.session;
+                let counts = format!("MSH|{}|{}|{}", session.messages_received(), session.acks_sent(), session.naks_sent());
+                AckDecision::ApplicationAck(counts.into_bytes())
+            }
+        }
+
+        for worker_threads in [None, Some(2)] {
+            let config = MllpServerConfig {
+                worker_threads,
+                auto_ack: true,
+                ..MllpServerConfig::default()
+            };
+            let server = MllpServer::bind("127.0.0.1:0", config).unwrap();
+            let addr = server.local_addr().unwrap();
+            let flow = server.flow_control();
+            thread::spawn(move || server.serve(Closing));
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut decoder = MllpDecoder::new();
+            stream.write_all(b"junk").unwrap();
+            stream.write_all(&MllpCodec::encode(b"MSH|1")).unwrap();
+            assert_eq!(decoder.read_frame(&mut stream).unwrap(), [NAK]);
+            assert_eq!(decoder.read_frame(&mut stream).unwrap(), [ACK]);
+            assert_eq!(decoder.read_frame(&mut stream).unwrap(), b"MSH|1|1|1");
+
+            let session = flow.sessions().pop().unwrap();
+            assert_eq!(session.peer_addr(), stream.local_addr().unwrap());
+            assert_eq!((session.messages_received(), session.acks_sent(), session.naks_sent()), (1, 1, 1));
+            assert!(session.uptime() > Duration::ZERO);
+
+            stream.write_all(&[MllpCodec::encode(b"MSH|close"), MllpCodec::encode(b"MSH|after")].concat()).unwrap();
+            assert_eq!(decoder.read_frame(&mut stream).unwrap(), [ACK]);
+            assert_eq!(decoder.read_frame(&mut stream).unwrap(), b"MSH|2|2|1");
+            assert!(matches!(decoder.read_frame(&mut stream), Err(MllpError::ConnectionClosed { .. })));
+            assert!(session.is_closing());
+            assert_eq!(session.messages_received(), 2);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_serves_over_unix_domain_socket() {
+        for worker_threads in [None, Some(2)] {
+            let name = format!("mllp-rs-{}-{}.sock", std::process::id(), worker_threads.unwrap_or(0));
+            let path = std::env::temp_dir().join(name);
+            let _ = std::fs::remove_file(&path);
+            let config = MllpServerConfig {
+                worker_threads,
+                ..MllpServerConfig::default()
+            };
+            let server = MllpServer::bind_uds(&path, config).unwrap();
+            let shutdown = server.shutdown_handle();
+            let serving = thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+            let mut client = MllpClient::connect_uds(&path, MllpClientConfig::default()).unwrap();
+            assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+            assert_eq!(client.send(b"MSH|2").unwrap(), Ack::Application(b"MSH|2".to_vec()));
+            drop(client);
+
+            shutdown.shutdown();
+            assert!(serving.join().unwrap().is_ok());
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_serves_over_transports() {
+        let (near, far) = duplex();
+        let server = MllpServer::bind_transport(far, MllpServerConfig::default()).unwrap();
+        let serving = thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+        let near = Mutex::new(Some(near));
+        let open = move || near.lock().unwrap().take().ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotConnected));
+        let mut client = MllpClient::connect_transport(open, MllpClientConfig::default()).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+        assert_eq!(client.peer_addr(), crate::UNSPECIFIED_ADDR);
+        drop(client);
+
+        // returns once the only connection ends
+        assert!(serving.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn it_serves_in_memory() {
+        let listener = MemoryListener::new();
+        let connector = listener.connector();
+        let server = MllpServer::bind_memory(listener, MllpServerConfig::default()).unwrap();
+        let shutdown = server.shutdown_handle();
+        let serving = thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+        let mut client = MllpClient::connect_memory(connector, MllpClientConfig::default()).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+        assert!(client.is_connected());
+        drop(client);
+
+        shutdown.shutdown();
+        assert!(serving.join().unwrap().is_ok());
+        let pooled = MllpServerConfig {
+            worker_threads: Some(2),
+            ..MllpServerConfig::default()
+        };
+        let server = MllpServer::bind_memory(MemoryListener::new(), pooled).unwrap();
+        assert_eq!(server.serve(|_: &[u8]| AckDecision::CommitAck).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn it_serves_with_worker_pool() {
+        let addr = spawn_server(MllpServerConfig {
+            worker_threads: Some(2),
+            ..MllpServerConfig::default()
+        });
+
+        let clients: Vec<_> = (0..10)
+            .map(|client| {
+                thread::spawn(move || {
+                    let mut client_connection = MllpClient::connect(addr).unwrap();
+                    for message in 0..3 {
+                        let payload = format!("MSH|{}-{}", client, message).into_bytes();
+                        assert_eq!(client_connection.send(&payload).unwrap(), Ack::Application(payload));
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn it_shuts_down_worker_pool() {
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            worker_threads: Some(1),
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let serving = thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+        let mut client = MllpClient::connect(addr).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+        let mut idle = TcpStream::connect(addr).unwrap();
+        shutdown.shutdown();
+
+        idle.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(idle.read(&mut [0u8; 16]).unwrap(), 0);
+        assert!(serving.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn it_runs_interceptors_before_handler() {
+        struct Uppercase;
+
+        impl Interceptor for Uppercase {
+            fn around(&self, message: &[u8], next: Next<'_>) -> AckDecision {
+                next(&message.to_ascii_uppercase())
+            }
+        }
+
+        let addr = spawn_server(MllpServerConfig {
+            interceptors: vec![Arc::new(Uppercase)],
+            ..MllpServerConfig::default()
+        });
+        let mut client = MllpClient::connect(addr).unwrap();
+
+        assert_eq!(client.send(b"msh|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+    }
+
+    #[test]
+    fn it_answers_commit_ack_before_handler() {
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            auto_ack: true,
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            server.serve(|message: &[u8]| match message {
+                b"MSH|app" => AckDecision::ApplicationAck(b"MSA|AA".to_vec()),
+                _ => AckDecision::CommitNak,
+            })
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut decoder = MllpDecoder::new();
+
+        stream.write_all(&MllpCodec::encode(b"MSH|1")).unwrap();
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), [ACK]);
+        stream.write_all(b"junk").unwrap();
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), [NAK]);
+        stream.write_all(&MllpCodec::encode(b"MSH|app")).unwrap();
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), [ACK]);
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), b"MSA|AA");
+    }
+
+    #[test]
+    fn it_coalesces_responses() {
+        /// Records the size of each write.
+        #[derive(Default)]
+        struct Writes(Vec<usize>);
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let handle = |coalescing: Option<WriteCoalescing>| {
+            let registry = ConnectionRegistry::default();
+            let config = MllpServerConfig {
+                write_coalescing: coalescing,
+                ..MllpServerConfig::default()
+            };
+            let settings = SettingsHandle::new(&config);
+            let mut session = ConnectionReader::new(config, settings, registry.register("127.0.0.1:2575".parse().unwrap()));
+            for message in [&b"MSH|1"[..], b"MSH|2", b"MSH|3"] {
+                session.received(&MllpCodec::encode(message));
+            }
+            let mut writes = Writes::default();
+            session.handle_frames(&mut writes, &|_: &[u8]| AckDecision::CommitAck).unwrap();
+            writes.0
+        };
+
+        assert_eq!(handle(None), vec![4, 4, 4]);
+        assert_eq!(handle(Some(WriteCoalescing::default())), vec![12]);
+        let bounded = WriteCoalescing {
+            max_bytes: 8,
+            ..WriteCoalescing::default()
+        };
+        assert_eq!(handle(Some(bounded)), vec![8, 4]);
+    }
+
+    #[test]
+    fn it_writes_frames_of_ack_mode() {
+        let responses = |mode: AckMode| {
+            let registry = ConnectionRegistry::default();
+            let config = MllpServerConfig {
+                ack_mode: Some(mode),
+                ..MllpServerConfig::default()
+            };
+            let settings = SettingsHandle::new(&config);
+            let mut session = ConnectionReader::new(config, settings, registry.register("127.0.0.1:2575".parse().unwrap()));
+            session.received(&MllpCodec::encode(b"MSH|1"));
+            session.received(&MllpCodec::encode(b"MSH|2"));
+            let mut written = Vec::new();
+            let handler = |message: &[u8]| match message {
+                b"MSH|1" => AckDecision::CommitNak,
+                _ => AckDecision::ApplicationAck(b"MSA|AA".to_vec()),
+            };
+            session.handle_frames(&mut written, &handler).unwrap();
+            written
+        };
+
+        assert_eq!(responses(AckMode::TransportOnly), MllpCodec::nak());
+        assert_eq!(responses(AckMode::ApplicationOnly), MllpCodec::encode(b"MSA|AA"));
+        let both = [&MllpCodec::ack()[..], &MllpCodec::ack(), &MllpCodec::encode(b"MSA|AA")].concat();
+        assert_eq!(responses(AckMode::Both), both);
+        assert!(responses(AckMode::None).is_empty());
+    }
+
+    #[derive(Default)]
+    struct Dispositions(Mutex<Vec<Disposition>>);
+
+    impl AuditSink for Dispositions {
+        fn record(&self, record: &AuditRecord) -> io::Result<()> {
+            self.0.lock().unwrap().push(record.disposition);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_audits_the_frames_written() {
+        let dispositions = |mode: AckMode| {
+            let registry = ConnectionRegistry::default();
+            let audit = Arc::new(Dispositions::default());
+            let config = MllpServerConfig {
+                ack_mode: Some(mode),
+                audit: Some(audit.clone()),
+                ..MllpServerConfig::default()
+            };
+            let settings = SettingsHandle::new(&config);
+            let mut session = ConnectionReader::new(config, settings, registry.register("127.0.0.1:2575".parse().unwrap()));
+            session.received(&MllpCodec::encode(b"MSH|1"));
+            session.received(&MllpCodec::encode(b"MSH|2"));
+            let handler = |message: &[u8]| match message {
+                b"MSH|1" => AckDecision::CommitNak,
+                _ => AckDecision::ApplicationAck(b"MSA|AA".to_vec()),
+            };
+            session.handle_frames(&mut Vec::new(), &handler).unwrap();
+            let dispositions = audit.0.lock().unwrap().clone();
+            dispositions
+        };
+
+        assert_eq!(dispositions(AckMode::TransportOnly), [Disposition::Naked, Disposition::Failed]);
+        assert_eq!(dispositions(AckMode::ApplicationOnly), [Disposition::Failed, Disposition::Acked]);
+        // the commit ACK was written before the handler refused the first message
+        assert_eq!(dispositions(AckMode::Both), [Disposition::Acked, Disposition::Acked]);
+        assert_eq!(dispositions(AckMode::None), [Disposition::Failed, Disposition::Failed]);
+    }
+
+    #[test]
+    fn it_applies_malformed_frame_policy() {
+        let responses = |policy: MalformedFramePolicy, auto_ack: bool| {
+            let registry = ConnectionRegistry::default();
+            let config = MllpServerConfig {
+                auto_ack,
+                malformed_frame_policy: policy,
+                ..MllpServerConfig::default()
+            };
+            let settings = SettingsHandle::new(&config);
+            let mut session = ConnectionReader::new(config, settings, registry.register("127.0.0.1:2575".parse().unwrap()));
+            session.received(b"JUNK\r");
+            session.received(&MllpCodec::encode(b"MSH|1"));
+            let mut written = Vec::new();
+            session.handle_frames(&mut written, &|_: &[u8]| AckDecision::CommitAck).unwrap();
+            (written, session.handle.is_closing())
+        };
+
+        for auto_ack in [true, false] {
+            assert_eq!(responses(MalformedFramePolicy::NakAndContinue, auto_ack), ([&MllpCodec::nak()[..], &MllpCodec::ack()].concat(), false));
+            assert_eq!(responses(MalformedFramePolicy::NakAndClose, auto_ack), (MllpCodec::nak().to_vec(), true));
+            assert_eq!(responses(MalformedFramePolicy::Close, auto_ack), (Vec::new(), true));
+        }
+    }
+
+    #[test]
+    fn it_closes_connections_silent_after_banner() {
+        let addr = spawn_server(MllpServerConfig {
+            auto_ack: true,
+            skip_banner: true,
+            first_frame_timeout: Some(Duration::from_millis(100)),
+            ..MllpServerConfig::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"HELLO\r\n").unwrap();
+        stream.write_all(&MllpCodec::encode(b"MSH|1")).unwrap();
+        let mut decoder = MllpDecoder::new();
+
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), [ACK]);
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), b"MSH|1");
+
+        let mut silent = TcpStream::connect(addr).unwrap();
+        silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let start = Instant::now();
+        assert_eq!(silent.read(&mut [0u8; 16]).unwrap(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn it_closes_silent_connections_before_idle_timeout() {
+        let timeline = Arc::new(Timeline::new());
+        let addr = spawn_server(MllpServerConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            first_frame_timeout: Some(Duration::from_millis(100)),
+            event_sink: Some(timeline.clone()),
+            ..MllpServerConfig::default()
+        });
+        let mut quiet = MllpClient::connect(addr).unwrap();
+        assert!(quiet.send(b"MSH|1").is_ok());
+        let mut silent = TcpStream::connect(addr).unwrap();
+        silent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        assert_eq!(silent.read(&mut [0u8; 16]).unwrap(), 0);
+        assert!(quiet.is_connected());
+        let connections = timeline.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].peer_addr, silent.local_addr().unwrap());
+        assert_eq!(connections[0].entries[0].kind, EventKind::FirstFrameTimeout);
+    }
+
+    #[test]
+    fn it_closes_connections_stalled_in_a_frame() {
+        let timeline = Arc::new(Timeline::new());
+        let addr = spawn_server(MllpServerConfig {
+            idle_timeout: Some(Duration::from_secs(60)),
+            inter_byte_timeout: Some(Duration::from_millis(100)),
+            event_sink: Some(timeline.clone()),
+            ..MllpServerConfig::default()
+        });
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stalled.write_all(&MllpCodec::encode(b"MSH|1")).unwrap();
+        assert!(MllpDecoder::new().read_frame(&mut stalled).is_ok());
+        stalled.write_all(&MllpCodec::encode(b"MSH|2")[..4]).unwrap();
+
+        assert_eq!(stalled.read(&mut [0u8; 16]).unwrap(), 0);
+        let connections = timeline.connections();
+        assert_eq!(connections[0].entries[0].kind, EventKind::TimedOut { timeout: Timeout::InterByte });
+    }
+
+    #[test]
+    fn it_answers_before_closing_half_closed_connections() {
+        let timeline = Arc::new(Timeline::new());
+        let addr = spawn_server(MllpServerConfig {
+            event_sink: Some(timeline.clone()),
+            ..MllpServerConfig::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(&[&MllpCodec::encode(b"MSH|1")[..], &MllpCodec::encode(b"MSH|2")[..4]].concat()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut decoder = MllpDecoder::new();
+        assert_eq!(decoder.read_frame(&mut stream).unwrap(), b"MSH|1");
+        assert!(matches!(decoder.read_frame(&mut stream), Err(MllpError::ConnectionClosed { partial_bytes: 0 })));
+        let connections = timeline.connections();
+        assert_eq!(connections[0].entries[0].kind, EventKind::ClosedMidFrame { partial_bytes: 4 });
+    }
+
+    #[test]
+    fn it_reloads_settings_of_running_server() {
+        for worker_threads in [None, Some(2)] {
+            let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+                worker_threads,
+                rate_limit_policy: RateLimitPolicy::Nak,
+                ..MllpServerConfig::default()
+            })
+            .unwrap();
+            let addr = server.local_addr().unwrap();
+            let settings = server.settings_handle();
+            thread::spawn(move || server.serve(|message: &[u8]| AckDecision::ApplicationAck(message.to_vec())));
+
+            let config = MllpClientConfig {
+                max_retries: 0,
+                ..MllpClientConfig::default()
+            };
+            let mut client = MllpClient::connect_with_config(addr, config).unwrap();
+            assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+            settings.update(|settings| settings.allowed_peers = Some(vec!["10.0.0.0/8".parse().unwrap()])).unwrap();
+            let mut rejected = TcpStream::connect(addr).unwrap();
+            rejected.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            assert!(!matches!(rejected.read(&mut [0u8; 16]), Ok(n) if n > 0));
+            assert_eq!(client.send(b"MSH|2").unwrap(), Ack::Application(b"MSH|2".to_vec()));
+
+            let zero_rate = RateLimit { per_second: 0.0, burst: 1 };
+            let error = settings.update(|settings| settings.global_rate_limit = Some(zero_rate)).unwrap_err();
+            assert_eq!(error.field, "global_rate_limit");
+            assert_eq!(settings.get().global_rate_limit, None);
+            let limit = RateLimit { per_second: 0.001, burst: 1 };
+            settings.update(|settings| settings.global_rate_limit = Some(limit)).unwrap();
+            assert_eq!(client.send(b"MSH|3").unwrap(), Ack::Application(b"MSH|3".to_vec()));
+            assert!(matches!(client.send(b"MSH|4"), Err(MllpError::Nak)));
+        }
+    }
+
+    #[test]
+    fn it_accepts_ipv4_peers_on_dual_stack_listeners() {
+        struct PeerEcho;
+
+        impl MllpHandler for PeerEcho {
+            fn on_message(&self, _: &[u8]) -> AckDecision {
+                AckDecision::CommitAck
+            }
+
+            fn on_frame(&self, frame: &ReceivedFrame<'_>) -> AckDecision {
+                AckDecision::ApplicationAck(frame.session.peer_addr().to_string().into_bytes())
+            }
+        }
+
+        let Ok(server) = MllpServer::bind("[::]:0", MllpServerConfig {
+            dual_stack: Some(true),
+            ..MllpServerConfig::default()
+        }) else {
+            // no IPv6 on this host
+            return;
+        };
+        let port = server.local_addr().unwrap().port();
+        thread::spawn(move || server.serve(PeerEcho));
+
+        let mut client = MllpClient::connect(("127.0.0.1", port)).unwrap();
+        let peer_addr = client.local_addr().to_string().into_bytes();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(peer_addr));
+
+        let server = MllpServer::bind("[::]:0", MllpServerConfig {
+            dual_stack: Some(false),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", server.local_addr().unwrap().port())).is_err());
+    }
+
+    #[test]
+    fn it_serves_several_addresses_with_one_handler() {
+        let mut group = MllpServerGroup::new();
+        let open = group.bind("127.0.0.1:0", MllpServerConfig::default()).unwrap().local_addr().unwrap();
+        let closed = group.bind("127.0.0.1:0", MllpServerConfig {
+            allowed_peers: Some(vec!["10.0.0.0/8".parse().unwrap()]),
+            ..MllpServerConfig::default()
+        })
+        .unwrap()
+        .local_addr()
+        .unwrap();
+        let fast = group.bind("127.0.0.1:0", MllpServerConfig {
+            worker_threads: Some(2),
+            ..MllpServerConfig::default()
+        })
+        .unwrap()
+        .local_addr()
+        .unwrap();
+        assert_eq!(group.servers().len(), 3);
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let shutdown = group.shutdown_handle();
+        let serving = thread::spawn(move || {
+            group.serve(move |message: &[u8]| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                AckDecision::ApplicationAck(message.to_vec())
+            })
+        });
+
+        for addr in [open, fast] {
+            let mut client = MllpClient::connect(addr).unwrap();
+            assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(b"MSH|1".to_vec()));
+        }
+        let mut rejected = TcpStream::connect(closed).unwrap();
+        rejected.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let _ = rejected.write_all(&MllpCodec::encode(b"MSH|1"));
+        assert!(!matches!(rejected.read(&mut [0u8; 16]), Ok(n) if n > 0));
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+
+        shutdown.shutdown();
+        assert!(serving.join().unwrap().is_ok());
+        assert!(shutdown.is_shutdown());
+    }
+
+    #[test]
+    fn it_drops_connections_from_peers_not_allowed() {
+        let timeline = Arc::new(Timeline::new());
+        let addr = spawn_server(MllpServerConfig {
+            allowed_peers: Some(vec!["10.0.0.0/8".parse().unwrap()]),
+            event_sink: Some(timeline.clone()),
+            ..MllpServerConfig::default()
+        });
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let _ = stream.write_all(&MllpCodec::encode(b"MSH|1"));
+
+        assert!(!matches!(stream.read(&mut [0u8; 16]), Ok(n) if n > 0));
+        let connections = timeline.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].entries[0].kind, EventKind::ConnectionRejected);
+
+        let filtered = Arc::new(Mutex::new(Vec::new()));
+        let seen = filtered.clone();
+        let addr = spawn_server(MllpServerConfig {
+            allowed_peers: Some(vec!["127.0.0.0/8".parse().unwrap()]),
+            connection_filter: Some(Arc::new(move |peer_addr: SocketAddr| {
+                seen.lock().unwrap().push(peer_addr);
+                true
+            })),
+            ..MllpServerConfig::default()
+        });
+        let mut client = MllpClient::connect(addr).unwrap();
+        assert!(client.send(b"MSH|1").is_ok());
+        assert_eq!(*filtered.lock().unwrap(), vec![client.local_addr()]);
+    }
+
+    #[test]
+    fn it_records_stats_per_connection() {
+        let server = Arc::new(MllpServer::bind("127.0.0.1:0", MllpServerConfig::default()).unwrap());
+        let addr = server.local_addr().unwrap();
+        let serving = server.clone();
+        thread::spawn(move || {
+            serving.serve(|message: &[u8]| match message.ends_with(b"|2") {
+                true => AckDecision::CommitNak,
+                false => AckDecision::CommitAck,
+            })
+        });
+
+        let mut client = MllpClient::connect_with_config(addr, MllpClientConfig { max_retries: 0, ..MllpClientConfig::default() }).unwrap();
+        client.send(b"MSH|1").unwrap();
+        client.send(b"MSH|2").unwrap_err();
+
+        // recorded once the response is written
+        let start = Instant::now();
+        while server.stats().total.messages < 2 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let stats = server.stats();
+        assert_eq!((stats.total.messages, stats.total.bytes, stats.total.errors), (2, 10, 1));
+        assert_eq!(stats.connections.len(), 1);
+        assert_eq!(stats.connections[0].peer_addr, client.local_addr());
+        assert_eq!((stats.connections[0].stats.messages, stats.connections[0].stats.errors), (2, 1));
+
+        drop(client);
+        while !server.stats().connections.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let stats = server.stats();
+        assert!(stats.connections.is_empty());
+        assert_eq!(stats.total.messages, 2);
+    }
+}
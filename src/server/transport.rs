@@ -0,0 +1,264 @@
+//! Listening socket and connections of the server, over TCP, a Unix domain socket, in memory,
+//! or over a transport.
+//!
+//! Connections over a Unix domain socket, in memory or over a transport have no IP address. They are given the
+//! unspecified address `0.0.0.0`, with a port numbering them, wherever a peer address is
+//! expected: in events, and in [`FlowControl`](super::FlowControl).
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use crate::memory::{DuplexStream, MemoryListener};
+#[cfg(feature = "tls")]
+use crate::tls::TlsServerStream;
+use crate::transport::Transport;
+use crate::UNSPECIFIED_ADDR;
+
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        /// Port of the address of the next connection.
+        next_port: AtomicU16,
+    },
+    Memory {
+        listener: MemoryListener,
+        /// Port of the address of the next connection.
+        next_port: AtomicU16,
+    },
+    /// Transport served as the only connection, until taken.
+    Transport(Mutex<Option<Box<dyn Transport>>>),
+}
+
+impl Listener {
+    pub(super) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix { .. } => Ok(UNSPECIFIED_ADDR),
+            Listener::Memory { .. } | Listener::Transport(_) => Ok(UNSPECIFIED_ADDR),
+        }
+    }
+
+    pub(super) fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                // IPv4 peers of a dual-stack listener, as ::ffff:a.b.c.d
+                Ok((Stream::Tcp(stream), SocketAddr::new(addr.ip().to_canonical(), addr.port())))
+            }
+            #[cfg(unix)]
+            Listener::Unix { listener, next_port } => {
+                let (stream, _) = listener.accept()?;
+                let port = next_port.fetch_add(1, Ordering::Relaxed).max(1);
+                Ok((Stream::Unix(stream), SocketAddr::new(UNSPECIFIED_ADDR.ip(), port)))
+            }
+            Listener::Memory { listener, next_port } => {
+                let stream = listener.accept()?;
+                let port = next_port.fetch_add(1, Ordering::Relaxed).max(1);
+                Ok((Stream::Memory(stream), SocketAddr::new(UNSPECIFIED_ADDR.ip(), port)))
+            }
+            Listener::Transport(transport) => match transport.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                Some(transport) => Ok((Stream::Transport(transport), SocketAddr::new(UNSPECIFIED_ADDR.ip(), 1))),
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "the transport was served already")),
+            },
+        }
+    }
+}
+
+/// Accepted connection.
+pub(super) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsServerStream>),
+    Memory(DuplexStream),
+    Transport(Box<dyn Transport>),
+}
+
+impl Stream {
+    pub(super) fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref().set_read_timeout(timeout),
+            Stream::Memory(stream) => stream.set_read_timeout(timeout),
+            Stream::Transport(transport) => transport.set_read_timeout(timeout),
+        }
+    }
+
+    /// Sets the write timeout. In-memory connections never block on writes, and transports are
+    /// left as they are.
+    pub(super) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref().set_write_timeout(timeout),
+            Stream::Memory(_) | Stream::Transport(_) => Ok(()),
+        }
+    }
+
+    pub(super) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.get_ref().set_nonblocking(nonblocking),
+            Stream::Memory(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "in-memory connections block")),
+            Stream::Transport(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "transports block")),
+        }
+    }
+
+    pub(super) fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+                stream.get_ref().shutdown(how)
+            }
+            Stream::Memory(stream) => stream.shutdown(how),
+            Stream::Transport(transport) => transport.close(),
+        }
+    }
+
+    /// Stream for the poller of the worker pool, once set non-blocking.
+    pub(super) fn into_polled(self) -> PolledStream {
+        match self {
+            Stream::Tcp(stream) => PolledStream::Tcp(mio::net::TcpStream::from_std(stream)),
+            #[cfg(unix)]
+            Stream::Unix(stream) => PolledStream::Unix(mio::net::UnixStream::from_std(stream)),
+            #[cfg(feature = "tls")]
+            Stream::Tls(_) => unreachable!("TLS connections have their own thread"),
+            Stream::Memory(_) => unreachable!("in-memory connections have their own thread"),
+            Stream::Transport(_) => unreachable!("transports have their own thread"),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Memory(stream) => stream.read(buf),
+            Stream::Transport(transport) => transport.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Memory(stream) => stream.write(buf),
+            Stream::Transport(transport) => transport.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Memory(stream) => stream.flush(),
+            Stream::Transport(transport) => transport.flush(),
+        }
+    }
+}
+
+/// Non-blocking connection, watched by the poller of the worker pool.
+pub(super) enum PolledStream {
+    Tcp(mio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(mio::net::UnixStream),
+}
+
+impl PolledStream {
+    pub(super) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            PolledStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            PolledStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for PolledStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            PolledStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            PolledStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for PolledStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PolledStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            PolledStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PolledStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            PolledStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for PolledStream {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            PolledStream::Tcp(stream) => stream.register(registry, token, interests),
+            #[cfg(unix)]
+            PolledStream::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            PolledStream::Tcp(stream) => stream.reregister(registry, token, interests),
+            #[cfg(unix)]
+            PolledStream::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            PolledStream::Tcp(stream) => stream.deregister(registry),
+            #[cfg(unix)]
+            PolledStream::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
@@ -0,0 +1,358 @@
+//! Worker-pool execution model of the server.
+//!
+//! A poller thread watches all the connections for readability. When data arrives on a
+//! connection, the connection is queued for one of the worker threads, which reads what is
+//! available, handles the complete frames and writes the responses. Between events, the poller
+//! closes the connections which are idle, shed or shut down, like the connection threads do in
+//! the thread-per-connection model.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use crate::clock;
+use crate::handler::MllpHandler;
+use super::transport::{PolledStream, Stream};
+use super::{ConnectionRegistry, ConnectionSlot, ConnectionReader, ShutdownHandle, SHUTDOWN_POLL_INTERVAL};
+
+/// Token of the waker telling the poller about new or closed connections.
+const WAKER: Token = Token(usize::MAX);
+
+/// How long a worker waits before writing again to a connection whose send buffer is full.
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+pub(super) struct WorkerPool {
+    new_connections: Sender<Connection>,
+    /// Cleared once the server stops accepting, for the poller to exit when the connections are
+    /// all closed.
+    accepting: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+    poller: JoinHandle<()>,
+}
+
+struct Connection {
+    stream: PolledStream,
+    session: ConnectionReader,
+    /// Reading stopped while the connection was paused, data may be waiting.
+    deferred: bool,
+    _slot: Option<ConnectionSlot>,
+}
+
+/// A connection watched by the poller.
+struct Entry {
+    connection: Mutex<Connection>,
+    schedule: Mutex<Schedule>,
+    /// Set by the worker which found the connection closed or failed.
+    closed: AtomicBool,
+}
+
+#[derive(Default)]
+struct Schedule {
+    /// A worker is reading the connection.
+    busy: bool,
+    /// Data arrived while a worker was reading the connection.
+    pending: bool,
+}
+
+impl WorkerPool {
+    pub(super) fn start<H>(
+        threads: usize,
+        shutdown: ShutdownHandle,
+        registry: Arc<ConnectionRegistry>,
+        handler: Arc<H>,
+    ) -> io::Result<Self>
+    where
+        H: MllpHandler + 'static,
+    {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (new_connections, received) = mpsc::channel();
+        let accepting = Arc::new(AtomicBool::new(true));
+
+        let (jobs, queue) = mpsc::channel::<Arc<Entry>>();
+        let queue = Arc::new(Mutex::new(queue));
+        let local_addr = shutdown.local_addr;
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let waker = waker.clone();
+                let handler = handler.clone();
+                thread::spawn(move || work(&queue, &waker, local_addr, &*handler))
+            })
+            .collect();
+
+        let poller = Poller {
+            poll,
+            received,
+            accepting: accepting.clone(),
+            shutdown,
+            registry,
+            jobs,
+            workers,
+            entries: HashMap::new(),
+            next_token: 0,
+        };
+
+        Ok(WorkerPool {
+            new_connections,
+            accepting,
+            waker,
+            poller: thread::spawn(move || poller.run()),
+        })
+    }
+
+    /// Hands a newly accepted connection to the pool.
+    pub(super) fn add(&self, stream: Stream, session: ConnectionReader, slot: Option<ConnectionSlot>) {
+        if stream.set_nonblocking(true).is_err() {
+            // have the poller close it right away
+            session.handle.state.shed.store(true, Ordering::Relaxed);
+        }
+        let connection = Connection {
+            stream: stream.into_polled(),
+            session,
+            deferred: false,
+            _slot: slot,
+        };
+        if self.new_connections.send(connection).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+
+    /// Waits for all the connections to be closed, once the server stopped accepting.
+    pub(super) fn join(self) {
+        self.accepting.store(false, Ordering::Relaxed);
+        let _ = self.waker.wake();
+        let _ = self.poller.join();
+    }
+}
+
+struct Poller {
+    poll: Poll,
+    received: Receiver<Connection>,
+    accepting: Arc<AtomicBool>,
+    shutdown: ShutdownHandle,
+    registry: Arc<ConnectionRegistry>,
+    jobs: Sender<Arc<Entry>>,
+    workers: Vec<JoinHandle<()>>,
+    entries: HashMap<Token, Arc<Entry>>,
+    next_token: usize,
+}
+
+impl Poller {
+    fn run(mut self) {
+        let mut events = Events::with_capacity(1024);
+
+        loop {
+            let started = Instant::now();
+            let polled = self.poll.poll(&mut events, Some(SHUTDOWN_POLL_INTERVAL));
+            self.skip(clock::stalled(started, SHUTDOWN_POLL_INTERVAL));
+            match polled {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+
+            for event in events.iter() {
+                if event.token() == WAKER {
+                    self.register_new_connections();
+                } else if let Some(entry) = self.entries.get(&event.token()) {
+                    schedule(entry, &self.jobs);
+                }
+            }
+            self.close_finished_connections();
+
+            if !self.accepting.load(Ordering::Relaxed) && self.entries.is_empty() {
+                break;
+            }
+        }
+
+        // an error of the poller leaves the connections to close
+        for (_, entry) in self.entries.drain() {
+            let connection = entry.connection.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = connection.stream.shutdown(Shutdown::Both);
+            self.registry.unregister(&connection.session.handle.state);
+        }
+        drop(self.jobs);
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+
+    fn register_new_connections(&mut self) {
+        while let Ok(mut connection) = self.received.try_recv() {
+            let token = Token(self.next_token);
+            self.next_token = (self.next_token + 1) % WAKER.0;
+
+            if self.poll.registry().register(&mut connection.stream, token, Interest::READABLE).is_err() {
+                self.registry.unregister(&connection.session.handle.state);
+                continue;
+            }
+            let entry = Arc::new(Entry {
+                connection: Mutex::new(connection),
+                schedule: Mutex::new(Schedule::default()),
+                closed: AtomicBool::new(false),
+            });
+            // data may have arrived before the registration
+            schedule(&entry, &self.jobs);
+            self.entries.insert(token, entry);
+        }
+    }
+
+    fn skip(&self, by: Duration) {
+        if by.is_zero() {
+            return;
+        }
+        // a connection a worker is reading has just received something anyway
+        for entry in self.entries.values() {
+            if let Ok(mut connection) = entry.connection.try_lock() {
+                connection.session.skip(by);
+            }
+        }
+    }
+
+    fn close_finished_connections(&mut self) {
+        let registry = self.poll.registry();
+        self.entries.retain(|_, entry| {
+            // a connection a worker is reading is checked at the next round
+            let Ok(mut connection) = entry.connection.try_lock() else {
+                return true;
+            };
+            // readiness is edge-triggered, a resumed connection is read without waiting for more
+            let paused = connection.session.is_paused();
+            if connection.deferred && !paused {
+                connection.deferred = false;
+                schedule(entry, &self.jobs);
+            }
+            let closing = entry.closed.load(Ordering::Relaxed) || connection.session.should_close(&self.shutdown);
+            if closing {
+                close(registry, &mut connection);
+                self.registry.unregister(&connection.session.handle.state);
+            }
+            !closing
+        });
+    }
+}
+
+fn close(registry: &Registry, connection: &mut Connection) {
+    let _ = registry.deregister(&mut connection.stream);
+    let _ = connection.stream.shutdown(Shutdown::Both);
+}
+
+/// Queues `entry` for a worker, or tells the worker already reading it to read again.
+fn schedule(entry: &Arc<Entry>, jobs: &Sender<Arc<Entry>>) {
+    let mut schedule = entry.schedule.lock().unwrap_or_else(|e| e.into_inner());
+    if schedule.busy {
+        schedule.pending = true;
+    } else {
+        schedule.busy = true;
+        let _ = jobs.send(entry.clone());
+    }
+}
+
+fn work<H>(
+    queue: &Mutex<Receiver<Arc<Entry>>>,
+    waker: &Waker,
+    local_addr: SocketAddr,
+    handler: &H,
+)
+where
+    H: MllpHandler,
+{
+    loop {
+        let job = queue.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        let Ok(entry) = job else {
+            return;
+        };
+
+        loop {
+            let open = {
+                let mut connection = entry.connection.lock().unwrap_or_else(|e| e.into_inner());
+                read_available(&mut connection, local_addr, handler).unwrap_or(false)
+            };
+            if !open {
+                entry.closed.store(true, Ordering::Relaxed);
+                let _ = waker.wake();
+            }
+
+            let mut schedule = entry.schedule.lock().unwrap_or_else(|e| e.into_inner());
+            if open && schedule.pending {
+                schedule.pending = false;
+                continue;
+            }
+            schedule.busy = false;
+            break;
+        }
+    }
+}
+
+/// Reads and handles everything received on `connection`. Returns whether the connection is
+/// still open.
+fn read_available<H>(
+    connection: &mut Connection,
+    local_addr: SocketAddr,
+    handler: &H,
+) -> io::Result<bool>
+where
+    H: MllpHandler,
+{
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        if connection.session.is_paused() {
+            connection.deferred = true;
+            return Ok(true);
+        }
+        match connection.stream.read(&mut chunk) {
+            Ok(0) => {
+                connection.session.closed_by_peer(local_addr);
+                return Ok(false);
+            }
+            Ok(n) => {
+                connection.session.received(&chunk[..n]);
+                let mut writer = NonBlockingWriter {
+                    stream: &mut connection.stream,
+                    timeout: connection.session.config.write_timeout,
+                };
+                if let Err(e) = connection.session.handle_frames(&mut writer, handler) {
+                    return Err(connection.session.write_error(local_addr, e));
+                }
+                if connection.session.handle.is_closing() {
+                    return Ok(false);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Writer waiting for room in the send buffer of a non-blocking stream, for at most `timeout`.
+struct NonBlockingWriter<'a> {
+    stream: &'a mut PolledStream,
+    timeout: Option<Duration>,
+}
+
+impl Write for NonBlockingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        loop {
+            match self.stream.write(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && self.timeout.is_some_and(|timeout| started.elapsed() >= timeout) => {
+                    return Err(e);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(WRITE_RETRY_DELAY),
+                result => return result,
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
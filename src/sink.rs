@@ -0,0 +1,338 @@
+//! Forwarding of the messages a server accepts, for bridges from MLLP to a queue or a database.
+//!
+//! A [`MessageSink`] set in
+//! [`MllpServerConfig::message_sink`](crate::server::MllpServerConfig::message_sink) is given
+//! every message the handler did not refuse with a NAK, once the handler decided. The handler
+//! still decides the acknowledgement; when the sink is full or failing,
+//! [`MllpServerConfig::sink_full_policy`](crate::server::MllpServerConfig::sink_full_policy) and
+//! [`MllpServerConfig::sink_failure_policy`](crate::server::MllpServerConfig::sink_failure_policy)
+//! tell whether the message is dropped, retried, or answered with a NAK for the sender to send
+//! it again.
+//!
+//! A bounded [`std::sync::mpsc::sync_channel`] sender is a sink as it is, the receiving end
+//! being read on another thread. With the `futures` feature, [`channel`] makes a sink whose
+//! receiving end is a `Stream`, read from an asynchronous task, and [`BlockingSink`] adapts an
+//! [`AsyncMessageSink`].
+//! ```no_run
+//! use std::sync::mpsc;
+//! use std::sync::Arc;
+//! use std::thread;
+//! use mllp_rs::handler::AckDecision;
+//! use mllp_rs::server::{MllpServer, MllpServerConfig, SinkPolicy};
+//! use mllp_rs::sink::ForwardedMessage;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let (sender, receiver) = mpsc::sync_channel::<ForwardedMessage>(1000);
+//! thread::spawn(move || {
+//!     for message in receiver {
+//!         println!("{} bytes from {}", message.payload.len(), message.peer_addr);
+//!     }
+//! });
+//! let config = MllpServerConfig {
+//!     message_sink: Some(Arc::new(sender)),
+//!     sink_full_policy: SinkPolicy::Nak,
+//!     ..MllpServerConfig::default()
+//! };
+//! let server = MllpServer::bind("0.0.0.0:2575", config)?;
+//! server.serve(|_: &[u8]| AckDecision::CommitAck)?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::SystemTime;
+use crate::handler::ReceivedFrame;
+#[cfg(feature = "futures")]
+use std::future::Future;
+#[cfg(feature = "futures")]
+use std::pin::{pin, Pin};
+#[cfg(feature = "futures")]
+use std::sync::Mutex;
+#[cfg(feature = "futures")]
+use std::task::{Context, Poll, Wake, Waker};
+#[cfg(feature = "futures")]
+use std::thread::{self, Thread};
+#[cfg(feature = "futures")]
+use futures_channel::mpsc;
+
+/// Why a sink did not take a message.
+#[derive(Debug)]
+pub enum SinkError {
+    /// The sink has no room for the message now, and may have later.
+    Full,
+    /// The sink failed, e.g. its broker or database is unreachable, or its receiving end is gone.
+    Failed(io::Error),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Full => write!(f, "Message sink full"),
+            SinkError::Failed(e) => write!(f, "Message sink failed: {}", e),
+        }
+    }
+}
+
+impl Error for SinkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SinkError::Full => None,
+            SinkError::Failed(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for SinkError {
+    fn from(e: io::Error) -> Self {
+        SinkError::Failed(e)
+    }
+}
+
+/// Message forwarded, owning its payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardedMessage {
+    pub payload: Vec<u8>,
+    pub peer_addr: SocketAddr,
+    /// Identifier of the connection, as in [`ReceivedFrame::connection_id`].
+    pub connection_id: u64,
+    pub received_at: SystemTime,
+}
+
+impl From<&ReceivedFrame<'_>> for ForwardedMessage {
+    fn from(frame: &ReceivedFrame<'_>) -> Self {
+        ForwardedMessage {
+            payload: frame.payload.to_vec(),
+            peer_addr: frame.peer_addr,
+            connection_id: frame.connection_id,
+            received_at: frame.received_at,
+        }
+    }
+}
+
+/// Destination of the messages accepted by a server.
+///
+/// Sinks are called synchronously on the connection's thread, before the response is written,
+/// and should hand the message over rather than process it: a slow sink holds up the sender.
+pub trait MessageSink: Send + Sync {
+    fn forward(&self, frame: &ReceivedFrame<'_>) -> Result<(), SinkError>;
+}
+
+impl<S: MessageSink + ?Sized> MessageSink for Arc<S> {
+    fn forward(&self, frame: &ReceivedFrame<'_>) -> Result<(), SinkError> {
+        (**self).forward(frame)
+    }
+}
+
+impl fmt::Debug for dyn MessageSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MessageSink")
+    }
+}
+
+/// Full when the channel is, failing once the receiver is dropped.
+impl MessageSink for SyncSender<ForwardedMessage> {
+    fn forward(&self, frame: &ReceivedFrame<'_>) -> Result<(), SinkError> {
+        self.try_send(frame.into()).map_err(|e| match e {
+            TrySendError::Full(_) => SinkError::Full,
+            TrySendError::Disconnected(_) => SinkError::Failed(io::ErrorKind::BrokenPipe.into()),
+        })
+    }
+}
+
+/// Sink of a channel read from an asynchronous task, made by [`channel`].
+#[cfg(feature = "futures")]
+#[derive(Debug)]
+pub struct ChannelSink {
+    sender: Mutex<mpsc::Sender<ForwardedMessage>>,
+}
+
+/// Makes a sink holding up to `capacity` messages not yet received, full beyond, and failing
+/// once the receiver is dropped. The receiver is a `Stream` of the messages.
+#[cfg(feature = "futures")]
+pub fn channel(capacity: usize) -> (ChannelSink, mpsc::Receiver<ForwardedMessage>) {
+    // the sender is given a slot of its own on top of the buffer
+    let (sender, receiver) = mpsc::channel(capacity.saturating_sub(1));
+    (ChannelSink { sender: Mutex::new(sender) }, receiver)
+}
+
+#[cfg(feature = "futures")]
+impl MessageSink for ChannelSink {
+    fn forward(&self, frame: &ReceivedFrame<'_>) -> Result<(), SinkError> {
+        let mut sender = self.sender.lock().unwrap_or_else(|e| e.into_inner());
+        sender.try_send(frame.into()).map_err(|e| match e.is_full() {
+            true => SinkError::Full,
+            false => SinkError::Failed(io::ErrorKind::BrokenPipe.into()),
+        })
+    }
+}
+
+/// Future of an [`AsyncMessageSink`].
+#[cfg(feature = "futures")]
+pub type SinkFuture = Pin<Box<dyn Future<Output = Result<(), SinkError>> + Send>>;
+
+/// Destination of the messages accepted by a server, forwarding them asynchronously. Set it in
+/// the server wrapped in a [`BlockingSink`].
+#[cfg(feature = "futures")]
+pub trait AsyncMessageSink: Send + Sync {
+    fn forward(&self, message: ForwardedMessage) -> SinkFuture;
+}
+
+#[cfg(feature = "futures")]
+impl<S: AsyncMessageSink + ?Sized> AsyncMessageSink for Arc<S> {
+    fn forward(&self, message: ForwardedMessage) -> SinkFuture {
+        (**self).forward(message)
+    }
+}
+
+/// Sink waiting for an [`AsyncMessageSink`] on the connection's thread.
+///
+/// The futures are polled outside of any runtime: those needing one, such as the I/O and
+/// timers of tokio, must run on a task of their runtime, e.g. fed through a [`channel`].
+#[cfg(feature = "futures")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockingSink<S>(pub S);
+
+#[cfg(feature = "futures")]
+impl<S: AsyncMessageSink> MessageSink for BlockingSink<S> {
+    fn forward(&self, frame: &ReceivedFrame<'_>) -> Result<(), SinkError> {
+        block_on(self.0.forward(frame.into()))
+    }
+}
+
+/// Waker of a thread waiting in [`block_on`].
+#[cfg(feature = "futures")]
+struct Unpark(Thread);
+
+#[cfg(feature = "futures")]
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls `future` to completion on the current thread, parking it while the future is pending.
+#[cfg(feature = "futures")]
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use crate::client::{MllpClient, MllpClientConfig};
+    use crate::handler::AckDecision;
+    use crate::server::{MllpServer, MllpServerConfig, SinkPolicy};
+    use crate::MllpError;
+
+    fn serve(config: MllpServerConfig) -> MllpClient {
+        let server = MllpServer::bind("127.0.0.1:0", config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            server.serve(|message: &[u8]| match message.ends_with(b"|refused") {
+                true => AckDecision::CommitNak,
+                false => AckDecision::CommitAck,
+            })
+        });
+        MllpClient::connect_with_config(addr, MllpClientConfig { max_retries: 0, ..MllpClientConfig::default() }).unwrap()
+    }
+
+    #[test]
+    fn it_forwards_accepted_messages() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let mut client = serve(MllpServerConfig {
+            message_sink: Some(Arc::new(sender)),
+            sink_full_policy: SinkPolicy::Nak,
+            sink_failure_policy: SinkPolicy::Drop,
+            ..MllpServerConfig::default()
+        });
+
+        client.send(b"MSH|1").unwrap();
+        assert!(matches!(client.send(b"MSH|refused"), Err(MllpError::Nak)));
+        // the first message is still in the channel
+        assert!(matches!(client.send(b"MSH|2"), Err(MllpError::Nak)));
+        let forwarded = receiver.recv().unwrap();
+        assert_eq!((forwarded.payload.as_slice(), forwarded.peer_addr), (&b"MSH|1"[..], client.local_addr()));
+        client.send(b"MSH|3").unwrap();
+        assert_eq!(receiver.recv().unwrap().payload, b"MSH|3");
+
+        // failing once the receiver is gone, the messages are dropped
+        drop(receiver);
+        client.send(b"MSH|4").unwrap();
+    }
+
+    #[test]
+    fn it_retries_full_sinks() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let mut client = serve(MllpServerConfig {
+            message_sink: Some(Arc::new(sender)),
+            sink_full_policy: SinkPolicy::Retry(std::time::Duration::from_secs(5)),
+            ..MllpServerConfig::default()
+        });
+        client.send(b"MSH|1").unwrap();
+        let receiving = thread::spawn(move || [receiver.recv().unwrap(), receiver.recv().unwrap()]);
+        client.send(b"MSH|2").unwrap();
+        let [first, second] = receiving.join().unwrap();
+        assert_eq!((first.payload, second.payload), (b"MSH|1".to_vec(), b"MSH|2".to_vec()));
+
+        // failing, and answered with a NAK by default
+        assert!(matches!(client.send(b"MSH|3"), Err(MllpError::Nak)));
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn it_forwards_to_async_sinks() {
+        use std::sync::Mutex;
+        use futures::executor::block_on;
+        use futures::StreamExt;
+        use crate::sink::{channel, AsyncMessageSink, BlockingSink, ForwardedMessage, SinkFuture};
+
+        let (sink, mut receiver) = channel(1);
+        let mut client = serve(MllpServerConfig { message_sink: Some(Arc::new(sink)), ..MllpServerConfig::default() });
+        client.send(b"MSH|1").unwrap();
+        assert!(matches!(client.send(b"MSH|2"), Err(MllpError::Nak)));
+        assert_eq!(block_on(receiver.next()).unwrap().payload, b"MSH|1");
+
+        /// Stores the messages after yielding once, as a write to a database would.
+        #[derive(Default)]
+        struct Store(Arc<Mutex<Vec<ForwardedMessage>>>);
+
+        impl AsyncMessageSink for Store {
+            fn forward(&self, message: ForwardedMessage) -> SinkFuture {
+                let stored = self.0.clone();
+                let mut yielded = false;
+                Box::pin(std::future::poll_fn(move |cx| {
+                    if !yielded {
+                        yielded = true;
+                        let waker = cx.waker().clone();
+                        thread::spawn(move || waker.wake());
+                        return std::task::Poll::Pending;
+                    }
+                    stored.lock().unwrap().push(message.clone());
+                    std::task::Poll::Ready(Ok(()))
+                }))
+            }
+        }
+
+        let store = Store::default();
+        let stored = store.0.clone();
+        let mut client = serve(MllpServerConfig { message_sink: Some(Arc::new(BlockingSink(store))), ..MllpServerConfig::default() });
+        client.send(b"MSH|1").unwrap();
+        assert!(matches!(client.send(b"MSH|refused"), Err(MllpError::Nak)));
+        let stored = stored.lock().unwrap();
+        assert_eq!(stored.iter().map(|message| message.payload.as_slice()).collect::<Vec<_>>(), [b"MSH|1"]);
+    }
+}
@@ -0,0 +1,965 @@
+//! Store-and-forward delivery through an on-disk journal.
+//!
+//! A [`SpoolingClient`] writes every outbound message to a [`Spool`] directory before sending
+//! it, and removes it only once it is acknowledged. Messages whose delivery did not complete,
+//! because the receiver was down or the process crashed, are found in the spool on restart and
+//! retransmitted, so no message is lost.
+//! ```no_run
+//! use mllp_rs::client::MllpClient;
+//! use mllp_rs::spool::SpoolingClient;
+//!
+//! # fn main() -> Result<(), mllp_rs::MllpError> {
+//! let client = MllpClient::connect("127.0.0.1:5000")?;
+//! let mut client = SpoolingClient::new(client, "/var/spool/mllp/lab")?;
+//! // messages left over by a previous run go first
+//! client.send_pending()?;
+//! client.send(b"MSH|^~\\&|...")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Crash recovery
+//!
+//! The spool also keeps, in a `delivered.log` file, the entry IDs and MSH-10 control IDs of the
+//! last [`DEDUP_WINDOW`] delivered messages, and marks the entry being sent as in flight. What a
+//! restart does depends on where the process crashed:
+//! - before the message was sent: the entry is sent, in its place in the order;
+//! - after it was sent, before the ACK was received: the entry is still marked as in flight, and
+//!   is sent again, first. The receiver may get it twice, and is expected to recognise the
+//!   duplicate by its control ID;
+//! - after the delivery was logged, before the entry was removed: the entry is removed and not
+//!   sent again;
+//! - after the entry was removed: a message with the same control ID, replayed by the
+//!   application from its own queue, is skipped by [`SpoolingClient::send`].
+//!
+//! The ACK being received and the delivery being logged cannot happen atomically: a crash in
+//! between resends the message, as in the second case.
+//!
+//! # Delivery status
+//!
+//! The entry ID of a message, returned by [`SpoolingClient::enqueue`] and handed to the
+//! [delivery callback](SpoolingClient::on_delivery), identifies it across restarts.
+//! [`Spool::status`] tells where its delivery is: pending, in flight, or acknowledged, with the
+//! acknowledgement code logged along with the delivery, as long as it is in the dedup window.
+//!
+//! # Metadata
+//!
+//! A message may be spooled with [`Metadata`], e.g. a tenant, a priority or the ID of a record of
+//! the application. It is stored next to the message, and handed back with the message to the
+//! [delivery callback](SpoolingClient::on_delivery) and in the
+//! [dead letter](crate::dead_letter::DeadLetter::metadata), so that the application can match
+//! deliveries with its own records, even after a restart.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "unstable")]
+use std::sync::Arc;
+use crate::client::{Ack, MllpClient};
+#[cfg(feature = "unstable")]
+use crate::leader::LeaderLock;
+use crate::{control_id, MllpError};
+
+/// Extension of the files holding spooled messages.
+const ENTRY_EXTENSION: &str = "msg";
+
+/// Extension of the files holding the metadata of spooled messages.
+const METADATA_EXTENSION: &str = "meta";
+
+/// Extension of the markers of the entries being sent.
+const IN_FLIGHT_EXTENSION: &str = "inflight";
+
+/// Log of the delivered messages.
+const DELIVERED_LOG: &str = "delivered.log";
+
+/// Number of delivered messages remembered, to recognise them when they are replayed.
+pub const DEDUP_WINDOW: usize = 10_000;
+
+/// Metadata attached by the application to a spooled message.
+pub type Metadata = BTreeMap<String, String>;
+
+/// Directory journal of messages waiting to be acknowledged.
+///
+/// Each message is stored in its own file, named after a sequence number which gives the order of
+/// delivery. Files are written to a temporary name, synced and renamed, so a crash never leaves a
+/// partially written entry behind. Sequence numbers keep increasing across restarts, even once
+/// the spool is empty.
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    next_id: u64,
+    delivered: Delivered,
+}
+
+/// Window of the last delivered messages.
+#[derive(Debug, Default)]
+struct Delivered {
+    /// Entry ID, control ID and acknowledgement code of the delivered messages, oldest first.
+    order: VecDeque<(u64, Option<String>, Option<String>)>,
+    /// Number of messages in the window with each control ID.
+    control_ids: HashMap<String, usize>,
+    /// Lines of the log, rewritten down to the window when twice as long.
+    logged: usize,
+}
+
+impl Delivered {
+    fn push(&mut self, id: u64, control_id: Option<String>, code: Option<String>) {
+        if let Some(control_id) = &control_id {
+            *self.control_ids.entry(control_id.clone()).or_default() += 1;
+        }
+        self.order.push_back((id, control_id, code));
+
+        while self.order.len() > DEDUP_WINDOW {
+            let Some((_, Some(oldest), _)) = self.order.pop_front() else { continue };
+            if let Some(count) = self.control_ids.get_mut(&oldest) {
+                *count -= 1;
+                if *count == 0 {
+                    self.control_ids.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+impl Spool {
+    /// Opens the spool in `dir`, creating the directory if needed, and cleans up after a crash:
+    /// entries logged as delivered are removed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+
+        let mut spool = Spool { dir, next_id: 1, delivered: Delivered::default() };
+        match fs::read_to_string(spool.dir.join(DELIVERED_LOG)) {
+            Ok(log) => {
+                for line in log.lines() {
+                    // the code is left out by the logs of earlier versions
+                    let (line, code) = line.split_once('\t').unwrap_or((line, ""));
+                    let (id, control_id) = line.split_once(' ').unwrap_or((line, ""));
+                    // a line cut short by a crash
+                    let Ok(id) = id.parse() else { continue };
+                    let field = |field: &str| (!field.is_empty()).then(|| field.to_owned());
+                    spool.delivered.push(id, field(control_id), field(code));
+                    spool.delivered.logged += 1;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        for (id, _, _) in &spool.delivered.order {
+            match fs::remove_file(spool.entry_path(*id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        for entry in fs::read_dir(&spool.dir)? {
+            let path = entry?.path();
+            let companion = path.extension().is_some_and(|ext| ext == IN_FLIGHT_EXTENSION || ext == METADATA_EXTENSION);
+            if companion && !path.with_extension(ENTRY_EXTENSION).exists() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        let last_pending = spool.pending()?.last().copied();
+        let last_delivered = spool.delivered.order.back().map(|(id, _, _)| *id);
+        spool.next_id = last_pending.max(last_delivered).map_or(1, |id| id + 1);
+
+        Ok(spool)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Durably stores `payload`, returning the ID of its entry.
+    pub fn push(&mut self, payload: &[u8]) -> io::Result<u64> {
+        self.push_with_metadata(payload, &Metadata::new())
+    }
+
+    /// Durably stores `payload` with `metadata`, returning the ID of its entry.
+    pub fn push_with_metadata(&mut self, payload: &[u8], metadata: &Metadata) -> io::Result<u64> {
+        let id = self.next_id;
+        let tmp = self.dir.join(format!("{:020}.tmp", id));
+
+        // written first, so that an entry is never without its metadata
+        if !metadata.is_empty() {
+            let mut file = File::create(&tmp)?;
+            for (key, value) in metadata {
+                writeln!(file, "{}\t{}", escape(key), escape(value))?;
+            }
+            file.sync_all()?;
+            fs::rename(&tmp, self.metadata_path(id))?;
+            sync_dir(&self.dir)?;
+        }
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(payload)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.entry_path(id))?;
+        sync_dir(&self.dir)?;
+        self.next_id += 1;
+
+        Ok(id)
+    }
+
+    /// IDs of the entries not completed yet, oldest first.
+    pub fn pending(&self) -> io::Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()?.parse().ok()) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+
+        Ok(ids)
+    }
+
+    /// IDs of the pending entries marked as in flight, oldest first. After a crash, these may or
+    /// may not have reached the receiver.
+    pub fn in_flight(&self) -> io::Result<Vec<u64>> {
+        Ok(self.pending()?.into_iter().filter(|id| self.in_flight_path(*id).exists()).collect())
+    }
+
+    /// Reads the payload of entry `id`.
+    pub fn read(&self, id: u64) -> io::Result<Vec<u8>> {
+        fs::read(self.entry_path(id))
+    }
+
+    /// Reads the metadata of entry `id`, empty if it was spooled without any.
+    pub fn metadata(&self, id: u64) -> io::Result<Metadata> {
+        let lines = match fs::read_to_string(self.metadata_path(id)) {
+            Ok(lines) => lines,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Metadata::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(lines
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .map(|(key, value)| (unescape(key), unescape(value)))
+            .collect())
+    }
+
+    /// Durably marks entry `id` as in flight, before it is sent.
+    pub fn mark_in_flight(&mut self, id: u64) -> io::Result<()> {
+        File::create(self.in_flight_path(id))?.sync_all()?;
+        sync_dir(&self.dir)
+    }
+
+    /// Marks entry `id` as delivered: logs it in the dedup window, then removes it from the
+    /// spool.
+    pub fn complete(&mut self, id: u64) -> io::Result<()> {
+        self.acknowledge(id, &Ack::None)
+    }
+
+    /// Same as [`Spool::complete`], logging the code of `ack` along with the delivery, for
+    /// [`Spool::status`].
+    pub fn acknowledge(&mut self, id: u64, ack: &Ack) -> io::Result<()> {
+        let control_id = control_id(&self.read(id)?);
+        self.log_delivered(id, control_id, ack_code(ack))?;
+        self.discard(id)
+    }
+
+    /// Where the delivery of entry `id` is.
+    pub fn status(&self, id: u64) -> io::Result<DeliveryStatus> {
+        if let Some((_, _, code)) = self.delivered.order.iter().rev().find(|(delivered, _, _)| *delivered == id) {
+            return Ok(DeliveryStatus::Acknowledged(code.clone()));
+        }
+        if self.in_flight_path(id).exists() {
+            return Ok(DeliveryStatus::InFlight);
+        }
+        match self.entry_path(id).try_exists()? {
+            true => Ok(DeliveryStatus::Pending),
+            false => Ok(DeliveryStatus::Unknown),
+        }
+    }
+
+    /// Removes entry `id` from the spool without logging it as delivered, e.g. once it was
+    /// dead-lettered, so that it can be sent again later.
+    pub fn discard(&mut self, id: u64) -> io::Result<()> {
+        fs::remove_file(self.entry_path(id))?;
+        for path in [self.in_flight_path(id), self.metadata_path(id)] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether a message with the control ID of `payload` was delivered recently.
+    pub fn is_delivered(&self, payload: &[u8]) -> bool {
+        control_id(payload).is_some_and(|control_id| self.delivered.control_ids.contains_key(&control_id))
+    }
+
+    fn log_delivered(&mut self, id: u64, control_id: Option<String>, code: Option<String>) -> io::Result<()> {
+        let mut log = OpenOptions::new().create(true).append(true).open(self.dir.join(DELIVERED_LOG))?;
+        log_line(&mut log, id, &control_id, &code)?;
+        log.sync_data()?;
+        self.delivered.push(id, control_id, code);
+        self.delivered.logged += 1;
+
+        if self.delivered.logged > 2 * DEDUP_WINDOW {
+            let tmp = self.dir.join(format!("{}.tmp", DELIVERED_LOG));
+            let mut file = File::create(&tmp)?;
+            for (id, control_id, code) in &self.delivered.order {
+                log_line(&mut file, *id, control_id, code)?;
+            }
+            file.sync_all()?;
+            fs::rename(&tmp, self.dir.join(DELIVERED_LOG))?;
+            sync_dir(&self.dir)?;
+            self.delivered.logged = self.delivered.order.len();
+        }
+
+        Ok(())
+    }
+
+    fn entry_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, ENTRY_EXTENSION))
+    }
+
+    fn in_flight_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, IN_FLIGHT_EXTENSION))
+    }
+
+    fn metadata_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", id, METADATA_EXTENSION))
+    }
+}
+
+/// Where the delivery of a spooled message is, from [`Spool::status`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeliveryStatus {
+    /// Spooled, not sent yet.
+    Pending,
+    /// Sent, its acknowledgement not received yet. It is sent again after a restart.
+    InFlight,
+    /// Acknowledged, with the code of the acknowledgement: `CA` for a commit ACK, MSA-1 for an
+    /// application acknowledgement, `None` if there was none or it was not logged.
+    Acknowledged(Option<String>),
+    /// Neither in the spool nor in the dedup window: dead-lettered, delivered too long ago, or
+    /// never spooled.
+    Unknown,
+}
+
+/// Syncs the entries of `dir`, so that a file renamed into it survives a crash. Directories
+/// cannot be opened for this outside of Unix, where renames are durable once the file is.
+pub(crate) fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+
+    Ok(())
+}
+
+/// Error of a standby instance, which leaves the spool to the one holding the leader lock.
+fn standby() -> io::Error {
+    io::Error::new(io::ErrorKind::ResourceBusy, "another instance holds the leader lock")
+}
+
+/// Line of the log of the delivered messages.
+fn log_line<W: Write>(log: &mut W, id: u64, control_id: &Option<String>, code: &Option<String>) -> io::Result<()> {
+    match code {
+        Some(code) => writeln!(log, "{} {}\t{}", id, control_id.as_deref().unwrap_or_default(), code),
+        None => writeln!(log, "{} {}", id, control_id.as_deref().unwrap_or_default()),
+    }
+}
+
+/// Code of `ack`: `CA` for a commit ACK, MSA-1 for an application acknowledgement.
+fn ack_code(ack: &Ack) -> Option<String> {
+    let payload = match ack {
+        Ack::Commit => return Some("CA".to_owned()),
+        Ack::Application(payload) => payload,
+        Ack::None => return None,
+    };
+    let msa = payload.split(|b| *b == b'\r' || *b == b'\n').find(|segment| segment.starts_with(b"MSA"))?;
+    let separator = *msa.get(3)?;
+    let code = msa.split(|b| *b == separator).nth(1).filter(|code| !code.is_empty())?;
+
+    Some(String::from_utf8_lossy(code).trim().to_owned())
+}
+
+/// Escapes the characters separating the keys and values of metadata files.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+
+    unescaped
+}
+
+/// Client journaling messages to a [`Spool`] until they are acknowledged.
+///
+/// Messages are delivered in the order they were spooled: before a new message, the messages
+/// still pending are sent. A message accepted by the client's
+/// [dead-letter sink](crate::client::MllpClientConfig::dead_letter) is removed from the spool, so it
+/// does not hold up the messages after it.
+///
+/// A message whose control ID is in the dedup window of the spool was already delivered, and is
+/// not sent again: see [crash recovery](self#crash-recovery).
+pub struct SpoolingClient {
+    client: MllpClient,
+    spool: Spool,
+    on_delivery: Option<Box<DeliveryCallback>>,
+    #[cfg(feature = "unstable")]
+    leader_lock: Option<Arc<dyn LeaderLock>>,
+    /// Whether the leader lock was held when last tried.
+    #[cfg(feature = "unstable")]
+    leading: bool,
+}
+
+type DeliveryCallback = dyn FnMut(&Delivery<'_>) + Send;
+
+/// Message acknowledged by the receiver, given to the
+/// [delivery callback](SpoolingClient::on_delivery).
+#[derive(Debug)]
+pub struct Delivery<'a> {
+    /// ID of the spool entry.
+    pub id: u64,
+    pub payload: &'a [u8],
+    pub metadata: &'a Metadata,
+    pub ack: &'a Ack,
+}
+
+impl SpoolingClient {
+    /// Wraps `client`, journaling its messages in `dir`.
+    pub fn new<P: AsRef<Path>>(client: MllpClient, dir: P) -> io::Result<Self> {
+        Ok(SpoolingClient {
+            client,
+            spool: Spool::open(dir)?,
+            on_delivery: None,
+            #[cfg(feature = "unstable")]
+            leader_lock: None,
+            #[cfg(feature = "unstable")]
+            leading: false,
+        })
+    }
+
+    pub fn spool(&self) -> &Spool {
+        &self.spool
+    }
+
+    pub fn client(&mut self) -> &mut MllpClient {
+        &mut self.client
+    }
+
+    /// Calls `callback` with each message acknowledged, once it is removed from the spool.
+    pub fn on_delivery<F>(&mut self, callback: F)
+    where
+        F: FnMut(&Delivery<'_>) + Send + 'static,
+    {
+        self.on_delivery = Some(Box::new(callback));
+    }
+
+    /// Uses the spool only while holding `lock`, shared with the other instances of the sender
+    /// using the same spool directory. The lock is tried before each message, which renews a
+    /// lease. A standby instance neither spools nor sends: `send` and `enqueue` fail with
+    /// [`io::ErrorKind::ResourceBusy`], and `send_pending` sends nothing. The instance taking
+    /// over opens the spool again, and sends the messages the previous one left.
+    #[cfg(feature = "unstable")]
+    pub fn leader_lock(&mut self, lock: Arc<dyn LeaderLock>) {
+        self.leader_lock = Some(lock);
+    }
+
+    /// Spools `payload` without sending it, returning the ID of its entry, for
+    /// [`Spool::status`]. It is sent by the next call to `send` or
+    /// [`SpoolingClient::send_pending`].
+    ///
+    /// Returns `None` without spooling anything if a message with the same control ID was
+    /// delivered recently.
+    pub fn enqueue(&mut self, payload: &[u8]) -> io::Result<Option<u64>> {
+        self.enqueue_with_metadata(payload, &Metadata::new())
+    }
+
+    /// Same as [`SpoolingClient::enqueue`], spooling `payload` with `metadata`.
+    pub fn enqueue_with_metadata(&mut self, payload: &[u8], metadata: &Metadata) -> io::Result<Option<u64>> {
+        if !self.lead()? {
+            return Err(standby());
+        }
+        if self.spool.is_delivered(payload) {
+            return Ok(None);
+        }
+        self.spool.push_with_metadata(payload, metadata).map(Some)
+    }
+
+    /// Spools `payload`, then sends the pending messages up to and including it.
+    ///
+    /// If an error is returned, the message stays in the spool and is sent again by the next call
+    /// to `send` or [`SpoolingClient::send_pending`].
+    ///
+    /// Returns [`Ack::None`] without sending anything if a message with the same control ID was
+    /// delivered recently.
+    pub fn send(&mut self, payload: &[u8]) -> Result<Ack, MllpError> {
+        self.send_with_metadata(payload, &Metadata::new())
+    }
+
+    /// Same as [`SpoolingClient::send`], spooling `payload` with `metadata`.
+    pub fn send_with_metadata(&mut self, payload: &[u8], metadata: &Metadata) -> Result<Ack, MllpError> {
+        let Some(id) = self.enqueue_with_metadata(payload, metadata)? else {
+            return Ok(Ack::None);
+        };
+
+        for pending in self.spool.pending()?.into_iter().filter(|pending| *pending < id) {
+            if !self.lead()? {
+                return Err(standby().into());
+            }
+            match self.deliver(pending) {
+                Ok(_) | Err((_, true)) => continue,
+                Err((e, false)) => return Err(e),
+            }
+        }
+
+        if !self.lead()? {
+            return Err(standby().into());
+        }
+        self.deliver(id).map_err(|(e, _)| e)
+    }
+
+    /// Sends all the pending messages, oldest first, and returns how many were delivered or
+    /// dead-lettered, stopping if the leader lock is lost.
+    pub fn send_pending(&mut self) -> Result<usize, MllpError> {
+        let pending = self.spool.pending()?;
+        for (sent, id) in pending.iter().enumerate() {
+            if !self.lead()? {
+                return Ok(sent);
+            }
+            match self.deliver(*id) {
+                Ok(_) | Err((_, true)) => continue,
+                Err((e, false)) => return Err(e),
+            }
+        }
+
+        Ok(pending.len())
+    }
+
+    /// Whether this instance may use the spool: always without a leader lock, otherwise while
+    /// holding it. The spool is opened again on taking over, for the entries left by the
+    /// previous leader.
+    fn lead(&mut self) -> io::Result<bool> {
+        #[cfg(feature = "unstable")]
+        if let Some(lock) = &self.leader_lock {
+            if !lock.try_acquire()? {
+                self.leading = false;
+                return Ok(false);
+            }
+            if !self.leading {
+                self.spool = Spool::open(self.spool.dir())?;
+                self.leading = true;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Sends entry `id`, telling on failure whether it was dead-lettered.
+    fn deliver(&mut self, id: u64) -> Result<Ack, (MllpError, bool)> {
+        let payload = self.spool.read(id).map_err(|e| (e.into(), false))?;
+        let metadata = self.spool.metadata(id).map_err(|e| (e.into(), false))?;
+        self.spool.mark_in_flight(id).map_err(|e| (e.into(), false))?;
+        let result = self.client.send_or_dead_letter(&payload, &metadata);
+        match &result {
+            Ok(ack) => self.spool.acknowledge(id, ack),
+            Err((_, true)) => self.spool.discard(id),
+            Err((_, false)) => Ok(()),
+        }
+        .map_err(|e| (e.into(), false))?;
+
+        if let (Ok(ack), Some(callback)) = (&result, self.on_delivery.as_mut()) {
+            callback(&Delivery { id, payload: &payload, metadata: &metadata, ack });
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use crate::client::{Ack, MllpClient, MllpClientConfig};
+    use crate::dead_letter::{DeadLetter, DeadLetterSink, DirectoryDeadLetterSink};
+    use crate::spool::{DeliveryStatus, Metadata, Spool, SpoolingClient};
+    use crate::{control_id, MllpCodec, MllpDecoder};
+
+    fn spool_dir(name: &str) -> std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("mllp-rs-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn it_keeps_entries_until_completed() {
+        let dir = spool_dir("entries");
+        let mut spool = Spool::open(&dir).unwrap();
+        let first = spool.push(b"MSH|1").unwrap();
+        let second = spool.push(b"MSH|2").unwrap();
+        spool.complete(first).unwrap();
+
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.pending().unwrap(), vec![second]);
+        assert_eq!(spool.read(second).unwrap(), b"MSH|2");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_resends_pending_messages_in_order() {
+        let dir = spool_dir("resend");
+        // messages left over by a crashed process
+        let mut spool = Spool::open(&dir).unwrap();
+        spool.push(b"MSH|1").unwrap();
+        spool.push(b"MSH|2").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                received.push(decoder.read_frame(&mut stream).unwrap());
+                stream.write_all(&MllpCodec::ack()).unwrap();
+            }
+            received
+        });
+        let config = MllpClientConfig {
+            ack_timeout: Some(Duration::from_secs(1)),
+            ..MllpClientConfig::default()
+        };
+        let client = MllpClient::connect_with_config(addr, config).unwrap();
+        let mut client = SpoolingClient::new(client, &dir).unwrap();
+
+        assert_eq!(client.send(b"MSH|3").unwrap(), Ack::Commit);
+        assert!(client.spool().pending().unwrap().is_empty());
+        assert_eq!(handler.join().unwrap(), vec![b"MSH|1".to_vec(), b"MSH|2".to_vec(), b"MSH|3".to_vec()]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_skips_dead_lettered_messages() {
+        let dir = spool_dir("dead-letter");
+        let mut spool = Spool::open(&dir).unwrap();
+        spool.push(b"MSH|poison").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::nak()).unwrap();
+            decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::ack()).unwrap();
+        });
+        let dead_letters = Arc::new(DirectoryDeadLetterSink::new(dir.join("dead")).unwrap());
+        let config = MllpClientConfig {
+            ack_timeout: Some(Duration::from_secs(1)),
+            max_retries: 0,
+            dead_letter: Some(dead_letters),
+            ..MllpClientConfig::default()
+        };
+        let client = MllpClient::connect_with_config(addr, config).unwrap();
+        let mut client = SpoolingClient::new(client, &dir).unwrap();
+
+        assert_eq!(client.send(b"MSH|next").unwrap(), Ack::Commit);
+        assert!(client.spool().pending().unwrap().is_empty());
+        assert_eq!(fs::read_dir(dir.join("dead")).unwrap().count(), 2);
+        handler.join().unwrap();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    /// Receiver acknowledging `count` messages on one connection, returning their control IDs.
+    fn receiver(count: usize) -> (std::net::SocketAddr, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            (0..count)
+                .map(|_| {
+                    let frame = decoder.read_frame(&mut stream).unwrap();
+                    stream.write_all(&MllpCodec::ack()).unwrap();
+                    control_id(&frame).unwrap()
+                })
+                .collect()
+        });
+        (addr, handler)
+    }
+
+    fn message(control_id: &str) -> Vec<u8> {
+        format!("MSH|^~\\&|LAB||EHR||20240101||ORU^R01|{}|P|2.5\rPID|1", control_id).into_bytes()
+    }
+
+    #[test]
+    #[cfg(feature = "unstable")]
+    fn it_drains_a_shared_spool_only_while_holding_the_leader_lock() {
+        use crate::leader::{FileLock, LeaderLock};
+
+        let dir = spool_dir("leader");
+        let path = env::temp_dir().join(format!("mllp-rs-spool-leader-{}.lock", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let receiving = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let received = receiving.clone();
+                thread::spawn(move || {
+                    let mut decoder = MllpDecoder::new();
+                    while let Ok(frame) = decoder.read_frame(&mut stream) {
+                        received.lock().unwrap().push(control_id(&frame).unwrap());
+                        stream.write_all(&MllpCodec::ack()).unwrap();
+                    }
+                });
+            }
+        });
+
+        let active_lock = Arc::new(FileLock::new(&path));
+        let mut active = SpoolingClient::new(MllpClient::connect(addr).unwrap(), &dir).unwrap();
+        active.leader_lock(active_lock.clone());
+        let mut standby = SpoolingClient::new(MllpClient::connect(addr).unwrap(), &dir).unwrap();
+        standby.leader_lock(Arc::new(FileLock::new(&path)));
+
+        assert_eq!(active.send(&message("MSG1")).unwrap(), Ack::Commit);
+        active.enqueue(&message("MSG2")).unwrap();
+        let refused = standby.send(&message("MSG3")).unwrap_err();
+        assert!(matches!(refused, crate::MllpError::Io(e) if e.kind() == io::ErrorKind::ResourceBusy));
+        assert_eq!(standby.send_pending().unwrap(), 0);
+
+        // the active instance stops, the standby one takes over with what it left
+        drop(active);
+        active_lock.release().unwrap();
+        assert_eq!(standby.send_pending().unwrap(), 1);
+        assert_eq!(standby.send(&message("MSG3")).unwrap(), Ack::Commit);
+        assert!(standby.spool().pending().unwrap().is_empty());
+        assert_eq!(*received.lock().unwrap(), vec!["MSG1", "MSG2", "MSG3"]);
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn it_reads_control_ids() {
+        assert_eq!(control_id(&message("MSG1")), Some("MSG1".to_owned()));
+        assert_eq!(control_id(b"MSH|^~\\&|LAB"), None);
+        assert_eq!(control_id(b"PID|1"), None);
+    }
+
+    #[test]
+    fn it_resends_in_flight_message_first_after_crash_before_ack() {
+        let dir = spool_dir("crash-before-ack");
+        let mut spool = Spool::open(&dir).unwrap();
+        let first = spool.push(&message("MSG1")).unwrap();
+        spool.push(&message("MSG2")).unwrap();
+        // sent, then crashed while waiting for the ACK
+        spool.mark_in_flight(first).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.in_flight().unwrap(), vec![first]);
+        drop(spool);
+
+        let (addr, handler) = receiver(2);
+        let client = MllpClient::connect(addr).unwrap();
+        let mut client = SpoolingClient::new(client, &dir).unwrap();
+        assert_eq!(client.send_pending().unwrap(), 2);
+        assert!(client.spool().in_flight().unwrap().is_empty());
+        assert_eq!(handler.join().unwrap(), vec!["MSG1", "MSG2"]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_does_not_resend_message_logged_before_crash() {
+        let dir = spool_dir("crash-after-ack");
+        let mut spool = Spool::open(&dir).unwrap();
+        let first = spool.push(&message("MSG1")).unwrap();
+        let second = spool.push(&message("MSG2")).unwrap();
+        // ACK received and logged, then crashed before the entry was removed
+        spool.mark_in_flight(first).unwrap();
+        spool.log_delivered(first, control_id(&message("MSG1")), None).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.pending().unwrap(), vec![second]);
+        assert!(spool.in_flight().unwrap().is_empty());
+        assert!(spool.is_delivered(&message("MSG1")));
+        drop(spool);
+
+        let (addr, handler) = receiver(2);
+        let client = MllpClient::connect(addr).unwrap();
+        let mut client = SpoolingClient::new(client, &dir).unwrap();
+        // replayed by the application, which crashed as well
+        assert_eq!(client.send(&message("MSG1")).unwrap(), Ack::None);
+        assert_eq!(client.send(&message("MSG3")).unwrap(), Ack::Commit);
+        assert_eq!(handler.join().unwrap(), vec!["MSG2", "MSG3"]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_keeps_ordering_and_window_across_restarts() {
+        let dir = spool_dir("restart");
+        let mut spool = Spool::open(&dir).unwrap();
+        let first = spool.push(&message("MSG1")).unwrap();
+        spool.complete(first).unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir).unwrap();
+        assert!(spool.is_delivered(&message("MSG1")));
+        assert!(!spool.is_delivered(&message("MSG2")));
+        // a spool emptied before the restart does not number entries from 1 again
+        assert!(spool.push(&message("MSG2")).unwrap() > first);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_tracks_delivery_status_across_restarts() {
+        let dir = spool_dir("status");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::encode(b"MSH|^~\\&|EHR\rMSA|AE|MSG1")).unwrap();
+            decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::ack()).unwrap();
+        });
+        let client = MllpClient::connect(addr).unwrap();
+        let mut client = SpoolingClient::new(client, &dir).unwrap();
+        let first = client.enqueue(&message("MSG1")).unwrap().unwrap();
+        let second = client.enqueue(&message("MSG2")).unwrap().unwrap();
+        assert_eq!(client.spool().status(first).unwrap(), DeliveryStatus::Pending);
+        assert_eq!(client.send_pending().unwrap(), 2);
+        handler.join().unwrap();
+        assert_eq!(client.enqueue(&message("MSG1")).unwrap(), None);
+        drop(client);
+
+        let mut spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.status(first).unwrap(), DeliveryStatus::Acknowledged(Some("AE".to_owned())));
+        assert_eq!(spool.status(second).unwrap(), DeliveryStatus::Acknowledged(Some("CA".to_owned())));
+        let third = spool.push(&message("MSG3")).unwrap();
+        spool.mark_in_flight(third).unwrap();
+        assert_eq!(spool.status(third).unwrap(), DeliveryStatus::InFlight);
+        spool.complete(third).unwrap();
+        assert_eq!(spool.status(third).unwrap(), DeliveryStatus::Acknowledged(None));
+        assert_eq!(spool.status(third + 1).unwrap(), DeliveryStatus::Unknown);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_keeps_metadata_across_restarts() {
+        let dir = spool_dir("metadata");
+        let metadata = Metadata::from([
+            ("tenant".to_owned(), "north".to_owned()),
+            ("note".to_owned(), "tab\there\nnew line \\ backslash".to_owned()),
+        ]);
+        let mut spool = Spool::open(&dir).unwrap();
+        let id = spool.push_with_metadata(b"MSH|1", &metadata).unwrap();
+        let bare = spool.push(b"MSH|2").unwrap();
+        drop(spool);
+
+        let mut spool = Spool::open(&dir).unwrap();
+        assert_eq!(spool.metadata(id).unwrap(), metadata);
+        assert!(spool.metadata(bare).unwrap().is_empty());
+        spool.complete(id).unwrap();
+        assert!(spool.metadata(id).unwrap().is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_hands_metadata_to_deliveries_and_dead_letters() {
+        #[derive(Default)]
+        struct Letters(Mutex<Vec<DeadLetter>>);
+
+        impl DeadLetterSink for Letters {
+            fn dead_letter(&self, letter: &DeadLetter) -> io::Result<()> {
+                self.0.lock().unwrap().push(letter.clone());
+                Ok(())
+            }
+        }
+
+        let dir = spool_dir("metadata-delivery");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut decoder = MllpDecoder::new();
+            decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::nak()).unwrap();
+            decoder.read_frame(&mut stream).unwrap();
+            stream.write_all(&MllpCodec::ack()).unwrap();
+        });
+        let letters = Arc::new(Letters::default());
+        let config = MllpClientConfig {
+            ack_timeout: Some(Duration::from_secs(1)),
+            max_retries: 0,
+            dead_letter: Some(letters.clone()),
+            ..MllpClientConfig::default()
+        };
+        let client = MllpClient::connect_with_config(addr, config).unwrap();
+        let mut client = SpoolingClient::new(client, &dir).unwrap();
+        let deliveries = Arc::new(Mutex::new(Vec::new()));
+        let delivered = deliveries.clone();
+        client.on_delivery(move |delivery| {
+            delivered.lock().unwrap().push((delivery.payload.to_vec(), delivery.metadata.clone(), delivery.ack.clone()));
+        });
+
+        let order = |reference: &str| Metadata::from([("order".to_owned(), reference.to_owned())]);
+        assert!(client.send_with_metadata(b"MSH|refused", &order("A-1")).is_err());
+        assert_eq!(client.send_with_metadata(b"MSH|accepted", &order("A-2")).unwrap(), Ack::Commit);
+
+        let letters = letters.0.lock().unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].metadata, order("A-1"));
+        assert_eq!(*deliveries.lock().unwrap(), vec![(b"MSH|accepted".to_vec(), order("A-2"), Ack::Commit)]);
+        handler.join().unwrap();
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn it_keeps_message_when_delivery_fails() {
+        let dir = spool_dir("failure");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = MllpClientConfig {
+            ack_timeout: Some(Duration::from_millis(50)),
+            max_retries: 0,
+            ..MllpClientConfig::default()
+        };
+        let client = MllpClient::connect_with_config(addr, config).unwrap();
+        let mut client = SpoolingClient::new(client, &dir).unwrap();
+
+        assert!(client.send(b"MSH|1").is_err());
+        assert_eq!(client.spool().pending().unwrap().len(), 1);
+        drop(listener);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
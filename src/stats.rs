@@ -0,0 +1,150 @@
+//! Statistics of the messages exchanged, for dashboards.
+//!
+//! [`MllpClient::stats`](crate::client::MllpClient::stats) returns a [`Stats`] snapshot for each
+//! destination the client sent to, and [`MllpServer::stats`](crate::server::MllpServer::stats)
+//! one for the listener as a whole and one for each open connection. Unlike
+//! [metrics](crate::metrics), they need no setting up, and are read when wanted rather than
+//! pushed.
+//! ```no_run
+//! use mllp_rs::server::{MllpServer, MllpServerConfig};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let server = MllpServer::bind("0.0.0.0:2575", MllpServerConfig::default())?;
+//! for connection in server.stats().connections {
+//!     let p99 = connection.stats.ack_latency.map(|latency| latency.p99);
+//!     println!("{} {} messages, p99 {:?}", connection.peer_addr, connection.stats.messages, p99);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Latencies kept for the percentiles, the latest ones.
+const LATENCY_WINDOW: usize = 1024;
+
+/// Snapshot of the messages exchanged with a peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Messages sent by the client, or received by the server, heartbeats left out.
+    pub messages: u64,
+    /// Bytes of the payloads of the messages.
+    pub bytes: u64,
+    /// Messages answered with a NAK, or failing: not acknowledged in time, or the connection
+    /// failed.
+    pub errors: u64,
+    /// Percentiles of the time to acknowledge the latest messages, `None` until one is. On the
+    /// client, from writing the message to receiving its acknowledgement, retransmissions
+    /// included; on the server, from receiving the message to writing its acknowledgement.
+    pub ack_latency: Option<Percentiles>,
+    /// When a message was last sent or received.
+    pub last_activity: Option<SystemTime>,
+}
+
+/// Percentiles of a distribution of durations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    /// Percentiles of `values`, by the nearest rank. `None` if there are none.
+    fn of(mut values: Vec<Duration>) -> Option<Self> {
+        values.sort_unstable();
+        let max = *values.last()?;
+        let rank = |percentile: usize| values[(values.len() * percentile).div_ceil(100) - 1];
+
+        Some(Percentiles { p50: rank(50), p90: rank(90), p99: rank(99), max })
+    }
+}
+
+/// Statistics of a destination of an [`MllpClient`](crate::client::MllpClient).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DestinationStats {
+    pub peer_addr: SocketAddr,
+    pub stats: Stats,
+}
+
+/// Statistics of an open connection of an [`MllpServer`](crate::server::MllpServer).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Identifier of the connection, as in [`Session::id`](crate::server::Session::id).
+    pub id: u64,
+    pub peer_addr: SocketAddr,
+    pub stats: Stats,
+}
+
+/// Statistics of an [`MllpServer`](crate::server::MllpServer).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerStats {
+    /// Messages of every connection since the server was bound, closed ones included.
+    pub total: Stats,
+    /// Open connections, in the order they were accepted.
+    pub connections: Vec<ConnectionStats>,
+}
+
+/// Statistics being recorded.
+#[derive(Debug, Default)]
+pub(crate) struct StatsRecorder {
+    recorded: Mutex<Recorded>,
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    stats: Stats,
+    latencies: VecDeque<Duration>,
+}
+
+impl StatsRecorder {
+    /// Records a message of `bytes`, acknowledged after `latency` unless it `failed`.
+    pub(crate) fn record(&self, bytes: usize, latency: Option<Duration>, failed: bool) {
+        let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        recorded.stats.messages += 1;
+        recorded.stats.bytes += bytes as u64;
+        recorded.stats.errors += failed as u64;
+        recorded.stats.last_activity = Some(SystemTime::now());
+        if let Some(latency) = latency.filter(|_| !failed) {
+            if recorded.latencies.len() == LATENCY_WINDOW {
+                recorded.latencies.pop_front();
+            }
+            recorded.latencies.push_back(latency);
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        let recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        Stats {
+            ack_latency: Percentiles::of(recorded.latencies.iter().copied().collect()),
+            ..recorded.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::stats::{Percentiles, StatsRecorder};
+
+    #[test]
+    fn it_records_stats() {
+        let recorder = StatsRecorder::default();
+        assert_eq!(recorder.snapshot().last_activity, None);
+        for millis in 1..=100 {
+            recorder.record(10, Some(Duration::from_millis(millis)), false);
+        }
+        recorder.record(10, Some(Duration::from_secs(60)), true);
+
+        let stats = recorder.snapshot();
+        assert_eq!((stats.messages, stats.bytes, stats.errors), (101, 1010, 1));
+        assert!(stats.last_activity.is_some());
+        let millis = Duration::from_millis;
+        assert_eq!(stats.ack_latency, Some(Percentiles { p50: millis(50), p90: millis(90), p99: millis(99), max: millis(100) }));
+        assert_eq!(Percentiles::of(Vec::new()), None);
+    }
+}
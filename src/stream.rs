@@ -0,0 +1,623 @@
+//! Asynchronous MLLP framing over any transport, with the `futures` feature.
+//!
+//! [`MllpStream`] wraps anything implementing [`AsyncRead`] and [`AsyncWrite`], a TCP or TLS
+//! stream of any runtime, a tunnel or an in-memory duplex in tests, and turns it into a
+//! [`Stream`] of received frames and a [`Sink`] of frames to send. It composes with the usual
+//! combinators, e.g. `StreamExt::split` to read and write from different tasks.
+//!
+//! Nothing here depends on a runtime: the sockets of smol and async-std implement these traits
+//! as they are, and those of tokio through the `compat` adapters of `tokio-util`. Timers are not
+//! portable either, so an [`MllpStream::request`] is bounded with the timeout of the runtime used.
+//! ```no_run
+//! use futures::{SinkExt, StreamExt};
+//! use mllp_rs::stream::MllpStream;
+//!
+//! # async fn run(socket: impl futures::AsyncRead + futures::AsyncWrite + Unpin) -> Result<(), mllp_rs::MllpError> {
+//! let mut stream = MllpStream::new(socket);
+//! stream.send(b"MSH|^~\\&|".to_vec()).await?;
+//! if let Some(ack) = stream.next().await {
+//!     println!("{}", String::from_utf8_lossy(&ack?));
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`MllpQueue`] shares a stream between tasks: each message waits in a bounded queue for the
+//! acknowledgement of the previous ones, and [`MllpQueue::send`] waits for room in the queue
+//! when the receiver is slow to acknowledge, rather than letting messages pile up in memory.
+//! [`MllpQueue::pipelined`] writes messages ahead of the acknowledgements, and matches these to
+//! their messages. Messages sent with [`MllpQueue::send_with_priority`] go ahead of those of a
+//! lower [`Priority`] waiting in the queue.
+
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use futures_channel::{mpsc, oneshot};
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_sink::Sink;
+use crate::client::Ack;
+use crate::{MllpCodec, MllpDecoder, MllpError, ACK, NAK};
+
+/// Payload of an MLLP frame, without the `<SB>` and `<EB><CR>` around it.
+pub type Frame = Vec<u8>;
+
+/// Encoded bytes buffered by the sink before it waits for the transport to take them.
+const WRITE_HIGH_WATER: usize = 64 * 1024;
+
+/// MLLP frames read from and written to an asynchronous transport.
+///
+/// Received bytes that are not a valid frame are reported as a [`MllpError::Syntax`] item, and
+/// the stream carries on with the next frame. An end of stream in the middle of a frame is
+/// reported as a [`MllpError::ConnectionClosed`] error before the stream ends.
+///
+/// Reading is cancellation safe: the bytes received are kept in the stream, not in the future
+/// reading them, so a [`read_frame`](MllpStream::read_frame) or `next()` dropped half way
+/// through a frame, e.g. by the losing branch of a `select!`, loses nothing, and the next read
+/// returns the whole frame.
+#[derive(Debug)]
+pub struct MllpStream<T> {
+    inner: T,
+    decoder: MllpDecoder,
+    eof: bool,
+    /// Encoded frames not yet written to the transport.
+    pending: Vec<u8>,
+    /// Bytes of `pending` already written.
+    written: usize,
+}
+
+impl<T> MllpStream<T> {
+    pub fn new(inner: T) -> Self {
+        MllpStream {
+            inner,
+            decoder: MllpDecoder::new(),
+            eof: false,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Reading or writing through the transport directly mixes up the framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the transport. Bytes received but not yet returned as a frame, and frames not
+    /// yet flushed, are lost.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> MllpStream<T> {
+    /// Waits for the next frame. The end of the stream is a [`MllpError::ConnectionClosed`]
+    /// error with no partial bytes, as for
+    /// [`MllpReader::read_frame`](crate::client::MllpReader::read_frame).
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancellation safe: dropping the future before it completes loses no byte
+    /// received, the frame being read being returned whole by the next read.
+    pub async fn read_frame(&mut self) -> Result<Frame, MllpError> {
+        match poll_fn(|cx| self.poll_read_frame(cx)).await {
+            Some(frame) => frame,
+            None => Err(MllpError::ConnectionClosed { partial_bytes: 0 }),
+        }
+    }
+
+    /// Polls for the next frame, `None` once the stream ended: the [`Stream`] implementation,
+    /// without pinning.
+    ///
+    /// Until a whole frame is received, the bytes read are kept in the stream and the call
+    /// returns [`Poll::Pending`], so that nothing is lost if it is not polled again.
+    pub fn poll_read_frame(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Frame, MllpError>>> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            if let Some(frame) = self.decoder.next_frame() {
+                return Poll::Ready(Some(frame.map_err(MllpError::from)));
+            }
+            if self.eof {
+                return Poll::Ready(None);
+            }
+
+            match ready!(Pin::new(&mut self.inner).poll_read(cx, &mut chunk)) {
+                Ok(0) => {
+                    self.eof = true;
+                    if self.decoder.buffered() > 0 {
+                        let partial_bytes = self.decoder.clear();
+                        return Poll::Ready(Some(Err(MllpError::ConnectionClosed { partial_bytes })));
+                    }
+                }
+                Ok(n) => self.decoder.extend(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Poll::Ready(Some(Err(e.into()))),
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> MllpStream<T> {
+    /// Sends `payload` and waits for the frame answering it, like
+    /// [`MllpClient::send`](crate::client::MllpClient::send) with no acknowledgement mode: a
+    /// commit ACK gives [`Ack::Commit`], a commit NAK a [`MllpError::Nak`] error, and any other
+    /// frame [`Ack::Application`]. Retries are left to the caller.
+    ///
+    /// # Cancel safety
+    ///
+    /// Cancelling a request does not corrupt the stream, but once its frame is written, the
+    /// answer to it is left to be read, and is returned by the next read or request.
+    pub async fn request(&mut self, payload: &[u8]) -> Result<Ack, MllpError> {
+        self.write_frame(payload.to_vec()).await?;
+        outcome(self.read_frame().await?)
+    }
+
+    /// Sends `frame` and flushes it.
+    async fn write_frame(&mut self, frame: Frame) -> Result<(), MllpError> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_ready(cx)).await?;
+        Pin::new(&mut *self).start_send(frame)?;
+        poll_fn(|cx| Pin::new(&mut *self).poll_flush(cx)).await
+    }
+}
+
+/// Outcome of a message answered with `frame`.
+fn outcome(frame: Frame) -> Result<Ack, MllpError> {
+    match frame.as_slice() {
+        [ACK] => Ok(Ack::Commit),
+        [NAK] => Err(MllpError::Nak),
+        _ => Ok(Ack::Application(frame)),
+    }
+}
+
+impl<T: AsyncWrite + Unpin> MllpStream<T> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), MllpError>> {
+        while self.written < self.pending.len() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.written..])) {
+                Ok(0) => return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero).into())),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Poll::Ready(Err(e.into())),
+            }
+        }
+        self.pending.clear();
+        self.written = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> Stream for MllpStream<T> {
+    type Item = Result<Frame, MllpError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_read_frame(cx)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Sink<Frame> for MllpStream<T> {
+    type Error = MllpError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if this.pending.len() >= WRITE_HIGH_WATER {
+            ready!(this.poll_write_pending(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        MllpCodec::encode_into(&frame, &mut this.pending);
+
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+
+        Poll::Ready(ready!(Pin::new(&mut this.inner).poll_flush(cx)).map_err(MllpError::from))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_pending(cx))?;
+
+        Poll::Ready(ready!(Pin::new(&mut this.inner).poll_close(cx)).map_err(MllpError::from))
+    }
+}
+
+/// Message waiting in an [`MllpQueue`], with where to send its outcome.
+type Queued = (Vec<u8>, oneshot::Sender<Result<Ack, MllpError>>);
+
+/// Lane of an [`MllpQueue`] a message waits in: the messages of a higher priority are written
+/// first, those of a priority in the order they were sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Bulk loads, such as billing messages or backfills.
+    Low,
+    #[default]
+    Normal,
+    /// Urgent messages, such as STAT results.
+    High,
+}
+
+impl Priority {
+    /// Lanes, highest priority first.
+    const LANES: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    fn lane(self) -> usize {
+        match self {
+            Priority::High => 0,
+            Priority::Normal => 1,
+            Priority::Low => 2,
+        }
+    }
+}
+
+/// Bounded queue of messages sent one after the other over an [`MllpStream`].
+///
+/// The messages are sent by the future returned along with the queue, which is to be spawned on
+/// the runtime and ends once every handle of the queue is dropped. Handles are cloned to send
+/// from several tasks, each [`MllpQueue::send`] resolving to the acknowledgement of its own
+/// message.
+/// ```no_run
+/// use mllp_rs::stream::{MllpQueue, MllpStream};
+///
+/// # async fn run(socket: impl futures::AsyncRead + futures::AsyncWrite + Unpin) -> Result<(), mllp_rs::MllpError> {
+/// let (mut queue, delivery) = MllpQueue::new(MllpStream::new(socket), 100);
+/// // spawned on the runtime, e.g. with tokio::spawn
+/// # let _ = delivery;
+/// let ack = queue.send(b"MSH|^~\\&|").await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MllpQueue {
+    /// Sender of each lane, highest priority first.
+    senders: [mpsc::Sender<Queued>; 3],
+    depth: Arc<AtomicUsize>,
+}
+
+impl MllpQueue {
+    /// Queue of at most `capacity` messages of each [`Priority`], plus one for each clone of the
+    /// handle, sent over `stream` by the returned future.
+    pub fn new<T>(stream: MllpStream<T>, capacity: usize) -> (MllpQueue, impl Future<Output = ()>)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        Self::pipelined(stream, capacity, 1)
+    }
+
+    /// Queue writing up to `max_in_flight` messages ahead of their acknowledgements.
+    ///
+    /// Application acknowledgements go to the message whose MSH-10 control ID is in their
+    /// MSA-2, and commit acknowledgements, which carry none, to the oldest message waiting, as
+    /// do application acknowledgements matching no message. A connection failing fails the
+    /// oldest message with the error, and the others with an [`io::ErrorKind::BrokenPipe`]
+    /// error.
+    pub fn pipelined<T>(stream: MllpStream<T>, capacity: usize, max_in_flight: usize) -> (MllpQueue, impl Future<Output = ()>)
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let lanes = Priority::LANES.map(|_| mpsc::channel(capacity.saturating_sub(1)));
+        let depth = Arc::new(AtomicUsize::new(0));
+        let queue = MllpQueue {
+            senders: lanes.each_ref().map(|(sender, _)| sender.clone()),
+            depth: depth.clone(),
+        };
+
+        (queue, deliver(stream, lanes.map(|(_, receiver)| receiver), depth, max_in_flight.max(1)))
+    }
+
+    /// Queues `payload` and waits for its acknowledgement, as [`MllpStream::request`] does.
+    /// Waits first for room in the queue while it is full.
+    ///
+    /// There is no timeout: bound the future with the timeout of the runtime. A message given
+    /// up on this way is still sent.
+    pub async fn send(&mut self, payload: &[u8]) -> Result<Ack, MllpError> {
+        self.send_with_priority(payload, Priority::Normal).await
+    }
+
+    /// Same as [`MllpQueue::send`], `payload` going ahead of the messages of a lower `priority`
+    /// not written yet. Messages already written are not overtaken.
+    pub async fn send_with_priority(&mut self, payload: &[u8], priority: Priority) -> Result<Ack, MllpError> {
+        let closed = || MllpError::from(io::Error::new(io::ErrorKind::BrokenPipe, "the delivery of the queue ended"));
+        let (reply, outcome) = oneshot::channel();
+        let sender = &mut self.senders[priority.lane()];
+
+        poll_fn(|cx| sender.poll_ready(cx)).await.map_err(|_| closed())?;
+        sender.start_send((payload.to_vec(), reply)).map_err(|_| closed())?;
+        self.depth.fetch_add(1, Ordering::Relaxed);
+
+        outcome.await.map_err(|_| closed())?
+    }
+
+    /// Number of messages queued or waiting for their acknowledgement.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+/// Message written and waiting for its acknowledgement: its control ID, and where to send its
+/// outcome.
+type InFlight = (Option<String>, oneshot::Sender<Result<Ack, MllpError>>);
+
+/// What the delivery of a queue wakes up for.
+enum Step {
+    Queued(Option<Queued>),
+    Received(Option<Result<Frame, MllpError>>),
+}
+
+/// Sends the messages of the `lanes` of a queue over `stream`, the highest priority first, up to
+/// `max_in_flight` ahead of their acknowledgements.
+async fn deliver<T>(mut stream: MllpStream<T>, mut lanes: [mpsc::Receiver<Queued>; 3], depth: Arc<AtomicUsize>, max_in_flight: usize)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut in_flight: VecDeque<InFlight> = VecDeque::new();
+    // the lanes close together, once every handle is dropped
+    let mut open = true;
+    let reply = |(_, reply): InFlight, outcome| {
+        depth.fetch_sub(1, Ordering::Relaxed);
+        // the sender may have stopped waiting
+        let _ = reply.send(outcome);
+    };
+
+    while open || !in_flight.is_empty() {
+        let step = poll_fn(|cx| {
+            if open && in_flight.len() < max_in_flight {
+                let mut closed = 0;
+                for lane in &mut lanes {
+                    match Pin::new(lane).poll_next(cx) {
+                        Poll::Ready(Some(queued)) => return Poll::Ready(Step::Queued(Some(queued))),
+                        Poll::Ready(None) => closed += 1,
+                        Poll::Pending => {}
+                    }
+                }
+                if closed == lanes.len() {
+                    return Poll::Ready(Step::Queued(None));
+                }
+            }
+            if !in_flight.is_empty() {
+                if let Poll::Ready(frame) = stream.poll_read_frame(cx) {
+                    return Poll::Ready(Step::Received(frame));
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+
+        match step {
+            Step::Queued(None) => open = false,
+            Step::Queued(Some((payload, sender))) => {
+                let control_id = crate::control_id(&payload);
+                match stream.write_frame(payload).await {
+                    Ok(()) => in_flight.push_back((control_id, sender)),
+                    Err(e) => reply((control_id, sender), Err(e)),
+                }
+            }
+            Step::Received(Some(Ok(frame))) => {
+                let acknowledged = match frame.as_slice() {
+                    [ACK] | [NAK] => None,
+                    frame => crate::acknowledged_id(frame),
+                };
+                let index = in_flight.iter().position(|(control_id, _)| acknowledged.is_some() && *control_id == acknowledged).unwrap_or(0);
+                if let Some(message) = in_flight.remove(index) {
+                    reply(message, outcome(frame));
+                }
+            }
+            Step::Received(Some(Err(e @ MllpError::Syntax(_)))) => reply(in_flight.pop_front().expect("a message in flight"), Err(e)),
+            Step::Received(failure) => {
+                let e = match failure {
+                    Some(Err(e)) => e,
+                    _ => MllpError::ConnectionClosed { partial_bytes: 0 },
+                };
+                reply(in_flight.pop_front().expect("a message in flight"), Err(e));
+                depth.fetch_sub(in_flight.len(), Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use futures::executor::block_on;
+    use futures::future::join;
+    use futures::{AsyncRead, AsyncWrite, FutureExt, SinkExt, StreamExt};
+    use crate::client::Ack;
+    use crate::{MllpCodec, MllpError};
+    use super::{MllpQueue, MllpStream, Priority};
+
+    /// Transport handing out its input a few bytes at a time, pending every other read.
+    struct Duplex {
+        input: Vec<u8>,
+        output: Vec<u8>,
+        ready: bool,
+    }
+
+    impl Duplex {
+        fn new(input: Vec<u8>) -> Self {
+            Duplex { input, output: Vec::new(), ready: false }
+        }
+    }
+
+    impl AsyncRead for Duplex {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(self.input.len()).min(3);
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for Duplex {
+        fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let n = buf.len().min(5);
+            self.output.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn it_reads_frames_split_across_reads() {
+        let mut input = MllpCodec::encode(b"first");
+        input.extend(b"junk");
+        input.extend(MllpCodec::encode(b"second"));
+        let stream = MllpStream::new(Duplex::new(input));
+
+        let items: Vec<_> = block_on(stream.collect());
+        let (frames, errors): (Vec<_>, Vec<_>) = items.into_iter().partition(Result::is_ok);
+        let frames: Vec<_> = frames.into_iter().map(Result::unwrap).collect();
+        assert_eq!(frames, vec![b"first".to_vec(), b"second".to_vec()]);
+        assert!(!errors.is_empty());
+        assert!(errors.iter().all(|e| matches!(e, Err(MllpError::Syntax(_)))));
+    }
+
+    #[test]
+    fn it_reports_eof_in_the_middle_of_a_frame() {
+        let mut stream = MllpStream::new(Duplex::new(b"\x0bMSH".to_vec()));
+
+        assert!(matches!(block_on(stream.next()), Some(Err(MllpError::ConnectionClosed { partial_bytes: 4 }))));
+        assert!(block_on(stream.next()).is_none());
+    }
+
+    #[test]
+    fn it_keeps_partial_frames_across_cancelled_reads() {
+        let input = [MllpCodec::encode(b"MSH|^~\\&|LAB|||||ORU^R01|1"), MllpCodec::ack().to_vec()].concat();
+        let mut stream = MllpStream::new(Duplex::new(input));
+
+        // each read is dropped after a few bytes, as by a select! polling another branch
+        let mut cancelled = 0;
+        let frame = loop {
+            match stream.read_frame().now_or_never() {
+                Some(frame) => break frame,
+                None => cancelled += 1,
+            }
+        };
+        assert!(cancelled > 5);
+        assert_eq!(frame.unwrap(), b"MSH|^~\\&|LAB|||||ORU^R01|1");
+        assert_eq!(block_on(stream.read_frame()).unwrap(), [crate::ACK]);
+        assert!(matches!(block_on(stream.read_frame()), Err(MllpError::ConnectionClosed { partial_bytes: 0 })));
+    }
+
+    #[test]
+    fn it_writes_encoded_frames_on_flush() {
+        let mut stream = MllpStream::new(Duplex::new(Vec::new()));
+
+        block_on(stream.feed(b"first".to_vec())).unwrap();
+        block_on(stream.feed(b"second".to_vec())).unwrap();
+        assert!(stream.get_ref().output.is_empty());
+        block_on(stream.flush()).unwrap();
+
+        let mut expected = MllpCodec::encode(b"first");
+        expected.extend(MllpCodec::encode(b"second"));
+        assert_eq!(stream.into_inner().output, expected);
+    }
+
+    #[test]
+    fn it_requests_acknowledgements() {
+        let mut input = MllpCodec::ack().to_vec();
+        input.extend(MllpCodec::encode(b"MSA|AA"));
+        input.extend(MllpCodec::nak());
+        let mut stream = MllpStream::new(Duplex::new(input));
+
+        assert_eq!(block_on(stream.request(b"MSH|1")).unwrap(), Ack::Commit);
+        assert_eq!(block_on(stream.request(b"MSH|2")).unwrap(), Ack::Application(b"MSA|AA".to_vec()));
+        assert!(matches!(block_on(stream.request(b"MSH|3")), Err(MllpError::Nak)));
+        assert!(matches!(block_on(stream.request(b"MSH|4")), Err(MllpError::ConnectionClosed { partial_bytes: 0 })));
+
+        let written = [&b"MSH|1"[..], b"MSH|2", b"MSH|3", b"MSH|4"].map(MllpCodec::encode).concat();
+        assert_eq!(stream.into_inner().output, written);
+    }
+
+    #[test]
+    fn it_queues_messages_with_backpressure() {
+        let input = [MllpCodec::ack(), MllpCodec::ack(), MllpCodec::nak()].concat();
+        let (mut queue, delivery) = MllpQueue::new(MllpStream::new(Duplex::new(input)), 2);
+
+        // nothing is delivered yet: two messages fill the queue, the third one waits
+        assert!(queue.send(b"MSH|1").now_or_never().is_none());
+        assert!(queue.send(b"MSH|2").now_or_never().is_none());
+        assert_eq!(queue.depth(), 2);
+        assert!(queue.send(b"MSH|3").now_or_never().is_none());
+        assert_eq!(queue.depth(), 2);
+
+        // the delivery ends once the queue is dropped
+        let sending = async move {
+            let outcome = queue.send(b"MSH|3").await;
+            (outcome, queue.depth())
+        };
+        let ((), (outcome, depth)) = block_on(join(delivery, sending));
+        assert!(matches!(outcome, Err(MllpError::Nak)));
+        assert_eq!(depth, 0);
+    }
+
+    #[test]
+    fn it_sends_higher_priorities_first() {
+        let input = [&b"MSA|1"[..], b"MSA|2", b"MSA|3", b"MSA|4"].map(MllpCodec::encode).concat();
+        let (queue, delivery) = MllpQueue::new(MllpStream::new(Duplex::new(input)), 4);
+
+        // all queued before the delivery starts, each answered by the next acknowledgement read
+        let send = |mut queue: MllpQueue, payload: &'static [u8], priority| async move {
+            queue.send_with_priority(payload, priority).await
+        };
+        let mut sending = [
+            send(queue.clone(), b"MSH|bulk1", Priority::Low).boxed(),
+            send(queue.clone(), b"MSH|routine", Priority::Normal).boxed(),
+            send(queue.clone(), b"MSH|bulk2", Priority::Low).boxed(),
+            send(queue, b"MSH|stat", Priority::High).boxed(),
+        ];
+        for send in &mut sending {
+            assert!(send.now_or_never().is_none());
+        }
+        let (outcomes, ()) = block_on(join(futures::future::join_all(sending), delivery));
+        let acks: Vec<_> = outcomes.into_iter().map(|outcome| outcome.unwrap()).collect();
+        let expected = [&b"MSA|3"[..], b"MSA|2", b"MSA|4", b"MSA|1"].map(|ack| Ack::Application(ack.to_vec()));
+        assert_eq!(acks, expected);
+    }
+
+    #[test]
+    fn it_correlates_pipelined_acknowledgements() {
+        let ack = |control_id: &str| format!("MSH|^~\\&|EHR\rMSA|AA|{}", control_id).into_bytes();
+        let input = [MllpCodec::encode(&ack("MSG2")), MllpCodec::encode(&ack("MSG1")), MllpCodec::ack().to_vec()].concat();
+        let (queue, delivery) = MllpQueue::pipelined(MllpStream::new(Duplex::new(input)), 4, 3);
+
+        // each task sends with a handle of its own, dropped once answered
+        let send = |mut queue: MllpQueue, control_id: &'static str| async move {
+            queue.send(format!("MSH|^~\\&|LAB||||||ORU^R01|{}", control_id).as_bytes()).await
+        };
+        let sending = join(join(send(queue.clone(), "MSG1"), send(queue.clone(), "MSG2")), send(queue, "MSG3"));
+        let (((first, second), third), ()) = block_on(join(sending, delivery));
+        assert_eq!(first.unwrap(), Ack::Application(ack("MSG1")));
+        assert_eq!(second.unwrap(), Ack::Application(ack("MSG2")));
+        // a commit acknowledgement goes to the oldest message waiting
+        assert_eq!(third.unwrap(), Ack::Commit);
+    }
+}
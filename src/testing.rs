@@ -0,0 +1,126 @@
+//! Helpers to test clients and servers without opening sockets, or against a scripted receiver.
+//!
+//! The [in-memory transport](crate::memory) is re-exported here: a [`MemoryListener`] accepts the
+//! connections made with its [`MemoryConnector`], and serves an
+//! [`MllpServer`](crate::server::MllpServer) reached by an
+//! [`MllpClient`](crate::client::MllpClient), neither binding a port.
+//! ```
+//! use mllp_rs::client::{Ack, MllpClient, MllpClientConfig};
+//! use mllp_rs::handler::AckDecision;
+//! use mllp_rs::server::{MllpServer, MllpServerConfig};
+//! use mllp_rs::testing::MemoryListener;
+//!
+//! let listener = MemoryListener::new();
+//! let connector = listener.connector();
+//! let server = MllpServer::bind_memory(listener, MllpServerConfig::default()).unwrap();
+//! std::thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+//!
+//! let mut client = MllpClient::connect_memory(connector, MllpClientConfig::default()).unwrap();
+//! assert_eq!(client.send(b"MSH|^~\\&|").unwrap(), Ack::Commit);
+//! ```
+//!
+//! To test sending logic against a receiver, a [`MockMllpServer`] listens on an ephemeral port,
+//! answers with scripted acknowledgements and records the messages received.
+//! ```
+//! use mllp_rs::client::{Ack, MllpClient};
+//! use mllp_rs::handler::AckDecision;
+//! use mllp_rs::testing::MockMllpServer;
+//!
+//! let server = MockMllpServer::start([AckDecision::CommitNak, AckDecision::CommitAck]).unwrap();
+//! let mut client = MllpClient::connect(server.local_addr()).unwrap();
+//! assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Commit);
+//! assert_eq!(server.received(), vec![b"MSH|1".to_vec(), b"MSH|1".to_vec()]);
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use crate::handler::AckDecision;
+use crate::server::{MllpServer, MllpServerConfig, ShutdownHandle};
+
+pub use crate::memory::{duplex, DuplexStream, MemoryConnector, MemoryListener};
+
+/// Receiver answering with a script of acknowledgements, for tests of senders.
+///
+/// It listens on an ephemeral port of the loopback interface. Each message received, from any
+/// connection, is recorded and answered with the next [`AckDecision`] of the script, a
+/// [`AckDecision::CommitAck`] once the script is over. The server is shut down when dropped.
+#[derive(Debug)]
+pub struct MockMllpServer {
+    local_addr: SocketAddr,
+    received: Arc<Mutex<Vec<Vec<u8>>>>,
+    shutdown: ShutdownHandle,
+    serving: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl MockMllpServer {
+    /// Starts a server answering with `responses`, in order.
+    pub fn start<I: IntoIterator<Item = AckDecision>>(responses: I) -> io::Result<Self> {
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig::default())?;
+        let local_addr = server.local_addr()?;
+        let shutdown = server.shutdown_handle();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let script = Mutex::new(responses.into_iter().collect::<VecDeque<_>>());
+
+        let recorded = received.clone();
+        let serving = thread::spawn(move || {
+            server.serve(move |message: &[u8]| {
+                recorded.lock().unwrap_or_else(|e| e.into_inner()).push(message.to_vec());
+                script.lock().unwrap_or_else(|e| e.into_inner()).pop_front().unwrap_or(AckDecision::CommitAck)
+            })
+        });
+
+        Ok(MockMllpServer {
+            local_addr,
+            received,
+            shutdown,
+            serving: Some(serving),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Payloads of the messages received so far, retransmissions included, in order.
+    pub fn received(&self) -> Vec<Vec<u8>> {
+        self.received.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl Drop for MockMllpServer {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(serving) = self.serving.take() {
+            let _ = serving.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::client::{Ack, MllpClient, MllpClientConfig};
+    use crate::handler::AckDecision;
+    use crate::testing::MockMllpServer;
+    use crate::MllpError;
+
+    #[test]
+    fn it_answers_with_the_script() {
+        let application = b"MSH|^~\\&|EHR||LAB||20240131||ACK|1|P|2.5\rMSA|AA|MSG1".to_vec();
+        let server = MockMllpServer::start([AckDecision::ApplicationAck(application.clone()), AckDecision::None]).unwrap();
+        let config = MllpClientConfig {
+            ack_timeout: Some(Duration::from_millis(100)),
+            max_retries: 0,
+            ..MllpClientConfig::default()
+        };
+        let mut client = MllpClient::connect_with_config(server.local_addr(), config).unwrap();
+
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Application(application));
+        assert!(matches!(client.send(b"MSH|2"), Err(MllpError::AckTimeout)));
+        assert_eq!(client.send(b"MSH|3").unwrap(), Ack::Commit);
+        assert_eq!(server.received(), vec![b"MSH|1".to_vec(), b"MSH|2".to_vec(), b"MSH|3".to_vec()]);
+    }
+}
@@ -0,0 +1,284 @@
+//! Per-connection protocol timelines.
+//!
+//! A [`Timeline`] is an [`EventSink`] keeping every event, grouped by connection. The recorded
+//! timelines are available as data with [`Timeline::connections`], and can be rendered as a
+//! sequence diagram in plain text or HTML, which helps telling which side of a connection was
+//! slow.
+//! ```no_run
+//! use std::sync::Arc;
+//! use mllp_rs::client::{MllpClient, MllpClientConfig};
+//! use mllp_rs::timeline::Timeline;
+//!
+//! # fn main() -> Result<(), mllp_rs::MllpError> {
+//! let timeline = Arc::new(Timeline::new());
+//! let config = MllpClientConfig {
+//!     event_sink: Some(timeline.clone()),
+//!     ..MllpClientConfig::default()
+//! };
+//! let mut client = MllpClient::connect_with_config("127.0.0.1:5000", config)?;
+//! client.send(b"MSH|^~\\&|...")?;
+//!
+//! println!("{}", timeline.render_text());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use crate::event::{Event, EventKind, EventSink};
+use crate::Timeout;
+
+/// Events of one connection, in the order they happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionTimeline {
+    pub local_addr: SocketAddr,
+    pub peer_addr: SocketAddr,
+    pub entries: Vec<TimelineEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimelineEntry {
+    pub time: SystemTime,
+    pub kind: EventKind,
+}
+
+/// Recorder of per-connection timelines.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    connections: Mutex<Vec<ConnectionTimeline>>,
+}
+
+enum Direction {
+    Outbound,
+    Inbound,
+    Local,
+}
+
+/// Width of the arrows in the text rendering.
+const ARROW_WIDTH: usize = 40;
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline::default()
+    }
+
+    /// Returns a copy of the timelines recorded so far.
+    pub fn connections(&self) -> Vec<ConnectionTimeline> {
+        self.lock().clone()
+    }
+
+    /// Forgets all the recorded timelines.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Renders the timelines as plain text sequence diagrams, one per connection.
+    ///
+    /// Times are relative to the first event of each connection.
+    pub fn render_text(&self) -> String {
+        let mut out = String::new();
+
+        for connection in self.lock().iter() {
+            let _ = writeln!(
+                out,
+                "{:>10}{:<width$}{}",
+                "",
+                connection.local_addr,
+                connection.peer_addr,
+                width = ARROW_WIDTH + 2
+            );
+            let start = connection.entries.first().map(|entry| entry.time);
+            for entry in &connection.entries {
+                let offset = format_offset(start, entry.time);
+                let (direction, label) = describe(&entry.kind);
+                let line = match direction {
+                    Direction::Outbound => format!("|{}>|", centered(&label, '-', ARROW_WIDTH - 1)),
+                    Direction::Inbound => format!("|<{}|", centered(&label, '-', ARROW_WIDTH - 1)),
+                    Direction::Local => format!("|  {:<width$}|", label, width = ARROW_WIDTH - 2),
+                };
+                let _ = writeln!(out, "{:>9} {}", offset, line);
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Renders the timelines as a standalone HTML page, with one table per connection.
+    pub fn render_html(&self) -> String {
+        let mut out = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>MLLP timeline</title>\n\
+             <style>\ntable { border-collapse: collapse; margin-bottom: 2em; font-family: monospace; }\n\
+             td, th { padding: 2px 12px; }\n.outbound { text-align: right; }\n.inbound { text-align: left; }\n\
+             .local { text-align: center; font-style: italic; }\n</style>\n</head>\n<body>\n",
+        );
+
+        for connection in self.lock().iter() {
+            let _ = writeln!(
+                out,
+                "<table>\n<tr><th>time</th><th>{}</th><th></th><th>{}</th></tr>",
+                escape_html(&connection.local_addr.to_string()),
+                escape_html(&connection.peer_addr.to_string())
+            );
+            let start = connection.entries.first().map(|entry| entry.time);
+            for entry in &connection.entries {
+                let offset = format_offset(start, entry.time);
+                let (direction, label) = describe(&entry.kind);
+                let label = escape_html(&label);
+                let cell = match direction {
+                    Direction::Outbound => format!("<td class=\"outbound\">{} &rarr;</td>", label),
+                    Direction::Inbound => format!("<td class=\"inbound\">&larr; {}</td>", label),
+                    Direction::Local => format!("<td class=\"local\">{}</td>", label),
+                };
+                let _ = writeln!(out, "<tr><td>{}</td><td>|</td>{}<td>|</td></tr>", offset, cell);
+            }
+            out.push_str("</table>\n");
+        }
+
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<ConnectionTimeline>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl EventSink for Timeline {
+    fn on_event(&self, event: &Event) {
+        let mut connections = self.lock();
+        let entry = TimelineEntry {
+            time: event.time,
+            kind: event.kind.clone(),
+        };
+
+        // A connection is identified by its address pair, until it is closed.
+        let open = connections.iter_mut().rev().find(|connection| {
+            connection.local_addr == event.local_addr
+                && connection.peer_addr == event.peer_addr
+                && connection.entries.last().map(|last| &last.kind) != Some(&EventKind::Disconnected)
+        });
+        match open {
+            Some(connection) => connection.entries.push(entry),
+            None => connections.push(ConnectionTimeline {
+                local_addr: event.local_addr,
+                peer_addr: event.peer_addr,
+                entries: vec![entry],
+            }),
+        }
+    }
+}
+
+fn describe(kind: &EventKind) -> (Direction, String) {
+    match kind {
+        EventKind::Connected => (Direction::Outbound, "connect".to_owned()),
+        EventKind::Disconnected => (Direction::Outbound, "close".to_owned()),
+        EventKind::ConnectionLost { message } => (Direction::Local, format!("connection lost: {}", message)),
+        EventKind::Reconnecting { attempt } => (Direction::Local, format!("reconnecting #{}", attempt)),
+        EventKind::MessageSent { bytes } => (Direction::Outbound, format!("message ({} bytes)", bytes)),
+        EventKind::Retry { attempt } => (Direction::Local, format!("retry #{}", attempt)),
+        EventKind::AckReceived => (Direction::Inbound, "ACK".to_owned()),
+        EventKind::NakReceived => (Direction::Inbound, "NAK".to_owned()),
+        EventKind::ApplicationAckReceived { bytes } => {
+            (Direction::Inbound, format!("application ACK ({} bytes)", bytes))
+        }
+        EventKind::AckTimeout => (Direction::Local, "ACK timeout".to_owned()),
+        EventKind::Error { message } => (Direction::Local, format!("error: {}", message)),
+        EventKind::AcceptPaused { open_fds } => (Direction::Local, format!("accept paused ({} fds open)", open_fds)),
+        EventKind::AcceptResumed => (Direction::Local, "accept resumed".to_owned()),
+        EventKind::ConnectionRejected => (Direction::Local, "rejected".to_owned()),
+        EventKind::ConnectionShed => (Direction::Local, "shed".to_owned()),
+        EventKind::FirstFrameTimeout => (Direction::Local, "no first frame".to_owned()),
+        EventKind::TimedOut { timeout } => {
+            let timeout = match timeout {
+                Timeout::Connect => "connect",
+                Timeout::FirstByte => "first byte",
+                Timeout::InterByte => "inter-byte",
+                Timeout::Write => "write",
+            };
+            (Direction::Local, format!("{} timeout", timeout))
+        }
+        EventKind::ClosedMidFrame { partial_bytes } => {
+            (Direction::Inbound, format!("closed mid-frame ({} bytes discarded)", partial_bytes))
+        }
+    }
+}
+
+fn format_offset(start: Option<SystemTime>, time: SystemTime) -> String {
+    let offset = start.and_then(|start| time.duration_since(start).ok()).unwrap_or(Duration::ZERO);
+    format!("+{:.3}s", offset.as_secs_f64())
+}
+
+/// Surrounds ` label ` with `fill` characters, to a total of `width` characters.
+fn centered(label: &str, fill: char, width: usize) -> String {
+    let label = format!(" {} ", label);
+    let padding = width.saturating_sub(label.chars().count());
+    let left = padding / 2;
+    let fill = |n| std::iter::repeat_n(fill, n).collect::<String>();
+
+    format!("{}{}{}", fill(left), label, fill(padding - left))
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
+    use crate::event::{Event, EventKind, EventSink};
+    use crate::timeline::Timeline;
+
+    fn event(local_port: u16, offset_ms: u64, kind: EventKind) -> Event {
+        Event {
+            time: SystemTime::UNIX_EPOCH + Duration::from_millis(offset_ms),
+            local_addr: SocketAddr::from(([127, 0, 0, 1], local_port)),
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 2575)),
+            kind,
+        }
+    }
+
+    #[test]
+    fn it_groups_events_by_connection() {
+        let timeline = Timeline::new();
+        timeline.on_event(&event(1000, 0, EventKind::Connected));
+        timeline.on_event(&event(1001, 5, EventKind::Connected));
+        timeline.on_event(&event(1000, 10, EventKind::MessageSent { bytes: 42 }));
+        timeline.on_event(&event(1000, 20, EventKind::Disconnected));
+        timeline.on_event(&event(1000, 30, EventKind::Connected));
+
+        let connections = timeline.connections();
+        assert_eq!(connections.len(), 3);
+        assert_eq!(connections[0].entries.len(), 3);
+        assert_eq!(connections[1].entries.len(), 1);
+        assert_eq!(connections[2].entries.len(), 1);
+    }
+
+    #[test]
+    fn it_renders_text_sequence_diagram() {
+        let timeline = Timeline::new();
+        timeline.on_event(&event(1000, 0, EventKind::MessageSent { bytes: 42 }));
+        timeline.on_event(&event(1000, 1500, EventKind::AckTimeout));
+        timeline.on_event(&event(1000, 1750, EventKind::NakReceived));
+
+        let text = timeline.render_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].contains("127.0.0.1:1000") && lines[0].contains("127.0.0.1:2575"));
+        assert!(lines[1].starts_with("  +0.000s |") && lines[1].contains(" message (42 bytes) ") && lines[1].ends_with("->|"));
+        assert!(lines[2].starts_with("  +1.500s |  ACK timeout"));
+        assert!(lines[3].starts_with("  +1.750s |<-") && lines[3].contains(" NAK "));
+    }
+
+    #[test]
+    fn it_renders_html_with_escaped_labels() {
+        let timeline = Timeline::new();
+        timeline.on_event(&event(1000, 0, EventKind::Error { message: "<SB> expected".to_owned() }));
+
+        let html = timeline.render_html();
+        assert!(html.contains("error: &lt;SB&gt; expected"));
+        assert!(!html.contains("<SB>"));
+    }
+}
@@ -0,0 +1,469 @@
+//! TLS connections, with the `tls` feature.
+//!
+//! A [`TlsConnector`] set in [`MllpClientConfig::tls`](crate::client::MllpClientConfig::tls)
+//! makes the client connect over TLS. The connector keeps the sessions of the connections it
+//! opened, as configured by [`ClientConfig::resumption`], so the next connections to the same
+//! receiver resume them instead of going through a full handshake. This matters for
+//! connection-per-message deployments, where most of the time of a send would otherwise be spent
+//! in handshakes. [`TlsConnector::handshakes`] tells how many handshakes were resumed.
+//!
+//! The sessions are only resumed with the receiver they were made with: a connector is meant for
+//! one destination, e.g. set with [`MllpConnectionPool::configure`](crate::pool::MllpConnectionPool::configure).
+//! ```no_run
+//! use std::sync::Arc;
+//! use mllp_rs::client::{MllpClient, MllpClientConfig};
+//! use mllp_rs::tls::TlsConnector;
+//! use rustls::{ClientConfig, RootCertStore};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let roots = RootCertStore::empty();
+//! let tls = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+//! let connector = Arc::new(TlsConnector::new(Arc::new(tls), "lab.example.org")?);
+//! let config = MllpClientConfig {
+//!     tls: Some(connector.clone()),
+//!     ..MllpClientConfig::default()
+//! };
+//! for message in [&b"MSH|1"[..], b"MSH|2"] {
+//!     MllpClient::connect_with_config("lab.example.org:2575", config.clone())?.send(message)?;
+//! }
+//! println!("{:.0}% resumed", connector.handshakes().resumption_rate() * 100.0);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The server name of the connector is sent in the SNI extension whatever the address connected
+//! to, for the gateways routing connections by SNI, and
+//! [`TlsConnector::with_alpn_protocols`] offers ALPN protocols to those expecting one.
+//!
+//! On the server side, a [`TlsAcceptor`] set in
+//! [`MllpServerConfig::tls`](crate::server::MllpServerConfig::tls) makes the server accept TLS
+//! connections. Its certificate can be replaced while the server runs, with
+//! [`TlsAcceptor::reload_certs`] or [`TlsAcceptor::reload_pem_files`]: the connections already
+//! open are not affected, and the next handshakes present the new certificate.
+//! ```no_run
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use mllp_rs::handler::AckDecision;
+//! use mllp_rs::server::{MllpServer, MllpServerConfig};
+//! use mllp_rs::tls::TlsAcceptor;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! let (cert, key) = ("/etc/mllp/fullchain.pem", "/etc/mllp/privkey.pem");
+//! let acceptor = Arc::new(TlsAcceptor::from_pem_files(cert, key)?);
+//! let config = MllpServerConfig {
+//!     tls: Some(acceptor.clone()),
+//!     ..MllpServerConfig::default()
+//! };
+//! // pick up the certificates renewed by the ACME client
+//! std::thread::spawn(move || loop {
+//!     std::thread::sleep(Duration::from_secs(3600));
+//!     let _ = acceptor.reload_pem_files(cert, key);
+//! });
+//! MllpServer::bind("0.0.0.0:2575", config)?.serve(|_: &[u8]| AckDecision::CommitAck)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A server answering under several names, such as a name per trading partner, presents the
+//! certificate of the name the client asked for in SNI, added with
+//! [`TlsAcceptor::add_server_name`], and its default certificate to the other clients.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use rustls::client::ClientConnection;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert, ServerConnection};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, HandshakeKind, ServerConfig, StreamOwned};
+
+/// Client side of a TLS connection.
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Server side of a TLS connection.
+pub(crate) type TlsServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Opens the TLS connections to a receiver, resuming the sessions of the previous ones.
+#[derive(Debug)]
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+    full: AtomicU64,
+    resumed: AtomicU64,
+}
+
+/// Counts of the handshakes of a [`TlsConnector`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakeStats {
+    /// Handshakes establishing a new session.
+    pub full: u64,
+    /// Handshakes resuming a previous session.
+    pub resumed: u64,
+}
+
+impl HandshakeStats {
+    /// Share of the handshakes which were resumed, 0 when there was none.
+    pub fn resumption_rate(&self) -> f64 {
+        match self.full + self.resumed {
+            0 => 0.0,
+            total => self.resumed as f64 / total as f64,
+        }
+    }
+}
+
+impl TlsConnector {
+    /// Creates a connector verifying that the receiver's certificate is valid for `server_name`,
+    /// a DNS name or an IP address.
+    pub fn new(config: Arc<ClientConfig>, server_name: &str) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            .to_owned();
+
+        Ok(TlsConnector {
+            config,
+            server_name,
+            full: AtomicU64::new(0),
+            resumed: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> &Arc<ClientConfig> {
+        &self.config
+    }
+
+    /// Name sent in the SNI extension, unless an IP address, and the receiver's certificate is
+    /// verified against.
+    pub fn server_name(&self) -> &ServerName<'static> {
+        &self.server_name
+    }
+
+    /// Offers `protocols` in the ALPN extension, most preferred first, such as `b"mllp"`. A
+    /// receiver supporting ALPN but none of them fails the handshake.
+    pub fn with_alpn_protocols(mut self, protocols: Vec<Vec<u8>>) -> Self {
+        let mut config = (*self.config).clone();
+        config.alpn_protocols = protocols;
+        self.config = Arc::new(config);
+        self
+    }
+
+    pub fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.config.alpn_protocols
+    }
+
+    pub fn handshakes(&self) -> HandshakeStats {
+        HandshakeStats {
+            full: self.full.load(Ordering::Relaxed),
+            resumed: self.resumed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Goes through the handshake over `stream`.
+    pub(crate) fn connect(&self, mut stream: TcpStream) -> io::Result<TlsStream> {
+        let mut connection = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+
+        let counter = match connection.handshake_kind() {
+            Some(HandshakeKind::Resumed) => &self.resumed,
+            _ => &self.full,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/// Accepts the TLS connections of a server, with a certificate which can be replaced while the
+/// server runs.
+#[derive(Debug)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+    certificate: Arc<ReloadableCert>,
+}
+
+impl TlsAcceptor {
+    /// Creates an acceptor presenting the certificate chain `certs`, the certificate of the
+    /// server first, without client authentication.
+    pub fn new(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Self> {
+        let certificate = Arc::new(ReloadableCert {
+            default: RwLock::new(certified_key(certs, key)?),
+            by_name: RwLock::new(HashMap::new()),
+        });
+        let config = ServerConfig::builder().with_no_client_auth().with_cert_resolver(certificate.clone());
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+            certificate,
+        })
+    }
+
+    /// Creates an acceptor from the PEM files of the certificate chain and of the private key,
+    /// as written by ACME clients.
+    pub fn from_pem_files<C: AsRef<Path>, K: AsRef<Path>>(cert_path: C, key_path: K) -> io::Result<Self> {
+        let (certs, key) = read_pem_files(cert_path.as_ref(), key_path.as_ref())?;
+        Self::new(certs, key)
+    }
+
+    pub fn config(&self) -> &Arc<ServerConfig> {
+        &self.config
+    }
+
+    /// Presents `certs` in the next handshakes. If the key does not match the certificate, an
+    /// error is returned and the current certificate is kept.
+    pub fn reload_certs(&self, certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<()> {
+        let certified = certified_key(certs, key)?;
+        *self.certificate.default.write().unwrap_or_else(|e| e.into_inner()) = certified;
+
+        Ok(())
+    }
+
+    /// Presents `certs` to the clients asking for `server_name` in SNI, compared regardless of
+    /// case, instead of the default certificate. Adding a name again replaces its certificate.
+    pub fn add_server_name(&self, server_name: &str, certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<()> {
+        let certified = certified_key(certs, key)?;
+        let mut by_name = self.certificate.by_name.write().unwrap_or_else(|e| e.into_inner());
+        by_name.insert(server_name.to_ascii_lowercase(), certified);
+
+        Ok(())
+    }
+
+    /// Presents the default certificate to the clients asking for `server_name` again, and
+    /// returns whether it had its own.
+    pub fn remove_server_name(&self, server_name: &str) -> bool {
+        let mut by_name = self.certificate.by_name.write().unwrap_or_else(|e| e.into_inner());
+        by_name.remove(&server_name.to_ascii_lowercase()).is_some()
+    }
+
+    /// Same as [`TlsAcceptor::reload_certs`], from PEM files.
+    pub fn reload_pem_files<C: AsRef<Path>, K: AsRef<Path>>(&self, cert_path: C, key_path: K) -> io::Result<()> {
+        let (certs, key) = read_pem_files(cert_path.as_ref(), key_path.as_ref())?;
+        self.reload_certs(certs, key)
+    }
+
+    /// Goes through the handshake over `stream`.
+    pub(crate) fn accept(&self, mut stream: TcpStream) -> io::Result<TlsServerStream> {
+        let mut connection = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream)?;
+        }
+
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/// Certificates presented by a [`TlsAcceptor`]: those of the server names asked for in SNI,
+/// and the default one.
+#[derive(Debug)]
+struct ReloadableCert {
+    default: RwLock<Arc<CertifiedKey>>,
+    /// By lowercase server name.
+    by_name: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let by_name = self.by_name.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(certified) = hello.server_name().and_then(|name| by_name.get(&name.to_ascii_lowercase())) {
+            return Some(certified.clone());
+        }
+
+        Some(self.default.read().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+}
+
+fn certified_key(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Arc<CertifiedKey>> {
+    if certs.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no certificate"));
+    }
+    let certified = CertifiedKey::from_der(certs, key, &ring::default_provider())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    Ok(Arc::new(certified))
+}
+
+fn read_pem_files(cert_path: &Path, key_path: &Path) -> io::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(invalid)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(invalid)?;
+
+    Ok((certs, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use rustls::{ClientConfig, RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+    use crate::client::{Ack, MllpClient, MllpClientConfig};
+    use crate::handler::AckDecision;
+    use crate::server::{MllpServer, MllpServerConfig};
+    use crate::tls::{HandshakeStats, TlsAcceptor, TlsConnector};
+    use crate::{MllpCodec, MllpDecoder};
+
+    fn self_signed() -> (CertificateDer<'static>, PrivateKeyDer<'static>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()));
+        (certified.cert.der().clone(), key)
+    }
+
+    fn trusting(cert: &CertificateDer<'static>) -> MllpClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        let client_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        MllpClientConfig {
+            tls: Some(Arc::new(TlsConnector::new(Arc::new(client_config), "localhost").unwrap())),
+            ..MllpClientConfig::default()
+        }
+    }
+
+    #[test]
+    fn it_resumes_sessions() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()));
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certified.cert.der().clone()], key)
+            .unwrap();
+        let server_config = Arc::new(server_config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                let (stream, _) = listener.accept().unwrap();
+                let connection = ServerConnection::new(server_config.clone()).unwrap();
+                let mut stream = StreamOwned::new(connection, stream);
+                received.push(MllpDecoder::new().read_frame(&mut stream).unwrap());
+                stream.write_all(&MllpCodec::ack()).unwrap();
+                stream.flush().unwrap();
+            }
+            received
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let connector = Arc::new(TlsConnector::new(Arc::new(client_config), "localhost").unwrap());
+        let config = MllpClientConfig {
+            tls: Some(connector.clone()),
+            ..MllpClientConfig::default()
+        };
+
+        for message in [&b"MSH|1"[..], b"MSH|2", b"MSH|3"] {
+            let mut client = MllpClient::connect_with_config(addr, config.clone()).unwrap();
+            assert_eq!(client.send(message).unwrap(), Ack::Commit);
+        }
+
+        assert_eq!(receiver.join().unwrap(), vec![b"MSH|1".to_vec(), b"MSH|2".to_vec(), b"MSH|3".to_vec()]);
+        assert_eq!(connector.handshakes(), HandshakeStats { full: 1, resumed: 2 });
+    }
+
+    #[test]
+    fn it_reloads_server_certificates() {
+        let (old_cert, old_key) = self_signed();
+        let acceptor = Arc::new(TlsAcceptor::new(vec![old_cert.clone()], old_key).unwrap());
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            tls: Some(acceptor.clone()),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+
+        let mut open = MllpClient::connect_with_config(addr, trusting(&old_cert)).unwrap();
+        assert_eq!(open.send(b"MSH|1").unwrap(), Ack::Commit);
+
+        let (new_cert, new_key) = self_signed();
+        let (_, other_key) = self_signed();
+        assert!(acceptor.reload_certs(vec![new_cert.clone()], other_key).is_err());
+        acceptor.reload_certs(vec![new_cert.clone()], new_key).unwrap();
+
+        assert_eq!(open.send(b"MSH|2").unwrap(), Ack::Commit);
+        let mut renewed = MllpClient::connect_with_config(addr, trusting(&new_cert)).unwrap();
+        assert_eq!(renewed.send(b"MSH|3").unwrap(), Ack::Commit);
+        assert!(MllpClient::connect_with_config(addr, trusting(&old_cert)).is_err());
+    }
+
+    #[test]
+    fn it_selects_certificates_by_server_name() {
+        let (default_cert, default_key) = self_signed();
+        let partner = rcgen::generate_simple_self_signed(vec!["partner.example".to_owned()]).unwrap();
+        let partner_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(partner.signing_key.serialize_der()));
+        let acceptor = Arc::new(TlsAcceptor::new(vec![default_cert.clone()], default_key).unwrap());
+        acceptor.add_server_name("Partner.Example", vec![partner.cert.der().clone()], partner_key).unwrap();
+        let server = MllpServer::bind("127.0.0.1:0", MllpServerConfig {
+            tls: Some(acceptor.clone()),
+            ..MllpServerConfig::default()
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.serve(|_: &[u8]| AckDecision::CommitAck));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(partner.cert.der().clone()).unwrap();
+        // a fresh config each time, so that sessions are not resumed
+        let partner_config = |server_name: &str| MllpClientConfig {
+            tls: Some(Arc::new(TlsConnector::new(Arc::new(ClientConfig::builder().with_root_certificates(roots.clone()).with_no_client_auth()), server_name).unwrap())),
+            ..MllpClientConfig::default()
+        };
+        let mut client = MllpClient::connect_with_config(addr, partner_config("partner.example")).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Commit);
+        let mut default = MllpClient::connect_with_config(addr, trusting(&default_cert)).unwrap();
+        assert_eq!(default.send(b"MSH|2").unwrap(), Ack::Commit);
+
+        assert!(acceptor.remove_server_name("partner.example"));
+        assert!(MllpClient::connect_with_config(addr, partner_config("partner.example")).is_err());
+    }
+
+    #[test]
+    fn it_offers_alpn_protocols() {
+        let (cert, key) = self_signed();
+        let mut server_config = ServerConfig::builder().with_no_client_auth().with_single_cert(vec![cert.clone()], key).unwrap();
+        server_config.alpn_protocols = vec![b"mllp".to_vec()];
+        let server_config = Arc::new(server_config);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut negotiated = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = StreamOwned::new(ServerConnection::new(server_config.clone()).unwrap(), stream);
+                if MllpDecoder::new().read_frame(&mut stream).is_ok() {
+                    negotiated.push(stream.conn.alpn_protocol().map(<[u8]>::to_vec));
+                    stream.write_all(&MllpCodec::ack()).unwrap();
+                    stream.flush().unwrap();
+                }
+            }
+            negotiated
+        });
+
+        let offering = |protocol: &[u8]| {
+            let config = trusting(&cert);
+            let connector = Arc::try_unwrap(config.tls.unwrap()).unwrap().with_alpn_protocols(vec![protocol.to_vec()]);
+            assert_eq!(connector.alpn_protocols(), [protocol.to_vec()]);
+            MllpClientConfig {
+                tls: Some(Arc::new(connector)),
+                ..MllpClientConfig::default()
+            }
+        };
+        let mut client = MllpClient::connect_with_config(addr, offering(b"mllp")).unwrap();
+        assert_eq!(client.send(b"MSH|1").unwrap(), Ack::Commit);
+        assert!(MllpClient::connect_with_config(addr, offering(b"h2")).is_err());
+
+        assert_eq!(receiver.join().unwrap(), vec![Some(b"mllp".to_vec())]);
+    }
+}
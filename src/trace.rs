@@ -0,0 +1,95 @@
+//! Instrumentation with [`tracing`](https://docs.rs/tracing), with the `tracing` feature.
+//!
+//! Connections accepted by the server are `mllp.connection` spans, and messages sent or handled
+//! `mllp.message` spans, with the peer address and, for messages, the frame size and the MSH-10
+//! message control ID. Each
+//! [`EventKind`] is also a tracing event, at the `WARN` level for failures and `DEBUG`
+//! otherwise, and frames are `TRACE` events. Without the feature, all of this compiles to
+//! nothing.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use crate::event::EventKind;
+
+/// Entered span, exited when dropped.
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::span::EnteredSpan,
+}
+
+/// Enters the span of a connection accepted by the server.
+pub(crate) fn connection(local_addr: SocketAddr, peer_addr: SocketAddr) -> Span {
+    #[cfg(feature = "tracing")]
+    return Span {
+        _entered: tracing::info_span!("mllp.connection", %local_addr, %peer_addr).entered(),
+    };
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (local_addr, peer_addr);
+        Span {}
+    }
+}
+
+/// Enters the span of a message sent or received.
+pub(crate) fn message(peer_addr: SocketAddr, payload: &[u8]) -> Span {
+    #[cfg(feature = "tracing")]
+    return Span {
+        _entered: tracing::debug_span!(
+            "mllp.message",
+            %peer_addr,
+            frame_size = payload.len() + 3,
+            control_id = crate::control_id(payload).as_deref()
+        )
+        .entered(),
+    };
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (peer_addr, payload);
+        Span {}
+    }
+}
+
+pub(crate) fn event(local_addr: SocketAddr, peer_addr: SocketAddr, kind: &EventKind) {
+    #[cfg(feature = "tracing")]
+    match kind {
+        EventKind::ConnectionLost { .. }
+        | EventKind::NakReceived
+        | EventKind::AckTimeout
+        | EventKind::Error { .. }
+        | EventKind::AcceptPaused { .. }
+        | EventKind::ConnectionRejected
+        | EventKind::ConnectionShed
+        | EventKind::FirstFrameTimeout
+        | EventKind::TimedOut { .. }
+        | EventKind::ClosedMidFrame { .. } => tracing::warn!(%local_addr, %peer_addr, "{:?}", kind),
+        _ => tracing::debug!(%local_addr, %peer_addr, "{:?}", kind),
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = (local_addr, peer_addr, kind);
+}
+
+pub(crate) fn frame_encoded(frame_size: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(frame_size, "frame encoded");
+    #[cfg(not(feature = "tracing"))]
+    let _ = frame_size;
+}
+
+/// A frame was decoded, `None` for bytes which were not a frame.
+pub(crate) fn frame_decoded(frame_size: Option<usize>) {
+    #[cfg(feature = "tracing")]
+    match frame_size {
+        Some(frame_size) => tracing::trace!(frame_size, "frame decoded"),
+        None => tracing::warn!("invalid frame"),
+    }
+    #[cfg(not(feature = "tracing"))]
+    let _ = frame_size;
+}
+
+/// An acknowledgement was received `round_trip` after the message was sent.
+pub(crate) fn round_trip(round_trip: Duration) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(round_trip_ms = round_trip.as_secs_f64() * 1000.0, "acknowledged");
+    #[cfg(not(feature = "tracing"))]
+    let _ = round_trip;
+}
@@ -0,0 +1,95 @@
+//! MLLP over byte streams other than sockets: serial links, PTYs, or the streams of a vendor SDK.
+//!
+//! A [`Transport`] is a blocking `Read` and `Write` stream able to time reads out. A client
+//! connects over one with [`MllpClient::connect_transport`], given a function opening it, called
+//! again to reconnect. A server serves one with [`MllpServer::bind_transport`], as a single
+//! connection: [`MllpServer::serve`] returns once it ends.
+//! ```no_run
+//! use std::fs::{File, OpenOptions};
+//! use std::io::{self, Read, Write};
+//! use std::time::Duration;
+//! use mllp_rs::handler::AckDecision;
+//! use mllp_rs::server::{MllpServer, MllpServerConfig};
+//! use mllp_rs::transport::Transport;
+//!
+//! /// Serial port of a lab analyzer, set up beforehand with `stty`.
+//! struct SerialPort(File);
+//!
+//! impl Read for SerialPort {
+//!     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+//!         self.0.read(buf)
+//!     }
+//! }
+//!
+//! impl Write for SerialPort {
+//!     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+//!         self.0.write(buf)
+//!     }
+//!
+//!     fn flush(&mut self) -> io::Result<()> {
+//!         self.0.flush()
+//!     }
+//! }
+//!
+//! impl Transport for SerialPort {
+//!     // reads block until bytes arrive, timeouts are not enforced
+//!     fn set_read_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+//!         Ok(())
+//!     }
+//! }
+//!
+//! # fn main() -> io::Result<()> {
+//! let port = SerialPort(OpenOptions::new().read(true).write(true).open("/dev/ttyS0")?);
+//! let server = MllpServer::bind_transport(port, MllpServerConfig::default())?;
+//! server.serve(|_: &[u8]| AckDecision::CommitAck)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Transports have no address, and are given the unspecified address `0.0.0.0`, like the
+//! connections over a Unix domain socket. Asynchronous transports need nothing of the kind:
+//! an [`MllpStream`](crate::stream::MllpStream) wraps any `AsyncRead` and `AsyncWrite`.
+//!
+//! [`MllpClient::connect_transport`]: crate::client::MllpClient::connect_transport
+//! [`MllpServer::bind_transport`]: crate::server::MllpServer::bind_transport
+//! [`MllpServer::serve`]: crate::server::MllpServer::serve
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::time::Duration;
+use crate::memory::DuplexStream;
+
+/// Byte stream carrying MLLP frames.
+pub trait Transport: Read + Write + Send {
+    /// Sets how long reading waits for bytes, `None` waiting forever, a read timing out failing
+    /// with [`io::ErrorKind::WouldBlock`] or [`io::ErrorKind::TimedOut`].
+    ///
+    /// A stream which cannot time out may ignore it, the timeouts of the client and the server
+    /// then being left unenforced, and a server shut down only once the stream ends.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Ends the stream, as closing a connection would. Does nothing unless implemented.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        (**self).close()
+    }
+}
+
+impl Transport for DuplexStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        DuplexStream::set_read_timeout(self, timeout)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
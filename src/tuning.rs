@@ -0,0 +1,285 @@
+//! Configuration suggestions from the traffic actually seen.
+//!
+//! [`TuningSampler`] is a [`Metrics`] receiver keeping the most recent frame sizes, ACK
+//! latencies and bursts of messages, in fixed memory. Once the connections have run for a while
+//! under a representative load, [`TuningSampler::tuning_report`] tells what was seen and which
+//! settings would fit it.
+//! ```no_run
+//! use std::sync::Arc;
+//! use mllp_rs::server::MllpServerConfig;
+//! use mllp_rs::tuning::TuningSampler;
+//!
+//! let sampler = Arc::new(TuningSampler::new());
+//! let config = MllpServerConfig {
+//!     metrics: Some(sampler.clone()),
+//!     ..MllpServerConfig::default()
+//! };
+//! // ... after a day of traffic
+//! println!("{}", sampler.tuning_report());
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::metrics::{Counter, Histogram, Metrics};
+
+/// Number of samples kept of each kind, the oldest being dropped first.
+pub const SAMPLES: usize = 1024;
+
+/// Messages closer than this belong to the same burst.
+const BURST_GAP: Duration = Duration::from_millis(10);
+
+/// Fewest samples a suggestion is made from.
+const MIN_SAMPLES: usize = 20;
+
+/// Peak of connections from which a pool of worker threads is suggested.
+const WORKER_POOL_CONNECTIONS: usize = 100;
+
+/// [`Metrics`] receiver sampling the traffic for a [`TuningReport`].
+#[derive(Debug, Default)]
+pub struct TuningSampler {
+    samples: Mutex<Samples>,
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    frame_sizes: VecDeque<usize>,
+    ack_latencies: VecDeque<Duration>,
+    /// Completed bursts: number of messages, and time from the first to the last.
+    bursts: VecDeque<(usize, Duration)>,
+    /// First and last message, and number of messages, of the burst going on.
+    burst: Option<(Instant, Instant, usize)>,
+    messages: u64,
+    open_connections: usize,
+    peak_connections: usize,
+}
+
+/// Pushes `sample`, dropping the oldest one once [`SAMPLES`] are kept.
+fn push<T>(samples: &mut VecDeque<T>, sample: T) {
+    if samples.len() == SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+impl Samples {
+    fn message(&mut self, now: Instant) {
+        self.messages += 1;
+        self.burst = match self.burst.take() {
+            Some((first, last, count)) if now.duration_since(last) < BURST_GAP => Some((first, now, count + 1)),
+            Some((first, last, count)) => {
+                push(&mut self.bursts, (count, last.duration_since(first)));
+                Some((now, now, 1))
+            }
+            None => Some((now, now, 1)),
+        };
+    }
+}
+
+impl TuningSampler {
+    pub fn new() -> Self {
+        TuningSampler::default()
+    }
+
+    /// What was sampled so far, and the settings suggested from it.
+    pub fn tuning_report(&self) -> TuningReport {
+        let samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        let mut report = TuningReport {
+            messages: samples.messages,
+            frame_size_p50: percentile(&samples.frame_sizes, 0.5),
+            frame_size_p99: percentile(&samples.frame_sizes, 0.99),
+            ack_latency_p50: percentile(&samples.ack_latencies, 0.5),
+            ack_latency_p99: percentile(&samples.ack_latencies, 0.99),
+            mean_burst_len: match samples.bursts.len() {
+                0 => 0.0,
+                len => samples.bursts.iter().map(|(count, _)| *count).sum::<usize>() as f64 / len as f64,
+            },
+            peak_connections: samples.peak_connections,
+            suggestions: Vec::new(),
+        };
+
+        if let Some(p99) = report.ack_latency_p99.filter(|_| samples.ack_latencies.len() >= MIN_SAMPLES) {
+            let timeout = Duration::from_secs((p99 * 4).as_secs_f64().ceil().max(1.0) as u64);
+            report.suggest(
+                "MllpClientConfig::ack_timeout",
+                format!("Some(Duration::from_secs({}))", timeout.as_secs()),
+                format!("4 times the 99th percentile ACK latency, {:?}", p99),
+            );
+        }
+
+        let frame_size = report.frame_size_p99.unwrap_or(0);
+        if samples.bursts.len() >= MIN_SAMPLES && report.mean_burst_len >= 4.0 {
+            let burst_bytes = (frame_size as f64 * report.mean_burst_len) as usize;
+            let max_bytes = burst_bytes.next_power_of_two().clamp(4 * 1024, 1024 * 1024);
+            let durations = samples.bursts.iter().map(|(_, duration)| *duration).collect();
+            let max_delay = percentile(&durations, 0.5)
+                .unwrap_or_default()
+                .clamp(Duration::from_millis(1), BURST_GAP);
+            report.suggest(
+                "MllpServerConfig::write_coalescing",
+                format!("Some(WriteCoalescing {{ max_delay: {:?}, max_bytes: {} }})", max_delay, max_bytes),
+                format!("messages arrive in bursts of {:.1} on average", report.mean_burst_len),
+            );
+        }
+
+        if report.peak_connections > 0 {
+            let max_connections = (report.peak_connections * 2).max(16);
+            report.suggest(
+                "MllpServerConfig::max_connections",
+                format!("Some({})", max_connections),
+                format!("twice the peak of {} connections", report.peak_connections),
+            );
+        }
+        if report.peak_connections >= WORKER_POOL_CONNECTIONS {
+            let threads = thread::available_parallelism().map_or(4, |threads| threads.get());
+            report.suggest(
+                "MllpServerConfig::worker_threads",
+                format!("Some({})", threads),
+                format!("{} connections open at once, a thread each", report.peak_connections),
+            );
+        }
+
+        report
+    }
+}
+
+impl Metrics for TuningSampler {
+    fn increment(&self, counter: Counter) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        match counter {
+            Counter::MessagesSent | Counter::MessagesReceived => samples.message(Instant::now()),
+            Counter::ConnectionsOpened => {
+                samples.open_connections += 1;
+                samples.peak_connections = samples.peak_connections.max(samples.open_connections);
+            }
+            Counter::ConnectionsClosed => samples.open_connections = samples.open_connections.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        let mut samples = self.samples.lock().unwrap_or_else(|e| e.into_inner());
+        match histogram {
+            Histogram::FrameSize => push(&mut samples.frame_sizes, value as usize),
+            Histogram::AckLatency => push(&mut samples.ack_latencies, Duration::from_secs_f64(value.max(0.0))),
+        }
+    }
+}
+
+/// Value at `rank`, between 0 and 1, of the sorted `samples`.
+fn percentile<T: Copy + Ord>(samples: &VecDeque<T>, rank: f64) -> Option<T> {
+    let mut sorted: Vec<T> = samples.iter().copied().collect();
+    sorted.sort_unstable();
+    let index = ((sorted.len() as f64 - 1.0) * rank).round() as usize;
+
+    sorted.get(index).copied()
+}
+
+/// Traffic seen by a [`TuningSampler`], over its most recent [`SAMPLES`] samples, and the
+/// settings suggested from it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningReport {
+    /// Messages sent or received since the sampler was created.
+    pub messages: u64,
+    /// Median frame size in bytes.
+    pub frame_size_p50: Option<usize>,
+    pub frame_size_p99: Option<usize>,
+    pub ack_latency_p50: Option<Duration>,
+    pub ack_latency_p99: Option<Duration>,
+    /// Messages per burst, messages less than 10 ms apart making a burst. 0 before the first
+    /// burst is over.
+    pub mean_burst_len: f64,
+    /// Most connections open at once.
+    pub peak_connections: usize,
+    /// Suggested settings, none if nothing stands out or too little was sampled.
+    pub suggestions: Vec<Suggestion>,
+}
+
+impl TuningReport {
+    fn suggest(&mut self, setting: &'static str, value: String, reason: String) {
+        self.suggestions.push(Suggestion { setting, value, reason });
+    }
+}
+
+impl fmt::Display for TuningReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "messages: {}", self.messages)?;
+        if let (Some(p50), Some(p99)) = (self.frame_size_p50, self.frame_size_p99) {
+            writeln!(f, "frame size: {} bytes median, {} bytes p99", p50, p99)?;
+        }
+        if let (Some(p50), Some(p99)) = (self.ack_latency_p50, self.ack_latency_p99) {
+            writeln!(f, "ACK latency: {:?} median, {:?} p99", p50, p99)?;
+        }
+        writeln!(f, "mean burst: {:.1} messages", self.mean_burst_len)?;
+        writeln!(f, "peak connections: {}", self.peak_connections)?;
+        for suggestion in &self.suggestions {
+            writeln!(f, "suggested {} = {} ({})", suggestion.setting, suggestion.value, suggestion.reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Setting suggested by a [`TuningReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    /// Configuration field, such as `MllpClientConfig::ack_timeout`.
+    pub setting: &'static str,
+    /// Suggested value, as Rust code.
+    pub value: String,
+    /// What the suggestion is based on.
+    pub reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use crate::metrics::{Counter, Histogram, Metrics};
+    use crate::tuning::TuningSampler;
+
+    #[test]
+    fn it_suggests_settings_from_samples() {
+        let sampler = TuningSampler::new();
+        for _ in 0..150 {
+            sampler.increment(Counter::ConnectionsOpened);
+        }
+        for _ in 0..100 {
+            sampler.increment(Counter::ConnectionsClosed);
+        }
+        for latency in 1..=100 {
+            sampler.observe(Histogram::AckLatency, latency as f64 / 100.0);
+            sampler.observe(Histogram::FrameSize, 1000.0);
+        }
+        for _ in 0..25 {
+            for _ in 0..5 {
+                sampler.increment(Counter::MessagesReceived);
+            }
+            thread::sleep(Duration::from_millis(15));
+        }
+
+        let report = sampler.tuning_report();
+        assert_eq!(report.messages, 125);
+        assert_eq!(report.peak_connections, 150);
+        assert_eq!(report.frame_size_p99, Some(1000));
+        assert_eq!(report.ack_latency_p99, Some(Duration::from_millis(990)));
+        assert!(report.mean_burst_len >= 4.0, "{}", report);
+
+        let value = |setting: &str| {
+            report.suggestions.iter().find(|suggestion| suggestion.setting == setting).map(|suggestion| suggestion.value.clone())
+        };
+        assert_eq!(value("MllpClientConfig::ack_timeout").unwrap(), "Some(Duration::from_secs(4))");
+        assert!(value("MllpServerConfig::write_coalescing").unwrap().contains("max_bytes: 8192"));
+        assert_eq!(value("MllpServerConfig::max_connections").unwrap(), "Some(300)");
+        assert!(value("MllpServerConfig::worker_threads").is_some());
+    }
+
+    #[test]
+    fn it_suggests_nothing_without_samples() {
+        let report = TuningSampler::new().tuning_report();
+        assert!(report.suggestions.is_empty());
+        assert_eq!(report.frame_size_p50, None);
+    }
+}
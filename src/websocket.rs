@@ -0,0 +1,524 @@
+//! MLLP tunnelled over WebSocket, with the `websocket` feature.
+//!
+//! Some HL7 endpoints, mostly cloud-hosted ones, are only reachable through WebSocket, often over
+//! TLS (`wss://`). Each WebSocket binary message carries MLLP-framed bytes, `<SB>...<EB><CR>`, so
+//! the peers keep the framing and acknowledgements of MLLP. A frame may also be split across
+//! messages, or several frames sent in one.
+//!
+//! The client side is [`MllpWebSocket::connect`], or [`MllpWebSocket::client`] over a stream set
+//! up by the caller. The server side is [`serve`], answering the messages of each connection with
+//! an [`MllpHandler`] on a thread of its own.
+//! ```no_run
+//! use mllp_rs::websocket::MllpWebSocket;
+//!
+//! # fn main() -> Result<(), mllp_rs::MllpError> {
+//! let mut socket = MllpWebSocket::connect("ws://hl7.example.org/mllp")?;
+//! let ack = socket.send(b"MSH|^~\\&|")?;
+//! socket.close()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only what MLLP needs of [RFC 6455](https://www.rfc-editor.org/rfc/rfc6455) is implemented: no
+//! extensions nor subprotocols, and text messages are taken as binary ones.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use crate::client::Ack;
+use crate::handler::MllpHandler;
+use crate::server::accept_retrying;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsAcceptor, TlsConnector, TlsStream};
+use crate::proxy::read_head;
+use crate::{base64, MllpCodec, MllpDecoder, MllpError, ACK, NAK};
+
+/// Appended to the key of the client to compute the accept header of the server.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest WebSocket frame accepted.
+const MAX_FRAME: u64 = 64 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+/// Status code of a close frame ending the connection normally.
+const NORMAL_CLOSURE: u16 = 1000;
+
+/// MLLP frames sent and received as WebSocket messages over `S`.
+///
+/// Pings are answered while reading. A close frame from the peer is answered, and reported as a
+/// [`MllpError::ConnectionClosed`] error.
+#[derive(Debug)]
+pub struct MllpWebSocket<S> {
+    stream: S,
+    /// Frames sent by a client are masked, those sent by a server are not.
+    client: bool,
+    decoder: MllpDecoder,
+    /// A close frame was received.
+    closed: bool,
+    /// A close frame was sent.
+    closing: bool,
+}
+
+impl MllpWebSocket<TcpStream> {
+    /// Connects to a `ws://` URL.
+    pub fn connect(url: &str) -> io::Result<Self> {
+        let (addr, host, path) = split_url(url, "ws://", 80)?;
+        MllpWebSocket::client(TcpStream::connect(addr)?, host, path)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl MllpWebSocket<TlsStream> {
+    /// Connects to a `wss://` URL. The certificate of the server is verified against the server
+    /// name of `connector`.
+    pub fn connect_tls(url: &str, connector: &TlsConnector) -> io::Result<Self> {
+        let (addr, host, path) = split_url(url, "wss://", 443)?;
+        let stream = connector.connect(TcpStream::connect(addr)?)?;
+        MllpWebSocket::client(stream, host, path)
+    }
+}
+
+impl<S: Read + Write> MllpWebSocket<S> {
+    /// Goes through the client side of the handshake over `stream`, asking for `path` on `host`.
+    pub fn client(mut stream: S, host: &str, path: &str) -> io::Result<Self> {
+        let key = base64(&[crate::random().to_be_bytes(), crate::random().to_be_bytes()].concat());
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        )?;
+        stream.flush()?;
+
+        let head = read_head(&mut stream)?;
+        let status = head.split(' ').nth(1);
+        if status != Some("101") {
+            return Err(invalid(format!("handshake refused: {}", head.lines().next().unwrap_or_default())));
+        }
+        if header(&head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+            return Err(invalid("handshake accept key mismatch"));
+        }
+
+        Ok(MllpWebSocket::new(stream, true))
+    }
+
+    /// Goes through the server side of the handshake over `stream`, refusing requests which are
+    /// not a WebSocket upgrade.
+    pub fn server(mut stream: S) -> io::Result<Self> {
+        let head = read_head(&mut stream)?;
+        let upgrade = header(&head, "Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+        let key = header(&head, "Sec-WebSocket-Key");
+        let (true, Some(key), true) = (head.starts_with("GET "), key, upgrade) else {
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+            return Err(invalid("not a WebSocket upgrade request"));
+        };
+
+        write!(
+            stream,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        )?;
+        stream.flush()?;
+
+        Ok(MllpWebSocket::new(stream, false))
+    }
+
+    /// Sends `payload` and waits for the frame answering it, like
+    /// [`MllpClient::send`](crate::client::MllpClient::send) with no acknowledgement mode: a
+    /// commit ACK gives [`Ack::Commit`], a commit NAK a [`MllpError::Nak`] error, and any other
+    /// frame [`Ack::Application`]. Timeouts are those set on the stream.
+    pub fn send(&mut self, payload: &[u8]) -> Result<Ack, MllpError> {
+        self.write_frame(payload)?;
+
+        match self.read_frame()?.as_slice() {
+            [ACK] => Ok(Ack::Commit),
+            [NAK] => Err(MllpError::Nak),
+            frame => Ok(Ack::Application(frame.to_vec())),
+        }
+    }
+
+    /// Sends `payload`, framed, in a binary message.
+    pub fn write_frame(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_message(BINARY, &MllpCodec::encode(payload))
+    }
+
+    /// Reads messages until a complete frame is received, and returns its payload.
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, MllpError> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame() {
+                return Ok(frame?);
+            }
+            if self.closed {
+                return Err(MllpError::ConnectionClosed { partial_bytes: self.decoder.clear() });
+            }
+
+            let (opcode, payload) = self.read_message()?;
+            match opcode {
+                CONTINUATION | TEXT | BINARY => self.decoder.extend(&payload),
+                PING => self.write_message(PONG, &payload)?,
+                PONG => {}
+                CLOSE => {
+                    self.closed = true;
+                    if !self.closing {
+                        // echoes the status code
+                        self.closing = true;
+                        self.write_message(CLOSE, &payload[..payload.len().min(2)])?;
+                    }
+                }
+                opcode => return Err(invalid(format!("unknown opcode {:#x}", opcode)).into()),
+            }
+        }
+    }
+
+    /// Answers the messages received with `handler` until the peer closes the connection.
+    /// Bytes which are not a valid frame are skipped.
+    pub fn respond<H: MllpHandler + ?Sized>(&mut self, handler: &H) -> Result<(), MllpError> {
+        loop {
+            match self.read_frame() {
+                Ok(payload) => {
+                    if let Some(frame) = handler.on_message(&payload).to_frame() {
+                        self.write_message(BINARY, &frame)?;
+                    }
+                }
+                Err(MllpError::Syntax(_)) => continue,
+                Err(MllpError::ConnectionClosed { partial_bytes: 0 }) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Sends a close frame. The peer answers with one of its own, read by
+    /// [`MllpWebSocket::read_frame`].
+    pub fn close(&mut self) -> io::Result<()> {
+        self.closing = true;
+        self.write_message(CLOSE, &NORMAL_CLOSURE.to_be_bytes())
+    }
+}
+
+impl<S> MllpWebSocket<S> {
+    fn new(stream: S, client: bool) -> Self {
+        MllpWebSocket {
+            stream,
+            client,
+            decoder: MllpDecoder::new(),
+            closed: false,
+            closing: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Reading or writing through the stream directly mixes up the framing.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read + Write> MllpWebSocket<S> {
+    fn write_message(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mask = if self.client { 0x80 } else { 0 };
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(mask | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(mask | 126);
+                frame.extend((len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask | 127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+
+        if self.client {
+            let key = (crate::random() as u32).to_be_bytes();
+            frame.extend(key);
+            frame.extend(payload.iter().zip(key.iter().cycle()).map(|(byte, key)| byte ^ key));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    /// Reads a WebSocket frame, and returns its opcode and unmasked payload.
+    fn read_message(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut head = [0u8; 2];
+        self.stream.read_exact(&mut head)?;
+        if head[0] & 0x70 != 0 {
+            return Err(invalid("reserved bits set without extension"));
+        }
+        let masked = head[1] & 0x80 != 0;
+        if masked == self.client {
+            return Err(invalid("frame masked by the wrong side"));
+        }
+
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut len = [0u8; 2];
+                self.stream.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                self.stream.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        if len > MAX_FRAME {
+            return Err(invalid(format!("frame of {} bytes", len)));
+        }
+
+        let mut key = [0u8; 4];
+        if masked {
+            self.stream.read_exact(&mut key)?;
+        }
+        let mut payload = vec![0u8; len as usize];
+        self.stream.read_exact(&mut payload)?;
+        if masked {
+            payload.iter_mut().zip(key.iter().cycle()).for_each(|(byte, key)| *byte ^= key);
+        }
+
+        Ok((head[0] & 0x0F, payload))
+    }
+}
+
+/// Accepts WebSocket connections on `listener`, and answers their messages with `handler`, each
+/// connection on a thread of its own. Returns when the listener fails.
+pub fn serve<H: MllpHandler + 'static>(listener: TcpListener, handler: H) -> io::Result<()> {
+    serve_with(listener, handler, Ok)
+}
+
+/// Same as [`serve`], for `wss://` URLs.
+#[cfg(feature = "tls")]
+pub fn serve_tls<H: MllpHandler + 'static>(listener: TcpListener, acceptor: Arc<TlsAcceptor>, handler: H) -> io::Result<()> {
+    serve_with(listener, handler, move |stream| acceptor.accept(stream))
+}
+
+fn serve_with<H, S, F>(listener: TcpListener, handler: H, secure: F) -> io::Result<()>
+where
+    H: MllpHandler + 'static,
+    S: Read + Write,
+    F: Fn(TcpStream) -> io::Result<S> + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let secure = Arc::new(secure);
+
+    loop {
+        let (stream, _) = accept_retrying(|| listener.accept())?;
+        let handler = handler.clone();
+        let secure = secure.clone();
+        thread::spawn(move || {
+            let socket = secure(stream).and_then(MllpWebSocket::server);
+            if let Ok(mut socket) = socket {
+                let _ = socket.respond(handler.as_ref());
+            }
+        });
+    }
+}
+
+/// Splits a URL into the address to connect to, the host and the path.
+fn split_url<'a>(url: &'a str, scheme: &str, default_port: u16) -> io::Result<(String, &'a str, &'a str)> {
+    let rest = url
+        .get(..scheme.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(scheme))
+        .map(|_| &url[scheme.len()..])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("expected a {} URL", scheme)))?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "URL without host"));
+    }
+
+    // the port follows the last colon, out of the brackets of an IPv6 address
+    let addr = match host.rsplit(']').next().is_some_and(|tail| tail.contains(':')) {
+        true => host.to_string(),
+        false => format!("{}:{}", host, default_port),
+    };
+
+    Ok((addr, host, path))
+}
+
+/// Value of the header `name` of an HTTP head.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
+
+/// `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` of a client.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, HANDSHAKE_GUID).as_bytes()))
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// SHA-1 digest, which the handshake is defined with. Not used for anything else.
+fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = bytes.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((bytes.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in state.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use crate::client::Ack;
+    use crate::handler::AckDecision;
+    use crate::{MllpCodec, MllpError};
+    use super::{accept_key, serve, split_url, MllpWebSocket, BINARY, PING};
+
+    #[test]
+    fn it_computes_the_accept_key_of_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn it_splits_urls() {
+        assert_eq!(split_url("ws://example.org", "ws://", 80).unwrap(), ("example.org:80".to_string(), "example.org", "/"));
+        assert_eq!(split_url("WSS://[::1]:8443/hl7?x=1", "wss://", 443).unwrap(), ("[::1]:8443".to_string(), "[::1]:8443", "/hl7?x=1"));
+        assert_eq!(split_url("wss://[::1]/hl7", "wss://", 443).unwrap().0, "[::1]:443");
+        assert!(split_url("http://example.org", "ws://", 80).is_err());
+        assert!(split_url("ws:///hl7", "ws://", 80).is_err());
+    }
+
+    #[test]
+    fn it_sends_messages_to_a_websocket_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            serve(listener, |message: &[u8]| match message {
+                b"MSH|1" => AckDecision::CommitAck,
+                b"MSH|2" => AckDecision::ApplicationAck(b"MSA|AA".to_vec()),
+                _ => AckDecision::CommitNak,
+            })
+        });
+
+        let mut socket = MllpWebSocket::connect(&format!("ws://{}/mllp", addr)).unwrap();
+        assert_eq!(socket.send(b"MSH|1").unwrap(), Ack::Commit);
+        assert_eq!(socket.send(b"MSH|2").unwrap(), Ack::Application(b"MSA|AA".to_vec()));
+        // large enough for a 64-bit length
+        assert!(matches!(socket.send(&vec![b'x'; 70_000]), Err(MllpError::Nak)));
+
+        socket.close().unwrap();
+        assert!(matches!(socket.read_frame(), Err(MllpError::ConnectionClosed { partial_bytes: 0 })));
+    }
+
+    #[test]
+    fn it_reads_frames_split_across_messages_and_answers_pings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut socket = MllpWebSocket::server(stream).unwrap();
+            let frame = MllpCodec::encode(b"MSH|^~\\&|");
+            socket.write_message(BINARY, &frame[..4]).unwrap();
+            socket.write_message(PING, b"still there?").unwrap();
+            socket.write_message(BINARY, &frame[4..]).unwrap();
+            socket.read_message().unwrap()
+        });
+
+        let mut socket = MllpWebSocket::client(TcpStream::connect(addr).unwrap(), "localhost", "/").unwrap();
+        assert_eq!(socket.read_frame().unwrap(), b"MSH|^~\\&|");
+        assert_eq!(server.join().unwrap(), (super::PONG, b"still there?".to_vec()));
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn it_sends_messages_over_tls() {
+        use std::sync::Arc;
+        use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+        use rustls::{ClientConfig, RootCertStore};
+        use crate::tls::{TlsAcceptor, TlsConnector};
+        use super::serve_tls;
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()));
+        let acceptor = Arc::new(TlsAcceptor::new(vec![certified.cert.der().clone()], key).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_tls(listener, acceptor, |_: &[u8]| AckDecision::CommitAck));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let connector = TlsConnector::new(Arc::new(config), "localhost").unwrap();
+        let mut socket = MllpWebSocket::connect_tls(&format!("wss://{}/mllp", addr), &connector).unwrap();
+        assert_eq!(socket.send(b"MSH|1").unwrap(), Ack::Commit);
+    }
+
+    #[test]
+    fn it_refuses_plain_http_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || MllpWebSocket::server(listener.accept().unwrap().0).map(|_| ()));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(server.join().unwrap().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
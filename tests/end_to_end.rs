@@ -0,0 +1,87 @@
+//! The example sender and receiver, delivering a drop folder to an archive and a webhook.
+#![cfg(all(feature = "cli", feature = "archive", feature = "prometheus"))]
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use mllp_rs::archive::ArchiveReader;
+
+/// Kills the process when dropped, for the test not to leave it behind when failing.
+struct Killed(Child);
+
+impl Drop for Killed {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn it_delivers_dropped_files_to_the_archive() {
+    let dir = env::temp_dir().join(format!("mllp-rs-end-to-end-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let (drop_dir, spool_dir, archive) = (dir.join("drop"), dir.join("spool"), dir.join("archive.gz"));
+    fs::create_dir_all(&drop_dir).unwrap();
+    fs::write(drop_dir.join("1.hl7"), "MSH|^~\\&|LAB||EHR||20240131||ORU^R01|MSG1|P|2.5\rPID|1").unwrap();
+    fs::write(drop_dir.join("2.hl7"), "MSH|^~\\&|LAB||EHR||20240131||ORU^R01|MSG2|P|2.5\rPID|2").unwrap();
+
+    let webhook = TcpListener::bind("127.0.0.1:0").unwrap();
+    let webhook_url = format!("http://{}/mllp", webhook.local_addr().unwrap());
+    let (notified, notifications) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in webhook.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0u8; 4096];
+            // the JSON body ends the request
+            while !request.ends_with(b"}") {
+                match stream.read(&mut chunk).unwrap() {
+                    0 => break,
+                    n => request.extend_from_slice(&chunk[..n]),
+                }
+            }
+            let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+            let _ = notified.send(String::from_utf8_lossy(&request).into_owned());
+        }
+    });
+
+    let mut receiver = Killed(
+        Command::new(env!("CARGO_BIN_EXE_mllp-receiver"))
+            .args(["--listen", "127.0.0.1:0", "--webhook", &webhook_url, "--archive"])
+            .arg(&archive)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap(),
+    );
+    let mut line = String::new();
+    BufReader::new(receiver.0.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    let addr = line.trim().strip_prefix("listening on ").unwrap().to_owned();
+
+    let _sender = Killed(
+        Command::new(env!("CARGO_BIN_EXE_mllp-sender"))
+            .args(["--to", &addr, "--poll-ms", "50", "--drop"])
+            .arg(&drop_dir)
+            .arg("--spool")
+            .arg(&spool_dir)
+            .spawn()
+            .unwrap(),
+    );
+
+    let bodies: Vec<String> = (0..2).map(|_| notifications.recv_timeout(Duration::from_secs(10)).unwrap()).collect();
+    assert!(bodies[0].ends_with("{\"control_id\":\"MSG1\",\"message_type\":\"ORU^R01\"}"), "{}", bodies[0]);
+    assert!(bodies[1].contains("\"control_id\":\"MSG2\""));
+
+    let started = Instant::now();
+    while fs::read_dir(&drop_dir).unwrap().next().is_some() && started.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(20));
+    }
+    let records: Vec<_> = ArchiveReader::open(&archive).unwrap().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 2);
+    assert!(records[0].1.starts_with(b"MSH|^~\\&|LAB||EHR||20240131||ORU^R01|MSG1|"));
+    let _ = fs::remove_dir_all(dir);
+}